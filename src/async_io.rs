@@ -0,0 +1,438 @@
+// Decoding and encoding over futures-io's async IO traits, to go with any
+// runtime (a thin adapter for tokio's own is in `tokio_io`), built on the
+// sans-IO cores so that services don't have to `spawn_blocking` around the
+// whole thing
+
+use std::io::SeekFrom;
+
+use futures_util::io::AsyncWriteExt;
+use futures_util::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite};
+
+use crate::common::{calc_sr_u64, duration_to_samples, samples_to_duration};
+use crate::common::{OGG_OPUS_SPS, UNSET_GRANULE};
+use crate::decode::{BISECT_SPAN, READ_SIZE};
+use crate::edit::PRE_ROLL;
+use crate::page::{check_page, PageInfo, CAPTURE_PATTERN, HEADER_SIZE};
+use crate::{
+    DecodeEvent, DecodeOptions, DecodedFrame, DecoderCore, EncodeReport, EncoderCore,
+    EncoderOptions, Error, LossReport, PlayData, Tags,
+};
+
+/**Same as `decode`, reading from an `AsyncRead` (an async-std or smol file,
+the body of an upload ...) instead of blocking on it. Decoding itself happens right there, a
+page at a time between reads, and so doesn't hold the runtime for longer than
+a packet takes*/
+pub async fn decode_async<T: AsyncRead + Unpin, const TARGET_SPS: u32>(
+    data: T,
+) -> Result<(Vec<i16>, PlayData), Error> {
+    decode_async_with_options::<T, TARGET_SPS>(data, &DecodeOptions::default()).await
+}
+
+/// Same as `decode_async`, with `DecodeOptions`
+pub async fn decode_async_with_options<T: AsyncRead + Unpin, const TARGET_SPS: u32>(
+    data: T,
+    options: &DecodeOptions,
+) -> Result<(Vec<i16>, PlayData), Error> {
+    let mut decoder = AsyncDecoder::<T, TARGET_SPS>::with_options(data, options).await?;
+    let play_data = decoder.play_data.clone();
+
+    let mut out = Vec::new();
+    while let Some(frame) = decoder.next_frame().await? {
+        // Chains can only go in one buffer if they agree
+        if decoder.play_data.channels != play_data.channels {
+            return Err(Error::MalformedAudio);
+        }
+        out.extend_from_slice(&frame.samples);
+        decoder.recycle(frame);
+    }
+    Ok((out, play_data))
+}
+
+/// Same as `Decoder`, over an `AsyncRead`. Over an `AsyncSeek` too (a file,
+/// an object store reader making range requests ...) it can seek
+pub struct AsyncDecoder<T, const TARGET_SPS: u32> {
+    data: T,
+    core: DecoderCore<TARGET_SPS>,
+    play_data: PlayData,
+    // Bytes read from `data` before any seek, to know where the stream starts
+    fed: u64,
+    start: Option<u64>,
+}
+
+impl<T: AsyncRead + Unpin, const TARGET_SPS: u32> AsyncDecoder<T, TARGET_SPS> {
+    /// Reads the headers, audio is decoded as it's asked for
+    pub async fn new(data: T) -> Result<Self, Error> {
+        Self::with_options(data, &DecodeOptions::default()).await
+    }
+
+    pub async fn with_options(mut data: T, options: &DecodeOptions) -> Result<Self, Error> {
+        let mut core = DecoderCore::new(options);
+        let mut fed = 0;
+        loop {
+            match core.poll()? {
+                Some(DecodeEvent::Headers(play_data)) => {
+                    return Ok(Self {
+                        data,
+                        core,
+                        play_data,
+                        fed,
+                        start: None,
+                    })
+                }
+                Some(_) => return Err(Error::MalformedAudio),
+                None => match feed(&mut data, &mut core).await? {
+                    // Cut before the headers were through
+                    0 if !core.has_head() => return Err(Error::MissingOpusHead),
+                    0 => return Err(Error::MissingOpusTags),
+                    read => fed += read as u64,
+                },
+            }
+        }
+    }
+
+    pub fn play_data(&self) -> &PlayData {
+        &self.play_data
+    }
+
+    /// See `DecoderCore::loss_report`
+    pub fn loss_report(&self) -> &LossReport {
+        self.core.loss_report()
+    }
+
+    /// See `DecoderCore::recycle`
+    pub fn recycle(&mut self, frame: DecodedFrame) {
+        self.core.recycle(frame);
+    }
+
+    /// Next frame with any audio left after trimming, `None` at the end
+    pub async fn next_frame(&mut self) -> Result<Option<DecodedFrame>, Error> {
+        loop {
+            match self.core.poll()? {
+                Some(DecodeEvent::Frame(frame)) => return Ok(Some(frame)),
+                // Another stream might be chained after this one
+                Some(DecodeEvent::End) => {}
+                Some(DecodeEvent::Headers(play_data)) => self.play_data = play_data,
+                None => match feed(&mut self.data, &mut self.core).await? {
+                    0 => {
+                        self.core.input_ended()?;
+                        return Ok(None);
+                    }
+                    read => self.fed += read as u64,
+                },
+            }
+        }
+    }
+
+    /// Gives back the source, read as far as decoding got
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+}
+
+impl<T: AsyncRead + AsyncSeek + Unpin, const TARGET_SPS: u32> AsyncDecoder<T, TARGET_SPS> {
+    /// Same as `Decoder::seek_to_sample`. Every step of the bisection is a
+    /// seek and a read of a few kB, so a remote file takes a couple dozen
+    /// range requests to seek through
+    pub async fn seek_to_sample(&mut self, sample: u64) -> Result<(), Error> {
+        let (serial, pre_skip) = self.core.seek_info()?;
+        let start = self.stream_start().await?;
+        let end = self.data.seek(SeekFrom::End(0)).await?;
+
+        let target = calc_sr_u64(sample.saturating_add(pre_skip), TARGET_SPS, OGG_OPUS_SPS);
+        let goal = target.saturating_sub(PRE_ROLL);
+        let page = find_page(&mut self.data, serial, goal, start, end).await?;
+
+        self.data.seek(SeekFrom::Start(page.end())).await?;
+        self.core.seeked(page.end(), page.granule, sample)
+    }
+
+    /// Same as `seek_to_sample`, with the position as time
+    pub async fn seek_to_time(&mut self, time: std::time::Duration) -> Result<(), Error> {
+        self.seek_to_sample(duration_to_samples(time, TARGET_SPS))
+            .await
+    }
+
+    /// Same as `Decoder::total_samples`
+    pub async fn total_samples(&mut self) -> Result<u64, Error> {
+        let (serial, pre_skip) = self.core.seek_info()?;
+        let start = self.stream_start().await?;
+        let position = self.data.stream_position().await?;
+        let end = self.data.seek(SeekFrom::End(0)).await?;
+
+        let last = last_granule(&mut self.data, serial, start, end).await;
+        self.data.seek(SeekFrom::Start(position)).await?;
+        let samples = calc_sr_u64(last?, OGG_OPUS_SPS, TARGET_SPS);
+        Ok(samples.saturating_sub(pre_skip))
+    }
+
+    /// Same as `total_samples`, as time
+    pub async fn duration(&mut self) -> Result<std::time::Duration, Error> {
+        Ok(samples_to_duration(self.total_samples().await?, TARGET_SPS))
+    }
+
+    // Where the stream begins in `data`, which might have something before it
+    async fn stream_start(&mut self) -> Result<u64, Error> {
+        if let Some(start) = self.start {
+            return Ok(start);
+        }
+        let start = self.data.stream_position().await? - self.fed;
+        self.start = Some(start);
+        Ok(start)
+    }
+}
+
+// Same as the one `Decoder` uses, over `Window`
+async fn last_granule<T: AsyncRead + AsyncSeek + Unpin>(
+    data: &mut T,
+    serial: u32,
+    start: u64,
+    end: u64,
+) -> Result<u64, Error> {
+    let mut window = 64 * 1024;
+    let mut read = Window::new(end);
+    loop {
+        let from = end.saturating_sub(window).max(start);
+        let mut last = None;
+        let mut offset = from;
+        while let Some(page) = read.next_page(data, offset).await? {
+            if page.serial == serial && page.granule != UNSET_GRANULE {
+                last = Some(page.granule);
+            }
+            offset = page.end();
+        }
+
+        match last {
+            Some(granule) => return Ok(granule),
+            None if from == start => return Err(Error::MalformedAudio),
+            None => window *= 2,
+        }
+    }
+}
+
+// Same as the one `Decoder` uses, over `Window`
+async fn find_page<T: AsyncRead + AsyncSeek + Unpin>(
+    data: &mut T,
+    serial: u32,
+    goal: u64,
+    start: u64,
+    end: u64,
+) -> Result<PageInfo, Error> {
+    let usable = |page: &PageInfo| page.serial == serial && page.granule != UNSET_GRANULE;
+
+    let mut read = Window::new(end);
+    let mut low = start;
+    let mut high = end;
+    while high - low > BISECT_SPAN {
+        let middle = low + (high - low) / 2;
+        let mut found = None;
+        let mut offset = middle;
+        while let Some(page) = read.next_page(data, offset).await? {
+            if page.end() > high {
+                break;
+            }
+            if usable(&page) {
+                found = Some(page);
+                break;
+            }
+            offset = page.end();
+        }
+        match found {
+            Some(page) if page.granule <= goal => low = page.offset,
+            _ => high = middle,
+        }
+    }
+
+    // Every page from `low` on might have a packet going on past it
+    for from in [low, start] {
+        let mut best = None;
+        let mut offset = from;
+        while let Some(page) = read.next_page(data, offset).await? {
+            if usable(&page) {
+                if page.granule > goal {
+                    break;
+                }
+                if page.ends_clean {
+                    best = Some(page);
+                }
+            }
+            offset = page.end();
+        }
+        if let Some(page) = best {
+            return Ok(page);
+        }
+    }
+    Err(Error::MalformedAudio)
+}
+
+// How much is read at once while looking for pages, several of them usually
+const WINDOW_READ: u64 = 16 * 1024;
+
+// The bytes of `data` around where pages are looked for, so that going
+// through them one after the other doesn't take a read (a request, remotely)
+// for each
+struct Window {
+    offset: u64,
+    bytes: Vec<u8>,
+    end: u64,
+}
+
+impl Window {
+    fn new(end: u64) -> Self {
+        Self {
+            offset: 0,
+            bytes: Vec::new(),
+            end,
+        }
+    }
+
+    // Same as `page::next_page`, up to the end given to `new`
+    async fn next_page<T: AsyncRead + AsyncSeek + Unpin>(
+        &mut self,
+        data: &mut T,
+        from: u64,
+    ) -> Result<Option<PageInfo>, Error> {
+        let mut offset = from;
+        while offset + HEADER_SIZE as u64 <= self.end {
+            let found = self.fill(data, offset, WINDOW_READ).await?;
+            let skip = found
+                .windows(CAPTURE_PATTERN.len())
+                .position(|bytes| bytes == CAPTURE_PATTERN);
+            let Some(skip) = skip else {
+                // The pattern might be cut by the end of what was read
+                offset += found.len().saturating_sub(CAPTURE_PATTERN.len() - 1).max(1) as u64;
+                continue;
+            };
+            offset += skip as u64;
+
+            let header = self.fill(data, offset, (HEADER_SIZE + 255) as u64).await?;
+            if let Some(len) = lacing_len(header) {
+                let mut page = self.fill(data, offset, len).await?.to_vec();
+                if page.len() as u64 == len {
+                    if let Some(page) = check_page(offset, &mut page) {
+                        return Ok(Some(page));
+                    }
+                }
+            }
+            offset += 1;
+        }
+        Ok(None)
+    }
+
+    // Up to `len` bytes from `offset` on, fewer only at the end
+    async fn fill<T: AsyncRead + AsyncSeek + Unpin>(
+        &mut self,
+        data: &mut T,
+        offset: u64,
+        len: u64,
+    ) -> Result<&[u8], Error> {
+        let want = (offset + len).min(self.end);
+        let have = self.offset + self.bytes.len() as u64;
+        if offset < self.offset || offset > have {
+            self.offset = offset;
+            self.bytes.clear();
+        } else {
+            // Only forward from here
+            self.bytes.drain(..(offset - self.offset) as usize);
+            self.offset = offset;
+        }
+
+        let have = self.offset + self.bytes.len() as u64;
+        if have < want {
+            let read = (want - have).max(WINDOW_READ).min(self.end - have);
+            data.seek(SeekFrom::Start(have)).await?;
+            (&mut *data).take(read).read_to_end(&mut self.bytes).await?;
+        }
+        let len = ((want - offset) as usize).min(self.bytes.len());
+        Ok(&self.bytes[..len])
+    }
+}
+
+// Length of the page whose header (with its lacing) starts `data`, if there's
+// enough of it to tell
+fn lacing_len(data: &[u8]) -> Option<u64> {
+    let segments = *data.get(26)? as usize;
+    let lacing = data.get(HEADER_SIZE..HEADER_SIZE + segments)?;
+    Some((HEADER_SIZE + segments + lacing.iter().map(|&s| s as usize).sum::<usize>()) as u64)
+}
+
+// Gives the core some more input, how much, 0 once there's none left
+async fn feed<T: AsyncRead + Unpin, const TARGET_SPS: u32>(
+    data: &mut T,
+    core: &mut DecoderCore<TARGET_SPS>,
+) -> Result<usize, Error> {
+    let mut buffer = [0u8; READ_SIZE];
+    let read = data.read(&mut buffer).await?;
+    core.push_bytes(&buffer[..read]);
+    Ok(read)
+}
+
+/**Same as `StreamEncoder`, writing to an `AsyncWrite` (a file, a response
+body ...): pages go out as soon as they're ready, and encoding
+happens right in `push_samples`, a frame at a time.*/
+pub struct AsyncEncoder<W, const S_PS: u32, const NUM_CHANNELS: u8> {
+    core: EncoderCore<S_PS, NUM_CHANNELS>,
+    writer: W,
+}
+
+impl<W: AsyncWrite + Unpin, const S_PS: u32, const NUM_CHANNELS: u8>
+    AsyncEncoder<W, S_PS, NUM_CHANNELS>
+{
+    /// Writes the headers, audio can be pushed right after
+    pub async fn new(writer: W) -> Result<Self, Error> {
+        Self::with_options(writer, &Tags::default(), &EncoderOptions::default()).await
+    }
+
+    /// Same as `new`, writing `tags` in the comment header
+    pub async fn with_tags(writer: W, tags: &Tags) -> Result<Self, Error> {
+        Self::with_options(writer, tags, &EncoderOptions::default()).await
+    }
+
+    /// Same as `with_tags`, with the codec settings of `options`, see
+    /// `EncoderCore::with_options`
+    pub async fn with_options(
+        writer: W,
+        tags: &Tags,
+        options: &EncoderOptions,
+    ) -> Result<Self, Error> {
+        let mut encoder = Self {
+            core: EncoderCore::with_options(tags, options)?,
+            writer,
+        };
+        encoder.write_output().await?;
+        Ok(encoder)
+    }
+
+    /// Encodes as many whole frames as possible, the rest is kept until more
+    /// audio arrives (or `finish` is called)
+    pub async fn push_samples(&mut self, audio: &[i16]) -> Result<(), Error> {
+        self.core.push_samples(audio)?;
+        self.write_output().await
+    }
+
+    /// Same as `push_samples`, for audio as `f32` (full scale being 1.0)
+    pub async fn push_float(&mut self, audio: &[f32]) -> Result<(), Error> {
+        self.core.push_float(audio)?;
+        self.write_output().await
+    }
+
+    /// The writer, with everything written so far in it
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Encodes whatever audio is left, ends the stream and flushes the
+    /// writer, giving it back
+    pub async fn finish(mut self) -> Result<(W, EncodeReport), Error> {
+        let report = self.core.finish_mut()?;
+        self.write_output().await?;
+        self.writer.flush().await?;
+        Ok((self.writer, report))
+    }
+
+    // Hands whatever the core has to the writer
+    async fn write_output(&mut self) -> Result<(), Error> {
+        let output = self.core.take_output();
+        self.writer.write_all(&output).await?;
+        Ok(())
+    }
+}
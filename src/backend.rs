@@ -0,0 +1,112 @@
+// Our own versions of the backend (audiopus) types that show up in the API,
+// so that moving to another audiopus version doesn't break anyone using us
+
+use std::fmt;
+
+/// What the encoder tunes itself for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Application {
+    /// Speech, intelligibility over fidelity
+    Voip,
+    /// Music or anything where the output should be close to the input
+    Audio,
+    /// Lowest latency possible, at the cost of quality
+    LowDelay,
+}
+
+impl From<Application> for audiopus::Application {
+    fn from(application: Application) -> Self {
+        match application {
+            Application::Voip => audiopus::Application::Voip,
+            Application::Audio => audiopus::Application::Audio,
+            Application::LowDelay => audiopus::Application::LowDelay,
+        }
+    }
+}
+
+/// Sample rates Opus works at, for when the rate is only known at runtime
+/// (`encode_at`, `decode_at`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SampleRate {
+    Hz8000,
+    Hz12000,
+    Hz16000,
+    Hz24000,
+    Hz48000,
+}
+
+impl SampleRate {
+    /// `InvalidSps` for anything Opus can't work at
+    pub fn from_hz(hz: u32) -> Result<Self, crate::Error> {
+        Ok(match hz {
+            8000 => SampleRate::Hz8000,
+            12000 => SampleRate::Hz12000,
+            16000 => SampleRate::Hz16000,
+            24000 => SampleRate::Hz24000,
+            48000 => SampleRate::Hz48000,
+            _ => return Err(crate::Error::InvalidSps),
+        })
+    }
+
+    pub fn as_hz(self) -> u32 {
+        match self {
+            SampleRate::Hz8000 => 8000,
+            SampleRate::Hz12000 => 12000,
+            SampleRate::Hz16000 => 16000,
+            SampleRate::Hz24000 => 24000,
+            SampleRate::Hz48000 => 48000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Bitrate {
+    BitsPerSecond(i32),
+    /// As much as a packet can hold
+    Max,
+    /// Let the encoder decide
+    Auto,
+}
+
+impl From<Bitrate> for audiopus::Bitrate {
+    fn from(bitrate: Bitrate) -> Self {
+        match bitrate {
+            Bitrate::BitsPerSecond(bps) => audiopus::Bitrate::BitsPerSecond(bps),
+            Bitrate::Max => audiopus::Bitrate::Max,
+            Bitrate::Auto => audiopus::Bitrate::Auto,
+        }
+    }
+}
+
+/// Error from libopus or its bindings, opaque so that its details don't tie
+/// us to a backend version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackendError(audiopus::Error);
+
+impl BackendError {
+    /// libopus error code (those are negative), when it was libopus failing
+    /// and not the bindings checking their input
+    pub fn code(&self) -> Option<i32> {
+        match self.0 {
+            audiopus::Error::Opus(code) => Some(code as i32),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+impl From<audiopus::Error> for crate::Error {
+    fn from(err: audiopus::Error) -> Self {
+        crate::Error::OpusError(BackendError(err))
+    }
+}
@@ -0,0 +1,147 @@
+// Ogg Opus assets for Bevy: a loader, and an audio source decoding as it
+// plays, the way Bevy's own `AudioSource` does for Vorbis
+
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bevy_app::{App, Plugin};
+use bevy_asset::io::Reader;
+use bevy_asset::{Asset, AssetApp, AssetLoader, LoadContext};
+use bevy_audio::{AddAudioSource, Decodable, Source};
+use bevy_reflect::TypePath;
+
+use crate::{ChannelPolicy, DecodeOptions, Decoder, Error};
+
+const BEVY_SPS: u32 = 48000;
+
+/**Registers `OpusLoader` and `OpusAudio` as an audio source, add it after
+Bevy's `AssetPlugin` and `AudioPlugin`. Then `AudioPlayer::<OpusAudio>` plays
+what `asset_server.load("music.opus")` gives.*/
+pub struct OpusPlugin;
+
+impl Plugin for OpusPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset_loader::<OpusLoader>()
+            .add_audio_source::<OpusAudio>();
+    }
+}
+
+/**Ogg Opus file loaded by `OpusLoader`, still encoded: it's decoded while
+it plays, at 48 kHz and always in stereo, whatever the file has. Mono is
+played on both sides and surround folded into two (Bevy's source can't change
+channels halfway, as a chained stream could).*/
+#[derive(Asset, Debug, Clone, TypePath)]
+pub struct OpusAudio {
+    bytes: Arc<[u8]>,
+    duration: Duration,
+}
+
+impl OpusAudio {
+    /// Checks the headers (and the length) of an Ogg Opus file
+    pub fn new(bytes: impl Into<Arc<[u8]>>) -> Result<Self, Error> {
+        let bytes = bytes.into();
+        let duration = open(&bytes)?.duration()?;
+        Ok(Self { bytes, duration })
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+impl Decodable for OpusAudio {
+    type DecoderItem = i16;
+    type Decoder = OpusSource;
+
+    fn decoder(&self) -> OpusSource {
+        OpusSource {
+            // The headers were read fine when loading
+            decoder: open(&self.bytes).ok(),
+            duration: self.duration,
+            frame: Vec::new(),
+            read: 0,
+        }
+    }
+}
+
+/// Loads `.opus` files. `.ogg` is left to Bevy's Vorbis loader, so Opus
+/// files have to be named `.opus`
+#[derive(Default)]
+pub struct OpusLoader;
+
+impl AssetLoader for OpusLoader {
+    type Asset = OpusAudio;
+    type Settings = ();
+    type Error = Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<OpusAudio, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        OpusAudio::new(bytes)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["opus"]
+    }
+}
+
+/// Samples of an `OpusAudio` as it plays. A stream broken halfway ends there
+pub struct OpusSource {
+    decoder: Option<Decoder<Cursor<Arc<[u8]>>, BEVY_SPS>>,
+    duration: Duration,
+    // Current frame, and how much of it went out
+    frame: Vec<i16>,
+    read: usize,
+}
+
+impl Iterator for OpusSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        while self.read == self.frame.len() {
+            let decoder = self.decoder.as_mut()?;
+            match decoder.next_frame() {
+                Ok(Some(frame)) => {
+                    self.frame = frame.samples;
+                    self.read = 0;
+                }
+                _ => self.decoder = None,
+            }
+        }
+        self.read += 1;
+        Some(self.frame[self.read - 1])
+    }
+}
+
+impl Source for OpusSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        BEVY_SPS
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(self.duration)
+    }
+}
+
+// Always two channels, so that the source's never change with chained streams
+fn open(bytes: &Arc<[u8]>) -> Result<Decoder<Cursor<Arc<[u8]>>, BEVY_SPS>, Error> {
+    let options = DecodeOptions {
+        channels: ChannelPolicy::ForceStereo,
+        ..DecodeOptions::default()
+    };
+    Decoder::with_options(Cursor::new(bytes.clone()), &options)
+}
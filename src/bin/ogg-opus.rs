@@ -0,0 +1,112 @@
+// Small tool over the library, both to have one at hand and to show how the
+// API goes together. Built with `--features cli`
+
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::process;
+
+use ogg_opus::{
+    decode_to_wav, encode_wav, headers, read_tags, rewrite_tags, Bitrate, Decoder, EncoderOptions,
+    Preset,
+};
+
+const USAGE: &str = "\
+Usage:
+  ogg-opus encode <in.wav> <out.opus> [--bitrate <bits/s>] [--preset voice|music|low-latency]
+  ogg-opus decode <in.opus> <out.wav>
+  ogg-opus info <in.opus>
+  ogg-opus retag <in.opus> <out.opus> [KEY=value ...] [--remove <KEY> ...]";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["encode", input, output, rest @ ..] => encode(input, output, rest),
+        ["decode", input, output] => decode(input, output),
+        ["info", input] => info(input),
+        ["retag", input, output, rest @ ..] => retag(input, output, rest),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("ogg-opus: {}", e);
+        process::exit(1);
+    }
+}
+
+fn encode(input: &str, output: &str, args: &[&str]) -> Result<(), Box<dyn Error>> {
+    let mut options = EncoderOptions::default();
+    let mut bitrate = None;
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match (arg, args.next()) {
+            ("--bitrate", Some(bits)) => bitrate = Some(bits.parse()?),
+            ("--preset", Some(&"voice")) => options = EncoderOptions::preset(Preset::VoiceMessage),
+            ("--preset", Some(&"music")) => options = EncoderOptions::preset(Preset::Music),
+            ("--preset", Some(&"low-latency")) => {
+                options = EncoderOptions::preset(Preset::LowLatency)
+            }
+            _ => return Err(format!("bad option '{}'\n{}", arg, USAGE).into()),
+        }
+    }
+    // Goes over whatever the preset has
+    if let Some(bits) = bitrate {
+        options = options.bitrate(Bitrate::BitsPerSecond(bits));
+    }
+
+    let opus = encode_wav(BufReader::new(File::open(input)?), &options)?;
+    fs::write(output, opus)?;
+    Ok(())
+}
+
+fn decode(input: &str, output: &str) -> Result<(), Box<dyn Error>> {
+    decode_to_wav(BufReader::new(File::open(input)?), output)?;
+    Ok(())
+}
+
+fn info(input: &str) -> Result<(), Box<dyn Error>> {
+    let (head, tags) = headers::parse(BufReader::new(File::open(input)?))?;
+    let duration = Decoder::<_, 48000>::new(File::open(input)?)?.duration()?;
+
+    println!("Channels: {}", head.channels);
+    println!("Input sample rate: {} Hz", head.input_sample_rate);
+    println!("Duration: {:.3} s", duration.as_secs_f64());
+    println!("Pre-skip: {}", head.pre_skip);
+    println!("Output gain: {:.2} dB", head.output_gain as f32 / 256.0);
+    println!("Channel mapping family: {}", head.channel_mapping_family);
+    println!("Vendor: {}", tags.vendor);
+    for (key, value) in &tags.comments {
+        println!("{}={}", key, value);
+    }
+    Ok(())
+}
+
+// Only the comment header changes, the audio is copied byte for byte
+fn retag(input: &str, output: &str, args: &[&str]) -> Result<(), Box<dyn Error>> {
+    let mut tags = read_tags(BufReader::new(File::open(input)?))?;
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match (arg, arg.split_once('=')) {
+            ("--remove", _) => {
+                let key = args.next().ok_or(USAGE)?;
+                tags.remove(key);
+            }
+            (_, Some((key, value))) => tags.set(key, value),
+            _ => return Err(format!("bad tag '{}', expected KEY=value", arg).into()),
+        }
+    }
+
+    let mut retagged = Vec::new();
+    rewrite_tags(BufReader::new(File::open(input)?), &mut retagged, &tags)?;
+    fs::write(output, retagged)?;
+    Ok(())
+}
@@ -0,0 +1,251 @@
+// Recording from a cpal input (a microphone ...) to Ogg Opus: whatever the
+// device gives, in any sample format and channel count, goes to a
+// `StreamEncoder`, on cpal's callbacks or on a `Pipeline` thread
+
+use std::io::Write;
+use std::iter;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{
+    BuildStreamError, Device, FromSample, Sample, SampleFormat, SizedSample, Stream, StreamConfig,
+    StreamError,
+};
+
+use crate::encode::{EncodeReport, Finished, StreamEncoder};
+use crate::{Error, Pipeline, PipelineBuilder};
+
+// Queued callbacks for `record`, a few seconds at the usual 10 ms of a callback
+const CAPTURE_CAPACITY: usize = 512;
+
+/**`StreamEncoder` taking audio as a cpal input stream with `config` gives
+it, to be fed from the data callback with `push`. Samples of any format are
+turned into `i16`, and channels into NUM_CHANNELS: mono is averaged down to
+or copied to every channel, otherwise channels go in order, those the device
+doesn't have left silent. The rate has to be S_PS already.*/
+pub struct CaptureSink<W: Write, const S_PS: u32, const NUM_CHANNELS: u8> {
+    encoder: StreamEncoder<W, S_PS, NUM_CHANNELS>,
+    // Those of the captured audio
+    channels: usize,
+    converted: Vec<i16>,
+}
+
+impl<W: Write, const S_PS: u32, const NUM_CHANNELS: u8> CaptureSink<W, S_PS, NUM_CHANNELS> {
+    /// Writes the headers to `writer` right away
+    pub fn new(writer: W, config: &StreamConfig) -> Result<Self, Error> {
+        Self::from_encoder(StreamEncoder::new(writer)?, config)
+    }
+
+    /// For an encoder with its own tags and options, nothing must have been
+    /// pushed to it yet
+    pub fn from_encoder(
+        encoder: StreamEncoder<W, S_PS, NUM_CHANNELS>,
+        config: &StreamConfig,
+    ) -> Result<Self, Error> {
+        check_config::<S_PS>(config)?;
+        Ok(Self {
+            encoder,
+            channels: config.channels as usize,
+            converted: Vec::new(),
+        })
+    }
+
+    /// Encodes what a data callback got, interleaved
+    pub fn push<T: SizedSample>(&mut self, data: &[T]) -> Result<(), Error>
+    where
+        i16: FromSample<T>,
+    {
+        self.converted.clear();
+        convert::<T, NUM_CHANNELS>(data, self.channels, &mut self.converted);
+        self.encoder.push_samples(&self.converted)
+    }
+
+    /// The writer, with everything written so far in it
+    pub fn get_ref(&self) -> &W {
+        self.encoder.get_ref()
+    }
+
+    /// Encodes whatever audio is left and ends the stream
+    pub fn finish(self) -> Result<StreamEncoder<W, S_PS, NUM_CHANNELS, Finished>, Error> {
+        self.encoder.finish()
+    }
+}
+
+/**Records from `device` to `writer` until `finish`. cpal's audio thread only
+converts the samples and queues them (a few seconds' worth at most), they get
+encoded and written on a `Pipeline` thread. The device's default input config
+is used if it's at S_PS, else the first one that can be.
+
+```no_run
+use cpal::traits::HostTrait;
+use ogg_opus::capture;
+
+let device = cpal::default_host().default_input_device().unwrap();
+let file = std::fs::File::create("mic.opus")?;
+let recording = capture::record::<_, 48000, 1>(&device, file)?;
+std::thread::sleep(std::time::Duration::from_secs(10));
+recording.finish()?;
+# Ok::<(), ogg_opus::Error>(())
+```*/
+pub fn record<W: Write + Send + 'static, const S_PS: u32, const NUM_CHANNELS: u8>(
+    device: &Device,
+    writer: W,
+) -> Result<Recording<S_PS, NUM_CHANNELS>, Error> {
+    let default = device.default_input_config().map_err(capture_error)?;
+    let supported = if default.sample_rate() == S_PS {
+        default
+    } else {
+        device
+            .supported_input_configs()
+            .map_err(capture_error)?
+            .find_map(|range| range.try_with_sample_rate(S_PS))
+            .ok_or(Error::InvalidSps)?
+    };
+    let config = supported.config();
+    check_config::<S_PS>(&config)?;
+    let pipeline = PipelineBuilder::new()
+        .capacity(CAPTURE_CAPACITY)
+        .spawn::<W, S_PS, NUM_CHANNELS>(writer)?;
+    let error = Arc::new(Mutex::new(None));
+
+    macro_rules! build {
+        ($sample:ty) => {
+            build_stream::<$sample, NUM_CHANNELS>(
+                device,
+                &config,
+                pipeline.sender(),
+                error.clone(),
+            )?
+        };
+    }
+    let stream = match supported.sample_format() {
+        SampleFormat::I8 => build!(i8),
+        SampleFormat::I16 => build!(i16),
+        SampleFormat::I24 => build!(cpal::I24),
+        SampleFormat::I32 => build!(i32),
+        SampleFormat::I64 => build!(i64),
+        SampleFormat::U8 => build!(u8),
+        SampleFormat::U16 => build!(u16),
+        SampleFormat::U24 => build!(cpal::U24),
+        SampleFormat::U32 => build!(u32),
+        SampleFormat::U64 => build!(u64),
+        SampleFormat::F32 => build!(f32),
+        SampleFormat::F64 => build!(f64),
+        // DSD and whatever comes next
+        _ => return Err(capture_error(BuildStreamError::StreamConfigNotSupported)),
+    };
+    stream.play().map_err(capture_error)?;
+
+    Ok(Recording {
+        stream,
+        pipeline,
+        error,
+    })
+}
+
+/// A capture started by `record`, going on until `finish`
+pub struct Recording<const S_PS: u32, const NUM_CHANNELS: u8> {
+    stream: Stream,
+    pipeline: Pipeline<S_PS, NUM_CHANNELS>,
+    error: Arc<Mutex<Option<Error>>>,
+}
+
+impl<const S_PS: u32, const NUM_CHANNELS: u8> Recording<S_PS, NUM_CHANNELS> {
+    /// Stops the capture, waits for the queued audio to be encoded and ends
+    /// the stream. The first error of the capture, if any, is given instead
+    pub fn finish(self) -> Result<EncodeReport, Error> {
+        // The callback's sender goes with the stream
+        drop(self.stream);
+        let report = self.pipeline.finish();
+        let mut error = self.error.lock().unwrap_or_else(PoisonError::into_inner);
+        match error.take() {
+            Some(e) => Err(e),
+            None => report,
+        }
+    }
+}
+
+fn build_stream<T, const NUM_CHANNELS: u8>(
+    device: &Device,
+    config: &StreamConfig,
+    sender: SyncSender<Vec<i16>>,
+    error: Arc<Mutex<Option<Error>>>,
+) -> Result<Stream, Error>
+where
+    T: SizedSample,
+    i16: FromSample<T>,
+{
+    let fail = move |e: Error| {
+        let mut error = error.lock().unwrap_or_else(PoisonError::into_inner);
+        error.get_or_insert(e);
+    };
+    let on_error = fail.clone();
+    let channels = config.channels as usize;
+    // Gone after an error, the rest of the capture is dropped
+    let mut sender = Some(sender);
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _| {
+            let Some(queue) = &sender else { return };
+            let mut converted = Vec::with_capacity(data.len() / channels * NUM_CHANNELS as usize);
+            convert::<T, NUM_CHANNELS>(data, channels, &mut converted);
+            // Never blocking the audio thread, a full queue loses audio
+            match queue.try_send(converted) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    fail(Error::CaptureOverrun);
+                    sender = None;
+                }
+                // The encoder failed, `Pipeline::finish` tells why
+                Err(TrySendError::Disconnected(_)) => sender = None,
+            }
+        },
+        move |e| {
+            // A glitch, the stream goes on
+            if e == StreamError::BufferUnderrun {
+                return;
+            }
+            on_error(capture_error(e));
+        },
+        None,
+    );
+    stream.map_err(capture_error)
+}
+
+fn check_config<const S_PS: u32>(config: &StreamConfig) -> Result<(), Error> {
+    if config.sample_rate != S_PS {
+        return Err(Error::InvalidSps);
+    }
+    if config.channels == 0 {
+        return Err(Error::InvalidChannels);
+    }
+    Ok(())
+}
+
+// Interleaved `data` with `channels` into NUM_CHANNELS of `i16`, after what's
+// in `out` already
+fn convert<T: SizedSample, const NUM_CHANNELS: u8>(data: &[T], channels: usize, out: &mut Vec<i16>)
+where
+    i16: FromSample<T>,
+{
+    let out_channels = NUM_CHANNELS as usize;
+    for frame in data.chunks_exact(channels) {
+        let mut frame = frame.iter().map(|&s| i16::from_sample(s));
+        if out_channels == 1 {
+            let sum: i32 = frame.map(i32::from).sum();
+            out.push((sum / channels as i32) as i16);
+        } else if channels == 1 {
+            let sample = frame.next().unwrap_or_default();
+            let len = out.len();
+            out.resize(len + out_channels, sample);
+        } else {
+            let frame = frame.chain(iter::repeat(0));
+            out.extend(frame.take(out_channels));
+        }
+    }
+}
+
+fn capture_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> Error {
+    Error::CaptureError(Box::new(e))
+}
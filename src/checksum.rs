@@ -0,0 +1,50 @@
+use std::io::{Cursor, Read};
+
+use byteorder::{ByteOrder, LittleEndian};
+use ogg::PacketReader;
+
+use crate::common::OGG_OPUS_SPS;
+use crate::{decode, page, EncoderOptions, Error, OggOpusEncoder, Tags};
+
+/// Tag where `encode_with_checksum` stores the MD5 of the audio
+pub const PCM_MD5_TAG: &str = "PCM_MD5";
+
+/**Encodes like `encode_with_options`, with `tags`, also storing the MD5 of
+the audio in the `PCM_MD5` comment so that `verify` can later tell whether
+the file got damaged, like FLAC does. Opus is lossy so the source PCM can't
+ever come back, the checksum is of what the stream decodes to instead (at
+48 kHz, pre-skip and end trimming taken out): remuxing or repaging keeps it
+valid, as long as the packets stay the same. libopus builds don't all decode
+bit-exactly the same (fixed point, other CPUs), a file can fail on one of
+those without being damaged.*/
+pub fn encode_with_checksum<const S_PS: u32, const NUM_CHANNELS: u8>(
+    audio: &[i16],
+    tags: &Tags,
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, Error> {
+    let opus = OggOpusEncoder::<S_PS, NUM_CHANNELS>::with_tags(tags, options)?.encode(audio)?;
+
+    let (md5, mut tags) = pcm_md5(opus.as_slice())?;
+    tags.set(PCM_MD5_TAG, md5);
+
+    // The header has to stay as it is, only the comments change
+    let head = PacketReader::new(Cursor::new(&opus)).read_packet_expected()?;
+    page::replace_headers(&opus, &head.data, &tags.to_packet())
+}
+
+/// Decodes the whole stream to check it against the checksum stored by
+/// `encode_with_checksum`
+pub fn verify<T: Read>(data: T) -> Result<bool, Error> {
+    let (md5, tags) = pcm_md5(data)?;
+    let expected = tags.get(PCM_MD5_TAG).ok_or(Error::MissingChecksum)?;
+    Ok(expected.eq_ignore_ascii_case(&md5))
+}
+
+// MD5 of the decoded samples, as little endian `i16`. Also gives the comment
+// header
+fn pcm_md5<T: Read>(data: T) -> Result<(String, Tags), Error> {
+    let (audio, play_data) = decode::<_, OGG_OPUS_SPS>(data)?;
+    let mut bytes = vec![0u8; audio.len() * 2];
+    LittleEndian::write_i16_into(&audio, &mut bytes);
+    Ok((format!("{:x}", md5::compute(bytes)), play_data.tags))
+}
@@ -1,56 +1,145 @@
+use std::convert::TryFrom;
+use std::io;
+use std::time::Duration;
+
 use audiopus::SampleRate;
+use byteorder::{ByteOrder, LittleEndian};
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use rand::Rng;
+
+use crate::Error;
 
 // We use this to check whether a file is ogg opus or not inside the client
 pub(crate) const OGG_OPUS_SPS: u32 = 48000;
-pub(crate) const MAX_NUM_CHANNELS: u8 = 2;
 pub(crate) const OPUS_MAGIC_HEADER: [u8; 8] = [b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd'];
 pub(crate) const MAX_FRAME_SAMPLES: usize = 5760; // According to opus_decode docs
-pub(crate) const MAX_FRAME_SIZE: usize = MAX_FRAME_SAMPLES * (MAX_NUM_CHANNELS as usize); // Our buffer will be i16 so, don't convert to bytes
 pub(crate) const FRAME_TIME_MS: u32 = 20;
 pub(crate) const MAX_PACKET: usize = 4000; // Maximum theorical recommended by Opus
 pub(crate) const MIN_FRAME_MICROS: u32 = 25;
+pub(crate) const UNSET_GRANULE: u64 = u64::MAX; // -1 as read by ogg
 pub(crate) const VENDOR_STR: &str = concat!("ogg-opus", " ", std::env!("CARGO_PKG_VERSION"));
-pub(crate) const VENDOR_STR_LEN: [u8; 4] = (VENDOR_STR.len() as u32).to_le_bytes();
-pub(crate) const VENDOR_STR_BYTES: &[u8] = VENDOR_STR.as_bytes();
-
-pub(crate) const OPUS_TAGS: [u8; 30] = [
-    b'O',
-    b'p',
-    b'u',
-    b's',
-    b'T',
-    b'a',
-    b'g',
-    b's',
-    VENDOR_STR_LEN[0],
-    VENDOR_STR_LEN[1],
-    VENDOR_STR_LEN[2],
-    VENDOR_STR_LEN[3],
-    VENDOR_STR_BYTES[0],
-    VENDOR_STR_BYTES[1],
-    VENDOR_STR_BYTES[2],
-    VENDOR_STR_BYTES[3],
-    VENDOR_STR_BYTES[4],
-    VENDOR_STR_BYTES[5],
-    VENDOR_STR_BYTES[6],
-    VENDOR_STR_BYTES[7],
-    VENDOR_STR_BYTES[8],
-    VENDOR_STR_BYTES[9],
-    VENDOR_STR_BYTES[10],
-    VENDOR_STR_BYTES[11],
-    VENDOR_STR_BYTES[12],
-    VENDOR_STR_BYTES[13],
-    0,
-    0,
-    0,
-    0,
-];
-
 pub(crate) const fn calc_sr(val: u16, org_sr: u32, dest_sr: u32) -> u16 {
     ((val as u32 * dest_sr) / org_sr) as u16
 }
+// Widened so that it can't overflow, the result can only be too big when
+// going up in rate, and then it saturates
 pub(crate) const fn calc_sr_u64(val: u64, org_sr: u32, dest_sr: u32) -> u64 {
-    (val * dest_sr as u64) / (org_sr as u64)
+    let res = (val as u128 * dest_sr as u128) / (org_sr as u128);
+    if res > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        res as u64
+    }
+}
+
+// Granules are signed 64 bits in libogg, -1 meaning "unset"
+pub(crate) const MAX_GRANULE: u64 = i64::MAX as u64;
+
+/// Granule for a sample count at `sps`, errors instead of giving something
+/// that decoders would take as negative or unset
+pub(crate) fn to_granule(samples: u64, sps: u32) -> Result<u64, Error> {
+    match calc_sr_u64(samples, sps, OGG_OPUS_SPS) {
+        granule if granule <= MAX_GRANULE => Ok(granule),
+        _ => Err(Error::GranuleOverflow),
+    }
+}
+
+/// ID header (OpusHead) for channel mapping family 0, anything else has to
+/// change byte 18 and append its mapping
+pub(crate) fn opus_head(channels: u8, pre_skip: u16, input_sps: u32, gain: i16) -> Vec<u8> {
+    let mut head = OPUS_MAGIC_HEADER.to_vec();
+    head.push(1); // Version number, always 1
+    head.push(channels);
+    head.extend_from_slice(&[0; 9]);
+    LittleEndian::write_u16(&mut head[10..12], pre_skip);
+    LittleEndian::write_u32(&mut head[12..16], input_sps); // Informational
+    LittleEndian::write_i16(&mut head[16..18], gain);
+    head
+}
+
+/// dB to the Q7.8 fixed point the header and R128 tags use
+pub(crate) fn db_to_q78(db: f64) -> i16 {
+    (db * 256.0).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+/// Serial to identify a new stream, `thread_rng` is seeded from the OS so two
+/// programs won't get the same one even if asking at the same time
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) fn new_serial() -> u32 {
+    rand::thread_rng().gen::<u32>()
+}
+
+/// Browsers have no OS to ask for randomness (not without JS glue), so
+/// serials are only told apart within the program here, by scrambling a
+/// counter. Streams from elsewhere may clash, an explicit serial avoids that
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) fn new_serial() -> u32 {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    // Murmur3's finalizer, consecutive values end up far apart
+    let mut x = COUNTER
+        .fetch_add(1, Ordering::Relaxed)
+        .wrapping_add(0x9e37_79b9);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x85eb_ca6b);
+    x ^= x >> 13;
+    x = x.wrapping_mul(0xc2b2_ae35);
+    x ^ (x >> 16)
+}
+
+pub(crate) fn samples_to_duration(samples: u64, sps: u32) -> Duration {
+    // Whole seconds apart, nanoseconds as a u64 only last 584 years
+    let secs = samples / sps as u64;
+    let rem = samples % sps as u64;
+    Duration::new(secs, (rem * 1_000_000_000 / sps as u64) as u32)
+}
+
+// Saturates, as `calc_sr_u64` does
+pub(crate) fn duration_to_samples(duration: Duration, sps: u32) -> u64 {
+    let samples = duration.as_nanos() * sps as u128 / 1_000_000_000;
+    u64::try_from(samples).unwrap_or(u64::MAX)
+}
+
+/// Sample types audio can come in and out as, full scale is 1.0 as `f32`
+pub(crate) trait Sample: Copy {
+    fn to_f32(self) -> f32;
+    /// Clipping whatever goes out of range
+    fn from_f32(value: f32) -> Self;
+    /// Multiplies by `factor`, clipping whatever goes out of range
+    fn scale(self, factor: f64) -> Self;
+}
+
+impl Sample for i16 {
+    fn to_f32(self) -> f32 {
+        self as f32 / 32768.0
+    }
+
+    fn from_f32(value: f32) -> Self {
+        (value * 32768.0)
+            .round()
+            .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+
+    fn scale(self, factor: f64) -> Self {
+        (self as f64 * factor)
+            .round()
+            .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+    }
+}
+
+impl Sample for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(value: f32) -> Self {
+        value.clamp(-1.0, 1.0)
+    }
+
+    fn scale(self, factor: f64) -> Self {
+        (self as f64 * factor).clamp(-1.0, 1.0) as f32
+    }
 }
 
 pub(crate) const fn s_ps_to_audiopus(s_ps: u32) -> Option<SampleRate> {
@@ -63,3 +152,11 @@ pub(crate) const fn s_ps_to_audiopus(s_ps: u32) -> Option<SampleRate> {
         _ => return None,
     })
 }
+
+// For adapters that have to speak `std::io`, I/O errors stay as they were
+pub(crate) fn into_io(e: Error) -> io::Error {
+    match e {
+        Error::OggWriteError(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
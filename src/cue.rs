@@ -0,0 +1,197 @@
+use std::fmt;
+use std::io::{Read, Seek};
+use std::time::Duration;
+
+use crate::common::*;
+use crate::edit::PacketStream;
+use crate::Error;
+
+pub(crate) const CUESHEET_TAG: &str = "CUESHEET";
+
+// CUE timestamps are mm:ss:ff, with 75 frames per second (as in CDs)
+const FRAMES_PER_SEC: u64 = 75;
+
+/// CUE sheet, as embedded in the `CUESHEET` comment, only what matters to
+/// tell the tracks apart is kept
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub file: Option<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CueTrack {
+    pub number: u8,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Where the track starts (its `INDEX 01`)
+    pub start: Duration,
+}
+
+impl CueSheet {
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut sheet = CueSheet::default();
+
+        for line in text.lines() {
+            let line = line.trim();
+            let (command, rest) = match line.find(char::is_whitespace) {
+                Some(pos) => (&line[..pos], line[pos..].trim()),
+                None => (line, ""),
+            };
+
+            match command.to_ascii_uppercase().as_str() {
+                "TITLE" | "PERFORMER" => {
+                    let value = Some(unquote(rest).to_string());
+                    let is_title = command.eq_ignore_ascii_case("TITLE");
+                    match (sheet.tracks.last_mut(), is_title) {
+                        (Some(track), true) => track.title = value,
+                        (Some(track), false) => track.performer = value,
+                        (None, true) => sheet.title = value,
+                        (None, false) => sheet.performer = value,
+                    }
+                }
+                "FILE" => {
+                    // The file type comes after the (maybe quoted) name
+                    let name = match rest.strip_prefix('"') {
+                        Some(quoted) => quoted.split('"').next().unwrap_or_default(),
+                        None => rest.split_whitespace().next().unwrap_or_default(),
+                    };
+                    sheet.file = Some(name.to_string());
+                }
+                "TRACK" => {
+                    let number = rest
+                        .split_whitespace()
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or(Error::InvalidCueSheet)?;
+                    sheet.tracks.push(CueTrack {
+                        number,
+                        title: None,
+                        performer: None,
+                        start: Duration::ZERO,
+                    });
+                }
+                "INDEX" => {
+                    let mut parts = rest.split_whitespace();
+                    let index = parts.next().ok_or(Error::InvalidCueSheet)?;
+                    let time = parts.next().ok_or(Error::InvalidCueSheet)?;
+                    // Index 00 is the pregap, the track itself starts at 01
+                    if index == "01" {
+                        let track = sheet.tracks.last_mut().ok_or(Error::InvalidCueSheet)?;
+                        track.start = parse_time(time)?;
+                    }
+                }
+                // REM, FLAGS, ISRC ... are of no use for us
+                _ => {}
+            }
+        }
+
+        if sheet.tracks.is_empty() {
+            return Err(Error::InvalidCueSheet);
+        }
+        Ok(sheet)
+    }
+}
+
+impl fmt::Display for CueSheet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(performer) = &self.performer {
+            writeln!(f, "PERFORMER \"{}\"", performer)?;
+        }
+        if let Some(title) = &self.title {
+            writeln!(f, "TITLE \"{}\"", title)?;
+        }
+        if let Some(file) = &self.file {
+            writeln!(f, "FILE \"{}\" OGG", file)?;
+        }
+        for track in &self.tracks {
+            writeln!(f, "  TRACK {:02} AUDIO", track.number)?;
+            if let Some(title) = &track.title {
+                writeln!(f, "    TITLE \"{}\"", title)?;
+            }
+            if let Some(performer) = &track.performer {
+                writeln!(f, "    PERFORMER \"{}\"", performer)?;
+            }
+            writeln!(f, "    INDEX 01 {}", format_time(track.start))?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a file holding a whole concert, album or show into its tracks,
+/// following the CUE sheet in its `CUESHEET` comment. Audio is copied, not
+/// re-encoded, and each track gets its own title and performer tags
+pub fn split_tracks<T: Read + Seek>(data: T) -> Result<Vec<Vec<u8>>, Error> {
+    let stream = PacketStream::read(data)?;
+    let cue = stream.tags.cue_sheet().ok_or(Error::InvalidCueSheet)?;
+    split_stream(&stream, &cue)
+}
+
+/// Same as `split_tracks`, with a CUE sheet that isn't inside the file
+pub fn split_tracks_with<T: Read + Seek>(data: T, cue: &CueSheet) -> Result<Vec<Vec<u8>>, Error> {
+    split_stream(&PacketStream::read(data)?, cue)
+}
+
+fn split_stream(stream: &PacketStream, cue: &CueSheet) -> Result<Vec<Vec<u8>>, Error> {
+    let to_samples = |d: Duration| duration_to_samples(d, OGG_OPUS_SPS);
+
+    let mut tracks = Vec::with_capacity(cue.tracks.len());
+    for (i, track) in cue.tracks.iter().enumerate() {
+        let start = to_samples(track.start);
+        let end = match cue.tracks.get(i + 1) {
+            Some(next) => to_samples(next.start),
+            None => stream.duration(),
+        };
+
+        let mut tags = stream.tags.clone();
+        tags.remove(CUESHEET_TAG);
+        tags.set("TRACKNUMBER", track.number.to_string());
+        if let Some(title) = &track.title {
+            tags.set("TITLE", title.as_str());
+        }
+        if let Some(performer) = track.performer.as_ref().or(cue.performer.as_ref()) {
+            tags.set("ARTIST", performer.as_str());
+        }
+        if let Some(album) = &cue.title {
+            tags.set("ALBUM", album.as_str());
+        }
+
+        tracks.push(stream.extract(start, end, &tags)?);
+    }
+
+    Ok(tracks)
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
+fn parse_time(time: &str) -> Result<Duration, Error> {
+    let mut parts = time.split(':').map(|p| p.parse::<u64>());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(mins)), Some(Ok(secs)), Some(Ok(frames)), None) if frames < FRAMES_PER_SEC => {
+            let frames = (mins * 60 + secs) * FRAMES_PER_SEC + frames;
+            Ok(Duration::from_nanos(
+                frames * 1_000_000_000 / FRAMES_PER_SEC,
+            ))
+        }
+        _ => Err(Error::InvalidCueSheet),
+    }
+}
+
+fn format_time(time: Duration) -> String {
+    let frames = time.as_nanos() as u64 * FRAMES_PER_SEC / 1_000_000_000;
+    let secs = frames / FRAMES_PER_SEC;
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 60,
+        secs % 60,
+        frames % FRAMES_PER_SEC
+    )
+}
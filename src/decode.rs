@@ -1,10 +1,18 @@
 use crate::common::*;
-use crate::Error;
+use crate::edit::PRE_ROLL;
+use crate::headers::OpusHead;
+use crate::interleave::{downmix, stereo_weights};
+use crate::multistream::{Mapping, MultistreamDecoder, MAX_SURROUND_CHANNELS};
+use crate::packet::Toc;
+use crate::page::{next_page, PacketSplitter, PageInfo};
+use crate::{deinterleave, Error, SampleRate, Tags};
 use audiopus::coder::{Decoder as OpusDec, GenericCtl};
 use byteorder::{ByteOrder, LittleEndian};
-use ogg::{Packet, PacketReader};
+use ogg::Packet;
+use std::collections::VecDeque;
 use std::convert::TryFrom;
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
 
 //--- Final range  things ------------------------------------------------------
 
@@ -32,135 +40,1461 @@ pub(crate) fn get_final_range() -> u32 {
 
 //--- Code ---------------------------------------------------------------------
 
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PlayData {
+    /// Those of the decoded audio, which go by `ChannelPolicy`
     pub channels: u16,
+    pub tags: Tags,
+    /// 48 kHz samples dropped from the start, as in the header
+    pub pre_skip: u16,
+    /// Rate of the audio before it was encoded, 0 if unknown. Informational
+    /// only, Opus itself always works at 48 kHz
+    pub input_sample_rate: u32,
+    /// Gain the header asks for, in 1/256 dB (Q7.8)
+    pub output_gain: i16,
+    /// 0 for mono and stereo, 1 for surround in Vorbis order, 255 for
+    /// channels with no defined meaning
+    pub channel_mapping_family: u8,
+    /// Of the Ogg stream being decoded
+    pub serial: u32,
 }
 
 struct DecodeData {
+    // Already at TARGET_SPS
     pre_skip: u16,
     gain: i32,
+    // Only for surround (family 1)
+    mapping: Option<Mapping>,
 }
 
-/**Reads audio from Ogg Opus, note: it only can read from the ones produced
-by itself, this is not ready for anything more, third return is final range just
-available while testing, otherwise it is a 0*/
-pub fn decode<T: Read + Seek, const TARGET_SPS: u32>(
+/// Tweaks on how the decoder treats its input
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DecodeOptions {
+    /// Accept streams that don't follow RFC 7845 to the letter: extra
+    /// non-audio packets (empty ones, repeated headers ...) found before the
+    /// first audio packet are skipped instead of failing the decode, and a
+    /// missing or truncated comment header leaves the tags empty (or with
+    /// what could be read), noted in `LossReport::broken_tags`
+    pub lenient: bool,
+    /// R128 gain tag to apply on top of the header's output gain, if the
+    /// stream has it
+    pub r128_gain: Option<R128Gain>,
+    /// Channels to give the audio in, whatever the stream has
+    pub channels: ChannelPolicy,
+    /// Gain to decode with, the header's (and `r128_gain`) by default
+    pub gain: GainPolicy,
+    /// Keep going on damaged input: pages that are corrupt (bad checksum,
+    /// garbage in between) are skipped up to the next one, and packets
+    /// libopus can't decode are concealed. What was lost goes in the
+    /// `LossReport`
+    pub tolerate_errors: bool,
+    /// Check the granule position of every page against the audio decoded up
+    /// to it, failing with `Error::GranulePositionMismatch` on the first one
+    /// that's off instead of going by the audio. Only the last page may
+    /// be short (end trimming). For validating encoders and fuzzing
+    pub check_granules: bool,
+    /// Hold the stream to RFC 7845's rules on pages: OpusHead alone on the
+    /// first page, OpusTags on pages of its own, granule 0 on both, and
+    /// every stream flagged as beginning and ending where it does. Whatever
+    /// breaks them fails with its own error (`Error::MissingBos`,
+    /// `Error::HeaderNotAlone` ...). For encoder validation suites
+    pub strict: bool,
+    /// Most audio to decode, in 48 kHz samples per channel whatever the rate
+    /// decoded at, failing with `Error::TooLong` past it. For servers taking
+    /// untrusted uploads, where a small file of tiny packets can decode to
+    /// gigabytes
+    pub max_samples: Option<u64>,
+    /// Most Ogg pages to read, failing with `Error::TooManyPages` past it
+    pub max_pages: Option<u64>,
+    /// Biggest packet to accept, in bytes, failing with
+    /// `Error::PacketTooLarge` as soon as one goes past it, before the pages
+    /// it spans pile up
+    pub max_packet_size: Option<usize>,
+}
+
+/// What decoding with `tolerate_errors` (or `lenient`) had to leave out
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LossReport {
+    /// Byte ranges of the input skipped as not making valid pages
+    pub skipped: Vec<Range<u64>>,
+    /// Packets that couldn't be decoded and were concealed instead
+    pub bad_packets: usize,
+    /// The comment header was missing or couldn't be read whole
+    pub broken_tags: bool,
+}
+
+/// How many channels come out of the decoder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChannelPolicy {
+    /// Those of the stream
+    #[default]
+    AsIs,
+    /// Everything mixed into one, e.g. for speech recognition
+    ForceMono,
+    /// Mono played on both sides, surround folded into two
+    ForceStereo,
+}
+
+/// What gain the decoder applies
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum GainPolicy {
+    /// The header's output gain, plus the R128 tag picked by
+    /// `DecodeOptions::r128_gain`
+    #[default]
+    Apply,
+    /// None at all, the signal as encoded (for mastering tools)
+    Ignore,
+    /// This one instead, in 1/256 dB (Q7.8). For a user volume on top of
+    /// the header's gain, add it to `PlayData::output_gain`
+    Custom(i32),
+}
+
+/// Which of the R128 gain tags to follow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum R128Gain {
+    /// `R128_TRACK_GAIN`
+    Track,
+    /// `R128_ALBUM_GAIN`, or the track one for streams without it
+    Album,
+}
+
+/**Reads audio from Ogg Opus, mono or stereo, as any RFC 7845 encoder writes
+it: packets can span pages, and other streams multiplexed with it (Skeleton,
+video ...) are skipped. Chained streams (files just put one after the other)
+come out as one, as long as their channels agree, with the `PlayData` of the
+first. Reading is forward only, so any `Read` works, stdin and sockets
+included*/
+pub fn decode<T: Read, const TARGET_SPS: u32>(data: T) -> Result<(Vec<i16>, PlayData), Error> {
+    decode_with_options::<T, TARGET_SPS>(data, &DecodeOptions::default())
+}
+
+/// Same as `decode`, but allows tweaking the decoder behaviour through
+/// `DecodeOptions`
+pub fn decode_with_options<T: Read, const TARGET_SPS: u32>(
     data: T,
+    options: &DecodeOptions,
 ) -> Result<(Vec<i16>, PlayData), Error> {
-    let opus_sr = const {
-        match s_ps_to_audiopus(TARGET_SPS) {
-            Some(v) => v,
-            None => panic!("Wrong SampleRate"),
-        }
+    let mut buffer = Vec::new();
+    let play_data = decode_into_with::<T, TARGET_SPS>(data, &mut buffer, options)?;
+    Ok((buffer, play_data))
+}
+
+/// Same as `decode`, for damaged files: whatever can't be read or decoded is
+/// skipped and given in the report (see `DecodeOptions::tolerate_errors`),
+/// and the rest of the audio comes out
+pub fn decode_lossy<T: Read, const TARGET_SPS: u32>(
+    data: T,
+) -> Result<(Vec<i16>, PlayData, LossReport), Error> {
+    let options = DecodeOptions {
+        tolerate_errors: true,
+        ..DecodeOptions::default()
     };
+    let mut decoder = Decoder::<T, TARGET_SPS>::with_options(data, &options)?;
+    let play_data = decoder.play_data.clone();
 
-    // Data
-    let mut reader = PacketReader::new(data);
+    let mut buffer = Vec::new();
+    while let Some(frame) = decoder.next_frame()? {
+        buffer.extend_from_slice(&frame.samples);
+    }
+    Ok((buffer, play_data, decoder.core.loss))
+}
+
+/// Same as `decode`, but the audio goes into `out` (cleared first), so that
+/// its memory can be used again and again when decoding lots of short clips
+pub fn decode_into<T: Read, const TARGET_SPS: u32>(
+    data: T,
+    out: &mut Vec<i16>,
+) -> Result<PlayData, Error> {
+    decode_into_with::<T, TARGET_SPS>(data, out, &DecodeOptions::default())
+}
+
+fn decode_into_with<T: Read, const TARGET_SPS: u32>(
+    data: T,
+    out: &mut Vec<i16>,
+    options: &DecodeOptions,
+) -> Result<PlayData, Error> {
+    out.clear();
+    let mut decoder = Decoder::<T, TARGET_SPS>::with_options(data, options)?;
+    let play_data = decoder.play_data.clone();
 
-    let fp = reader
-        .read_packet_expected()
-        .map_err(|_| Error::MalformedAudio)?;
-    let (play_data, dec_data) = check_fp::<TARGET_SPS>(&fp)?;
+    while let Some(frame) = decoder.next_frame()? {
+        // Chains can only go in one buffer if they agree
+        if decoder.play_data.channels != play_data.channels {
+            return Err(Error::MalformedAudio);
+        }
+        out.extend_from_slice(&frame.samples);
+        decoder.recycle(frame);
+    }
 
-    let chans = match play_data.channels {
-        1 => audiopus::Channels::Mono,
-        2 => audiopus::Channels::Stereo,
-        _ => return Err(Error::MalformedAudio),
+    if cfg!(test) {
+        set_final_range(decoder.core.final_range().unwrap())
     };
 
-    // According to RFC7845 if a device supports 48Khz, decode at this rate
-    let mut decoder = OpusDec::new(opus_sr, chans)?;
-    decoder.set_gain(dec_data.gain)?;
+    Ok(play_data)
+}
+
+/// Reads just the headers and gives back the comment header (vendor, title,
+/// artist ...), without decoding any audio
+pub fn read_tags<T: Read>(data: T) -> Result<Tags, Error> {
+    let decoder = Decoder::<T, OGG_OPUS_SPS>::new(data)?;
+    Ok(decoder.play_data.tags)
+}
+
+/// Same as `decode`, with the output rate chosen at runtime instead of at
+/// compile time
+pub fn decode_at<T: Read>(data: T, rate: SampleRate) -> Result<(Vec<i16>, PlayData), Error> {
+    match rate {
+        SampleRate::Hz8000 => decode::<T, 8000>(data),
+        SampleRate::Hz12000 => decode::<T, 12000>(data),
+        SampleRate::Hz16000 => decode::<T, 16000>(data),
+        SampleRate::Hz24000 => decode::<T, 24000>(data),
+        SampleRate::Hz48000 => decode::<T, 48000>(data),
+    }
+}
+
+/// Same as `decode`, giving the audio as `f32` (full scale being 1.0) straight
+/// from the decoder, without going through `i16`
+pub fn decode_float<T: Read, const TARGET_SPS: u32>(
+    data: T,
+) -> Result<(Vec<f32>, PlayData), Error> {
+    let mut decoder = Decoder::<T, TARGET_SPS>::new(data)?;
+
+    let mut buffer = Vec::new();
+    while let Some(frame) = decoder.next_frame_float()? {
+        buffer.extend_from_slice(&frame.samples);
+    }
 
-    // Vendor and other tags, do a basic check
-    let sp = reader
-        .read_packet_expected()
-        .map_err(|_| Error::MalformedAudio)?;
+    Ok((buffer, decoder.play_data))
+}
 
-    check_sp(&sp)?;
+/// Same as `decode_float`, scaled to 24 bits (full scale being 8388608) in
+/// `i32`s, for 24-bit WAV or FLAC without going through 16 bits first
+pub fn decode_i24<T: Read, const TARGET_SPS: u32>(data: T) -> Result<(Vec<i32>, PlayData), Error> {
+    let mut decoder = Decoder::<T, TARGET_SPS>::new(data)?;
 
     let mut buffer = Vec::new();
-    let mut rem_skip = dec_data.pre_skip as usize;
-    let mut dec_absgsp = 0;
+    while let Some(frame) = decoder.next_frame_float()? {
+        buffer.extend(frame.samples.iter().map(|&s| to_i24(s)));
+    }
+
+    Ok((buffer, decoder.play_data))
+}
+
+// Clipping whatever goes out of range, as the float decoder can
+pub(crate) fn to_i24(sample: f32) -> i32 {
+    (sample as f64 * I24_SCALE)
+        .round()
+        .clamp(-I24_SCALE, I24_SCALE - 1.0) as i32
+}
+
+const I24_SCALE: f64 = 8388608.0;
+
+type DecodedSplit = (Vec<i16>, Vec<i16>, PlayData);
+
+/// Same as `decode`, with left and right in their own buffers, split as each
+/// packet is decoded instead of in a second pass. A mono stream gives the same
+/// audio in both
+pub fn decode_split<T: Read, const TARGET_SPS: u32>(data: T) -> Result<DecodedSplit, Error> {
+    let mut decoder = Decoder::<T, TARGET_SPS>::new(data)?;
+    let stereo = decoder.play_data().channels == 2;
+
+    let mut left = Vec::new();
+    let mut right = Vec::new();
+    while let Some(frame) = decoder.next_frame()? {
+        if stereo {
+            for pair in frame.samples.chunks_exact(2) {
+                left.push(pair[0]);
+                right.push(pair[1]);
+            }
+        } else {
+            left.extend_from_slice(&frame.samples);
+            right.extend_from_slice(&frame.samples);
+        }
+    }
+
+    Ok((left, right, decoder.play_data))
+}
+
+/// Same as `decode`, with each channel in its own buffer (planar), for any
+/// number of channels. Split as each packet is decoded, like `decode_split`
+pub fn decode_planar<T: Read, const TARGET_SPS: u32>(
+    data: T,
+) -> Result<(Vec<Vec<i16>>, PlayData), Error> {
+    let mut decoder = Decoder::<T, TARGET_SPS>::new(data)?;
+    let channels = decoder.play_data().channels as usize;
+
+    let mut planes = vec![Vec::new(); channels];
+    while let Some(frame) = decoder.next_frame()? {
+        if decoder.play_data.channels as usize != channels {
+            return Err(Error::MalformedAudio);
+        }
+        for (plane, split) in planes
+            .iter_mut()
+            .zip(deinterleave(&frame.samples, channels))
+        {
+            plane.extend(split);
+        }
+    }
+
+    Ok((planes, decoder.play_data))
+}
+
+/// How raw PCM is laid out, samples are always interleaved little endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PcmFormat {
+    I16,
+    /// From -1.0 to 1.0, straight from libopus' float decoder
+    F32,
+    /// 3 bytes per sample, from the float decoder as well
+    I24,
+}
+
+/// Same as `decode`, giving the audio as raw bytes, ready to be piped to
+/// sox/ffmpeg or sent through a socket
+pub fn decode_pcm_bytes<T: Read, const TARGET_SPS: u32>(
+    data: T,
+    format: PcmFormat,
+) -> Result<(Vec<u8>, PlayData), Error> {
+    let (bytes, play_data) = match format {
+        PcmFormat::I16 => {
+            let (audio, play_data) = decode::<T, TARGET_SPS>(data)?;
+            let mut bytes = vec![0u8; audio.len() * 2];
+            LittleEndian::write_i16_into(&audio, &mut bytes);
+            (bytes, play_data)
+        }
+        PcmFormat::F32 => {
+            let (audio, play_data) = decode_float::<T, TARGET_SPS>(data)?;
+            let mut bytes = vec![0u8; audio.len() * 4];
+            LittleEndian::write_f32_into(&audio, &mut bytes);
+            (bytes, play_data)
+        }
+        PcmFormat::I24 => {
+            let (audio, play_data) = decode_i24::<T, TARGET_SPS>(data)?;
+            (i24_bytes(&audio), play_data)
+        }
+    };
+
+    Ok((bytes, play_data))
+}
+
+pub(crate) fn i24_bytes(samples: &[i32]) -> Vec<u8> {
+    let mut bytes = vec![0u8; samples.len() * 3];
+    for (sample, out) in samples.iter().zip(bytes.chunks_exact_mut(3)) {
+        LittleEndian::write_i24(out, *sample);
+    }
+    bytes
+}
+
+/// Audio coming out of a single Opus packet, as `i16` or `f32`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedFrame<S = i16> {
+    /// Interleaved samples
+    pub samples: Vec<S>,
+    /// When the first sample is to be played, counted from the start of the
+    /// audio (the pre-skip is already taken out)
+    pub timestamp: std::time::Duration,
+    /// What the packet it came from holds, `None` for concealed audio
+    pub toc: Option<Toc>,
+    /// Where that packet is in the stream, `None` for concealed audio
+    pub info: Option<FrameInfo>,
+}
+
+/// Where the packet a frame was decoded from is, for waveforms, subtitles or
+/// progress bars tied to exact positions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrameInfo {
+    /// Same as the frame's `timestamp`
+    pub start_time: std::time::Duration,
+    /// Granule position at the end of the packet, 48 kHz samples counting
+    /// the pre-skip, as decoded rather than as the page says
+    pub granule: u64,
+    /// Sequence number of the page the packet ends in
+    pub page_seq: u32,
+    /// Size of the packet, bytes
+    pub packet_len: usize,
+}
+
+/**What `DecoderCore` has to say after being fed. Chained streams (one
+stream after the other, as `cat a.opus b.opus` gives) go through `Headers`,
+frames and `End` each, timestamps starting over.*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeEvent<S = i16> {
+    /// Both headers were read, always the first event
+    Headers(PlayData),
+    Frame(DecodedFrame<S>),
+    /// The stream ended, only another chained one can follow
+    End,
+}
+
+// libopus has a different decoder for surround
+pub(crate) enum Codec {
+    Single(OpusDec),
+    Surround(MultistreamDecoder),
+}
+
+impl Codec {
+    // `mapping` as `decoder_mapping` gives it, `channels` are those decoded
+    pub(crate) fn open<const TARGET_SPS: u32>(
+        channels: u8,
+        mapping: Option<&Mapping>,
+    ) -> Result<Self, Error> {
+        let opus_sr = const {
+            match s_ps_to_audiopus(TARGET_SPS) {
+                Some(v) => v,
+                None => panic!("Wrong SampleRate"),
+            }
+        };
+        Ok(match mapping {
+            Some(mapping) => {
+                Codec::Surround(MultistreamDecoder::new(TARGET_SPS, channels, mapping)?)
+            }
+            None if channels == 2 => {
+                Codec::Single(OpusDec::new(opus_sr, audiopus::Channels::Stereo)?)
+            }
+            None => Codec::Single(OpusDec::new(opus_sr, audiopus::Channels::Mono)?),
+        })
+    }
+
+    // With `fec` what comes out is the packet before `packet`, recovered from
+    // the copy inside it, `output` has to be just as long as what was lost
+    fn decode(
+        &mut self,
+        packet: Option<&[u8]>,
+        output: &mut [i16],
+        fec: bool,
+    ) -> Result<usize, Error> {
+        match self {
+            Codec::Single(decoder) => {
+                let packet = packet.map(audiopus::packet::Packet::try_from).transpose()?;
+                let output = audiopus::MutSignals::try_from(output)?;
+                Ok(decoder.decode(packet, output, fec)?)
+            }
+            Codec::Surround(decoder) => decoder.decode(packet, output, fec),
+        }
+    }
+
+    pub(crate) fn decode_float(
+        &mut self,
+        packet: Option<&[u8]>,
+        output: &mut [f32],
+        fec: bool,
+    ) -> Result<usize, Error> {
+        match self {
+            Codec::Single(decoder) => {
+                let packet = packet.map(audiopus::packet::Packet::try_from).transpose()?;
+                let output = audiopus::MutSignals::try_from(output)?;
+                Ok(decoder.decode_float(packet, output, fec)?)
+            }
+            Codec::Surround(decoder) => decoder.decode_float(packet, output, fec),
+        }
+    }
+
+    // Forget everything decoded, as if it was just made
+    pub(crate) fn reset(&mut self) -> Result<(), Error> {
+        match self {
+            Codec::Single(decoder) => Ok(decoder.reset_state()?),
+            Codec::Surround(decoder) => decoder.reset(),
+        }
+    }
+
+    pub(crate) fn set_gain(&mut self, gain: i32) -> Result<(), Error> {
+        match self {
+            Codec::Single(decoder) => Ok(decoder.set_gain(gain)?),
+            Codec::Surround(decoder) => decoder.set_gain(gain),
+        }
+    }
+
+    fn final_range(&self) -> Result<u32, Error> {
+        match self {
+            Codec::Single(decoder) => Ok(decoder.final_range()?),
+            Codec::Surround(decoder) => decoder.final_range(),
+        }
+    }
+}
+
+// Everything that's needed once the headers are read
+struct AudioState {
+    decoder: Codec,
+    // Per channel samples still to be skipped
+    rem_skip: usize,
+    // Per channel samples decoded so far, granule but at TARGET_SPS
+    dec_absgsp: u64,
+    // Per channel samples handed out so far
+    played: u64,
+    // Only used in lenient mode, until then anything might be a stray header
+    audio_started: bool,
     // We don't need to reallocate temp_buffer because:
     // 1) We dont borrow
     // 2) Decoder fully rewrites temp_buffer
-    let mut temp_buffer = [0; MAX_FRAME_SIZE];
+    // Sized for the longest packet with every channel
+    temp_buffer: Vec<i16>,
+    // Same for float output, only allocated if asked for it
+    float_buffer: Vec<f32>,
+    // A frame handed back through `recycle`, to put the next one in
+    spare: Vec<i16>,
+    // Those coming out of libopus and those handed out
+    channels: usize,
+    out_channels: usize,
+    // To go from one to the other, see `stereo_weights`
+    weights: Vec<(f32, f32)>,
+}
 
-    while let Some(packet) = reader.read_packet()? {
-        let inner_packet = audiopus::packet::Packet::try_from(&packet.data)?;
-        let again_buffer = audiopus::MutSignals::try_from(&mut temp_buffer[..])?;
+impl AudioState {
+    // Hands out what was just decoded, minus what still has to be skipped and
+    // whatever goes past `end` (a granule at TARGET_SPS)
+    fn take<S: OutSample>(
+        &mut self,
+        out_size: usize,
+        end: Option<u64>,
+        sps: u32,
+    ) -> Option<DecodedFrame<S>> {
+        self.dec_absgsp += out_size as u64;
 
-        let out_size = decoder.decode(Some(inner_packet), again_buffer, false)?;
+        // out_size == num of samples *per channel*
+        if self.rem_skip >= out_size {
+            self.rem_skip -= out_size;
+            return None;
+        }
 
-        dec_absgsp += out_size;
+        let mut trimmed_end = out_size;
+        if let Some(end) = end {
+            if self.dec_absgsp > end {
+                trimmed_end -= (self.dec_absgsp - end).min(out_size as u64) as usize;
+            }
+        }
 
-        // out_size == num of samples *per channel*
-        if rem_skip < out_size {
-            let mut trimmed_end = out_size * play_data.channels as usize;
-            if packet.last_in_stream() {
-                let absgsp = calc_sr_u64(packet.absgp_page(), OGG_OPUS_SPS, TARGET_SPS) as usize;
+        let skip = self.rem_skip;
+        self.rem_skip = 0;
+        if trimmed_end <= skip {
+            return None;
+        }
+
+        let mut samples = S::spare(self);
+        let decoded = &S::decoded(self)[skip * self.channels..trimmed_end * self.channels];
+        let samples = if self.out_channels == self.channels {
+            samples.clear();
+            samples.extend_from_slice(decoded);
+            samples
+        } else {
+            downmix(decoded, &self.weights, self.out_channels)
+        };
+        let timestamp = samples_to_duration(self.played, sps);
+        self.played += (trimmed_end - skip) as u64;
+        Some(DecodedFrame {
+            samples,
+            timestamp,
+            toc: None,
+            info: None,
+        })
+    }
+}
+
+// What can come out of the decoder
+trait OutSample: Sample {
+    // Per channel samples decoded, `len` (also per channel) is how much room
+    // the decoder gets, see `Codec::decode` for `fec`
+    fn decode(
+        audio: &mut AudioState,
+        packet: Option<&[u8]>,
+        len: usize,
+        fec: bool,
+    ) -> Result<usize, Error>;
+    fn decoded(audio: &AudioState) -> &[Self];
+    // Somewhere to put the next frame, empty if there's nothing to reuse
+    fn spare(audio: &mut AudioState) -> Vec<Self>;
+}
+
+impl OutSample for i16 {
+    fn decode(
+        audio: &mut AudioState,
+        packet: Option<&[u8]>,
+        len: usize,
+        fec: bool,
+    ) -> Result<usize, Error> {
+        let output = &mut audio.temp_buffer[..len * audio.channels];
+        audio.decoder.decode(packet, output, fec)
+    }
+
+    fn decoded(audio: &AudioState) -> &[Self] {
+        &audio.temp_buffer
+    }
+
+    fn spare(audio: &mut AudioState) -> Vec<Self> {
+        std::mem::take(&mut audio.spare)
+    }
+}
+
+impl OutSample for f32 {
+    fn decode(
+        audio: &mut AudioState,
+        packet: Option<&[u8]>,
+        len: usize,
+        fec: bool,
+    ) -> Result<usize, Error> {
+        if audio.float_buffer.is_empty() {
+            audio.float_buffer = vec![0.0; audio.temp_buffer.len()];
+        }
+        let output = &mut audio.float_buffer[..len * audio.channels];
+        audio.decoder.decode_float(packet, output, fec)
+    }
+
+    fn decoded(audio: &AudioState) -> &[Self] {
+        &audio.float_buffer
+    }
+
+    fn spare(_audio: &mut AudioState) -> Vec<Self> {
+        Vec::new()
+    }
+}
+
+/**Sans-IO decoder: Ogg bytes go in, events come out, with nothing being read
+on its own. `Decoder` and `decode` are built on top of it, and it's what to use
+to plug the decoder somewhere else (async, FFI ...). Bytes can come in chunks
+of any size, `poll` until it gives `None`, then feed some more.*/
+pub struct DecoderCore<const TARGET_SPS: u32> {
+    splitter: PacketSplitter,
+    packets: VecDeque<Packet>,
+    lenient: bool,
+    r128_gain: Option<R128Gain>,
+    channel_policy: ChannelPolicy,
+    gain_policy: GainPolicy,
+    tolerate_errors: bool,
+    check_granules: bool,
+    max_samples: Option<u64>,
+    max_pages: Option<u64>,
+    pages: u64,
+    loss: LossReport,
+    // Got from the first header, waiting for the second
+    head: Option<(PlayData, DecodeData)>,
+    // Stream we decode, others multiplexed with it (video, Skeleton ...) are
+    // skipped
+    serial: Option<u32>,
+    audio: Option<AudioState>,
+    // Per channel samples lost right before the next packet, to be recovered
+    // from it
+    lost: usize,
+    ended: bool,
+    end_sent: bool,
+    // Found something that isn't Ogg after the end, nothing else is read
+    trailing: bool,
+}
+
+impl<const TARGET_SPS: u32> DecoderCore<TARGET_SPS> {
+    pub fn new(options: &DecodeOptions) -> Self {
+        let mut splitter = PacketSplitter::new();
+        if let Some(max) = options.max_packet_size {
+            splitter.limit_packets(max);
+        }
+        if options.strict {
+            splitter.strict();
+        }
+        Self {
+            splitter,
+            packets: VecDeque::new(),
+            lenient: options.lenient,
+            r128_gain: options.r128_gain,
+            channel_policy: options.channels,
+            gain_policy: options.gain,
+            tolerate_errors: options.tolerate_errors,
+            check_granules: options.check_granules,
+            max_samples: options.max_samples,
+            max_pages: options.max_pages,
+            pages: 0,
+            loss: LossReport::default(),
+            head: None,
+            serial: None,
+            audio: None,
+            lost: 0,
+            ended: false,
+            end_sent: false,
+            trailing: false,
+        }
+    }
+
+    pub fn push_bytes(&mut self, data: &[u8]) {
+        if !self.trailing {
+            self.splitter.push_bytes(data);
+        }
+    }
+
+    /// Whether there are bytes waiting for the rest of their page, if the
+    /// input ends here, the stream was cut short
+    pub fn has_pending(&self) -> bool {
+        if self.ended {
+            // Anything not Ogg after the end is not ours to worry about
+            !self.trailing && self.splitter.pending_page()
+        } else {
+            self.splitter.has_pending()
+        }
+    }
+
+    /**Tells that a packet lasting `duration` went missing right before the
+    next one pushed (e.g. a gap in RTP sequence numbers). If that next packet
+    carries FEC data (see `EncoderOptions::inband_fec`), the lost audio is
+    recovered from it, otherwise libopus conceals it; either way the next
+    `poll` gives it as its own frame before the packet's audio. `duration` is
+    rounded down to a multiple of 2.5 ms and capped at 120 ms, and losses
+    before any audio was decoded are ignored*/
+    pub fn packet_lost(&mut self, duration: std::time::Duration) {
+        if !self.audio.as_ref().is_some_and(|audio| audio.audio_started) {
+            return;
+        }
+        self.lost = (self.lost + loss_samples(duration, TARGET_SPS)).min(max_loss(TARGET_SPS));
+    }
+
+    /**Makes up audio for a packet lasting `duration` that's gone for good
+    (libopus packet loss concealment), to fill the gap right where it is
+    instead of going silent. Same rounding as `packet_lost`, fails with
+    `Error::InvalidRange` if that leaves nothing, and with
+    `Error::MissingHeaders` before the headers are read*/
+    pub fn conceal(&mut self, duration: std::time::Duration) -> Result<DecodedFrame, Error> {
+        self.conceal_as(duration)
+    }
 
-                if dec_absgsp > absgsp {
-                    trimmed_end -= dec_absgsp - absgsp;
+    /// Same as `conceal`, with the audio as `f32`
+    pub fn conceal_float(
+        &mut self,
+        duration: std::time::Duration,
+    ) -> Result<DecodedFrame<f32>, Error> {
+        self.conceal_as(duration)
+    }
+
+    fn conceal_as<S: OutSample>(
+        &mut self,
+        duration: std::time::Duration,
+    ) -> Result<DecodedFrame<S>, Error> {
+        let audio = self.audio.as_mut().ok_or(Error::MissingHeaders)?;
+        let len = loss_samples(duration, TARGET_SPS).min(max_loss(TARGET_SPS));
+        if len == 0 {
+            return Err(Error::InvalidRange);
+        }
+
+        let out_size = S::decode(audio, None, len, false)?;
+        // Might all go into the pre-skip
+        let frame = audio
+            .take(out_size, None, TARGET_SPS)
+            .unwrap_or_else(|| DecodedFrame {
+                samples: Vec::new(),
+                timestamp: samples_to_duration(audio.played, TARGET_SPS),
+                toc: None,
+                info: None,
+            });
+        check_played(audio, self.max_samples, TARGET_SPS)?;
+        Ok(frame)
+    }
+
+    /// Next event, `None` if more bytes are needed for it
+    pub fn poll(&mut self) -> Result<Option<DecodeEvent>, Error> {
+        self.poll_as()
+    }
+
+    /// Same as `poll`, with the audio as `f32` straight from the decoder
+    pub fn poll_float(&mut self) -> Result<Option<DecodeEvent<f32>>, Error> {
+        self.poll_as()
+    }
+
+    fn poll_as<S: OutSample>(&mut self) -> Result<Option<DecodeEvent<S>>, Error> {
+        loop {
+            if self.ended && !self.end_sent {
+                self.end_sent = true;
+                return Ok(Some(DecodeEvent::End));
+            }
+            if self.trailing {
+                return Ok(None);
+            }
+
+            let packet = match self.next_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return Ok(None),
+                // Whatever comes after the end (ID3 tags ...) is ignored
+                Err(_) if self.ended => {
+                    self.trailing = true;
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            };
+
+            if self.ended {
+                // Chained streams: a new one begins, just like the first did
+                if !packet.first_in_stream() || !packet.data.starts_with(&OPUS_MAGIC_HEADER) {
+                    continue;
                 }
+                self.serial = None;
+                self.head = None;
+                self.audio = None;
+                self.lost = 0;
+                self.ended = false;
+                self.end_sent = false;
             }
 
-            buffer.extend_from_slice(&temp_buffer[rem_skip..trimmed_end]);
-            rem_skip = 0;
-        } else {
-            rem_skip -= out_size;
+            match self.serial {
+                Some(serial) if packet.stream_serial() != serial => continue,
+                Some(_) => {}
+                // Other streams might start before ours
+                None if packet.first_in_stream()
+                    && !packet.data.starts_with(&OPUS_MAGIC_HEADER) =>
+                {
+                    continue
+                }
+                None => self.serial = Some(packet.stream_serial()),
+            }
+
+            let audio = match &mut self.audio {
+                Some(audio) => audio,
+                None => {
+                    match self.head.take() {
+                        None => self.head = Some(check_fp::<TARGET_SPS>(&packet)?),
+                        Some((mut play_data, dec_data)) => {
+                            // According to RFC7845 if a device supports 48Khz, decode at this rate
+                            let out_channels = match self.channel_policy {
+                                ChannelPolicy::AsIs => play_data.channels as u8,
+                                ChannelPolicy::ForceMono => 1,
+                                ChannelPolicy::ForceStereo => 2,
+                            };
+                            // libopus goes between mono and stereo on its own,
+                            // surround has to be mixed down afterwards
+                            let channels = match dec_data.mapping {
+                                Some(_) => play_data.channels as u8,
+                                None => out_channels,
+                            };
+                            play_data.channels = out_channels as u16;
+                            let mut decoder =
+                                Codec::open::<TARGET_SPS>(channels, dec_data.mapping.as_ref())?;
+                            // Vendor and other tags, do a basic check
+                            play_data.tags = match Tags::from_packet(&packet.data) {
+                                Ok(tags) => tags,
+                                Err(_) if self.lenient => {
+                                    self.loss.broken_tags = true;
+                                    match Tags::from_packet_lenient(&packet.data) {
+                                        Some(tags) => tags,
+                                        // Not there at all, this is audio
+                                        None => {
+                                            self.packets.push_front(packet);
+                                            Tags {
+                                                vendor: String::new(),
+                                                comments: Vec::new(),
+                                            }
+                                        }
+                                    }
+                                }
+                                Err(e) => return Err(e),
+                            };
+
+                            let tag_gain = match self.r128_gain {
+                                Some(R128Gain::Track) => play_data.tags.track_gain(),
+                                Some(R128Gain::Album) => play_data
+                                    .tags
+                                    .album_gain()
+                                    .or_else(|| play_data.tags.track_gain()),
+                                None => None,
+                            };
+                            let gain = match self.gain_policy {
+                                GainPolicy::Apply => dec_data.gain + tag_gain.unwrap_or(0) as i32,
+                                GainPolicy::Ignore => 0,
+                                GainPolicy::Custom(gain) => gain,
+                            };
+                            decoder.set_gain(gain.clamp(i16::MIN as i32, i16::MAX as i32))?;
+
+                            self.audio = Some(AudioState {
+                                decoder,
+                                rem_skip: dec_data.pre_skip as usize,
+                                dec_absgsp: 0,
+                                played: 0,
+                                audio_started: false,
+                                temp_buffer: vec![0; MAX_FRAME_SAMPLES * channels as usize],
+                                float_buffer: Vec::new(),
+                                spare: Vec::new(),
+                                channels: channels as usize,
+                                out_channels: out_channels as usize,
+                                weights: stereo_weights(
+                                    channels as usize,
+                                    play_data.channel_mapping_family,
+                                ),
+                            });
+                            self.head = Some((play_data.clone(), dec_data));
+                            return Ok(Some(DecodeEvent::Headers(play_data)));
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            if self.lost > 0 && audio.audio_started {
+                let lost = std::mem::take(&mut self.lost);
+                let recovered = S::decode(audio, Some(&packet.data), lost, true)?;
+                // The packet itself still has to be decoded
+                self.packets.push_front(packet);
+                let frame = audio.take(recovered, None, TARGET_SPS);
+                check_played(audio, self.max_samples, TARGET_SPS)?;
+                match frame {
+                    Some(frame) => return Ok(Some(DecodeEvent::Frame(frame))),
+                    None => continue,
+                }
+            }
+            self.ended = packet.last_in_stream();
+
+            if self.lenient && !audio.audio_started && is_non_audio(&packet.data) {
+                continue;
+            }
+
+            let out_size = match S::decode(audio, Some(&packet.data), MAX_FRAME_SAMPLES, false) {
+                Ok(size) => size,
+                // Some recorders put their own stuff before the audio
+                Err(_) if self.lenient && !audio.audio_started => continue,
+                // Concealed along with the next packet, as if lost
+                Err(_) if self.tolerate_errors => {
+                    self.loss.bad_packets += 1;
+                    if audio.audio_started {
+                        let samples = Toc::parse(&packet.data)
+                            .map_or(MAX_FRAME_SAMPLES, |toc| toc.samples() as usize);
+                        let samples = calc_sr_u64(samples as u64, OGG_OPUS_SPS, TARGET_SPS);
+                        self.lost = (self.lost + samples as usize).min(max_loss(TARGET_SPS));
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            audio.audio_started = true;
+
+            // Live captures might end without a granule, nothing to trim then
+            let end = if packet.last_in_stream() && packet.absgp_page() != UNSET_GRANULE {
+                Some(calc_sr_u64(packet.absgp_page(), OGG_OPUS_SPS, TARGET_SPS))
+            } else {
+                None
+            };
+            let mut frame = audio.take(out_size, end, TARGET_SPS);
+            check_played(audio, self.max_samples, TARGET_SPS)?;
+            if let Some(frame) = &mut frame {
+                frame.toc = Toc::parse(&packet.data);
+                frame.info = Some(FrameInfo {
+                    start_time: frame.timestamp,
+                    granule: calc_sr_u64(audio.dec_absgsp, TARGET_SPS, OGG_OPUS_SPS),
+                    page_seq: self.splitter.sequence(),
+                    packet_len: packet.data.len(),
+                });
+            }
+            if self.check_granules && packet.last_in_page() {
+                let decoded = calc_sr_u64(audio.dec_absgsp, TARGET_SPS, OGG_OPUS_SPS);
+                let found = packet.absgp_page();
+                let valid = found == decoded || (packet.last_in_stream() && found < decoded);
+                if !valid {
+                    return Err(Error::GranulePositionMismatch {
+                        page: self.splitter.sequence(),
+                        expected: decoded,
+                        found,
+                    });
+                }
+            }
+            if let Some(frame) = frame {
+                return Ok(Some(DecodeEvent::Frame(frame)));
+            }
         }
     }
 
-    if cfg!(test) {
-        set_final_range(decoder.final_range().unwrap())
-    };
+    /// libopus' range coder state after the last packet decoded, to compare
+    /// with `EncodeReport::final_range`. None before the headers
+    pub fn final_range(&self) -> Option<u32> {
+        self.audio.as_ref()?.decoder.final_range().ok()
+    }
 
-    Ok((buffer, play_data))
+    // Serial and pre-skip of the stream being decoded, what seeking needs
+    pub(crate) fn seek_info(&self) -> Result<(u32, u64), Error> {
+        match (self.serial, &self.head) {
+            (Some(serial), Some((_, dec_data))) => Ok((serial, dec_data.pre_skip as u64)),
+            _ => Err(Error::MissingHeaders),
+        }
+    }
+
+    // The input jumped to right after a page ending at `granule`, and what
+    // comes out next has to start at `target` (per channel samples at
+    // TARGET_SPS, without the pre-skip)
+    pub(crate) fn seeked(&mut self, offset: u64, granule: u64, target: u64) -> Result<(), Error> {
+        let pre_skip = match &self.head {
+            Some((_, dec_data)) => dec_data.pre_skip as u64,
+            None => return Err(Error::MissingHeaders),
+        };
+        let audio = self.audio.as_mut().ok_or(Error::MissingHeaders)?;
+        audio.decoder.reset()?;
+        audio.dec_absgsp = calc_sr_u64(granule, OGG_OPUS_SPS, TARGET_SPS);
+        let start = target.saturating_add(pre_skip);
+        audio.rem_skip = start.saturating_sub(audio.dec_absgsp) as usize;
+        audio.played = target;
+        audio.audio_started = true;
+
+        self.splitter.seeked(offset);
+        self.packets.clear();
+        self.lost = 0;
+        self.ended = false;
+        Ok(())
+    }
+
+    fn next_packet(&mut self) -> Result<Option<Packet>, Error> {
+        while self.packets.is_empty() {
+            match self.splitter.read_page() {
+                Ok(Some(packets)) => {
+                    self.pages += 1;
+                    if self.max_pages.is_some_and(|max| self.pages > max) {
+                        return Err(Error::TooManyPages);
+                    }
+                    self.packets.extend(packets)
+                }
+                Ok(None) => return Ok(None),
+                Err(_) if self.tolerate_errors && !self.ended => {
+                    let offset = self.splitter.offset();
+                    let len = self.splitter.resync();
+                    self.skipped(offset..offset + len);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.packets.pop_front())
+    }
+
+    // Regions found one right after the other go together
+    fn skipped(&mut self, range: Range<u64>) {
+        match self.loss.skipped.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => self.loss.skipped.push(range),
+        }
+    }
+
+    /// Gives back a frame that's done with, its memory then goes to the next
+    /// one so that frames don't each take an allocation
+    pub fn recycle(&mut self, frame: DecodedFrame) {
+        if let Some(audio) = &mut self.audio {
+            audio.spare = frame.samples;
+        }
+    }
+
+    /// What had to be left out so far with `tolerate_errors`
+    pub fn loss_report(&self) -> &LossReport {
+        &self.loss
+    }
+
+    // Whether the first header was read, to tell which one is missing when
+    // the input ends early
+    pub(crate) fn has_head(&self) -> bool {
+        self.head.is_some()
+    }
+
+    // The input is over, with `tolerate_errors` a page cut short is one more
+    // loss, otherwise the stream is broken
+    pub(crate) fn input_ended(&mut self) -> Result<(), Error> {
+        if self.has_pending() {
+            if !self.tolerate_errors {
+                return Err(Error::MalformedAudio);
+            }
+            let offset = self.splitter.offset();
+            let mut len = 0;
+            while self.splitter.has_pending() {
+                len += self.splitter.resync();
+            }
+            self.skipped(offset..offset + len);
+        }
+        self.splitter.check_ended()
+    }
+}
+
+// How much is read from the input at once
+pub(crate) const READ_SIZE: usize = 4096;
+
+/**Streaming decoder, gives the audio back packet by packet instead of all at
+once, so that playback can start right away. Pre-skip and end trimming are
+applied just like in `decode`. With chained streams it goes on to the next
+one, `play_data` then tells about that one.*/
+pub struct Decoder<T: Read, const TARGET_SPS: u32> {
+    data: T,
+    core: DecoderCore<TARGET_SPS>,
+    play_data: PlayData,
+    // Bytes read from `data` before any seek, to know where the stream starts
+    fed: u64,
+    start: Option<u64>,
+}
+
+impl<T: Read, const TARGET_SPS: u32> Decoder<T, TARGET_SPS> {
+    /// Reads the headers, audio is decoded as it's asked for
+    pub fn new(data: T) -> Result<Self, Error> {
+        Self::with_options(data, &DecodeOptions::default())
+    }
+
+    pub fn with_options(mut data: T, options: &DecodeOptions) -> Result<Self, Error> {
+        let mut core = DecoderCore::new(options);
+        let mut fed = 0;
+        loop {
+            match core.poll()? {
+                Some(DecodeEvent::Headers(play_data)) => {
+                    return Ok(Self {
+                        data,
+                        core,
+                        play_data,
+                        fed,
+                        start: None,
+                    })
+                }
+                Some(_) => return Err(Error::MalformedAudio),
+                None => match feed(&mut data, &mut core)? {
+                    // Cut before the headers were through
+                    0 if !core.has_head() => return Err(Error::MissingOpusHead),
+                    0 => return Err(Error::MissingOpusTags),
+                    read => fed += read as u64,
+                },
+            }
+        }
+    }
+
+    pub fn play_data(&self) -> &PlayData {
+        &self.play_data
+    }
+
+    /// Audio to fill in for a packet lasting `duration` that was dropped, see
+    /// `DecoderCore::conceal`
+    pub fn conceal_frame(&mut self, duration: std::time::Duration) -> Result<DecodedFrame, Error> {
+        self.core.conceal(duration)
+    }
+
+    /// Same as `conceal_frame`, with the audio as `f32`
+    pub fn conceal_frame_float(
+        &mut self,
+        duration: std::time::Duration,
+    ) -> Result<DecodedFrame<f32>, Error> {
+        self.core.conceal_float(duration)
+    }
+
+    /// See `DecoderCore::loss_report`
+    pub fn loss_report(&self) -> &LossReport {
+        self.core.loss_report()
+    }
+
+    /// See `DecoderCore::final_range`
+    pub fn final_range(&self) -> Option<u32> {
+        self.core.final_range()
+    }
+
+    /// See `DecoderCore::recycle`
+    pub fn recycle(&mut self, frame: DecodedFrame) {
+        self.core.recycle(frame);
+    }
+
+    /// Next frame with any audio left after trimming, `None` at the end
+    pub fn next_frame(&mut self) -> Result<Option<DecodedFrame>, Error> {
+        self.next_as()
+    }
+
+    /// Same as `next_frame`, with the audio as `f32`
+    pub fn next_frame_float(&mut self) -> Result<Option<DecodedFrame<f32>>, Error> {
+        self.next_as()
+    }
+
+    fn next_as<S: OutSample>(&mut self) -> Result<Option<DecodedFrame<S>>, Error> {
+        loop {
+            match self.core.poll_as()? {
+                Some(DecodeEvent::Frame(frame)) => return Ok(Some(frame)),
+                // Another stream might be chained after this one
+                Some(DecodeEvent::End) => {}
+                Some(DecodeEvent::Headers(play_data)) => self.play_data = play_data,
+                None => match feed(&mut self.data, &mut self.core)? {
+                    // A clean end without the end of stream flag is fine
+                    // (unless strict), a page cut in half is not
+                    0 => {
+                        self.core.input_ended()?;
+                        return Ok(None);
+                    }
+                    read => self.fed += read as u64,
+                },
+            }
+        }
+    }
 }
 
-fn check_sp(sp: &Packet) -> Result<(), Error> {
-    if sp.data.len() < 12 {
-        return Err(Error::MalformedAudio);
+// `DecodeOptions::max_samples` against all that came out so far, decoded,
+// recovered or concealed
+fn check_played(audio: &AudioState, max_samples: Option<u64>, sps: u32) -> Result<(), Error> {
+    match max_samples {
+        Some(max) if calc_sr_u64(audio.played, sps, OGG_OPUS_SPS) > max => Err(Error::TooLong),
+        _ => Ok(()),
+    }
+}
+
+// Per channel samples in `duration`, in whole 2.5 ms steps as libopus wants
+fn loss_samples(duration: std::time::Duration, sps: u32) -> usize {
+    let step = sps as u64 / 400;
+    let samples = duration.as_micros() as u64 * sps as u64 / 1_000_000;
+    (samples / step * step) as usize
+}
+
+// The longest packet there can be, 120 ms
+fn max_loss(sps: u32) -> usize {
+    (MAX_FRAME_SAMPLES as u64 * sps as u64 / OGG_OPUS_SPS as u64) as usize
+}
+
+// Gives the core some more input, how much, 0 once there's none left
+fn feed<T: Read, const TARGET_SPS: u32>(
+    data: &mut T,
+    core: &mut DecoderCore<TARGET_SPS>,
+) -> Result<usize, Error> {
+    let mut buffer = [0u8; READ_SIZE];
+    let read = data.read(&mut buffer)?;
+    core.push_bytes(&buffer[..read]);
+    Ok(read)
+}
+
+// Below this many bytes bisection gives way to going page by page
+pub(crate) const BISECT_SPAN: u64 = 16 * 1024;
+
+impl<T: Read + Seek, const TARGET_SPS: u32> Decoder<T, TARGET_SPS> {
+    /**Jumps to `sample` (per channel, at TARGET_SPS, counted like
+    `DecodedFrame::timestamp`), the next frame starts right there. Pages are
+    found by bisection over their granule positions, and decoding starts 80 ms
+    early to be thrown away, as RFC 7845 asks. Seeking past the end leaves
+    nothing else to decode*/
+    pub fn seek_to_sample(&mut self, sample: u64) -> Result<(), Error> {
+        let (serial, pre_skip) = self.core.seek_info()?;
+        let start = self.stream_start()?;
+        let end = self.data.seek(SeekFrom::End(0))?;
+
+        let target = calc_sr_u64(sample.saturating_add(pre_skip), TARGET_SPS, OGG_OPUS_SPS);
+        let goal = target.saturating_sub(PRE_ROLL);
+        let page = find_page(&mut self.data, serial, goal, start, end)?;
+
+        self.data.seek(SeekFrom::Start(page.end()))?;
+        self.core.seeked(page.end(), page.granule, sample)
+    }
+
+    /// Same as `seek_to_sample`, with the position as time
+    pub fn seek_to_time(&mut self, time: std::time::Duration) -> Result<(), Error> {
+        self.seek_to_sample(duration_to_samples(time, TARGET_SPS))
+    }
+
+    /// Per channel samples in the whole stream at TARGET_SPS, the same that
+    /// decoding it would give, from the granule position of its last page.
+    /// Only the end of the input is read, and decoding carries on from where
+    /// it was
+    pub fn total_samples(&mut self) -> Result<u64, Error> {
+        let (serial, pre_skip) = self.core.seek_info()?;
+        let start = self.stream_start()?;
+        let position = self.data.stream_position()?;
+        let end = self.data.seek(SeekFrom::End(0))?;
+
+        let last = last_granule(&mut self.data, serial, start, end);
+        self.data.seek(SeekFrom::Start(position))?;
+        let samples = calc_sr_u64(last?, OGG_OPUS_SPS, TARGET_SPS);
+        Ok(samples.saturating_sub(pre_skip))
+    }
+
+    /// Same as `total_samples`, as time
+    pub fn duration(&mut self) -> Result<std::time::Duration, Error> {
+        Ok(samples_to_duration(self.total_samples()?, TARGET_SPS))
+    }
+
+    /**Decodes everything left into `out`, after what's already there, and
+    tells how many samples (of all channels) went in. The room for them is
+    reserved up front from the last page's granule (as far as the size of
+    the input makes it believable), and the same frame buffer is used over
+    and over, so a clip takes a handful of allocations at most (none with an
+    `out` that's big enough already).*/
+    pub fn read_to_end(&mut self, out: &mut Vec<i16>) -> Result<usize, Error> {
+        let before = out.len();
+        // Just a hint, a stream with a broken end still decodes
+        if let (Ok(mut total), Ok(len)) = (self.total_samples(), self.input_len()) {
+            // Nor is a lying granule trusted for more than the input could
+            // hold (120 ms in every 2 bytes, a lacing value and a TOC) or
+            // than the limit
+            let most = len / 2 * MAX_FRAME_SAMPLES as u64;
+            total = total.min(calc_sr_u64(most, OGG_OPUS_SPS, TARGET_SPS));
+            if let Some(max) = self.core.max_samples {
+                total = total.min(calc_sr_u64(max, OGG_OPUS_SPS, TARGET_SPS));
+            }
+            let room = usize::try_from(total)
+                .ok()
+                .and_then(|total| total.checked_mul(self.play_data.channels as usize));
+            if let Some(room) = room {
+                let _ = out.try_reserve(room);
+            }
+        }
+        while let Some(frame) = self.next_frame()? {
+            out.extend_from_slice(&frame.samples);
+            self.recycle(frame);
+        }
+        Ok(out.len() - before)
     }
 
-    let head = std::str::from_utf8(&sp.data[0..8]).map_err(|_| Error::MalformedAudio)?;
-    if head != "OpusTags" {
-        return Err(Error::MalformedAudio);
+    // Bytes from where the stream begins to the end of `data`
+    fn input_len(&mut self) -> Result<u64, Error> {
+        let start = self.stream_start()?;
+        let position = self.data.stream_position()?;
+        let end = self.data.seek(SeekFrom::End(0))?;
+        self.data.seek(SeekFrom::Start(position))?;
+        Ok(end.saturating_sub(start))
     }
 
-    Ok(())
+    // Where the stream begins in `data`, which might have something before it
+    fn stream_start(&mut self) -> Result<u64, Error> {
+        if let Some(start) = self.start {
+            return Ok(start);
+        }
+        let start = self.data.stream_position()? - self.fed;
+        self.start = Some(start);
+        Ok(start)
+    }
 }
 
-// Analyze first page, where all the metadata we need is contained
-fn check_fp<const TARGET_SPS: u32>(fp: &Packet) -> Result<(PlayData, DecodeData), Error> {
-    // Check size
-    if fp.data.len() < 19 {
-        return Err(Error::MalformedAudio);
+// Granule position of the last page of `serial`, looking further back from
+// `end` until there's one
+pub(crate) fn last_granule<T: Read + Seek>(
+    data: &mut T,
+    serial: u32,
+    start: u64,
+    end: u64,
+) -> Result<u64, Error> {
+    let mut window = 64 * 1024;
+    loop {
+        let from = end.saturating_sub(window).max(start);
+        let mut last = None;
+        let mut offset = from;
+        while let Some(page) = next_page(data, offset, end)? {
+            if page.serial == serial && page.granule != UNSET_GRANULE {
+                last = Some(page.granule);
+            }
+            offset = page.end();
+        }
+
+        match last {
+            Some(granule) => return Ok(granule),
+            None if from == start => return Err(Error::MalformedAudio),
+            None => window *= 2,
+        }
     }
+}
 
-    // Read magic header
-    if fp.data[0..8] != OPUS_MAGIC_HEADER {
-        return Err(Error::MalformedAudio);
+// Last page of `serial` that ends no later than `goal` (48 kHz granule) and
+// with no packet going on past it, the headers ones at least qualify
+pub(crate) fn find_page<T: Read + Seek>(
+    data: &mut T,
+    serial: u32,
+    goal: u64,
+    start: u64,
+    end: u64,
+) -> Result<PageInfo, Error> {
+    let usable = |page: &PageInfo| page.serial == serial && page.granule != UNSET_GRANULE;
+
+    let mut low = start;
+    let mut high = end;
+    while high - low > BISECT_SPAN {
+        let middle = low + (high - low) / 2;
+        let mut found = None;
+        let mut offset = middle;
+        while let Some(page) = next_page(data, offset, high)? {
+            if usable(&page) {
+                found = Some(page);
+                break;
+            }
+            offset = page.end();
+        }
+        match found {
+            Some(page) if page.granule <= goal => low = page.offset,
+            _ => high = middle,
+        }
     }
 
-    // Read version
-    if fp.data[8] != 1 {
-        return Err(Error::MalformedAudio);
+    // Every page from `low` on might have a packet going on past it
+    for from in [low, start] {
+        let mut best = None;
+        let mut offset = from;
+        while let Some(page) = next_page(data, offset, end)? {
+            if usable(&page) {
+                if page.granule > goal {
+                    break;
+                }
+                if page.ends_clean {
+                    best = Some(page);
+                }
+            }
+            offset = page.end();
+        }
+        if let Some(page) = best {
+            return Ok(page);
+        }
+    }
+    Err(Error::MalformedAudio)
+}
+
+impl<T: Read, const TARGET_SPS: u32> Iterator for Decoder<T, TARGET_SPS> {
+    type Item = Result<DecodedFrame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
     }
+}
+
+type DecodedLoop = (Vec<i16>, PlayData, Option<Range<usize>>);
+
+/// Decodes a track meant to be looped (e.g. game music), also giving back the
+/// loop region from its `LOOPSTART`/`LOOPLENGTH` tags as indices into the
+/// decoded buffer, `None` if there's no loop region
+pub fn decode_looped<T: Read, const TARGET_SPS: u32>(data: T) -> Result<DecodedLoop, Error> {
+    let (buffer, play_data) = decode::<T, TARGET_SPS>(data)?;
+    let region = play_data.tags.loop_points().map(|points| {
+        let range = points.sample_range(TARGET_SPS, play_data.channels);
+        range.start.min(buffer.len())..range.end.min(buffer.len())
+    });
+
+    Ok((buffer, play_data, region))
+}
+
+// Packets that can't possibly be Opus audio: empty ones and anything
+// carrying an Opus header magic (e.g. a repeated OpusTags)
+fn is_non_audio(data: &[u8]) -> bool {
+    data.is_empty() || data.starts_with(b"Opus")
+}
+
+// Analyze first page, where all the metadata we need is contained
+fn check_fp<const TARGET_SPS: u32>(fp: &Packet) -> Result<(PlayData, DecodeData), Error> {
+    let head = OpusHead::from_packet(&fp.data)?;
+    let mapping = decoder_mapping(&head)?;
 
     Ok((
         PlayData {
-            channels: fp.data[9] as u16, // Number of channels
+            channels: head.channels as u16, // Number of channels
+            tags: Tags::default(),
+            pre_skip: head.pre_skip,
+            input_sample_rate: head.input_sample_rate,
+            output_gain: head.output_gain,
+            channel_mapping_family: head.channel_mapping_family,
+            serial: fp.stream_serial(),
         },
         DecodeData {
-            pre_skip: calc_sr(
-                LittleEndian::read_u16(&fp.data[10..12]),
-                OGG_OPUS_SPS,
-                TARGET_SPS,
-            ),
-            gain: LittleEndian::read_i16(&fp.data[16..18]) as i32,
+            pre_skip: calc_sr(head.pre_skip, OGG_OPUS_SPS, TARGET_SPS),
+            gain: head.output_gain as i32,
+            mapping,
         },
     ))
 }
+
+// Mapping for the multistream decoder, None when plain libopus will do
+pub(crate) fn decoder_mapping(head: &OpusHead) -> Result<Option<Mapping>, Error> {
+    Ok(match head.channel_mapping_family {
+        // Mono or stereo, nothing else to it
+        0 => None,
+        // Same thing in family 1's clothes, plain libopus does it
+        1 if head.stream_count == 1
+            && head.coupled_count + 1 == head.channels
+            && head.channel_mapping.iter().copied().eq(0..head.channels) =>
+        {
+            None
+        }
+        // Surround, Vorbis channel order
+        1 if head.channels <= MAX_SURROUND_CHANNELS => head.mapping(),
+        1 => return Err(Error::BadChannelCount(head.channels)),
+        // Streams with no defined meaning, handed out as they are
+        255 => head.mapping(),
+        family => return Err(Error::UnsupportedMappingFamily(family)),
+    })
+}
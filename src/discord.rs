@@ -0,0 +1,64 @@
+// Raw Opus packets for Discord's voice gateway: 48 kHz stereo in 20 ms
+// frames, no Ogg around them
+
+use crate::{Bitrate, EncoderCore, EncoderOptions, Error, FrameSize, OpusPacket};
+
+/// Rate and channels Discord voice takes
+pub const DISCORD_SPS: u32 = 48000;
+pub const DISCORD_CHANNELS: u8 = 2;
+
+/// Packet Discord wants five of whenever the audio stops, so that clients
+/// don't interpolate over the gap
+pub const SILENCE_FRAME: [u8; 3] = [0xf8, 0xff, 0xfe];
+const SILENCE_FRAMES: usize = 5;
+
+// What Discord voice channels use unless boosted
+const DISCORD_BITRATE: Bitrate = Bitrate::BitsPerSecond(64000);
+
+/**Encoder giving Opus packets ready to be sent to Discord one by one, every
+20 ms: audio goes in interleaved at 48 kHz stereo, in pushes of any size,
+and the packets for every whole frame come back right away.*/
+pub struct DiscordEncoder {
+    core: EncoderCore<DISCORD_SPS, DISCORD_CHANNELS>,
+}
+
+impl DiscordEncoder {
+    /// At 64 kb/s, as voice channels are by default
+    pub fn new() -> Result<Self, Error> {
+        Self::with_options(&EncoderOptions::default().bitrate(DISCORD_BITRATE))
+    }
+
+    /// Same as `new`, with the codec settings of `options`. The frame size
+    /// is always 20 ms, Discord times packets by it
+    pub fn with_options(options: &EncoderOptions) -> Result<Self, Error> {
+        let options = options.clone().frame_size(FrameSize::Ms20);
+        Ok(Self {
+            core: EncoderCore::configure(&options)?,
+        })
+    }
+
+    pub fn push_samples(&mut self, audio: &[i16]) -> Result<Vec<OpusPacket>, Error> {
+        Ok(into_packets(self.core.push_raw(audio)?))
+    }
+
+    pub fn push_float(&mut self, audio: &[f32]) -> Result<Vec<OpusPacket>, Error> {
+        Ok(into_packets(self.core.push_raw(audio)?))
+    }
+
+    /// The last frame, filled with silence, followed by the silence frames
+    /// that tell Discord the audio stopped
+    pub fn finish(mut self) -> Result<Vec<OpusPacket>, Error> {
+        let mut packets: Vec<OpusPacket> = self
+            .core
+            .finish_raw()?
+            .into_iter()
+            .map(OpusPacket::from)
+            .collect();
+        packets.extend((0..SILENCE_FRAMES).map(|_| OpusPacket::from(SILENCE_FRAME.to_vec())));
+        Ok(packets)
+    }
+}
+
+fn into_packets(packets: Vec<Vec<u8>>) -> Vec<OpusPacket> {
+    packets.into_iter().map(OpusPacket::from).collect()
+}
@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+// Drift under this is just capture jitter, leave it alone
+const TOLERANCE_MS: u32 = 20;
+// Most we correct, in frames per million; real clocks drift way less than
+// this, and spreading it keeps the corrections inaudible
+const MAX_CORRECTION_PPM: usize = 2000;
+
+/**Keeps a live capture aligned with wall time. Capture clocks drift from the
+system one, over hours a stream would accumulate seconds of difference
+between its granules and real time. Put this in front of the encoder: each
+chunk is tagged with the instant its capture ended, single frames (all
+channels) get duplicated or dropped, spread over the chunk, whenever the
+audio gets ahead or behind.*/
+pub struct DriftCompensator<const S_PS: u32, const NUM_CHANNELS: u8> {
+    origin: Option<Instant>,
+    // Frames handed out so far
+    produced: u64,
+}
+
+impl<const S_PS: u32, const NUM_CHANNELS: u8> Default for DriftCompensator<S_PS, NUM_CHANNELS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const S_PS: u32, const NUM_CHANNELS: u8> DriftCompensator<S_PS, NUM_CHANNELS> {
+    pub fn new() -> Self {
+        Self {
+            origin: None,
+            produced: 0,
+        }
+    }
+
+    /// Gives back the audio, corrected if needed, ready for the encoder
+    pub fn process(&mut self, audio: &[i16], captured_at: Instant) -> Vec<i16> {
+        let channels = (NUM_CHANNELS as usize).max(1);
+        let frames = audio.len() / channels;
+
+        // The first chunk started its capture before arriving
+        let origin = *self.origin.get_or_insert_with(|| {
+            let chunk = Duration::from_nanos(frames as u64 * 1_000_000_000 / S_PS as u64);
+            captured_at.checked_sub(chunk).unwrap_or(captured_at)
+        });
+
+        let elapsed = captured_at.saturating_duration_since(origin);
+        let expected = (elapsed.as_nanos() * S_PS as u128 / 1_000_000_000) as i64;
+        let error = expected - (self.produced + frames as u64) as i64;
+        let tolerance = (S_PS * TOLERANCE_MS / 1000) as i64;
+
+        let max_correction = (frames * MAX_CORRECTION_PPM).div_ceil(1_000_000);
+        let correction = if error.abs() > tolerance {
+            (error.unsigned_abs() as usize).min(max_correction)
+        } else {
+            0
+        };
+
+        let mut out = Vec::with_capacity(audio.len() + correction * channels);
+        match frames.checked_div(correction) {
+            None => out.extend_from_slice(audio),
+            // Touch one frame every `spacing`, evenly across the chunk
+            Some(spacing) => {
+                let mut done = 0;
+                for (i, frame) in audio.chunks_exact(channels).enumerate() {
+                    let touch = done < correction && (i + 1) % spacing == 0;
+                    if touch {
+                        done += 1;
+                    }
+                    if !(touch && error < 0) {
+                        out.extend_from_slice(frame);
+                    }
+                    if touch && error > 0 {
+                        out.extend_from_slice(frame);
+                    }
+                }
+            }
+        }
+
+        self.produced += (out.len() / channels) as u64;
+        out
+    }
+}
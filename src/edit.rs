@@ -0,0 +1,474 @@
+use std::convert::TryFrom;
+use std::io::{Read, Seek, Write};
+use std::ops::Range;
+use std::time::Duration;
+
+use audiopus::coder::Decoder as OpusDec;
+use audiopus::{Channels, SampleRate};
+use byteorder::{ByteOrder, LittleEndian};
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+
+use crate::common::*;
+use crate::encode::EncoderCore;
+use crate::{EncoderOptions, Error, Tags};
+
+// RFC 7845 asks for at least 80 ms of audio to be decoded before the point
+// we seek to, so that the decoder has converged
+pub(crate) const PRE_ROLL: u64 = 3840;
+
+// Packets as they come out of the encoder, with their granules
+type Encoded = Vec<(Vec<u8>, u64)>;
+
+struct TimedPacket {
+    data: Vec<u8>,
+    // Granule where the packet starts and its length, in 48 kHz samples
+    start: u64,
+    duration: u64,
+}
+
+/// A whole stream in memory, as packets with their positions, so that pieces
+/// of it can be copied around without decoding
+pub(crate) struct PacketStream {
+    head: Vec<u8>,
+    pub(crate) tags: Tags,
+    pre_skip: u64,
+    packets: Vec<TimedPacket>,
+    // Final granule of the stream
+    end: u64,
+}
+
+impl PacketStream {
+    pub(crate) fn read<T: Read + Seek>(data: T) -> Result<Self, Error> {
+        let mut reader = PacketReader::new(data);
+
+        let head = reader.read_packet_expected()?.data;
+        if head.len() < 19 || head[0..8] != OPUS_MAGIC_HEADER {
+            return Err(Error::MalformedAudio);
+        }
+        let pre_skip = LittleEndian::read_u16(&head[10..12]) as u64;
+        let tags = Tags::from_packet(&reader.read_packet_expected()?.data)?;
+
+        let mut packets = Vec::new();
+        let mut position = 0;
+        let mut end = 0;
+        let mut first_page = true;
+        while let Some(packet) = reader.read_packet()? {
+            let inner = audiopus::packet::Packet::try_from(&packet.data)?;
+            let duration = audiopus::packet::nb_samples(inner, SampleRate::Hz48000)? as u64;
+            let last_in_page = packet.last_in_page();
+            let granule = packet.absgp_page();
+
+            packets.push(TimedPacket {
+                data: packet.data,
+                start: position,
+                duration,
+            });
+            position += duration;
+
+            if last_in_page && granule != UNSET_GRANULE {
+                // Streams don't need to start at 0, the first page tells
+                if first_page && granule > position {
+                    let offset = granule - position;
+                    packets.iter_mut().for_each(|p| p.start += offset);
+                    position += offset;
+                }
+                first_page = false;
+                end = granule;
+            }
+        }
+        // Without a final granule there's nothing to trim at the end
+        if end == 0 || end > position {
+            end = position;
+        }
+
+        Ok(Self {
+            head,
+            tags,
+            pre_skip,
+            packets,
+            end,
+        })
+    }
+
+    /// Copies the audio from `start` to `end` (48 kHz samples after the
+    /// pre-skip) into a new stream, without re-encoding. The cut is still
+    /// sample-accurate, thanks to the pre-skip and end trimming
+    pub(crate) fn extract(&self, start: u64, end: u64, tags: &Tags) -> Result<Vec<u8>, Error> {
+        let (g_start, g_end) = self.granule_range(start, end)?;
+
+        let roll_start = g_start.saturating_sub(PRE_ROLL);
+        let first = self
+            .packets
+            .iter()
+            .rposition(|p| p.start <= roll_start)
+            .unwrap_or(0);
+        let last = self
+            .packets
+            .iter()
+            .position(|p| p.start + p.duration >= g_end)
+            .unwrap_or(self.packets.len() - 1);
+
+        let base = self.packets[first].start;
+        let pre_skip = u16::try_from(g_start - base).map_err(|_| Error::InvalidRange)?;
+
+        let mut head = self.head.clone();
+        LittleEndian::write_u16(&mut head[10..12], pre_skip);
+
+        let serial = new_serial();
+        let mut out = Vec::new();
+        let mut writer = PacketWriter::new(&mut out);
+        writer.write_packet(head, serial, PacketWriteEndInfo::EndPage, 0)?;
+        writer.write_packet(tags.to_packet(), serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        for (i, packet) in self.packets[first..=last].iter().enumerate() {
+            if first + i == last {
+                // Trims whatever goes past the end
+                writer.write_packet(
+                    packet.data.clone(),
+                    serial,
+                    PacketWriteEndInfo::EndStream,
+                    g_end - base,
+                )?;
+            } else {
+                writer.write_packet(
+                    packet.data.clone(),
+                    serial,
+                    PacketWriteEndInfo::NormalPacket,
+                    packet.start + packet.duration - base,
+                )?;
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Same as `extract`, but the audio from `start` to the first packet
+    /// after it is decoded and encoded again with `options`, instead of
+    /// going along with the pre-roll before it and a pre-skip as long. Only
+    /// for mono and stereo, other streams are extracted as they are
+    pub(crate) fn extract_reencoded(
+        &self,
+        start: u64,
+        end: u64,
+        tags: &Tags,
+        options: &EncoderOptions,
+    ) -> Result<Vec<u8>, Error> {
+        let (g_start, g_end) = self.granule_range(start, end)?;
+        let boundary = match self.packets.iter().position(|p| p.start >= g_start) {
+            // From the first packet on nothing needs decoding before
+            Some(boundary) if boundary > 0 && self.packets[boundary].start < g_end => boundary,
+            _ => return self.extract(start, end, tags),
+        };
+        let (lead, pre_skip) = match (self.head[18], self.head[9]) {
+            (0, 1) => self.reencode_lead::<1>(g_start, boundary, options)?,
+            (0, 2) => self.reencode_lead::<2>(g_start, boundary, options)?,
+            _ => return self.extract(start, end, tags),
+        };
+        let pre_skip = u16::try_from(pre_skip).map_err(|_| Error::InvalidRange)?;
+
+        let last = self
+            .packets
+            .iter()
+            .position(|p| p.start + p.duration >= g_end)
+            .unwrap_or(self.packets.len() - 1);
+        // Copied packets go on from where the new ones end
+        let base = self.packets[boundary].start;
+        let lead_end = lead.last().map_or(0, |(_, granule)| *granule);
+
+        let mut head = self.head.clone();
+        LittleEndian::write_u16(&mut head[10..12], pre_skip);
+
+        let serial = new_serial();
+        let mut out = Vec::new();
+        let mut writer = PacketWriter::new(&mut out);
+        writer.write_packet(head, serial, PacketWriteEndInfo::EndPage, 0)?;
+        writer.write_packet(tags.to_packet(), serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        for (packet, granule) in lead {
+            writer.write_packet(packet, serial, PacketWriteEndInfo::NormalPacket, granule)?;
+        }
+        for (i, packet) in self.packets[boundary..=last].iter().enumerate() {
+            let (end_info, granule) = if boundary + i == last {
+                (PacketWriteEndInfo::EndStream, g_end)
+            } else {
+                (
+                    PacketWriteEndInfo::NormalPacket,
+                    packet.start + packet.duration,
+                )
+            };
+            writer.write_packet(
+                packet.data.clone(),
+                serial,
+                end_info,
+                lead_end + granule - base,
+            )?;
+        }
+
+        Ok(out)
+    }
+
+    // Packets for the audio from `g_start` to where packet `boundary` starts,
+    // along with the pre-skip they need
+    fn reencode_lead<const NUM_CHANNELS: u8>(
+        &self,
+        g_start: u64,
+        boundary: usize,
+        options: &EncoderOptions,
+    ) -> Result<(Encoded, u64), Error> {
+        let mut encoder = EncoderCore::<OGG_OPUS_SPS, NUM_CHANNELS>::configure(options)?;
+        encoder.drop_lead_in();
+        let lookahead = (encoder.lookahead_samples() / NUM_CHANNELS as usize) as u64;
+
+        // Frames go in steps of 2.5 ms, the pre-skip takes whatever is over
+        let step = (OGG_OPUS_SPS * MIN_FRAME_MICROS / 10_000) as u64;
+        let boundary = self.packets[boundary].start;
+        let pad = (step - (lookahead + boundary - g_start) % step) % step;
+
+        // Whatever goes in comes out a lookahead later, so the encoder needs
+        // to hear that much past the boundary
+        let audio = self.decode_span(
+            g_start as i64 - pad as i64,
+            boundary + lookahead,
+            NUM_CHANNELS,
+        )?;
+        let mut packets = encoder.encode_frames(&audio)?;
+        packets.extend(encoder.finish_frames(false)?);
+        Ok((packets, lookahead + pad))
+    }
+
+    // Interleaved audio for granules `from..until`, silence where the stream
+    // has none. Decoding starts a pre-roll earlier, to have converged
+    fn decode_span(&self, from: i64, until: u64, channels: u8) -> Result<Vec<f32>, Error> {
+        let opus_channels = if channels == 1 {
+            Channels::Mono
+        } else {
+            Channels::Stereo
+        };
+        let mut decoder = OpusDec::new(SampleRate::Hz48000, opus_channels)?;
+        let channels = channels as usize;
+
+        let roll_start = (from - PRE_ROLL as i64).max(0) as u64;
+        let first = self
+            .packets
+            .iter()
+            .rposition(|p| p.start <= roll_start)
+            .unwrap_or(0);
+        let mut decoded = Vec::new();
+        let mut buffer = vec![0f32; MAX_FRAME_SAMPLES * channels];
+        for packet in self.packets[first..].iter().take_while(|p| p.start < until) {
+            let data = audiopus::packet::Packet::try_from(&packet.data)?;
+            let output = audiopus::MutSignals::try_from(&mut buffer[..])?;
+            let len = decoder.decode_float(Some(data), output, false)?;
+            decoded.extend_from_slice(&buffer[..len * channels]);
+        }
+
+        let base = self.packets[first].start as i64;
+        let mut audio = vec![0f32; (until as i64 - from) as usize * channels];
+        for (i, frame) in audio.chunks_exact_mut(channels).enumerate() {
+            let at = from + i as i64 - base;
+            if let Some(samples) = usize::try_from(at)
+                .ok()
+                .and_then(|at| decoded.get(at * channels..(at + 1) * channels))
+            {
+                frame.copy_from_slice(samples);
+            }
+        }
+        Ok(audio)
+    }
+
+    // Granules `start` and `end` play at, within the audio
+    fn granule_range(&self, start: u64, end: u64) -> Result<(u64, u64), Error> {
+        // There's no audio before the first packet, wherever the stream starts
+        let g_start = start
+            .saturating_add(self.pre_skip)
+            .max(self.first_start())
+            .min(self.end);
+        let g_end = end.saturating_add(self.pre_skip).min(self.end);
+        if g_end <= g_start {
+            return Err(Error::InvalidRange);
+        }
+        Ok((g_start, g_end))
+    }
+
+    /// Length of the audio, 48 kHz samples
+    pub(crate) fn duration(&self) -> u64 {
+        self.end.saturating_sub(self.pre_skip)
+    }
+
+    // Whether the packets of both can go in a single stream: same channels,
+    // mapping and output gain, the pre-skip and input rate aside
+    fn same_layout(&self, other: &Self) -> bool {
+        self.head[..10] == other.head[..10] && self.head[16..] == other.head[16..]
+    }
+
+    // Granule the audio starts at, streams don't need to start at 0
+    fn first_start(&self) -> u64 {
+        self.packets.first().map_or(0, |p| p.start)
+    }
+
+    // Length of all packets, nothing trimmed
+    fn packets_duration(&self) -> u64 {
+        self.packets.iter().map(|p| p.duration).sum()
+    }
+
+    fn write_headers<W: Write>(
+        &self,
+        writer: &mut PacketWriter<W>,
+        serial: u32,
+    ) -> Result<(), Error> {
+        writer.write_packet(self.head.clone(), serial, PacketWriteEndInfo::EndPage, 0)?;
+        writer.write_packet(
+            self.tags.to_packet(),
+            serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+        Ok(())
+    }
+}
+
+/**Cuts a file in two at `at` without re-encoding, e.g. for podcast chapters.
+Each half is a file of its own, with the same tags, cut exact to the sample
+by the pre-skip and end trimming: the pages around the cut are copied into
+both, along with the 80 ms the second one needs to decode before `at` to
+sound right. Fails with `Error::InvalidRange` if `at` isn't inside the audio*/
+pub fn split_at<T: Read + Seek>(data: T, at: Duration) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let stream = PacketStream::read(data)?;
+    let at = duration_to_samples(at, OGG_OPUS_SPS);
+    Ok((
+        stream.extract(0, at, &stream.tags)?,
+        stream.extract(at, stream.duration(), &stream.tags)?,
+    ))
+}
+
+/// Copies the audio in `range` into a file of its own, as `split_at` does.
+/// A range going past the end stops at the end
+pub fn extract_range<T: Read + Seek>(data: T, range: Range<Duration>) -> Result<Vec<u8>, Error> {
+    let stream = PacketStream::read(data)?;
+    stream.extract(
+        duration_to_samples(range.start, OGG_OPUS_SPS),
+        duration_to_samples(range.end, OGG_OPUS_SPS),
+        &stream.tags,
+    )
+}
+
+/**Same as `split_at`, for cuts that don't fall on a page: the audio from `at`
+to the first packet after it is decoded and encoded again with `options`
+(ideally at the bitrate the file already has) to start the second half,
+and the rest is copied. The first half is the same as with `split_at`, the
+second no longer has to carry the pre-roll, a few milliseconds are
+re-encoded instead. Streams with more than 2 channels are only copied*/
+pub fn split_at_reencoded<T: Read + Seek>(
+    data: T,
+    at: Duration,
+    options: &EncoderOptions,
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let stream = PacketStream::read(data)?;
+    let at = duration_to_samples(at, OGG_OPUS_SPS);
+    Ok((
+        stream.extract(0, at, &stream.tags)?,
+        stream.extract_reencoded(at, stream.duration(), &stream.tags, options)?,
+    ))
+}
+
+/// Same as `extract_range`, re-encoding its start as `split_at_reencoded` does
+pub fn extract_range_reencoded<T: Read + Seek>(
+    data: T,
+    range: Range<Duration>,
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, Error> {
+    let stream = PacketStream::read(data)?;
+    stream.extract_reencoded(
+        duration_to_samples(range.start, OGG_OPUS_SPS),
+        duration_to_samples(range.end, OGG_OPUS_SPS),
+        &stream.tags,
+        options,
+    )
+}
+
+/**Puts Ogg Opus files one after the other without re-encoding, for voice
+note segments and such. When they all have the same channels, mapping and
+output gain, the result is a single stream with the headers of the first
+one. Only the very end of a stream can be trimmed though, so each file
+after the first one also plays the pre-skip it starts with, and each one
+before the last one the padding it ends with, a few milliseconds each. If
+they don't match, they are chained instead, see `concat_chained`*/
+pub fn concat<T: Read + Seek, I: IntoIterator<Item = T>>(inputs: I) -> Result<Vec<u8>, Error> {
+    let streams = read_streams(inputs)?;
+    let (first, rest) = streams.split_first().ok_or(Error::MissingOpusHead)?;
+    if !rest.iter().all(|stream| first.same_layout(stream)) {
+        return chain(&streams);
+    }
+
+    let serial = new_serial();
+    let mut out = Vec::new();
+    let mut writer = PacketWriter::new(&mut out);
+    first.write_headers(&mut writer, serial)?;
+
+    let (last, whole) = streams.split_last().ok_or(Error::MissingOpusHead)?;
+    let base = first.first_start();
+    let end = base
+        + whole
+            .iter()
+            .map(PacketStream::packets_duration)
+            .sum::<u64>()
+        + (last.end - last.first_start());
+
+    let packets: Vec<&TimedPacket> = streams.iter().flat_map(|s| &s.packets).collect();
+    let mut position = base;
+    for (i, packet) in packets.iter().enumerate() {
+        position += packet.duration;
+        let (end_info, granule) = if i + 1 == packets.len() {
+            (PacketWriteEndInfo::EndStream, end)
+        } else {
+            (PacketWriteEndInfo::NormalPacket, position)
+        };
+        writer.write_packet(packet.data.clone(), serial, end_info, granule)?;
+    }
+
+    Ok(out)
+}
+
+/// Same as `concat`, but always as chained streams (RFC 7845, section 3):
+/// each file is kept as it was, tags and trimming included, with a serial
+/// of its own. Decoders go from one to the next on their own
+pub fn concat_chained<T: Read + Seek, I: IntoIterator<Item = T>>(
+    inputs: I,
+) -> Result<Vec<u8>, Error> {
+    chain(&read_streams(inputs)?)
+}
+
+fn read_streams<T: Read + Seek, I: IntoIterator<Item = T>>(
+    inputs: I,
+) -> Result<Vec<PacketStream>, Error> {
+    inputs.into_iter().map(PacketStream::read).collect()
+}
+
+fn chain(streams: &[PacketStream]) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    let mut serials = Vec::with_capacity(streams.len());
+    for stream in streams {
+        // Chained streams can't share a serial
+        let mut serial = new_serial();
+        while serials.contains(&serial) {
+            serial = new_serial();
+        }
+        serials.push(serial);
+
+        let mut writer = PacketWriter::new(&mut out);
+        stream.write_headers(&mut writer, serial)?;
+        for (i, packet) in stream.packets.iter().enumerate() {
+            let (end_info, granule) = if i + 1 == stream.packets.len() {
+                (PacketWriteEndInfo::EndStream, stream.end)
+            } else {
+                (
+                    PacketWriteEndInfo::NormalPacket,
+                    packet.start + packet.duration,
+                )
+            };
+            writer.write_packet(packet.data.clone(), serial, end_info, granule)?;
+        }
+    }
+
+    Ok(out)
+}
@@ -1,17 +1,22 @@
 use std::borrow::Cow;
-use std::cmp::min;
-use std::process;
+use std::convert::{TryFrom, TryInto};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::time::Duration;
 
 use crate::common::*;
-use crate::Error;
+use crate::loudness;
+use crate::multistream::{Mapping, SurroundEncoder, MAX_SURROUND_CHANNELS};
+#[cfg(feature = "opusenc")]
+use crate::opusenc::OpusEncCore;
+use crate::packet::{Bandwidth, Mode, Toc};
+use crate::{Application, Bitrate, Error, SampleRate, Tags};
 
-use audiopus::{
-    coder::{Encoder as OpusEnc, GenericCtl},
-    Bitrate,
-};
+use audiopus::coder::{Encoder as OpusEnc, GenericCtl};
+use audiopus::ffi;
 use byteorder::{ByteOrder, LittleEndian};
 use ogg::PacketWriter;
-use rand::Rng;
 
 //--- Final range  things ------------------------------------------------------
 
@@ -38,10 +43,6 @@ pub(crate) fn get_final_range() -> u32 {
 }
 
 //--- Code ---------------------------------------------------------------------
-const fn to_samples<const S_PS: u32>(ms: u32) -> usize {
-    ((S_PS * ms) / 1000) as usize
-}
-
 // In microseconds
 const fn calc_fr_size(us: u32, channels: u8, sps: u32) -> usize {
     let samps_ms = sps * us;
@@ -49,212 +50,1642 @@ const fn calc_fr_size(us: u32, channels: u8, sps: u32) -> usize {
     ((samps_ms * channels as u32) / (1000 * US_TO_MS)) as usize
 }
 
-// Determine opus channels at compile-time if possible
-const fn opus_channels(val: u8) -> audiopus::Channels {
+// Determine opus channels at compile-time if possible, `None` for surround
+const fn opus_channels(val: u8) -> Option<audiopus::Channels> {
     if val == 1 || val == 0 {
-        audiopus::Channels::Mono
+        Some(audiopus::Channels::Mono)
     } else if val == 2 {
-        audiopus::Channels::Stereo
+        Some(audiopus::Channels::Stereo)
+    } else if val <= MAX_SURROUND_CHANNELS {
+        None
     } else {
-        panic!("Invalid number of channels. Use 1 or 2 instead.")
+        panic!("Invalid number of channels. Use 1 to 8 instead.")
     }
 }
 
-const fn is_end_of_stream(pos: usize, max: usize) -> ogg::PacketWriteEndInfo {
-    if pos == max {
-        ogg::PacketWriteEndInfo::EndStream
-    } else {
-        ogg::PacketWriteEndInfo::NormalPacket
+// What this crate always used, fine for speech and what Telegram voice notes
+// go with
+const VOICE_BITRATE: Bitrate = Bitrate::BitsPerSecond(24000);
+
+/// Bitrate when none was asked for, as opusenc does: 64 kb/s for a stream
+/// with a channel on its own and 96 kb/s for a coupled stereo pair. Each
+/// packet costs a couple of bytes on its own, so shorter frames get a bit
+/// more and longer ones less
+pub(crate) fn default_bitrate(streams: u8, coupled: u8, frame_us: u32) -> Bitrate {
+    let base = (streams - coupled) as i32 * 64000 + coupled as i32 * 96000;
+    let packets_per_sec = (1_000_000 / frame_us) as i32;
+    Bitrate::BitsPerSecond(base + (packets_per_sec - 50) * 16 * streams as i32)
+}
+
+// How far a frame's bitrate can go from the average with two passes, both
+// ways
+const TWO_PASS_SPREAD: f32 = 2.0;
+
+/// How the bitrate is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RateControl {
+    /// Packets get as many bytes as their audio needs, best quality for the
+    /// size
+    Vbr,
+    /// Variable, but never going over what the bitrate allows for too long
+    /// (libopus' default)
+    ConstrainedVbr,
+    /// Every packet the same size, for links and formats that need it
+    Cbr,
+}
+
+/// How much audio goes in each packet. Shorter is less latency, longer is
+/// less overhead (and better quality for the same bitrate)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FrameSize {
+    Ms2_5,
+    Ms5,
+    Ms10,
+    #[default]
+    Ms20,
+    Ms40,
+    Ms60,
+}
+
+impl FrameSize {
+    pub fn as_micros(self) -> u32 {
+        match self {
+            FrameSize::Ms2_5 => 2500,
+            FrameSize::Ms5 => 5000,
+            FrameSize::Ms10 => 10000,
+            FrameSize::Ms20 => 20000,
+            FrameSize::Ms40 => 40000,
+            FrameSize::Ms60 => 60000,
+        }
+    }
+
+    // Per channel samples in a frame
+    fn samples(self, sps: u32) -> usize {
+        (sps as u64 * self.as_micros() as u64 / 1_000_000) as usize
     }
 }
+// Ogg page header without the lacing values
+const PAGE_HEADER: u64 = 27;
 
 // Compile-time granule position calculation
-const fn granule<const S_PS: u32>(val: usize) -> u64 {
-    calc_sr_u64(val as u64, S_PS, OGG_OPUS_SPS)
+const fn granule<const S_PS: u32>(val: u64) -> u64 {
+    calc_sr_u64(val, S_PS, OGG_OPUS_SPS)
 }
 
 pub fn encode<const S_PS: u32, const NUM_CHANNELS: u8>(audio: &[i16]) -> Result<Vec<u8>, Error> {
-    let opus_sr = const {
-        match s_ps_to_audiopus(S_PS) {
-            Some(v) => v,
-            None => panic!("Wrong SampleRate"),
+    encode_with_options::<S_PS, NUM_CHANNELS>(audio, &EncoderOptions::default())
+}
+
+/**Tweaks on how audio gets encoded. With the `serde` feature it can be
+loaded from config files too, every field is optional:
+
+```toml
+bitrate = { BitsPerSecond = 48000 }
+application = "Voip"
+complexity = 8
+rate_control = "Cbr"
+frame_size = "Ms10"
+inband_fec = 10
+output_gain = -3.0
+normalize_to = -16.0
+loudness_gain = "R128Tag"
+trim_silence = { threshold_db = -60.0, padding_ms = 200 }
+max_packing = false
+two_pass = true
+serial = 1234
+reproducible = true
+```*/
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct EncoderOptions {
+    pub(crate) bitrate: Option<Bitrate>,
+    pub(crate) application: Option<Application>,
+    pub(crate) complexity: Option<u8>,
+    pub(crate) rate_control: Option<RateControl>,
+    pub(crate) frame_size: Option<FrameSize>,
+    // Expected packet loss, in percent
+    pub(crate) inband_fec: Option<u8>,
+    // dB
+    pub(crate) output_gain: Option<f64>,
+    normalize_to: Option<f64>,
+    loudness_gain: Option<LoudnessGain>,
+    trim_silence: Option<SilenceTrim>,
+    pub(crate) max_packing: bool,
+    two_pass: bool,
+    serial: Option<u32>,
+    reproducible: bool,
+    #[cfg(feature = "opusenc")]
+    libopusenc: bool,
+}
+
+/// Where the gain found by measuring the loudness goes, the audio itself is
+/// left as it is either way
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LoudnessGain {
+    /// Into the header's output gain, so that every decoder plays it at
+    /// `target_lufs` (e.g. -16 for podcasts and voice notes)
+    OutputGain { target_lufs: f64 },
+    /// Into an `R128_TRACK_GAIN` tag, to the -23 LUFS of EBU R128, for players
+    /// that know about it
+    R128Tag,
+}
+
+// Reference level of the R128 gain tags
+const R128_REFERENCE_LUFS: f64 = -23.0;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+struct SilenceTrim {
+    threshold_db: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    padding_ms: u64,
+}
+
+/// Settings bundled per use case, see `EncoderOptions::preset`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Preset {
+    /// Speech at 24 kb/s in 20 ms frames, as Telegram voice notes are
+    VoiceMessage,
+    /// Full band at opusenc's bitrate, with plain VBR to spend bits where
+    /// the music needs them
+    Music,
+    /// 10 ms frames without the speech/music lookahead, for live monitoring
+    /// and calls where every millisecond counts
+    LowLatency,
+}
+
+impl EncoderOptions {
+    /// Preset for speech at 24 kb/s, which is what Telegram voice notes use
+    /// and what the encoder did before computing a default bitrate
+    pub fn voice() -> Self {
+        Self::default().bitrate(VOICE_BITRATE)
+    }
+
+    /// Starting point for a use case, any setting can still be changed
+    /// afterwards
+    pub fn preset(preset: Preset) -> Self {
+        match preset {
+            Preset::VoiceMessage => Self::voice()
+                .application(Application::Voip)
+                .frame_size(FrameSize::Ms20),
+            Preset::Music => Self::default()
+                .application(Application::Audio)
+                .rate_control(RateControl::Vbr)
+                .complexity(10),
+            Preset::LowLatency => Self::default()
+                .application(Application::LowDelay)
+                .frame_size(FrameSize::Ms10),
         }
+    }
+
+    /// Without it, the bitrate depends on the channels and frame size,
+    /// around 64 kb/s per channel as opusenc does
+    pub fn bitrate(mut self, bitrate: Bitrate) -> Self {
+        self.bitrate = Some(bitrate);
+        self
+    }
+
+    /// What the encoder tunes itself for, `Application::Audio` by default
+    pub fn application(mut self, application: Application) -> Self {
+        self.application = Some(application);
+        self
+    }
+
+    /// From 0 to 10, higher is better quality for the same size but slower,
+    /// libopus defaults to 10. Anything over 10 fails when encoding starts
+    pub fn complexity(mut self, complexity: u8) -> Self {
+        self.complexity = Some(complexity);
+        self
+    }
+
+    /// libopus goes with constrained VBR unless told otherwise
+    pub fn rate_control(mut self, rate_control: RateControl) -> Self {
+        self.rate_control = Some(rate_control);
+        self
+    }
+
+    /// 20 ms by default, or 60 ms with `max_packing`
+    pub fn frame_size(mut self, frame_size: FrameSize) -> Self {
+        self.frame_size = Some(frame_size);
+        self
+    }
+
+    /// Spend some of the bitrate on a low quality copy of each frame inside
+    /// the next packet, so that a decoder can recover it if the packet gets
+    /// lost (see `DecoderCore::packet_lost`). `packet_loss_perc` (0 to 100)
+    /// is how much loss the link is expected to have, the higher it is the
+    /// more goes into that copy. Only speech (SILK and hybrid) frames carry
+    /// it, so it's best paired with `Application::Voip`
+    pub fn inband_fec(mut self, packet_loss_perc: u8) -> Self {
+        self.inband_fec = Some(packet_loss_perc);
+        self
+    }
+
+    /// Gain in dB that decoders apply on playback, kept in the header so the
+    /// audio itself isn't touched. Goes in steps of 1/256 dB, from -128 to
+    /// 128 dB
+    pub fn output_gain(mut self, db: f64) -> Self {
+        self.output_gain = Some(db);
+        self
+    }
+
+    /// Measure the input loudness (EBU R128) and apply the gain needed to hit
+    /// `lufs` (e.g. -16 for podcasts) before encoding, anything that would
+    /// go over full scale gets clipped. Silence is left as it is
+    pub fn normalize_to(mut self, lufs: f64) -> Self {
+        self.normalize_to = Some(lufs);
+        self
+    }
+
+    /// Measure the input loudness (EBU R128) like `normalize_to`, but instead
+    /// of changing the audio write the gain it needs into the stream, see
+    /// `LoudnessGain`. Silent input gets no gain
+    pub fn loudness_gain(mut self, gain: LoudnessGain) -> Self {
+        self.loudness_gain = Some(gain);
+        self
+    }
+
+    /// Strip leading and trailing audio quieter than `threshold_db` (dBFS,
+    /// e.g. -60), keeping `padding` of it at each side. How much was taken
+    /// is in the `EncodeReport`
+    pub fn trim_silence(mut self, threshold_db: f64, padding: Duration) -> Self {
+        self.trim_silence = Some(SilenceTrim {
+            threshold_db,
+            padding_ms: padding.as_millis().try_into().unwrap_or(u64::MAX),
+        });
+        self
+    }
+
+    /// Storage over latency, for archival: the longest frames Opus has
+    /// (60 ms) and pages as full as Ogg allows, so that as little as
+    /// possible goes into framing. How much was saved is in the
+    /// `EncodeReport`. A `frame_size` given explicitly still wins
+    pub fn max_packing(mut self) -> Self {
+        self.max_packing = true;
+        self
+    }
+
+    /// Go through the audio once before encoding it to see where the bits
+    /// are best spent, then move the bitrate frame by frame around the one
+    /// asked for: louder frames get more, near silent ones less, for about
+    /// the same size overall. Helps most at low bitrates (speech archives).
+    /// Only with a bitrate in bits per second
+    pub fn two_pass(mut self) -> Self {
+        self.two_pass = true;
+        self
+    }
+
+    /// Stream serial to write instead of a random one, for output that's the
+    /// same from run to run, or where there's no good source of randomness
+    /// (browsers)
+    pub fn serial(mut self, serial: u32) -> Self {
+        self.serial = Some(serial);
+        self
+    }
+
+    /// Same audio in, same bytes out, for content-addressed storage and
+    /// snapshot tests: a fixed serial (unless one is given) and a vendor
+    /// string without the crate version (unless the tags have their own).
+    /// Holds as long as libopus stays the same
+    pub fn reproducible(mut self) -> Self {
+        self.reproducible = true;
+        self
+    }
+
+    /// Encode and page through libopusenc instead, for output laid out as
+    /// opusenc's. Applies to the `encode` functions and `StreamEncoder`, the
+    /// rest (`EncoderCore`, `encode_trimmed` ...) always go through libopus
+    /// directly. Its own vendor string is written whatever the tags have,
+    /// `two_pass` is left aside and input at other rates is resampled to
+    /// 48 kHz by libopusenc itself
+    #[cfg(feature = "opusenc")]
+    pub fn libopusenc(mut self) -> Self {
+        self.libopusenc = true;
+        self
+    }
+}
+
+// What reproducible streams use, "Opus" in ASCII
+const REPRODUCIBLE_SERIAL: u32 = 0x4f70_7573;
+const REPRODUCIBLE_VENDOR: &str = "ogg-opus";
+
+/// Same as `encode`, but allows tweaking the encoder through `EncoderOptions`
+pub fn encode_with_options<const S_PS: u32, const NUM_CHANNELS: u8>(
+    audio: &[i16],
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, Error> {
+    Ok(encode_with_report::<S_PS, NUM_CHANNELS>(audio, options)?.0)
+}
+
+/// Same as `encode_with_options`, with the rate and channels chosen at runtime
+/// (e.g. from a WAV header) instead of at compile time. From 1 to 8 channels
+pub fn encode_at(
+    rate: SampleRate,
+    channels: u8,
+    audio: &[i16],
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, Error> {
+    macro_rules! at {
+        ($sps:literal) => {
+            match channels {
+                1 => encode_with_options::<$sps, 1>(audio, options),
+                2 => encode_with_options::<$sps, 2>(audio, options),
+                3 => encode_with_options::<$sps, 3>(audio, options),
+                4 => encode_with_options::<$sps, 4>(audio, options),
+                5 => encode_with_options::<$sps, 5>(audio, options),
+                6 => encode_with_options::<$sps, 6>(audio, options),
+                7 => encode_with_options::<$sps, 7>(audio, options),
+                8 => encode_with_options::<$sps, 8>(audio, options),
+                _ => Err(Error::InvalidChannels),
+            }
+        };
+    }
+
+    match rate {
+        SampleRate::Hz8000 => at!(8000),
+        SampleRate::Hz12000 => at!(12000),
+        SampleRate::Hz16000 => at!(16000),
+        SampleRate::Hz24000 => at!(24000),
+        SampleRate::Hz48000 => at!(48000),
+    }
+}
+
+/// Same as `encode`, for audio as `f32` (full scale being 1.0), it goes to
+/// the encoder as it is, without going through `i16` first
+pub fn encode_float<const S_PS: u32, const NUM_CHANNELS: u8>(
+    audio: &[f32],
+) -> Result<Vec<u8>, Error> {
+    encode_float_with_options::<S_PS, NUM_CHANNELS>(audio, &EncoderOptions::default())
+}
+
+/// Same as `encode_with_options`, for audio as `f32`
+pub fn encode_float_with_options<const S_PS: u32, const NUM_CHANNELS: u8>(
+    audio: &[f32],
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, Error> {
+    Ok(encode_samples::<_, S_PS, NUM_CHANNELS>(audio, options)?.0)
+}
+
+/// Same as `encode_with_options`, for audio as raw bytes: interleaved `i16` in
+/// little endian, as capture APIs and FFI boundaries tend to hand it over.
+/// Bytes have to make whole samples for every channel
+pub fn encode_pcm_bytes<const S_PS: u32, const NUM_CHANNELS: u8>(
+    pcm: &[u8],
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, Error> {
+    let frame_bytes = 2 * (NUM_CHANNELS as usize).max(1);
+    if !pcm.len().is_multiple_of(frame_bytes) {
+        return Err(Error::InvalidPcmLength);
+    }
+
+    let mut audio = vec![0i16; pcm.len() / 2];
+    LittleEndian::read_i16_into(pcm, &mut audio);
+    encode_with_options::<S_PS, NUM_CHANNELS>(&audio, options)
+}
+
+/// Same as `encode_with_options`, also telling how the encode went
+pub fn encode_with_report<const S_PS: u32, const NUM_CHANNELS: u8>(
+    audio: &[i16],
+    options: &EncoderOptions,
+) -> Result<(Vec<u8>, EncodeReport), Error> {
+    encode_samples::<_, S_PS, NUM_CHANNELS>(audio, options)
+}
+
+// Per channel, where the bitrate fitting a size is looked for
+const MIN_TARGET_BITRATE: i32 = 6000;
+const MAX_TARGET_BITRATE: i32 = 256000;
+// Encodes tried at most, and how close to the best bitrate is close enough
+const TARGET_PASSES: usize = 6;
+const TARGET_STEP: i32 = 1000;
+
+/**Same as `encode_with_report`, at the highest bitrate that keeps the whole
+stream (headers and framing included) within `max_bytes`, e.g. Telegram's
+1 MB for voice notes. The first encode goes at the bitrate the duration
+allows for, each of the next ones corrects it by how far off the size was,
+so the audio is encoded a few times over. Any bitrate in `options` is left
+aside. If not even 6 kb/s per channel fits, fails with
+`Error::TargetSizeTooSmall`*/
+pub fn encode_with_target_size<const S_PS: u32, const NUM_CHANNELS: u8>(
+    audio: &[i16],
+    max_bytes: usize,
+    options: &EncoderOptions,
+) -> Result<(Vec<u8>, EncodeReport), Error> {
+    let channels = (NUM_CHANNELS as i32).max(1);
+    let (mut low, mut high) = (MIN_TARGET_BITRATE * channels, MAX_TARGET_BITRATE * channels);
+    let encode_at = |bitrate: i32| {
+        let options = options.clone().bitrate(Bitrate::BitsPerSecond(bitrate));
+        encode_with_report::<S_PS, NUM_CHANNELS>(audio, &options)
     };
 
-    // This should have a bitrate of 24 Kb/s, exactly what IBM recommends
-
-    // More frame time, sligtly less overhead more problematic packet loses,
-    // a frame time of 20ms is considered good enough for most applications
-
-    // Data
-    let frame_samples = const { to_samples::<S_PS>(FRAME_TIME_MS) };
-    let frame_size = const { to_samples::<S_PS>(FRAME_TIME_MS) * (NUM_CHANNELS as usize) };
-    // Generate the serial which is nothing but a value to identify a stream, we
-    // will also use the process id so that two programs don't use
-    // the same serial even if getting one at the same time
-    let serial = rand::thread_rng().gen::<u32>() ^ process::id();
-
-    let mut opus_encoder = OpusEnc::new(
-        opus_sr,
-        const { opus_channels(NUM_CHANNELS) },
-        audiopus::Application::Audio,
-    )?;
-    // Balance with quality, speed and size, especially for Telegram
-    opus_encoder.set_bitrate(Bitrate::BitsPerSecond(24000))?;
-
-    let skip = opus_encoder.lookahead()? as u16;
-    let inner_encoder = InnerEncoder {
-        encoder: opus_encoder,
+    let seconds = audio.len() as f64 / (channels as u32 * S_PS) as f64;
+    let mut bitrate = if seconds > 0.0 {
+        (max_bytes as f64 * 8.0 / seconds) as i32
+    } else {
+        high
     };
-    let skip_us = skip as usize;
-    let tot_samples = audio.len() + skip_us;
-    let skip_48 = calc_sr(skip, S_PS, OGG_OPUS_SPS);
-
-    let max = (tot_samples as f32 / frame_size as f32).floor() as usize;
-
-    let mut buffer = Vec::with_capacity(frame_size * max);
-    let mut packet_writer = PacketWriter::new(&mut buffer);
-
-    let mut opus_head: [u8; 19] = [
-        OPUS_MAGIC_HEADER[0],
-        OPUS_MAGIC_HEADER[1],
-        OPUS_MAGIC_HEADER[2],
-        OPUS_MAGIC_HEADER[3],
-        OPUS_MAGIC_HEADER[4],
-        OPUS_MAGIC_HEADER[5],
-        OPUS_MAGIC_HEADER[6],
-        OPUS_MAGIC_HEADER[7],
-        // Magic header
-        1,            // Version number, always 1
-        NUM_CHANNELS, // Channels
-        0,
-        0, //Pre-skip
-        0,
-        0,
-        0,
-        0, // Original Hz (informational)
-        0,
-        0, // Output gain
-        0, // Channel map family
-           // If Channel map != 0, here should go channel mapping table
-    ];
-
-    LittleEndian::write_u16(&mut opus_head[10..12], skip_48);
-    LittleEndian::write_u32(&mut opus_head[12..16], S_PS);
-
-    packet_writer.write_packet(&opus_head[..], serial, ogg::PacketWriteEndInfo::EndPage, 0)?;
-    packet_writer.write_packet(&OPUS_TAGS, serial, ogg::PacketWriteEndInfo::EndPage, 0)?;
-
-    for counter in 0..max {
-        let pos_a = counter * frame_size;
-        let pos_b = (counter + 1) * frame_size;
-
-        let new_buffer = inner_encoder.encode_with_skip(audio, pos_a, pos_b, skip_us)?;
-
-        packet_writer.write_packet(
-            new_buffer,
-            serial,
-            is_end_of_stream(pos_b, tot_samples),
-            granule::<S_PS>(skip_us + (counter + 1) * frame_samples),
-        )?;
+    let mut best = None;
+    for _ in 0..TARGET_PASSES {
+        bitrate = bitrate.clamp(low, high);
+        let (opus, report) = encode_at(bitrate)?;
+        let size = opus.len();
+        if size <= max_bytes {
+            low = bitrate;
+            best = Some((opus, report));
+        } else if bitrate == low {
+            return Err(Error::TargetSizeTooSmall);
+        } else {
+            high = bitrate;
+        }
+        if high - low <= TARGET_STEP {
+            break;
+        }
+
+        // Size goes about in proportion to the bitrate, when that doesn't
+        // get any closer halve the range instead
+        bitrate = (bitrate as f64 * max_bytes as f64 / size as f64) as i32;
+        if bitrate <= low || bitrate >= high {
+            bitrate = low + (high - low) / 2;
+        }
+    }
+
+    match best {
+        Some(best) => Ok(best),
+        None => {
+            let (opus, report) = encode_at(low)?;
+            if opus.len() > max_bytes {
+                return Err(Error::TargetSizeTooSmall);
+            }
+            Ok((opus, report))
+        }
+    }
+}
+
+/**Same as `encode_with_report`, for the samples (per channel) in `range` of
+`audio` only, exact to the sample. The encoder's lookahead goes in the
+pre-skip and is filled with the audio right after the range (or silence past
+the end of the input), with the same `normalize_to` gain, the end-trimming
+granule (RFC 7845, section 4) cutting playback at `range.end`. Encoding `&audio[range]` on its own loses the last
+lookahead worth of audio instead and delays the rest by as much. Fails with
+`Error::InvalidRange` if `range` isn't within `audio`*/
+pub fn encode_trimmed<const S_PS: u32, const NUM_CHANNELS: u8>(
+    audio: &[i16],
+    range: Range<usize>,
+    options: &EncoderOptions,
+) -> Result<(Vec<u8>, EncodeReport), Error> {
+    let channels = (NUM_CHANNELS as usize).max(1);
+    if range.start > range.end || range.end * channels > audio.len() {
+        return Err(Error::InvalidRange);
+    }
+    let offset = range.start * channels;
+    let prepared = prepare::<_, S_PS, NUM_CHANNELS>(&audio[offset..range.end * channels], options);
+
+    let mut encoder =
+        EncoderCore::<S_PS, NUM_CHANNELS>::with_options(&prepared.tags, &prepared.options)?;
+    encoder.drop_lead_in();
+    encoder.plan_bitrates(prepared.audio());
+    for chunk in prepared.audio().chunks(S_PS as usize * channels) {
+        encoder.push(chunk)?;
+    }
+
+    let lookahead = encoder.lookahead_samples();
+    let after = offset + prepared.bounds.end;
+    let tail = prepared.with_gain(&audio[after..(after + lookahead).min(audio.len())]);
+    encoder.push(&tail)?;
+    encoder.push(&vec![0i16; lookahead - tail.len()])?;
+
+    let (opus, mut report) = encoder.finish()?;
+    prepared.fill_report(&mut report);
+    Ok((opus, report))
+}
+
+/// Same as `encode_with_options`, writing the stream to `writer` page by page
+/// instead of building it all in memory, e.g. to go straight to a file or a
+/// socket. For audio that isn't all there yet, use `StreamEncoder`
+pub fn encode_to_writer<const S_PS: u32, const NUM_CHANNELS: u8, W: Write>(
+    audio: &[i16],
+    options: &EncoderOptions,
+    writer: W,
+) -> Result<EncodeReport, Error> {
+    encode_samples_to::<_, S_PS, NUM_CHANNELS, W>(audio, options, writer)
+}
+
+fn encode_samples<S: Sample, const S_PS: u32, const NUM_CHANNELS: u8>(
+    audio: &[S],
+    options: &EncoderOptions,
+) -> Result<(Vec<u8>, EncodeReport), Error> {
+    let mut opus = Vec::new();
+    let report = encode_samples_to::<S, S_PS, NUM_CHANNELS, _>(audio, options, &mut opus)?;
+    Ok((opus, report))
+}
+
+fn encode_samples_to<S: Sample, const S_PS: u32, const NUM_CHANNELS: u8, W: Write>(
+    audio: &[S],
+    options: &EncoderOptions,
+    mut writer: W,
+) -> Result<EncodeReport, Error> {
+    let prepared = prepare::<S, S_PS, NUM_CHANNELS>(audio, options);
+    let channels = (NUM_CHANNELS as usize).max(1);
+
+    let mut encoder = Core::<S_PS, NUM_CHANNELS>::configure(&prepared.options)?;
+    encoder.write_headers(&prepared.tags)?;
+    encoder.plan_bitrates(prepared.audio());
+    // A second at a time, so that neither the encoder nor the writer have
+    // to hold everything at once
+    for chunk in prepared.audio().chunks(S_PS as usize * channels) {
+        encoder.push(chunk)?;
+        writer.write_all(&encoder.take_output())?;
+    }
+    let mut report = encoder.finish_mut()?;
+    writer.write_all(&encoder.take_output())?;
+    prepared.fill_report(&mut report);
+    Ok(report)
+}
+
+// Input and settings once everything that needs the whole input at once
+// (trimming, normalizing, loudness gain) is done
+pub(crate) struct Prepared<'a, S> {
+    trimmed: &'a [S],
+    normalized: Option<Vec<S>>,
+    pub(crate) tags: Tags,
+    pub(crate) options: EncoderOptions,
+    pub(crate) loudness_gain: Option<i16>,
+    // Of `normalize_to`, in dB
+    normalize_gain: Option<f64>,
+    // Where `trimmed` is in the input
+    bounds: Range<usize>,
+    // Silence taken from each side
+    trimmed_start: Duration,
+    trimmed_end: Duration,
+}
+
+impl<S> Prepared<'_, S> {
+    pub(crate) fn audio(&self) -> &[S] {
+        self.normalized.as_deref().unwrap_or(self.trimmed)
+    }
+
+    // More of the input going along with `audio`, with the same gain
+    pub(crate) fn with_gain<'b>(&self, audio: &'b [S]) -> Cow<'b, [S]>
+    where
+        S: Sample,
+    {
+        match self.normalize_gain {
+            Some(gain) => Cow::Owned(loudness::apply_gain(audio, gain)),
+            None => Cow::Borrowed(audio),
+        }
+    }
+
+    // What the encoder can't know about on its own
+    pub(crate) fn fill_report(&self, report: &mut EncodeReport) {
+        report.loudness_gain = self.loudness_gain;
+        report.trimmed_start = self.trimmed_start;
+        report.trimmed_end = self.trimmed_end;
     }
+}
 
-    let frame_sizes = const {
-        [
-            calc_fr_size(MIN_FRAME_MICROS, NUM_CHANNELS, S_PS),
-            calc_fr_size(50, NUM_CHANNELS, S_PS),
-            calc_fr_size(100, NUM_CHANNELS, S_PS),
-            calc_fr_size(200, NUM_CHANNELS, S_PS),
-        ]
+pub(crate) fn prepare<'a, S: Sample, const S_PS: u32, const NUM_CHANNELS: u8>(
+    audio: &'a [S],
+    options: &EncoderOptions,
+) -> Prepared<'a, S> {
+    let channels = (NUM_CHANNELS as usize).max(1);
+    let bounds = match options.trim_silence {
+        Some(trim) => {
+            let padding = duration_to_samples(Duration::from_millis(trim.padding_ms), S_PS);
+            let padding = usize::try_from(padding).unwrap_or(usize::MAX);
+            sound_bounds(audio, channels, trim.threshold_db, padding)
+        }
+        None => 0..audio.len(),
     };
+    let trimmed_duration = |samples: usize| samples_to_duration((samples / channels) as u64, S_PS);
+    let trimmed_start = trimmed_duration(bounds.start);
+    let trimmed_end = trimmed_duration(audio.len() - bounds.end);
+    let trimmed = &audio[bounds.clone()];
 
-    let mut last_sample = max * frame_size;
+    // Needs the whole input, which is why it can't be done while streaming
+    let normalize_gain = options
+        .normalize_to
+        .and_then(|target| Some(target - loudness::integrated_loudness(trimmed, S_PS, channels)?));
+    let normalized = normalize_gain.map(|gain| loudness::apply_gain(trimmed, gain));
+    let audio = normalized.as_deref().unwrap_or(trimmed);
 
-    while last_sample < tot_samples {
-        let rem_samples = tot_samples - last_sample;
-        let last_audio_s = last_sample - min(last_sample, skip_us);
+    let mut tags = Tags::default();
+    let mut options = options.clone();
+    let measured = options
+        .loudness_gain
+        .and_then(|gain| Some((gain, loudness::integrated_loudness(audio, S_PS, channels)?)));
+    let loudness_gain = measured.map(|(gain, measured)| {
+        let current = options.output_gain.unwrap_or(0.0);
+        match gain {
+            LoudnessGain::OutputGain { target_lufs } => {
+                let gain = db_to_q78(current + target_lufs - measured);
+                options.output_gain = Some(gain as f64 / 256.0);
+                gain
+            }
+            // Tags go on top of the output gain
+            LoudnessGain::R128Tag => {
+                let gain = db_to_q78(R128_REFERENCE_LUFS - measured - current);
+                tags.set_track_gain(gain);
+                gain
+            }
+        }
+    });
 
-        if let Some(&frame_size) = frame_sizes.iter().rev().find(|&&size| size <= rem_samples) {
-            let enc = if last_sample >= skip_us {
-                inner_encoder.encode_no_skip(audio, last_audio_s, frame_size)?
-            } else {
-                inner_encoder.encode_with_skip(
-                    audio,
-                    last_sample,
-                    last_sample + frame_size,
-                    skip_us,
-                )?
-            };
-            last_sample += frame_size;
-            packet_writer.write_packet(
-                enc,
-                serial,
-                is_end_of_stream(last_sample, tot_samples),
-                granule::<S_PS>(last_sample / NUM_CHANNELS as usize),
+    Prepared {
+        trimmed,
+        normalized,
+        tags,
+        options,
+        loudness_gain,
+        normalize_gain,
+        bounds,
+        trimmed_start,
+        trimmed_end,
+    }
+}
+
+// Where the audio louder than the threshold is, plus some padding, whole
+// frames (all channels) are always kept
+fn sound_bounds<S: Sample>(
+    audio: &[S],
+    channels: usize,
+    threshold_db: f64,
+    padding: usize,
+) -> Range<usize> {
+    // In i16 steps whatever the sample type
+    let threshold = (10f64.powf(threshold_db / 20.0) * i16::MAX as f64) as i32 as f32;
+    let is_sound = |frame: &[S]| {
+        frame
+            .iter()
+            .any(|&s| (s.to_f32() * 32768.0).abs() > threshold)
+    };
+
+    let frames = audio.len() / channels;
+    let first = audio.chunks_exact(channels).position(is_sound);
+    let last = audio.chunks_exact(channels).rposition(is_sound);
+    match (first, last) {
+        (Some(first), Some(last)) => {
+            let start = first.saturating_sub(padding);
+            let end = (last + 1).saturating_add(padding).min(frames);
+            start * channels..end * channels
+        }
+        // Nothing but silence
+        _ => 0..0,
+    }
+}
+
+/**Encoder to be kept around for many short encodes (bot messages, TTS
+replies ...): libopus' encoder and the buffers are set up once and reset
+for every call, instead of being made anew as `encode_with_options` does.
+Every call gives a whole stream of its own, with the same tags.*/
+pub struct OggOpusEncoder<const S_PS: u32, const NUM_CHANNELS: u8> {
+    core: EncoderCore<S_PS, NUM_CHANNELS>,
+    options: EncoderOptions,
+    tags: Tags,
+}
+
+impl<const S_PS: u32, const NUM_CHANNELS: u8> OggOpusEncoder<S_PS, NUM_CHANNELS> {
+    pub fn new(options: &EncoderOptions) -> Result<Self, Error> {
+        Self::with_tags(&Tags::default(), options)
+    }
+
+    pub fn with_tags(tags: &Tags, options: &EncoderOptions) -> Result<Self, Error> {
+        Ok(Self {
+            core: EncoderCore::configure(options)?,
+            options: options.clone(),
+            tags: tags.clone(),
+        })
+    }
+
+    /// Same as `encode_with_options`, everything in the options applies
+    pub fn encode(&mut self, audio: &[i16]) -> Result<Vec<u8>, Error> {
+        self.encode_samples(audio)
+    }
+
+    /// Same as `encode`, for audio as `f32` (full scale being 1.0)
+    pub fn encode_float(&mut self, audio: &[f32]) -> Result<Vec<u8>, Error> {
+        self.encode_samples(audio)
+    }
+
+    fn encode_samples<S: Sample>(&mut self, audio: &[S]) -> Result<Vec<u8>, Error> {
+        let prepared = prepare::<S, S_PS, NUM_CHANNELS>(audio, &self.options);
+        let mut tags = self.tags.clone();
+        if let Some(gain) = prepared.tags.track_gain() {
+            tags.set_track_gain(gain);
+        }
+        let output_gain = prepared.options.output_gain.map_or(0, db_to_q78);
+        self.core
+            .restart(&tags, pick_serial(&self.options), output_gain)?;
+        self.core.plan_bitrates(prepared.audio());
+
+        let channels = (NUM_CHANNELS as usize).max(1);
+        for chunk in prepared.audio().chunks(S_PS as usize * channels) {
+            self.core.push(chunk)?;
+        }
+        self.core.finish_mut()?;
+        prepared.fill_report(&mut self.core.report);
+        Ok(self.core.take_output())
+    }
+
+    /// Report of the last stream encoded
+    pub fn report(&self) -> EncodeReport {
+        self.core.report
+    }
+
+    /// The encoder's lookahead, which every stream has as pre-skip, in 48 kHz
+    /// samples
+    pub fn pre_skip(&self) -> u16 {
+        self.core.pre_skip()
+    }
+}
+
+/// `StreamEncoder` state: nothing written yet, still open to tweaks
+pub struct Configured;
+/// `StreamEncoder` state: headers are out, audio can be pushed
+pub struct Writing;
+/// `StreamEncoder` state: the stream is ended, only the writer and the report
+/// are left
+pub struct Finished;
+
+/// Numbers about an encoded stream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EncodeReport {
+    /// Audio packets, headers not included
+    pub packets: u64,
+    /// Size of all audio packets, without the Ogg framing
+    pub bytes: u64,
+    /// Pre-skip as written in the header, in 48 kHz samples
+    pub pre_skip: u16,
+    /// Length of the audio, pre-skip taken out
+    pub duration: Duration,
+    /// Bytes spent on Ogg framing (page headers and lacing) for the audio
+    pub overhead: u64,
+    /// With max packing: framing saved compared to the usual 20 ms packets,
+    /// an estimate as those would have been encoded differently
+    pub overhead_saved: u64,
+    /// With `loudness_gain`, the output gain or tag written, in 1/256 dB
+    pub loudness_gain: Option<i16>,
+    /// libopus' range coder state after the last packet, a decoder going
+    /// through the same packets bit-exactly ends with the same one
+    pub final_range: u32,
+    /// With `trim_silence`, how much silence was taken from the start and
+    /// from the end of the input
+    pub trimmed_start: Duration,
+    pub trimmed_end: Duration,
+}
+
+/// What the encoder did with a single packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PacketStats {
+    pub bytes: usize,
+    pub duration: Duration,
+    /// Granule at the end of the packet, so counting the pre-skip
+    pub granule: u64,
+    /// Mode and bandwidth the encoder picked, if the packet has a TOC
+    pub mode: Option<Mode>,
+    pub bandwidth: Option<Bandwidth>,
+}
+
+pub(crate) type PacketCallback = Box<dyn FnMut(&PacketStats) + Send>;
+
+/**Sans-IO encoder: PCM goes in, Ogg bytes come out, with nothing being
+read or written on its own. Everything else encoding (`StreamEncoder`,
+`encode` ...) is built on top of it, and it's what to use to plug the
+encoder somewhere else (async, FFI ...). Headers are ready right after
+creation, take whatever's available with `take_output` after every push.*/
+pub struct EncoderCore<const S_PS: u32, const NUM_CHANNELS: u8> {
+    encoder: InnerEncoder,
+    writer: PacketWriter<'static, Vec<u8>>,
+    serial: u32,
+    reproducible: bool,
+    pre_skip: u16,
+    // Q7.8 dB, as in the header
+    output_gain: i16,
+    // Interleaved samples of lookahead
+    lookahead: usize,
+    bitrate: Bitrate,
+    two_pass: bool,
+    // With two passes, the bitrate for every frame and the one set last
+    bitrate_plan: Vec<i32>,
+    planned: Option<i32>,
+    // Audio waiting for a whole frame, starts with the lookahead as silence.
+    // As f32 whatever comes in, that's what libopus works with anyway
+    pending: Vec<f32>,
+    // We can only know whether a packet is the last one once we see the next
+    held: Option<(Vec<u8>, u64)>,
+    flush_pages: bool,
+    frame: FrameSize,
+    // Output already taken and the size of both header packets, needed to
+    // know how much of the stream is framing
+    taken: u64,
+    header_bytes: u64,
+    report: EncodeReport,
+    on_packet: Option<PacketCallback>,
+}
+
+impl<const S_PS: u32, const NUM_CHANNELS: u8> EncoderCore<S_PS, NUM_CHANNELS> {
+    pub fn new(tags: &Tags) -> Result<Self, Error> {
+        Self::with_options(tags, &EncoderOptions::default())
+    }
+
+    /// Same as `new`, with the codec settings of `options` (bitrate,
+    /// application, complexity, max packing). Those that need the whole
+    /// input at once (normalizing, trimming, two passes) don't apply here
+    pub fn with_options(tags: &Tags, options: &EncoderOptions) -> Result<Self, Error> {
+        let mut core = Self::configure(options)?;
+        core.write_headers(tags)?;
+        Ok(core)
+    }
+
+    // Sets up the encoder without writing anything
+    pub(crate) fn configure(options: &EncoderOptions) -> Result<Self, Error> {
+        let opus_sr = const {
+            match s_ps_to_audiopus(S_PS) {
+                Some(v) => v,
+                None => panic!("Wrong SampleRate"),
+            }
+        };
+
+        // More frame time, sligtly less overhead more problematic packet loses,
+        // a frame time of 20ms is considered good enough for most applications
+
+        let serial = pick_serial(options);
+
+        let frame = options.frame_size.unwrap_or(if options.max_packing {
+            FrameSize::Ms60
+        } else {
+            FrameSize::Ms20
+        });
+
+        // The application has to be known from the start, the lookahead
+        // depends on it
+        let application = options.application.unwrap_or(Application::Audio);
+        let mut opus_encoder = match const { opus_channels(NUM_CHANNELS) } {
+            Some(channels) => Codec::Single(OpusEnc::new(opus_sr, channels, application.into())?),
+            None => Codec::Surround(SurroundEncoder::new(S_PS, NUM_CHANNELS, application)?),
+        };
+        let (streams, coupled) = match &opus_encoder {
+            Codec::Single(_) => (1, NUM_CHANNELS.saturating_sub(1)),
+            Codec::Surround(encoder) => (encoder.mapping().streams, encoder.mapping().coupled),
+        };
+        let bitrate = options
+            .bitrate
+            .unwrap_or_else(|| default_bitrate(streams, coupled, frame.as_micros()));
+        opus_encoder.set_bitrate(bitrate)?;
+        if let Some(complexity) = options.complexity {
+            opus_encoder.set_complexity(complexity)?;
+        }
+        if let Some(rate_control) = options.rate_control {
+            opus_encoder.set_rate_control(rate_control)?;
+        }
+        if let Some(packet_loss_perc) = options.inband_fec {
+            opus_encoder.set(ffi::OPUS_SET_INBAND_FEC_REQUEST, 1)?;
+            opus_encoder.set(
+                ffi::OPUS_SET_PACKET_LOSS_PERC_REQUEST,
+                packet_loss_perc as i32,
             )?;
+        }
+
+        let skip = opus_encoder.lookahead()? as u16;
+        let skip_48 = calc_sr(skip, S_PS, OGG_OPUS_SPS);
+
+        Ok(Self {
+            encoder: InnerEncoder {
+                encoder: opus_encoder,
+                position: 0,
+                max_packet: MAX_PACKET * streams as usize,
+            },
+            writer: PacketWriter::new(Vec::new()),
+            serial,
+            reproducible: options.reproducible,
+            pre_skip: skip_48,
+            output_gain: options.output_gain.map_or(0, db_to_q78),
+            lookahead: skip as usize * (NUM_CHANNELS as usize).max(1),
+            bitrate,
+            two_pass: options.two_pass,
+            bitrate_plan: Vec::new(),
+            planned: None,
+            pending: vec![0.0; skip as usize * (NUM_CHANNELS as usize).max(1)],
+            held: None,
+            flush_pages: false,
+            frame,
+            taken: 0,
+            header_bytes: 0,
+            on_packet: None,
+            report: EncodeReport {
+                pre_skip: skip_48,
+                ..EncodeReport::default()
+            },
+        })
+    }
+
+    pub(crate) fn write_headers(&mut self, tags: &Tags) -> Result<(), Error> {
+        let mut opus_head = opus_head(NUM_CHANNELS, self.pre_skip, S_PS, self.output_gain);
+
+        // Surround goes with family 1 and its mapping table
+        if let Some(mapping) = self.encoder.encoder.mapping() {
+            opus_head[18] = 1;
+            mapping.write(&mut opus_head);
+        }
+
+        self.writer.write_packet(
+            opus_head.clone(),
+            self.serial,
+            ogg::PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+        let tags = if self.reproducible && tags.vendor == VENDOR_STR {
+            Tags {
+                vendor: REPRODUCIBLE_VENDOR.to_string(),
+                ..tags.clone()
+            }
+            .to_packet()
         } else {
-            // Maximum size for a 2.5 ms frame
-            const MAX_25_SIZE: usize =
-                calc_fr_size(MIN_FRAME_MICROS, MAX_NUM_CHANNELS, OGG_OPUS_SPS);
-            let mut in_buffer = [0i16; MAX_25_SIZE];
-            let rem_skip = skip_us - min(last_sample, skip_us);
-            in_buffer[rem_skip..rem_samples].copy_from_slice(&audio[last_audio_s..]);
-
-            last_sample = tot_samples; // We end this here
-
-            let enc = inner_encoder.encode_no_skip(&in_buffer, 0, frame_sizes[0])?;
-            packet_writer.write_packet(
-                enc,
-                serial,
+            tags.to_packet()
+        };
+        self.header_bytes = (opus_head.len() + tags.len()) as u64;
+        self.writer
+            .write_packet(tags, self.serial, ogg::PacketWriteEndInfo::EndPage, 0)?;
+
+        Ok(())
+    }
+
+    /// Calls `on_packet` for every audio packet, as it gets written
+    pub fn on_packet<F: FnMut(&PacketStats) + Send + 'static>(&mut self, on_packet: F) {
+        self.on_packet = Some(Box::new(on_packet));
+    }
+
+    /// End a page after every `push_samples`, so that whatever was encoded
+    /// is available right away
+    pub(crate) fn set_flush_pages(&mut self, flush: bool) {
+        self.flush_pages = flush;
+    }
+
+    /// Pre-skip as written in the header, in 48 kHz samples: the encoder's
+    /// lookahead
+    pub fn pre_skip(&self) -> u16 {
+        self.pre_skip
+    }
+
+    /// Encodes as many whole frames as possible, the rest is kept until more
+    /// audio arrives (or `finish` is called)
+    pub fn push_samples(&mut self, audio: &[i16]) -> Result<(), Error> {
+        self.push(audio)
+    }
+
+    /// Same as `push_samples`, for audio as `f32` (full scale being 1.0)
+    pub fn push_float(&mut self, audio: &[f32]) -> Result<(), Error> {
+        self.push(audio)
+    }
+
+    pub(crate) fn push<S: Sample>(&mut self, audio: &[S]) -> Result<(), Error> {
+        let encoded = self.encode_frames(audio)?;
+        self.write_encoded(encoded)
+    }
+
+    /// Same as `push`, but the packets are given back instead of going into
+    /// Ogg, for those that send them as they are (Discord, RTP ...)
+    pub(crate) fn push_raw<S: Sample>(&mut self, audio: &[S]) -> Result<Vec<Vec<u8>>, Error> {
+        let encoded = self.encode_frames(audio)?;
+        Ok(encoded.into_iter().map(|(packet, _)| packet).collect())
+    }
+
+    /// What's left of the audio, filled with silence up to a whole frame,
+    /// so that every packet lasts the same. Nothing if it was all encoded
+    pub(crate) fn finish_raw(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+        let frame_size = self.frame.samples(S_PS) * (NUM_CHANNELS as usize);
+        self.pending.resize(frame_size, 0.0);
+        let pending = std::mem::take(&mut self.pending);
+        let (packet, _) = self.encoder.encode_frame::<S_PS, NUM_CHANNELS>(&pending)?;
+        Ok(Some(packet))
+    }
+
+    /// Drops the lookahead silence and goes on as if `frames` whole frames
+    /// were already encoded, to encode the rest of a stream from there
+    pub(crate) fn resume_at(&mut self, frames: u64) {
+        self.pending.clear();
+        self.encoder.position = frames * self.frame_samples() as u64;
+    }
+
+    /// Audio starts right away instead of after the lookahead silence, for
+    /// those that push the lookahead at the end themselves
+    pub(crate) fn drop_lead_in(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Interleaved samples in a frame
+    pub(crate) fn frame_samples(&self) -> usize {
+        self.frame.samples(S_PS) * NUM_CHANNELS as usize
+    }
+
+    /// Interleaved samples of lookahead silence the stream starts with
+    pub(crate) fn lookahead_samples(&self) -> usize {
+        self.lookahead
+    }
+
+    /// Starts another stream with the same encoder and buffers, as if it
+    /// had just been created (and the headers written)
+    pub(crate) fn restart(
+        &mut self,
+        tags: &Tags,
+        serial: u32,
+        output_gain: i16,
+    ) -> Result<(), Error> {
+        self.encoder.encoder.reset()?;
+        if self.planned.take().is_some() {
+            self.encoder.encoder.set_bitrate(self.bitrate)?;
+        }
+        self.bitrate_plan.clear();
+        self.encoder.position = 0;
+        self.pending.clear();
+        self.pending.resize(self.lookahead, 0.0);
+        self.held = None;
+
+        let mut buffer =
+            std::mem::replace(&mut self.writer, PacketWriter::new(Vec::new())).into_inner();
+        buffer.clear();
+        self.writer = PacketWriter::new(buffer);
+        self.serial = serial;
+        self.output_gain = output_gain;
+        self.taken = 0;
+        self.report = EncodeReport {
+            pre_skip: self.pre_skip,
+            ..EncodeReport::default()
+        };
+        self.write_headers(tags)
+    }
+
+    /// With `two_pass`, the first pass: how loud each frame of `audio` (the
+    /// whole input, as it'll be pushed) is sets the bitrate it gets
+    pub(crate) fn plan_bitrates<S: Sample>(&mut self, audio: &[S]) {
+        let bitrate = match self.bitrate {
+            Bitrate::BitsPerSecond(bitrate) if self.two_pass => bitrate,
+            _ => return,
+        };
+
+        // Frames start with the lookahead silence, as they're encoded
+        let frame = self.frame_samples();
+        let lead_in = self.pending.len();
+        let frames = (lead_in + audio.len()).div_ceil(frame);
+        let levels: Vec<f32> = (0..frames)
+            .map(|index| {
+                let start = (index * frame).saturating_sub(lead_in);
+                let end = ((index + 1) * frame)
+                    .saturating_sub(lead_in)
+                    .min(audio.len());
+                let energy: f32 = audio[start..end]
+                    .iter()
+                    .map(|s| s.to_f32() * s.to_f32())
+                    .sum();
+                (energy / frame as f32).sqrt()
+            })
+            .collect();
+
+        let mean = levels.iter().sum::<f32>() / frames.max(1) as f32;
+        if mean == 0.0 {
+            return;
+        }
+        let weights: Vec<f32> = levels
+            .iter()
+            .map(|level| {
+                (level / mean)
+                    .sqrt()
+                    .clamp(1.0 / TWO_PASS_SPREAD, TWO_PASS_SPREAD)
+            })
+            .collect();
+        // Same bitrate on average as asked for
+        let scale = frames as f32 / weights.iter().sum::<f32>();
+        self.bitrate_plan = weights
+            .iter()
+            .map(|weight| (bitrate as f32 * weight * scale) as i32)
+            .collect();
+    }
+
+    // Whole frames of what's pending plus `audio`, the rest keeps waiting
+    pub(crate) fn encode_frames<S: Sample>(
+        &mut self,
+        audio: &[S],
+    ) -> Result<Vec<(Vec<u8>, u64)>, Error> {
+        let frame_size = self.frame.samples(S_PS) * (NUM_CHANNELS as usize);
+
+        self.pending.extend(audio.iter().map(|s| s.to_f32()));
+
+        let mut encoded = Vec::new();
+        let mut start = 0;
+        while self.pending.len() - start >= frame_size {
+            let index = (self.encoder.position / frame_size as u64) as usize;
+            if let Some(&bitrate) = self.bitrate_plan.get(index) {
+                if self.planned != Some(bitrate) {
+                    self.encoder
+                        .encoder
+                        .set_bitrate(Bitrate::BitsPerSecond(bitrate))?;
+                    self.planned = Some(bitrate);
+                }
+            }
+            encoded
+                .push(self.encoder.encode_frame::<S_PS, NUM_CHANNELS>(
+                    &self.pending[start..start + frame_size],
+                )?);
+            start += frame_size;
+        }
+        self.pending.drain(..start);
+
+        Ok(encoded)
+    }
+
+    /// Ogg data produced so far and not taken yet, always whole pages
+    pub fn take_output(&mut self) -> Vec<u8> {
+        let output = std::mem::take(self.writer.inner_mut());
+        self.taken += output.len() as u64;
+        output
+    }
+
+    /// Encodes whatever audio is left and ends the stream, giving back the
+    /// rest of the output
+    pub fn finish(mut self) -> Result<(Vec<u8>, EncodeReport), Error> {
+        let report = self.finish_mut()?;
+        Ok((self.take_output(), report))
+    }
+
+    // Only for wrappers that make sure nothing is pushed afterwards
+    pub(crate) fn finish_mut(&mut self) -> Result<EncodeReport, Error> {
+        // A stream needs at least one audio packet
+        let encoded = self.finish_frames(self.held.is_none())?;
+
+        self.write_encoded(encoded)?;
+        if let Some((packet, granule)) = self.held.take() {
+            self.count(&packet, granule);
+            self.writer.write_packet(
+                packet,
+                self.serial,
                 ogg::PacketWriteEndInfo::EndStream,
-                granule::<S_PS>((skip_us + audio.len()) / NUM_CHANNELS as usize),
+                granule,
             )?;
         }
+
+        self.report.final_range = self.encoder.encoder.final_range()?;
+        if cfg!(test) {
+            set_final_range(self.report.final_range)
+        }
+
+        let total = self.taken + self.writer.inner().len() as u64;
+        self.report.overhead = total - self.report.bytes - self.header_bytes;
+        if self.frame != FrameSize::Ms20 {
+            let usual = usual_overhead(&self.report);
+            self.report.overhead_saved = usual.saturating_sub(self.report.overhead);
+        }
+
+        Ok(self.report)
     }
 
-    if cfg!(test) {
-        set_final_range(inner_encoder.encoder.final_range().unwrap())
+    /// Encodes what's pending in smaller frames, the padding of the last one
+    /// taken out of its granule. With `at_least_one` there's a packet even
+    /// if nothing was pending
+    pub(crate) fn finish_frames(
+        &mut self,
+        at_least_one: bool,
+    ) -> Result<Vec<(Vec<u8>, u64)>, Error> {
+        let frame_sizes = const {
+            [
+                calc_fr_size(MIN_FRAME_MICROS, NUM_CHANNELS, S_PS),
+                calc_fr_size(50, NUM_CHANNELS, S_PS),
+                calc_fr_size(100, NUM_CHANNELS, S_PS),
+                calc_fr_size(200, NUM_CHANNELS, S_PS),
+            ]
+        };
+
+        // What's left doesn't fill a frame, use smaller ones
+        let mut encoded = Vec::new();
+        let mut start = 0;
+        while start < self.pending.len() || (encoded.is_empty() && at_least_one) {
+            let rem_samples = self.pending.len() - start;
+
+            if let Some(&frame_size) = frame_sizes.iter().rev().find(|&&size| size <= rem_samples) {
+                encoded.push(self.encoder.encode_frame::<S_PS, NUM_CHANNELS>(
+                    &self.pending[start..start + frame_size],
+                )?);
+                start += frame_size;
+            } else {
+                let mut in_buffer = vec![0f32; frame_sizes[0]];
+                in_buffer[..rem_samples].copy_from_slice(&self.pending[start..]);
+
+                // We end this here, the padding is not counted in the granule
+                let mut packet = self
+                    .encoder
+                    .encode_frame::<S_PS, NUM_CHANNELS>(&in_buffer[..frame_sizes[0]])?;
+                packet.1 -= granule::<S_PS>(
+                    ((frame_sizes[0] - rem_samples) / NUM_CHANNELS as usize) as u64,
+                );
+                encoded.push(packet);
+                start = self.pending.len();
+            }
+        }
+        self.pending.clear();
+        Ok(encoded)
+    }
+
+    pub(crate) fn write_encoded(&mut self, encoded: Vec<(Vec<u8>, u64)>) -> Result<(), Error> {
+        if encoded.is_empty() {
+            return Ok(());
+        }
+
+        let mut to_write: Vec<_> = self.held.take().into_iter().chain(encoded).collect();
+        self.held = to_write.pop();
+
+        let last = to_write.len();
+        for (i, (packet, granule)) in to_write.into_iter().enumerate() {
+            let end_info = if self.flush_pages && i + 1 == last {
+                ogg::PacketWriteEndInfo::EndPage
+            } else {
+                ogg::PacketWriteEndInfo::NormalPacket
+            };
+            self.count(&packet, granule);
+            self.writer
+                .write_packet(packet, self.serial, end_info, granule)?;
+        }
+
+        Ok(())
     }
 
-    Ok(buffer)
+    fn count(&mut self, packet: &[u8], granule: u64) {
+        self.report.packets += 1;
+        self.report.bytes += packet.len() as u64;
+        self.report.duration =
+            samples_to_duration(granule.saturating_sub(self.pre_skip as u64), OGG_OPUS_SPS);
+
+        if let Some(on_packet) = &mut self.on_packet {
+            let toc = Toc::parse(packet);
+            on_packet(&PacketStats {
+                bytes: packet.len(),
+                duration: samples_to_duration(
+                    toc.map(|t| t.samples()).unwrap_or(0) as u64,
+                    OGG_OPUS_SPS,
+                ),
+                granule,
+                mode: toc.map(|t| t.mode),
+                bandwidth: toc.map(|t| t.bandwidth),
+            });
+        }
+    }
 }
 
-struct InnerEncoder {
-    encoder: OpusEnc,
+// Whichever encoder the options asked for, behind `StreamEncoder` and the
+// `encode` functions
+enum Core<const S_PS: u32, const NUM_CHANNELS: u8> {
+    Native(EncoderCore<S_PS, NUM_CHANNELS>),
+    #[cfg(feature = "opusenc")]
+    Libopusenc(OpusEncCore<S_PS, NUM_CHANNELS>),
 }
 
-impl InnerEncoder {
-    fn encode_vec(&self, audio: &[i16]) -> Result<Cow<'_, [u8]>, Error> {
-        let mut output = vec![0; MAX_PACKET];
-        let result = self.encoder.encode(audio, &mut output)?;
-        output.truncate(result);
-        Ok(output.into())
-    }
-
-    fn encode_with_skip(
-        &self,
-        audio: &[i16],
-        pos_a: usize,
-        pos_b: usize,
-        skip_us: usize,
-    ) -> Result<Cow<'_, [u8]>, Error> {
-        if pos_a > skip_us {
-            self.encode_vec(&audio[pos_a - skip_us..pos_b - skip_us])
-        } else {
-            let mut buf = vec![0; pos_b - pos_a];
-            if pos_b > skip_us {
-                buf[skip_us - pos_a..].copy_from_slice(&audio[..pos_b - skip_us]);
+impl<const S_PS: u32, const NUM_CHANNELS: u8> Core<S_PS, NUM_CHANNELS> {
+    fn configure(options: &EncoderOptions) -> Result<Self, Error> {
+        #[cfg(feature = "opusenc")]
+        if options.libopusenc {
+            return Ok(Core::Libopusenc(OpusEncCore::configure(options)?));
+        }
+        Ok(Core::Native(EncoderCore::configure(options)?))
+    }
+
+    fn write_headers(&mut self, tags: &Tags) -> Result<(), Error> {
+        match self {
+            Core::Native(core) => core.write_headers(tags),
+            #[cfg(feature = "opusenc")]
+            Core::Libopusenc(core) => core.write_headers(tags),
+        }
+    }
+
+    fn on_packet<F: FnMut(&PacketStats) + Send + 'static>(&mut self, on_packet: F) {
+        match self {
+            Core::Native(core) => core.on_packet(on_packet),
+            #[cfg(feature = "opusenc")]
+            Core::Libopusenc(core) => core.on_packet(Box::new(on_packet)),
+        }
+    }
+
+    fn set_flush_pages(&mut self, flush: bool) {
+        match self {
+            Core::Native(core) => core.set_flush_pages(flush),
+            #[cfg(feature = "opusenc")]
+            Core::Libopusenc(core) => core.set_flush_pages(flush),
+        }
+    }
+
+    // Two passes are ours only
+    fn plan_bitrates<S: Sample>(&mut self, audio: &[S]) {
+        match self {
+            Core::Native(core) => core.plan_bitrates(audio),
+            #[cfg(feature = "opusenc")]
+            Core::Libopusenc(_) => {}
+        }
+    }
+
+    fn push<S: Sample>(&mut self, audio: &[S]) -> Result<(), Error> {
+        match self {
+            Core::Native(core) => core.push(audio),
+            #[cfg(feature = "opusenc")]
+            Core::Libopusenc(core) => core.push(audio),
+        }
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        match self {
+            Core::Native(core) => core.take_output(),
+            #[cfg(feature = "opusenc")]
+            Core::Libopusenc(core) => core.take_output(),
+        }
+    }
+
+    fn finish_mut(&mut self) -> Result<EncodeReport, Error> {
+        match self {
+            Core::Native(core) => core.finish_mut(),
+            #[cfg(feature = "opusenc")]
+            Core::Libopusenc(core) => core.finish_mut(),
+        }
+    }
+
+    fn report(&self) -> EncodeReport {
+        match self {
+            Core::Native(core) => core.report,
+            #[cfg(feature = "opusenc")]
+            Core::Libopusenc(core) => core.report(),
+        }
+    }
+}
+
+/**Encodes audio as it comes, writing Ogg pages to `W` as soon as they are
+ready, so that long recordings don't need to be in memory all at once.
+
+Its state is part of the type: a `Configured` encoder has to `start` (which
+writes the headers) before taking any audio, and `finish` consumes a
+`Writing` one, so pushing after the end or forgetting the headers won't
+compile. `new` and `with_tags` are shortcuts that give a `Writing` encoder
+right away.*/
+pub struct StreamEncoder<W: Write, const S_PS: u32, const NUM_CHANNELS: u8, State = Writing> {
+    core: Core<S_PS, NUM_CHANNELS>,
+    writer: W,
+    // Only needed until the headers are written
+    tags: Tags,
+    state: PhantomData<State>,
+}
+
+impl<W: Write, const S_PS: u32, const NUM_CHANNELS: u8, State>
+    StreamEncoder<W, S_PS, NUM_CHANNELS, State>
+{
+    // Same encoder in another state
+    fn into_state<Next>(self) -> StreamEncoder<W, S_PS, NUM_CHANNELS, Next> {
+        StreamEncoder {
+            core: self.core,
+            writer: self.writer,
+            tags: self.tags,
+            state: PhantomData,
+        }
+    }
+
+    // Hands whatever the core has to the writer
+    fn write_output(&mut self) -> Result<(), Error> {
+        let output = self.core.take_output();
+        self.writer.write_all(&output)?;
+        Ok(())
+    }
+}
+
+impl<W: Write, const S_PS: u32, const NUM_CHANNELS: u8>
+    StreamEncoder<W, S_PS, NUM_CHANNELS, Configured>
+{
+    /// Tags for the comment header
+    pub fn tags(mut self, tags: &Tags) -> Self {
+        self.tags = tags.clone();
+        self
+    }
+
+    /// See `EncoderCore::on_packet`
+    pub fn on_packet<F: FnMut(&PacketStats) + Send + 'static>(mut self, on_packet: F) -> Self {
+        self.core.on_packet(on_packet);
+        self
+    }
+
+    /// End a page after every push, so that whatever was encoded reaches the
+    /// writer right away (live streams), at the cost of more framing
+    pub fn flush_pages(mut self) -> Self {
+        self.core.set_flush_pages(true);
+        self
+    }
+
+    /// Writes the headers, audio can be pushed from now on
+    pub fn start(mut self) -> Result<StreamEncoder<W, S_PS, NUM_CHANNELS, Writing>, Error> {
+        let tags = std::mem::take(&mut self.tags);
+        self.core.write_headers(&tags)?;
+        self.write_output()?;
+        Ok(self.into_state())
+    }
+}
+
+impl<W: Write, const S_PS: u32, const NUM_CHANNELS: u8> StreamEncoder<W, S_PS, NUM_CHANNELS> {
+    /// Sets up the encoder, nothing is written until `start`
+    pub fn configure(writer: W) -> Result<StreamEncoder<W, S_PS, NUM_CHANNELS, Configured>, Error> {
+        Self::configure_with(writer, &EncoderOptions::default())
+    }
+
+    /// Same as `configure`, with the codec settings of `options`, see
+    /// `EncoderCore::with_options`
+    pub fn configure_with(
+        writer: W,
+        options: &EncoderOptions,
+    ) -> Result<StreamEncoder<W, S_PS, NUM_CHANNELS, Configured>, Error> {
+        Ok(StreamEncoder {
+            core: Core::configure(options)?,
+            writer,
+            tags: Tags::default(),
+            state: PhantomData,
+        })
+    }
+
+    pub fn new(writer: W) -> Result<Self, Error> {
+        StreamEncoder::configure(writer)?.start()
+    }
+
+    /// Same as `new`, writing `tags` in the comment header
+    pub fn with_tags(writer: W, tags: &Tags) -> Result<Self, Error> {
+        StreamEncoder::configure(writer)?.tags(tags).start()
+    }
+
+    /// Encodes as many whole frames as possible, the rest is kept until more
+    /// audio arrives (or `finish` is called)
+    pub fn push_samples(&mut self, audio: &[i16]) -> Result<(), Error> {
+        self.core.push(audio)?;
+        self.write_output()
+    }
+
+    /// Same as `push_samples`, for audio as `f32` (full scale being 1.0)
+    pub fn push_float(&mut self, audio: &[f32]) -> Result<(), Error> {
+        self.core.push(audio)?;
+        self.write_output()
+    }
+
+    /// The writer, with everything written so far in it
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Encodes whatever audio is left and ends the stream
+    pub fn finish(mut self) -> Result<StreamEncoder<W, S_PS, NUM_CHANNELS, Finished>, Error> {
+        self.core.finish_mut()?;
+        self.write_output()?;
+        Ok(self.into_state())
+    }
+}
+
+impl<W: Write, const S_PS: u32, const NUM_CHANNELS: u8>
+    StreamEncoder<W, S_PS, NUM_CHANNELS, Finished>
+{
+    pub fn report(&self) -> EncodeReport {
+        self.core.report()
+    }
+
+    /// Gives back the writer, with the whole stream in it
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// A fixed one if asked for, random otherwise
+pub(crate) fn pick_serial(options: &EncoderOptions) -> u32 {
+    match options.serial {
+        Some(serial) => serial,
+        None if options.reproducible => REPRODUCIBLE_SERIAL,
+        None => new_serial(),
+    }
+}
+
+// Framing the same audio would take as 20 ms packets of the same total size
+pub(crate) fn usual_overhead(report: &EncodeReport) -> u64 {
+    let packets = (report.duration.as_micros() as u64).div_ceil(FRAME_TIME_MS as u64 * 1000);
+    if packets == 0 {
+        return 0;
+    }
+    let segments = packets * (report.bytes / packets / 255 + 1);
+    segments.div_ceil(255) * PAGE_HEADER + segments
+}
+
+// libopus has a different encoder for surround
+enum Codec {
+    Single(OpusEnc),
+    Surround(SurroundEncoder),
+}
+
+impl Codec {
+    fn encode_float(&self, audio: &[f32], output: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Codec::Single(encoder) => Ok(encoder.encode_float(audio, output)?),
+            Codec::Surround(encoder) => encoder.encode_float(audio, output),
+        }
+    }
+
+    fn set_bitrate(&mut self, bitrate: Bitrate) -> Result<(), Error> {
+        match self {
+            Codec::Single(encoder) => Ok(encoder.set_bitrate(bitrate.into())?),
+            Codec::Surround(encoder) => encoder.set_bitrate(bitrate),
+        }
+    }
+
+    fn set_complexity(&mut self, complexity: u8) -> Result<(), Error> {
+        match self {
+            Codec::Single(encoder) => Ok(encoder.set_complexity(complexity)?),
+            Codec::Surround(encoder) => {
+                encoder.set(ffi::OPUS_SET_COMPLEXITY_REQUEST, complexity as i32)
             }
-            self.encode_vec(&buf)
         }
     }
 
-    fn encode_no_skip(
-        &self,
-        audio: &[i16],
-        start: usize,
-        frame_size: usize,
-    ) -> Result<Cow<'_, [u8]>, Error> {
-        self.encode_vec(&audio[start..start + frame_size])
+    // Any setting taking an integer
+    fn set(&mut self, request: i32, value: i32) -> Result<(), Error> {
+        match self {
+            Codec::Single(encoder) => Ok(encoder.set_encoder_ctl_request(request, value)?),
+            Codec::Surround(encoder) => encoder.set(request, value),
+        }
+    }
+
+    fn set_rate_control(&mut self, rate_control: RateControl) -> Result<(), Error> {
+        let (vbr, constrained) = match rate_control {
+            RateControl::Vbr => (1, 0),
+            RateControl::ConstrainedVbr => (1, 1),
+            RateControl::Cbr => (0, 0),
+        };
+        self.set(ffi::OPUS_SET_VBR_REQUEST, vbr)?;
+        self.set(ffi::OPUS_SET_VBR_CONSTRAINT_REQUEST, constrained)
+    }
+
+    fn lookahead(&self) -> Result<u32, Error> {
+        match self {
+            Codec::Single(encoder) => Ok(encoder.lookahead()?),
+            Codec::Surround(encoder) => encoder.lookahead(),
+        }
+    }
+
+    // Back to how it was right after being created, settings are kept
+    fn reset(&mut self) -> Result<(), Error> {
+        match self {
+            Codec::Single(encoder) => Ok(encoder.reset_state()?),
+            Codec::Surround(encoder) => encoder.reset(),
+        }
+    }
+
+    fn final_range(&self) -> Result<u32, Error> {
+        match self {
+            Codec::Single(encoder) => Ok(encoder.final_range()?),
+            Codec::Surround(encoder) => encoder.final_range(),
+        }
+    }
+
+    // Channel mapping for the header, `None` for family 0 (mono/stereo)
+    fn mapping(&self) -> Option<&Mapping> {
+        match self {
+            Codec::Single(_) => None,
+            Codec::Surround(encoder) => Some(encoder.mapping()),
+        }
+    }
+}
+
+struct InnerEncoder {
+    encoder: Codec,
+    // Samples (counting all channels and the lookahead) encoded so far, not
+    // a usize as that fills up in a day on 32 bits targets
+    position: u64,
+    // Surround packets hold a packet for each stream
+    max_packet: usize,
+}
+
+impl InnerEncoder {
+    // Gives back the packet along with its granule
+    fn encode_frame<const S_PS: u32, const NUM_CHANNELS: u8>(
+        &mut self,
+        frame: &[f32],
+    ) -> Result<(Vec<u8>, u64), Error> {
+        let packet = self.encode_vec(frame)?;
+        self.position += frame.len() as u64;
+        let granule = to_granule(self.position / NUM_CHANNELS as u64, S_PS)?;
+        Ok((packet, granule))
+    }
+
+    fn encode_vec(&self, audio: &[f32]) -> Result<Vec<u8>, Error> {
+        let mut output = vec![0; self.max_packet];
+        let result = self.encoder.encode_float(audio, &mut output)?;
+        output.truncate(result);
+        Ok(output)
     }
 }
@@ -0,0 +1,128 @@
+// The two header packets of an Ogg Opus stream on their own, for probing
+// files (indexers, upload checks ...) without setting up a decoder
+
+use std::io::Read;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::common::OPUS_MAGIC_HEADER;
+use crate::multistream::Mapping;
+use crate::page::{is_page, OpusPackets, BOS, HEADER_SIZE};
+use crate::{Error, Tags};
+
+/// Identification header, everything in it as written
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OpusHead {
+    pub version: u8,
+    pub channels: u8,
+    /// 48 kHz samples to drop from the start
+    pub pre_skip: u16,
+    /// Rate of the audio before encoding, 0 if unknown
+    pub input_sample_rate: u32,
+    /// In 1/256 dB (Q7.8)
+    pub output_gain: i16,
+    pub channel_mapping_family: u8,
+    /// Opus streams in every packet, and how many of them are stereo
+    pub stream_count: u8,
+    pub coupled_count: u8,
+    /// Stream channel of every output channel, 255 for silence
+    pub channel_mapping: Vec<u8>,
+}
+
+/// The comment header
+pub type OpusTags = Tags;
+
+impl OpusHead {
+    /// Any family with a sensible mapping table is read, whether it can be
+    /// decoded is up to the decoder
+    pub(crate) fn from_packet(data: &[u8]) -> Result<Self, Error> {
+        if !data.starts_with(&OPUS_MAGIC_HEADER) {
+            return Err(Error::MissingOpusHead);
+        }
+        if data.len() < 19 {
+            return Err(Error::MalformedAudio);
+        }
+        // Versions up to 15 only add to the header, RFC 7845 5.1
+        if data[8] >> 4 != 0 {
+            return Err(Error::UnsupportedVersion { found: data[8] });
+        }
+
+        let channels = data[9];
+        let family = data[18];
+        let (stream_count, coupled_count, channel_mapping) = match family {
+            // Implicit: a single stream, coupled when stereo
+            0 if channels == 1 || channels == 2 => {
+                (1, channels - 1, (0..channels).collect::<Vec<_>>())
+            }
+            _ if channels == 0 || family == 0 => return Err(Error::BadChannelCount(channels)),
+            _ => {
+                let mapping = Mapping::parse(&data[19..], channels).ok_or(Error::MalformedAudio)?;
+                (mapping.streams, mapping.coupled, mapping.table)
+            }
+        };
+
+        Ok(Self {
+            version: data[8],
+            channels,
+            pre_skip: LittleEndian::read_u16(&data[10..12]),
+            input_sample_rate: LittleEndian::read_u32(&data[12..16]),
+            output_gain: LittleEndian::read_i16(&data[16..18]),
+            channel_mapping_family: family,
+            stream_count,
+            coupled_count,
+            channel_mapping,
+        })
+    }
+
+    // What the multistream decoder needs, only for explicit mappings
+    pub(crate) fn mapping(&self) -> Option<Mapping> {
+        if self.channel_mapping_family == 0 {
+            return None;
+        }
+        Some(Mapping {
+            streams: self.stream_count,
+            coupled: self.coupled_count,
+            table: self.channel_mapping.clone(),
+        })
+    }
+}
+
+/// Reads the first two packets of the (first) Opus stream in `data` and
+/// nothing more, no audio gets decoded
+pub fn parse<T: Read>(data: T) -> Result<(OpusHead, OpusTags), Error> {
+    let mut packets = OpusPackets::new(data);
+    let head = packets.next_packet()?.ok_or(Error::MissingOpusHead)?;
+    let head = OpusHead::from_packet(&head.data)?;
+    let tags = packets.next_packet()?.ok_or(Error::MissingOpusTags)?;
+    let tags = Tags::from_packet(&tags.data)?;
+    Ok((head, tags))
+}
+
+/// Cheap check of whether `data` is Ogg Opus, going by the capture pattern
+/// and the OpusHead magic alone, to route uploads and such before decoding.
+/// Only the first pages (those starting streams) are read
+pub fn is_ogg_opus<T: Read>(mut data: T) -> bool {
+    let mut header = [0u8; HEADER_SIZE];
+    let mut segments = [0u8; 255];
+    // Every stream starts before any has data, there's only so many of them
+    for _ in 0..MAX_PROBED_STREAMS {
+        if data.read_exact(&mut header).is_err() || !is_page(&header) || header[5] & BOS == 0 {
+            return false;
+        }
+        let segments = &mut segments[..header[26] as usize];
+        if data.read_exact(segments).is_err() {
+            return false;
+        }
+        let mut body = vec![0u8; segments.iter().map(|&s| s as usize).sum()];
+        if data.read_exact(&mut body).is_err() {
+            return false;
+        }
+        if body.starts_with(&OPUS_MAGIC_HEADER) {
+            return true;
+        }
+    }
+    false
+}
+
+const MAX_PROBED_STREAMS: usize = 16;
@@ -0,0 +1,200 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::common::*;
+use crate::page;
+use crate::Error;
+
+const PLAYLIST_NAME: &str = "playlist.m3u8";
+const INIT_NAME: &str = "init.ogg";
+
+/**Cuts an Ogg Opus stream into HLS segments, writing an HLS media playlist
+plus the segment files into a directory. Anything producing Ogg Opus can be
+written into it (e.g. the output of `encode`). The headers go into their own
+init segment (`#EXT-X-MAP`), so that init + segments is a valid stream again.
+Segments are cut at page boundaries, once they reach the target duration.*/
+pub struct HlsSegmenter {
+    dir: PathBuf,
+    target: Duration,
+
+    pending: Vec<u8>,
+    init: Option<Vec<u8>>,
+    segment: Vec<u8>,
+    // In 48 kHz samples, added up page by page as granules can go back (a
+    // chained stream starts over from 0)
+    segment_length: u64,
+    // Starts at the pre-skip of every link, which doesn't play
+    last_granule: u64,
+    new_link: bool,
+
+    // Durations of the finished segments, in 48 kHz samples
+    segments: Vec<u64>,
+}
+
+impl HlsSegmenter {
+    /// Segments will be written into `dir`, which must already exist
+    pub fn new<P: Into<PathBuf>>(dir: P, target_duration: Duration) -> Self {
+        Self {
+            dir: dir.into(),
+            target: target_duration,
+            pending: Vec::new(),
+            init: Some(Vec::new()),
+            segment: Vec::new(),
+            segment_length: 0,
+            last_granule: 0,
+            new_link: false,
+            segments: Vec::new(),
+        }
+    }
+
+    /// Writes any remaining audio as the last segment and closes the playlist
+    pub fn finish(mut self) -> Result<(), Error> {
+        if !self.pending.is_empty() {
+            return Err(Error::MalformedAudio);
+        }
+
+        self.write_init()?;
+        if !self.segment.is_empty() {
+            self.close_segment()?;
+        }
+        self.write_playlist(true)?;
+        Ok(())
+    }
+
+    fn push_page(&mut self, page: &[u8]) -> io::Result<()> {
+        let granule = page::granule(page);
+        if let Some(pre_skip) = link_pre_skip(page) {
+            self.last_granule = pre_skip as u64;
+            self.new_link = true;
+        }
+
+        // Headers are the pages at the start with a granule of 0
+        if let Some(init) = &mut self.init {
+            if granule == 0 {
+                init.extend_from_slice(page);
+                return Ok(());
+            }
+        }
+        self.write_init()?;
+
+        self.segment.extend_from_slice(page);
+        // Header pages have a granule of 0
+        if granule != UNSET_GRANULE && granule != 0 {
+            if self.new_link {
+                // Audio that's all pre-skip so far
+                self.segment_length += granule.saturating_sub(self.last_granule);
+                self.last_granule = self.last_granule.max(granule);
+                self.new_link = false;
+            } else {
+                // Going back, a broken stream counts from 0
+                let start = if granule < self.last_granule {
+                    0
+                } else {
+                    self.last_granule
+                };
+                self.segment_length += granule - start;
+                self.last_granule = granule;
+            }
+        }
+
+        if self.segment_length >= self.target_samples() {
+            self.close_segment()?;
+        }
+        Ok(())
+    }
+
+    fn target_samples(&self) -> u64 {
+        (self.target.as_secs_f64() * OGG_OPUS_SPS as f64) as u64
+    }
+
+    fn write_init(&mut self) -> io::Result<()> {
+        if let Some(init) = self.init.take() {
+            fs::write(self.dir.join(INIT_NAME), init)?;
+        }
+        Ok(())
+    }
+
+    fn close_segment(&mut self) -> io::Result<()> {
+        let name = segment_name(self.segments.len());
+        fs::write(self.dir.join(name), &self.segment)?;
+        self.segment.clear();
+
+        self.segments.push(std::mem::take(&mut self.segment_length));
+        self.write_playlist(false)
+    }
+
+    fn write_playlist(&self, ended: bool) -> io::Result<()> {
+        let secs = |samples: u64| samples as f64 / OGG_OPUS_SPS as f64;
+
+        // Target duration must be at least as big as any segment, rounded
+        let target = self
+            .segments
+            .iter()
+            .map(|&s| secs(s).round() as u64)
+            .max()
+            .unwrap_or(0)
+            .max(self.target.as_secs_f64().ceil() as u64);
+
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-TARGETDURATION:{}\n\
+            #EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-MAP:URI=\"{}\"\n",
+            target, INIT_NAME
+        );
+        for (i, &samples) in self.segments.iter().enumerate() {
+            playlist += &format!("#EXTINF:{:.3},\n{}\n", secs(samples), segment_name(i));
+        }
+        if ended {
+            playlist += "#EXT-X-ENDLIST\n";
+        }
+
+        fs::write(self.dir.join(PLAYLIST_NAME), playlist)
+    }
+}
+
+impl Write for HlsSegmenter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+
+        let mut start = 0;
+        while let Some(len) = page::page_len(&self.pending[start..]) {
+            if !page::is_page(&self.pending[start..]) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "input is not an Ogg stream",
+                ));
+            }
+
+            let page = self.pending[start..start + len].to_vec();
+            self.push_page(&page)?;
+            start += len;
+        }
+        self.pending.drain(..start);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Pre-skip of the OpusHead if `page` starts a link
+fn link_pre_skip(page: &[u8]) -> Option<u16> {
+    const BOS: u8 = 0x02;
+    if page.get(5)? & BOS == 0 {
+        return None;
+    }
+    let body = page.get(page::HEADER_SIZE + *page.get(26)? as usize..)?;
+    if !body.starts_with(&OPUS_MAGIC_HEADER) {
+        return None;
+    }
+    Some(LittleEndian::read_u16(body.get(10..12)?))
+}
+
+fn segment_name(index: usize) -> String {
+    format!("segment{}.ogg", index)
+}
@@ -0,0 +1,135 @@
+// Going between interleaved audio (what Opus takes and gives) and one buffer
+// per channel (what most processing wants). Works for any sample type, mono
+// and stereo get their own loops as they're what everyone uses
+
+use crate::common::Sample;
+
+/// Joins one buffer per channel into interleaved audio, as long as the
+/// shortest one
+pub fn interleave<T: Copy>(planes: &[&[T]]) -> Vec<T> {
+    let frames = planes.iter().map(|p| p.len()).min().unwrap_or(0);
+
+    match planes {
+        [] => Vec::new(),
+        [mono] => mono[..frames].to_vec(),
+        [left, right] => {
+            let mut out = Vec::with_capacity(frames * 2);
+            for (&l, &r) in left[..frames].iter().zip(&right[..frames]) {
+                out.push(l);
+                out.push(r);
+            }
+            out
+        }
+        _ => {
+            let mut out = Vec::with_capacity(frames * planes.len());
+            for i in 0..frames {
+                out.extend(planes.iter().map(|p| p[i]));
+            }
+            out
+        }
+    }
+}
+
+/// Splits interleaved audio into one buffer per channel, samples of an
+/// incomplete last frame are dropped
+pub fn deinterleave<T: Copy>(audio: &[T], channels: usize) -> Vec<Vec<T>> {
+    match channels {
+        0 => Vec::new(),
+        1 => vec![audio.to_vec()],
+        2 => {
+            let frames = audio.len() / 2;
+            let mut left = Vec::with_capacity(frames);
+            let mut right = Vec::with_capacity(frames);
+            for frame in audio.chunks_exact(2) {
+                left.push(frame[0]);
+                right.push(frame[1]);
+            }
+            vec![left, right]
+        }
+        _ => {
+            let frames = audio.len() / channels;
+            let mut planes = vec![Vec::with_capacity(frames); channels];
+            for frame in audio.chunks_exact(channels) {
+                for (plane, &sample) in planes.iter_mut().zip(frame) {
+                    plane.push(sample);
+                }
+            }
+            planes
+        }
+    }
+}
+
+/// Same as `deinterleave`, into buffers the caller already has: as many
+/// frames as fit in the shortest of `planes`, which are how many it gives back.
+/// Extra samples of channels without a plane are dropped
+pub fn deinterleave_into<T: Copy>(audio: &[T], channels: usize, planes: &mut [&mut [T]]) -> usize {
+    if channels == 0 {
+        return 0;
+    }
+    let room = planes.iter().map(|p| p.len()).min().unwrap_or(0);
+    let frames = (audio.len() / channels).min(room);
+
+    for (i, frame) in audio.chunks_exact(channels).take(frames).enumerate() {
+        for (plane, &sample) in planes.iter_mut().zip(frame) {
+            plane[i] = sample;
+        }
+    }
+    frames
+}
+
+/// How much of each channel goes left and right when folding it into stereo.
+/// Surround (family 1) goes by the Vorbis order with LFE left out, channels
+/// of other families have no defined meaning and all go evenly to both
+pub(crate) fn stereo_weights(channels: usize, family: u8) -> Vec<(f32, f32)> {
+    match surround_weights(channels) {
+        Some(weights) if family == 1 => weights.to_vec(),
+        _ => vec![(0.707, 0.707); channels],
+    }
+}
+
+// One weight for every channel, family 1 only goes up to 8
+fn surround_weights(channels: usize) -> Option<&'static [(f32, f32)]> {
+    const C: (f32, f32) = (0.707, 0.707);
+    const L: (f32, f32) = (1.0, 0.0);
+    const R: (f32, f32) = (0.0, 1.0);
+    const SL: (f32, f32) = (0.707, 0.0);
+    const SR: (f32, f32) = (0.0, 0.707);
+    const LFE: (f32, f32) = (0.0, 0.0);
+    Some(match channels {
+        1 => &[C],
+        2 => &[L, R],
+        3 => &[L, C, R],
+        4 => &[L, R, SL, SR],
+        5 => &[L, C, R, SL, SR],
+        6 => &[L, C, R, SL, SR, LFE],
+        7 => &[L, C, R, SL, SR, (0.5, 0.5), LFE],
+        8 => &[L, C, R, SL, SR, SL, SR, LFE],
+        _ => return None,
+    })
+}
+
+/// Interleaved audio, with as many channels as `weights` (from
+/// `stereo_weights`), to stereo or mono. Only multistream audio comes here,
+/// libopus itself goes between mono and stereo
+pub(crate) fn downmix<S: Sample>(audio: &[S], weights: &[(f32, f32)], out: usize) -> Vec<S> {
+    let channels = weights.len();
+    // Full scale on every channel stays full scale
+    let norm = 1.0 / weights.iter().map(|w| w.0).sum::<f32>();
+
+    let mut mixed = Vec::with_capacity(audio.len() / channels * out);
+    for frame in audio.chunks_exact(channels) {
+        let (left, right) = frame
+            .iter()
+            .zip(weights)
+            .fold((0.0, 0.0), |(l, r), (&s, w)| {
+                (l + s.to_f32() * w.0, r + s.to_f32() * w.1)
+            });
+        if out == 1 {
+            mixed.push(S::from_f32((left + right) * norm / 2.0));
+        } else {
+            mixed.push(S::from_f32(left * norm));
+            mixed.push(S::from_f32(right * norm));
+        }
+    }
+    mixed
+}
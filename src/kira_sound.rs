@@ -0,0 +1,97 @@
+// Ogg Opus as kira sound data, static for effects and streaming for music,
+// decoded to 48 kHz stereo (kira resamples to the output itself)
+
+use std::convert::TryFrom;
+use std::io::{Read, Seek};
+
+use kira::sound::static_sound::{StaticSoundData, StaticSoundSettings};
+use kira::sound::streaming::{self, StreamingSoundData};
+use kira::Frame;
+
+use crate::{ChannelPolicy, DecodeOptions, DecodedFrame, Decoder, Error};
+
+const KIRA_SPS: u32 = 48000;
+
+/// Decodes the whole stream into kira's `StaticSoundData`, for sound effects
+/// and anything short enough to be held in memory. Mono is played on both
+/// sides and surround is folded into two
+pub fn static_sound_data<T: Read>(data: T) -> Result<StaticSoundData, Error> {
+    let mut decoder = Decoder::<T, KIRA_SPS>::with_options(data, &kira_options())?;
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.next_frame_float()? {
+        frames.extend(to_frames(&frame));
+    }
+    Ok(StaticSoundData {
+        sample_rate: KIRA_SPS,
+        frames: frames.into(),
+        settings: StaticSoundSettings::default(),
+        slice: None,
+    })
+}
+
+/// `StreamingSoundData` decoding as kira plays it, for music too long to be
+/// decoded up front. Seeking (and looping) goes through
+/// `Decoder::seek_to_sample`
+pub fn streaming_sound_data<T: Read + Seek + Send + 'static>(
+    data: T,
+) -> Result<StreamingSoundData<Error>, Error> {
+    Ok(StreamingSoundData::from_decoder(KiraDecoder::new(data)?))
+}
+
+/**kira's streaming `Decoder` over `Decoder`, a packet at a time. The length
+kira goes by is the one the last granule position tells, which is exactly
+what decoding gives.*/
+pub struct KiraDecoder<T: Read + Seek> {
+    decoder: Decoder<T, KIRA_SPS>,
+    num_frames: usize,
+}
+
+impl<T: Read + Seek> KiraDecoder<T> {
+    pub fn new(data: T) -> Result<Self, Error> {
+        let mut decoder = Decoder::with_options(data, &kira_options())?;
+        let num_frames = usize::try_from(decoder.total_samples()?).unwrap_or(usize::MAX);
+        Ok(Self {
+            decoder,
+            num_frames,
+        })
+    }
+}
+
+impl<T: Read + Seek + Send> streaming::Decoder for KiraDecoder<T> {
+    type Error = Error;
+
+    fn sample_rate(&self) -> u32 {
+        KIRA_SPS
+    }
+
+    fn num_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    fn decode(&mut self) -> Result<Vec<Frame>, Error> {
+        Ok(match self.decoder.next_frame_float()? {
+            Some(frame) => to_frames(&frame).collect(),
+            None => Vec::new(),
+        })
+    }
+
+    fn seek(&mut self, index: usize) -> Result<usize, Error> {
+        self.decoder.seek_to_sample(index as u64)?;
+        Ok(index)
+    }
+}
+
+// Always two channels, that's all kira plays
+fn kira_options() -> DecodeOptions {
+    DecodeOptions {
+        channels: ChannelPolicy::ForceStereo,
+        ..DecodeOptions::default()
+    }
+}
+
+fn to_frames(frame: &DecodedFrame<f32>) -> impl Iterator<Item = Frame> + '_ {
+    frame
+        .samples
+        .chunks_exact(2)
+        .map(|pair| Frame::new(pair[0], pair[1]))
+}
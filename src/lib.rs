@@ -1,32 +1,135 @@
+#[cfg(feature = "async")]
+mod async_io;
+mod backend;
+#[cfg(feature = "bevy")]
+mod bevy_loader;
+#[cfg(feature = "capture")]
+pub mod capture;
+mod checksum;
 mod common;
+mod cue;
 mod decode;
+mod discord;
+mod drift;
+mod edit;
 mod encode;
+pub mod headers;
+mod hls;
+mod interleave;
+#[cfg(feature = "kira")]
+mod kira_sound;
+mod loudness;
+mod mse;
+mod multistream;
+#[cfg(feature = "opusenc")]
+mod opusenc;
+#[cfg(feature = "opusfile")]
+mod opusfile;
+pub mod packet;
+mod page;
+mod parallel;
+mod peaks;
+mod picture;
+mod pipeline;
+mod reader;
+mod remux;
+#[cfg(feature = "resample")]
+mod resample;
+#[cfg(feature = "rodio")]
+mod rodio_source;
+mod stt;
+#[cfg(feature = "symphonia")]
+mod symphonia_opus;
+mod tags;
+#[cfg(feature = "telegram")]
+pub mod telegram;
+#[cfg(feature = "tokio")]
+pub mod tokio_io;
+#[cfg(feature = "wav")]
+mod wav;
 
 use thiserror::Error;
 
-pub use decode::decode;
-pub use encode::encode;
+#[cfg(feature = "async")]
+pub use async_io::{decode_async, decode_async_with_options, AsyncDecoder, AsyncEncoder};
+pub use backend::{Application, BackendError, Bitrate, SampleRate};
+#[cfg(feature = "bevy")]
+pub use bevy_loader::{OpusAudio, OpusLoader, OpusPlugin, OpusSource};
+pub use checksum::{encode_with_checksum, verify, PCM_MD5_TAG};
+pub use cue::{split_tracks, split_tracks_with, CueSheet, CueTrack};
+pub use decode::{
+    decode, decode_at, decode_float, decode_i24, decode_into, decode_looped, decode_lossy,
+    decode_pcm_bytes, decode_planar, decode_split, decode_with_options, read_tags, ChannelPolicy,
+    DecodeEvent, DecodeOptions, DecodedFrame, Decoder, DecoderCore, FrameInfo, GainPolicy,
+    LossReport, PcmFormat, PlayData, R128Gain,
+};
+pub use discord::{DiscordEncoder, DISCORD_CHANNELS, DISCORD_SPS, SILENCE_FRAME};
+pub use drift::DriftCompensator;
+pub use edit::{
+    concat, concat_chained, extract_range, extract_range_reencoded, split_at, split_at_reencoded,
+};
+pub use encode::{
+    encode, encode_at, encode_float, encode_float_with_options, encode_pcm_bytes, encode_to_writer,
+    encode_trimmed, encode_with_options, encode_with_report, encode_with_target_size, Configured,
+    EncodeReport, EncoderCore, EncoderOptions, Finished, FrameSize, LoudnessGain, OggOpusEncoder,
+    PacketStats, Preset, RateControl, StreamEncoder, Writing,
+};
+pub use headers::is_ogg_opus;
+pub use hls::HlsSegmenter;
+pub use interleave::{deinterleave, deinterleave_into, interleave};
+#[cfg(feature = "kira")]
+pub use kira_sound::{static_sound_data, streaming_sound_data, KiraDecoder};
+pub use mse::MseChunker;
+#[cfg(feature = "opusfile")]
+pub use opusfile::OggOpusFile;
+pub use packet::{Bandwidth, Mode, Toc};
+pub use parallel::encode_parallel;
+pub use peaks::peaks;
+pub use picture::{Picture, FRONT_COVER};
+pub use pipeline::{Pipeline, PipelineBuilder};
+pub use reader::DecodedReader;
+pub use remux::{demux, mux_packets, Demuxer, MuxMeta, OpusPacket};
+#[cfg(feature = "resample")]
+pub use resample::{decode_resampled, encode_resampled, resample, resample_float};
+#[cfg(feature = "rodio")]
+pub use rodio_source::OggOpusSource;
+pub use stt::{SttEncoder, TimedChunk, STT_SPS};
+#[cfg(feature = "symphonia")]
+pub use symphonia_opus::{OggOpusReader, OpusDecoder};
+pub use tags::{
+    rewrite_tags, LoopPoints, Tags, ALBUM, ARTIST, ENCODER, R128_ALBUM_GAIN, R128_TRACK_GAIN, TITLE,
+};
+#[cfg(feature = "wav")]
+pub use wav::{decode_to_wav, decode_to_wav_writer, encode_wav, encode_wav_file};
 
-use std::io::{Read, Seek, SeekFrom};
-pub fn is_ogg_opus<T: Read + Seek>(mut d: T) -> bool {
-    let mut buff = [0u8; 8];
-    if d.seek(SeekFrom::Start(28)).is_ok() {
-        if let Ok(d) = d.read(&mut buff) {
-            if d == 8 {
-                return buff == common::OPUS_MAGIC_HEADER;
-            }
-        }
-    }
-    // If anything fails
-    false
-}
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Input audio was malformed")]
     MalformedAudio,
 
+    #[error("No Opus stream, its OpusHead header is missing")]
+    MissingOpusHead,
+
+    #[error("OpusHead version {found} isn't supported (major version isn't 0)")]
+    UnsupportedVersion { found: u8 },
+
+    #[error("{0} channels don't go with the channel mapping")]
+    BadChannelCount(u8),
+
+    #[error("Channel mapping family {0} isn't supported")]
+    UnsupportedMappingFamily(u8),
+
+    #[error("The OpusTags header is missing")]
+    MissingOpusTags,
+
+    #[error("OpusTags header cut short, at byte {offset} of it")]
+    TruncatedCommentHeader { offset: usize },
+
+    #[error("No Ogg page where one should start, at byte {offset}")]
+    LostSync { offset: u64 },
+
     #[error("Encoding error")]
-    OpusError(#[from] audiopus::Error),
+    OpusError(#[source] BackendError),
 
     #[error("Failed to decode ogg")]
     OggReadError(#[from] ogg::OggReadError),
@@ -36,12 +139,96 @@ pub enum Error {
 
     #[error("Invalid samples per second")]
     InvalidSps,
+
+    #[error("Audio has no checksum to verify against")]
+    MissingChecksum,
+
+    #[error("CUE sheet is missing or invalid")]
+    InvalidCueSheet,
+
+    #[error("Requested range is empty or out of the audio")]
+    InvalidRange,
+
+    #[error("Encoding thread is gone")]
+    PipelineClosed,
+
+    #[error("Stream is too long for its granule positions")]
+    GranuleOverflow,
+
+    #[error("PCM bytes don't make whole samples")]
+    InvalidPcmLength,
+
+    #[error("Only 1 to 8 channels are supported")]
+    InvalidChannels,
+
+    #[error("Page {page} has granule position {found}, {expected} after what was decoded")]
+    GranulePositionMismatch {
+        page: u32,
+        expected: u64,
+        found: u64,
+    },
+
+    #[error("Stream headers haven't been read yet")]
+    MissingHeaders,
+
+    #[error("First page of stream {serial} isn't flagged as its beginning")]
+    MissingBos { serial: u32 },
+
+    #[error("Stream {serial} is flagged as beginning again")]
+    UnexpectedBos { serial: u32 },
+
+    #[error("Stream {serial} goes on after being flagged as ended")]
+    PageAfterEos { serial: u32 },
+
+    #[error("Stream {serial} isn't flagged as ended on its last page")]
+    MissingEos { serial: u32 },
+
+    #[error("Header on page {page} doesn't have its pages to itself")]
+    HeaderNotAlone { page: u32 },
+
+    #[error("Header page {page} has granule position {found} instead of 0")]
+    HeaderGranule { page: u32, found: u64 },
+
+    #[error("Not a 16-bit PCM WAV file")]
+    InvalidWav,
+
+    #[error("Picture block is invalid")]
+    InvalidPicture,
+
+    #[error("Not even the lowest bitrate fits in the size asked for")]
+    TargetSizeTooSmall,
+
+    #[error("Audio goes on for longer than the decode limit")]
+    TooLong,
+
+    #[error("Input has more pages than the decode limit")]
+    TooManyPages,
+
+    #[error("Packet of {size} bytes or more, over the decode limit")]
+    PacketTooLarge { size: usize },
+
+    #[cfg(feature = "opusfile")]
+    #[error("libopusfile failed with code {0}")]
+    OpusFileError(i32),
+
+    #[cfg(feature = "opusenc")]
+    #[error("libopusenc failed with code {0}")]
+    OpusEncError(i32),
+
+    #[cfg(feature = "capture")]
+    #[error("Audio capture failed")]
+    CaptureError(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[cfg(feature = "capture")]
+    #[error("Capture got too far ahead of the encoder, audio was lost")]
+    CaptureOverrun,
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs::File;
     use std::io::Cursor;
+    use std::time::Duration;
 
     fn read_file_i16(path: &str) -> Vec<i16> {
         let mut f = File::open(path).expect("no file found");
@@ -51,6 +238,55 @@ mod tests {
         b.try_into_sixteen().unwrap()
     }
 
+    // Copies a stream packet by packet, letting the caller pick the page
+    // granule of each audio packet
+    fn rewrite_stream(
+        opus: Vec<u8>,
+        squash_headers: bool,
+        granule: impl Fn(&ogg::Packet) -> u64,
+    ) -> Vec<u8> {
+        use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+
+        let mut reader = PacketReader::new(Cursor::new(opus));
+        let mut out = Vec::new();
+        let mut writer = PacketWriter::new(&mut out);
+
+        let head = reader.read_packet_expected().unwrap();
+        let serial = head.stream_serial();
+        let tags = reader.read_packet_expected().unwrap();
+        if squash_headers {
+            // Both headers on the first page and an empty packet sitting in
+            // between them and the audio, like some hardware recorders do
+            for data in [head.data, tags.data, Vec::new()] {
+                writer
+                    .write_packet(data, serial, PacketWriteEndInfo::NormalPacket, 0)
+                    .unwrap();
+            }
+        } else {
+            for data in [head.data, tags.data] {
+                writer
+                    .write_packet(data, serial, PacketWriteEndInfo::EndPage, 0)
+                    .unwrap();
+            }
+        }
+
+        while let Some(packet) = reader.read_packet().unwrap() {
+            let end_info = if packet.last_in_stream() {
+                PacketWriteEndInfo::EndStream
+            } else if packet.last_in_page() {
+                PacketWriteEndInfo::EndPage
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            let absgp = granule(&packet);
+            writer
+                .write_packet(packet.data, serial, end_info, absgp)
+                .unwrap();
+        }
+
+        out
+    }
+
     #[test]
     fn dec_enc_empty() {
         let audio = Vec::new();
@@ -86,6 +322,3258 @@ mod tests {
         assert_eq!(audio.len(), a2.len());
     }
 
+    #[test]
+    fn dec_lenient_squashed_headers() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = rewrite_stream(crate::encode::<16000, 1>(&audio).unwrap(), true, |p| {
+            p.absgp_page()
+        });
+        assert!(crate::decode::<_, 16000>(Cursor::new(&opus)).is_err());
+
+        let options = crate::DecodeOptions {
+            lenient: true,
+            ..Default::default()
+        };
+        let (a2, _) = crate::decode_with_options::<_, 16000>(Cursor::new(&opus), &options).unwrap();
+        assert_eq!(audio.len(), a2.len());
+    }
+
+    #[test]
+    fn dec_unset_last_granule() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let opus = rewrite_stream(opus, false, |p| {
+            if p.last_in_stream() {
+                u64::MAX
+            } else {
+                p.absgp_page()
+            }
+        });
+
+        // Without granule there's no end trimming, so we get the padding too
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        assert!(a2.len() >= audio.len());
+    }
+
+    #[test]
+    fn hls_segments() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("ogg-opus-hls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let mut segmenter = crate::HlsSegmenter::new(&dir, Duration::from_secs(2));
+        segmenter.write_all(&opus).unwrap();
+        segmenter.finish().unwrap();
+
+        let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+
+        // Init + segments must be the stream we started with
+        let mut joined = std::fs::read(dir.join("init.ogg")).unwrap();
+        let segments: Vec<_> = playlist.lines().filter(|l| !l.starts_with('#')).collect();
+        assert!(segments.len() > 1);
+        for segment in segments {
+            joined.extend(std::fs::read(dir.join(segment)).unwrap());
+        }
+        assert_eq!(joined, opus);
+
+        // Segments add up to what plays, pre-skip left out. Granules start
+        // over in the second link, its time still counts
+        let chained = crate::concat_chained([Cursor::new(&opus), Cursor::new(&opus)]).unwrap();
+        for stream in [&opus, &chained] {
+            let mut segmenter = crate::HlsSegmenter::new(&dir, Duration::from_secs(2));
+            segmenter.write_all(stream).unwrap();
+            segmenter.finish().unwrap();
+            let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+            let extinf: Vec<f64> = playlist
+                .lines()
+                .filter_map(|l| l.strip_prefix("#EXTINF:"))
+                .map(|l| l.trim_end_matches(',').parse::<f64>().unwrap())
+                .collect();
+            let total: f64 = extinf.iter().sum();
+            let (decoded, _) = crate::decode::<_, 48000>(Cursor::new(stream)).unwrap();
+            let expected = decoded.len() as f64 / 48000.0;
+            // EXTINF has 3 decimals
+            let rounding = 0.0005 * extinf.len() as f64;
+            assert!(
+                (total - expected).abs() <= rounding,
+                "{} {}",
+                total,
+                expected
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mse_chunks() {
+        use std::io::Write;
+
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+
+        let mut chunks = Vec::new();
+        let mut chunker = crate::MseChunker::new(|c: &[u8]| {
+            chunks.push(c.to_vec());
+            Ok(())
+        });
+        // Feed it in small pieces, as a live stream would
+        for piece in opus.chunks(100) {
+            chunker.write_all(piece).unwrap();
+        }
+        chunker.finish().unwrap();
+
+        assert!(chunks.len() > 2);
+        assert!(chunks[0].starts_with(&[0x1A, 0x45, 0xDF, 0xA3])); // EBML
+        for cluster in &chunks[1..] {
+            assert!(cluster.starts_with(&[0x1F, 0x43, 0xB6, 0x75]));
+        }
+
+        // CodecDelay of the pre-skip, 6.5 ms
+        let find = |chunk: &[u8], id: &[u8]| chunk.windows(id.len()).position(|w| w == id);
+        let delay = find(&chunks[0], &[0x56, 0xAA]).unwrap();
+        assert_eq!(chunks[0][delay + 2..delay + 6], [0x83, 0x63, 0x2E, 0xA0]);
+
+        // The end trimming, as DiscardPadding on the last block
+        let packets: Vec<_> = crate::demux(Cursor::new(&opus))
+            .unwrap()
+            .map(|p| p.unwrap().duration().unwrap())
+            .collect();
+        let (decoded, play_data) = crate::decode::<_, 48000>(Cursor::new(&opus)).unwrap();
+        let padding = packets.iter().sum::<Duration>()
+            - crate::common::samples_to_duration(
+                decoded.len() as u64 + play_data.pre_skip as u64,
+                48000,
+            );
+        assert!(padding > Duration::ZERO);
+        let last = chunks.last().unwrap();
+        let discard = find(last, &[0x75, 0xA2]).unwrap();
+        let len = (last[discard + 2] & 0x7F) as usize;
+        let ns = last[discard + 3..discard + 3 + len]
+            .iter()
+            .fold(0u64, |ns, &b| ns << 8 | b as u64);
+        assert_eq!(Duration::from_nanos(ns), padding);
+
+        // A new init segment for every link, starting over from 0
+        let chained = crate::concat_chained([Cursor::new(&opus), Cursor::new(&opus)]).unwrap();
+        let mut more = Vec::new();
+        let mut chunker = crate::MseChunker::new(|c: &[u8]| {
+            more.push(c.to_vec());
+            Ok(())
+        });
+        chunker.write_all(&chained).unwrap();
+        chunker.finish().unwrap();
+        let inits: Vec<_> = more
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]))
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(inits, [0, chunks.len()]);
+        assert_eq!(more[chunks.len() + 1], chunks[1]);
+    }
+
+    #[test]
+    fn stt_timed_chunks() {
+        let audio = read_file_i16("test_assets/small.wav");
+
+        let mut encoder = crate::SttEncoder::new().unwrap();
+        let mut chunks = Vec::new();
+        for piece in audio.chunks(1600) {
+            chunks.extend(encoder.push_samples(piece).unwrap());
+        }
+        chunks.extend(encoder.finish().unwrap());
+
+        for pair in chunks.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+        let duration = audio.len() as f64 / crate::STT_SPS as f64;
+        let end = chunks.last().unwrap().end.as_secs_f64();
+        assert!((end - duration).abs() < 0.001);
+
+        let opus: Vec<u8> = chunks.into_iter().flat_map(|c| c.data).collect();
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        assert_eq!(audio.len(), a2.len());
+    }
+
+    #[test]
+    fn loop_points_roundtrip() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let mut tags = crate::Tags::default();
+        tags.set_loop_points(crate::LoopPoints {
+            start: 4800,
+            length: 2400,
+        });
+
+        let mut encoder =
+            crate::StreamEncoder::<_, 16000, 1>::with_tags(Vec::new(), &tags).unwrap();
+        encoder.push_samples(&audio).unwrap();
+        let opus = encoder.finish().unwrap().into_inner();
+
+        let (_, play_data, region) = crate::decode_looped::<_, 16000>(Cursor::new(opus)).unwrap();
+        assert_eq!(play_data.tags, tags);
+        assert_eq!(region, Some(1600..2400));
+    }
+
+    #[test]
+    fn checksum_verify() {
+        use crate::EncoderOptions;
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let tags = crate::Tags::default().with(crate::TITLE, "Small");
+        let options = EncoderOptions::voice().normalize_to(-16.0);
+        let mut opus = crate::encode_with_checksum::<16000, 1>(&audio, &tags, &options).unwrap();
+        assert!(crate::verify(Cursor::new(&opus)).unwrap());
+        let read = crate::read_tags(Cursor::new(&opus)).unwrap();
+        assert_eq!(read.title(), Some("Small"));
+        assert_eq!(read.get(crate::PCM_MD5_TAG).map(str::len), Some(32));
+
+        // Same packets in other pages, with another serial
+        let mut repaged = crate::StreamEncoder::<_, 16000, 1>::configure_with(Vec::new(), &options)
+            .unwrap()
+            .tags(&read)
+            .flush_pages()
+            .start()
+            .unwrap();
+        let normalized = crate::encode::prepare::<_, 16000, 1>(&audio, &options);
+        for chunk in normalized.audio().chunks(700) {
+            repaged.push_samples(chunk).unwrap();
+        }
+        let repaged = repaged.finish().unwrap().into_inner();
+        assert_ne!(
+            crate::page::split_pages(&repaged).unwrap().len(),
+            crate::page::split_pages(&opus).unwrap().len()
+        );
+        assert!(crate::verify(Cursor::new(&repaged)).unwrap());
+
+        // Damage the audio and fix the page checksum, so that only ours can
+        // notice
+        let pages = crate::page::split_pages(&opus).unwrap();
+        let offset = pages[..2].iter().map(|p| p.len()).sum::<usize>();
+        let len = pages[2].len();
+        opus[offset + len - 1] ^= 0xFF;
+        crate::page::update_checksum(&mut opus[offset..offset + len]);
+        assert!(!crate::verify(Cursor::new(&opus)).unwrap());
+
+        // Always checked at 48 kHz, whatever rate the header says the input
+        // was
+        let mut opus = crate::encode_with_checksum::<16000, 1>(&audio, &tags, &options).unwrap();
+        let first = crate::page::split_pages(&opus).unwrap()[0].len();
+        opus[28 + 12..28 + 16].copy_from_slice(&44100u32.to_le_bytes());
+        crate::page::update_checksum(&mut opus[..first]);
+        assert!(crate::verify(Cursor::new(&opus)).unwrap());
+
+        let plain = crate::encode::<16000, 1>(&audio).unwrap();
+        assert!(matches!(
+            crate::verify(Cursor::new(plain)),
+            Err(crate::Error::MissingChecksum)
+        ));
+    }
+
+    #[test]
+    fn cue_split() {
+        let cue = crate::CueSheet::parse(
+            "PERFORMER \"Band\"\nTITLE \"Live\"\nFILE \"live.opus\" OGG\n\
+             TRACK 01 AUDIO\n  TITLE \"One\"\n  INDEX 01 00:00:00\n\
+             TRACK 02 AUDIO\n  TITLE \"Two\"\n  INDEX 00 00:02:00\n  INDEX 01 00:03:00\n\
+             TRACK 03 AUDIO\n  TITLE \"Three\"\n  INDEX 01 00:07:30\n",
+        )
+        .unwrap();
+        assert_eq!(crate::CueSheet::parse(&cue.to_string()).unwrap(), cue);
+
+        let audio = read_file_i16("test_assets/big.wav");
+        let mut tags = crate::Tags::default();
+        tags.set_cue_sheet(&cue);
+        let mut encoder =
+            crate::StreamEncoder::<_, 16000, 1>::with_tags(Vec::new(), &tags).unwrap();
+        encoder.push_samples(&audio).unwrap();
+        let opus = encoder.finish().unwrap().into_inner();
+
+        let tracks = crate::split_tracks(Cursor::new(opus)).unwrap();
+        let lengths: Vec<_> = tracks
+            .into_iter()
+            .map(|t| {
+                let (a, play_data) = crate::decode::<_, 16000>(Cursor::new(t)).unwrap();
+                assert!(play_data.tags.cue_sheet().is_none());
+                a.len()
+            })
+            .collect();
+        // 07:30 is 7.4 s
+        assert_eq!(lengths, vec![48000, 70400, audio.len() - 118400]);
+    }
+
+    #[test]
+    fn normalize_loudness() {
+        let audio: Vec<i16> = read_file_i16("test_assets/big.wav")
+            .into_iter()
+            .map(|s| s / 10)
+            .collect();
+        let options = crate::EncoderOptions::default().normalize_to(-16.0);
+        let opus = crate::encode_with_options::<16000, 1>(&audio, &options).unwrap();
+
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        let lufs = crate::loudness::integrated_loudness(&a2, 16000, 1).unwrap();
+        assert!((lufs + 16.0).abs() < 1.5, "got {} LUFS", lufs);
+    }
+
+    #[test]
+    fn trim_silence() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let mut padded = vec![0i16; 16000];
+        padded.extend(&audio);
+        padded.extend(vec![0i16; 16000]);
+
+        let options = crate::EncoderOptions::default().trim_silence(-60.0, Duration::ZERO);
+        let decode_len = |audio: &[i16]| {
+            let opus = crate::encode_with_options::<16000, 1>(audio, &options).unwrap();
+            crate::decode::<_, 16000>(Cursor::new(opus))
+                .unwrap()
+                .0
+                .len()
+        };
+        assert_eq!(decode_len(&padded), decode_len(&audio));
+        assert!(decode_len(&padded) <= audio.len());
+
+        let (_, report) = crate::encode_with_report::<16000, 1>(&padded, &options).unwrap();
+        assert!(report.trimmed_start >= Duration::from_secs(1));
+        assert!(report.trimmed_end >= Duration::from_secs(1));
+        assert!(report.trimmed_start < Duration::from_secs(2));
+        let (_, report) =
+            crate::encode_with_report::<16000, 1>(&audio, &Default::default()).unwrap();
+        assert_eq!(report.trimmed_start, Duration::ZERO);
+
+        // Padding longer than the audio keeps all of it, however long
+        for padding in [Duration::from_secs(100), Duration::MAX] {
+            let options = crate::EncoderOptions::default().trim_silence(-60.0, padding);
+            let (_, report) = crate::encode_with_report::<48000, 1>(&padded, &options).unwrap();
+            assert_eq!(report.trimmed_start, Duration::ZERO);
+            assert_eq!(report.trimmed_end, Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn decoder_timestamps() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+
+        let mut played = 0;
+        let decoder = crate::Decoder::<_, 16000>::new(Cursor::new(opus)).unwrap();
+        for frame in decoder {
+            let frame = frame.unwrap();
+            assert_eq!(
+                frame.timestamp,
+                Duration::from_micros(played * 1_000_000 / 16000)
+            );
+            played += frame.samples.len() as u64;
+        }
+        assert_eq!(played as usize, audio.len());
+    }
+
+    #[test]
+    fn stream_encoder_report() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let mut tags = crate::Tags::default();
+        tags.set("TITLE", "small");
+
+        let mut encoder = crate::StreamEncoder::<_, 16000, 1>::configure(Vec::new())
+            .unwrap()
+            .tags(&tags)
+            .start()
+            .unwrap();
+        encoder.push_samples(&audio).unwrap();
+        let finished = encoder.finish().unwrap();
+        let report = finished.report();
+        let opus = finished.into_inner();
+
+        let (a2, play_data) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(play_data.tags, tags);
+        assert_eq!(
+            report.duration,
+            Duration::from_micros(a2.len() as u64 * 1_000_000 / 16000)
+        );
+        assert!(report.packets > 0 && (report.bytes as usize) < opus.len());
+    }
+
+    #[test]
+    fn sans_io_roundtrip() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let mut encoder = crate::EncoderCore::<16000, 1>::new(&crate::Tags::default()).unwrap();
+        let mut opus = encoder.take_output();
+        for piece in audio.chunks(100) {
+            encoder.push_samples(piece).unwrap();
+            opus.extend(encoder.take_output());
+        }
+        let (rest, _) = encoder.finish().unwrap();
+        opus.extend(rest);
+
+        // Feed it byte by byte, the worst case
+        let mut decoder = crate::DecoderCore::<16000>::new(&crate::DecodeOptions::default());
+        let mut decoded = Vec::new();
+        let mut got_headers = false;
+        'feed: for byte in &opus {
+            decoder.push_bytes(std::slice::from_ref(byte));
+            while let Some(event) = decoder.poll().unwrap() {
+                match event {
+                    crate::DecodeEvent::Headers(play_data) => {
+                        assert_eq!(play_data.channels, 1);
+                        got_headers = true;
+                    }
+                    crate::DecodeEvent::Frame(frame) => decoded.extend(frame.samples),
+                    crate::DecodeEvent::End => break 'feed,
+                }
+            }
+        }
+        assert!(got_headers);
+
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(decoded, a2);
+        assert_eq!(decoded.len(), audio.len());
+    }
+
+    #[test]
+    fn pipeline_to_sink() {
+        use std::sync::{Arc, Mutex};
+
+        // Lets the test look at what the thread wrote
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let sink = Shared::default();
+        let pipeline = crate::PipelineBuilder::new()
+            .capacity(2)
+            .spawn::<_, 16000, 1>(sink.clone())
+            .unwrap();
+
+        let sender = pipeline.sender();
+        let half = audio.len() / 2;
+        let first = audio[..half].to_vec();
+        std::thread::spawn(move || {
+            for piece in first.chunks(320) {
+                sender.send(piece.to_vec()).unwrap();
+            }
+        })
+        .join()
+        .unwrap();
+        for piece in audio[half..].chunks(320) {
+            pipeline.push(piece.to_vec()).unwrap();
+        }
+        let report = pipeline.finish().unwrap();
+
+        let opus = sink.0.lock().unwrap().clone();
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        assert_eq!(a2.len(), audio.len());
+        assert!(report.packets > 0);
+    }
+
+    #[test]
+    fn granule_math_limits() {
+        use crate::common::{calc_sr_u64, samples_to_duration, to_granule};
+
+        // A year at 48 kHz, far past what u32 holds, let alone usize on 32 bits
+        let year = 48000 * 3600 * 24 * 365u64;
+        assert_eq!(calc_sr_u64(year, 48000, 16000), year / 3);
+        assert_eq!(to_granule(year / 3, 16000).unwrap(), year);
+        assert_eq!(
+            samples_to_duration(year, 48000),
+            Duration::from_secs(3600 * 24 * 365)
+        );
+
+        assert!(matches!(
+            to_granule(u64::MAX / 2, 16000),
+            Err(crate::Error::GranuleOverflow)
+        ));
+        assert_eq!(calc_sr_u64(u64::MAX, 16000, 48000), u64::MAX);
+    }
+
+    #[test]
+    fn max_packing() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let (usual, usual_report) =
+            crate::encode_with_report::<16000, 1>(&audio, &Default::default()).unwrap();
+        let options = crate::EncoderOptions::default().max_packing();
+        let (packed, report) = crate::encode_with_report::<16000, 1>(&audio, &options).unwrap();
+
+        assert!(report.overhead < usual_report.overhead);
+        assert_eq!(
+            report.duration,
+            crate::common::samples_to_duration(audio.len() as u64, 16000)
+        );
+        assert_eq!(usual_report.overhead_saved, 0);
+        assert!(packed.len() < usual.len());
+
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(packed)).unwrap();
+        assert_eq!(a2.len(), audio.len());
+    }
+
+    #[test]
+    fn default_bitrate_policy() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let (_, usual) =
+            crate::encode_with_report::<16000, 1>(&audio, &Default::default()).unwrap();
+        let (_, voice) =
+            crate::encode_with_report::<16000, 1>(&audio, &crate::EncoderOptions::voice()).unwrap();
+
+        // Around 64 kb/s against 24 kb/s, within what VBR moves around
+        let kbps = |r: &crate::EncodeReport| r.bytes * 8 / r.duration.as_millis() as u64;
+        assert!(kbps(&voice) < 32);
+        assert!(kbps(&usual) > 40);
+    }
+
+    #[test]
+    fn encode_from_bytes() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let bytes: Vec<u8> = audio.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let options = crate::EncoderOptions::default();
+
+        let opus = crate::encode_pcm_bytes::<16000, 1>(&bytes, &options).unwrap();
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        assert_eq!(a2.len(), audio.len());
+
+        assert!(matches!(
+            crate::encode_pcm_bytes::<16000, 1>(&bytes[1..], &options),
+            Err(crate::Error::InvalidPcmLength)
+        ));
+        assert!(matches!(
+            crate::encode_pcm_bytes::<16000, 2>(&bytes[2..], &options),
+            Err(crate::Error::InvalidPcmLength)
+        ));
+    }
+
+    #[test]
+    fn decode_to_bytes() {
+        use byteorder::{ByteOrder, LittleEndian};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let (decoded, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+
+        let (bytes, _) =
+            crate::decode_pcm_bytes::<_, 16000>(Cursor::new(&opus), crate::PcmFormat::I16).unwrap();
+        let mut samples = vec![0i16; bytes.len() / 2];
+        LittleEndian::read_i16_into(&bytes, &mut samples);
+        assert_eq!(samples, decoded);
+
+        let (bytes, _) =
+            crate::decode_pcm_bytes::<_, 16000>(Cursor::new(&opus), crate::PcmFormat::F32).unwrap();
+        assert_eq!(bytes.len(), decoded.len() * 4);
+        // Straight from the float decoder, not through 16 bits
+        let (floats, _) = crate::decode_float::<_, 16000>(Cursor::new(&opus)).unwrap();
+        let mut samples = vec![0f32; bytes.len() / 4];
+        LittleEndian::read_f32_into(&bytes, &mut samples);
+        assert_eq!(samples, floats);
+    }
+
+    #[test]
+    fn interleave_roundtrip() {
+        let left = [1i16, 2, 3];
+        let right = [-1i16, -2, -3, -4];
+        let stereo = crate::interleave(&[&left[..], &right[..]]);
+        assert_eq!(stereo, [1, -1, 2, -2, 3, -3]);
+        assert_eq!(
+            crate::deinterleave(&stereo, 2),
+            [left.to_vec(), right[..3].to_vec()]
+        );
+
+        let planes = [[0.1f32, 0.2], [0.3, 0.4], [0.5, 0.6]];
+        let refs: Vec<&[f32]> = planes.iter().map(|p| &p[..]).collect();
+        let surround = crate::interleave(&refs);
+        assert_eq!(surround, [0.1, 0.3, 0.5, 0.2, 0.4, 0.6]);
+        assert_eq!(
+            crate::deinterleave(&surround, 3),
+            planes.map(|p| p.to_vec())
+        );
+    }
+
+    #[test]
+    fn decode_dual_mono() {
+        let left = read_file_i16("test_assets/small.wav");
+        let right: Vec<i16> = left.iter().map(|s| s / 2).collect();
+        let stereo = crate::interleave(&[&left[..], &right[..]]);
+        let opus = crate::encode::<16000, 2>(&stereo).unwrap();
+
+        let (decoded, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        let (l2, r2, play_data) = crate::decode_split::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(play_data.channels, 2);
+        assert_eq!(crate::deinterleave(&decoded, 2), [l2, r2]);
+    }
+
+    #[test]
+    fn packet_stats_callback() {
+        use std::sync::{Arc, Mutex};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = seen.clone();
+        let mut encoder = crate::StreamEncoder::<_, 16000, 1>::configure(Vec::new())
+            .unwrap()
+            .on_packet(move |stats| seen2.lock().unwrap().push(*stats))
+            .start()
+            .unwrap();
+        encoder.push_samples(&audio).unwrap();
+        let report = encoder.finish().unwrap().report();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len() as u64, report.packets);
+        assert_eq!(
+            seen.iter().map(|s| s.bytes as u64).sum::<u64>(),
+            report.bytes
+        );
+        assert_eq!(seen[0].duration, Duration::from_millis(20));
+        assert!(seen.windows(2).all(|w| w[0].granule < w[1].granule));
+        assert!(seen
+            .iter()
+            .all(|s| s.mode.is_some() && s.bandwidth.is_some()));
+    }
+
+    #[test]
+    fn encoder_settings() {
+        use crate::{Application, EncoderOptions};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let (usual, usual_report) =
+            crate::encode_with_report::<16000, 1>(&audio, &Default::default()).unwrap();
+        let low_delay = EncoderOptions::default()
+            .application(Application::LowDelay)
+            .complexity(2);
+        let (opus, report) = crate::encode_with_report::<16000, 1>(&audio, &low_delay).unwrap();
+
+        // Restricted low delay has a shorter lookahead
+        assert!(report.pre_skip < usual_report.pre_skip);
+        assert_ne!(opus, usual);
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        assert_eq!(a2.len(), audio.len());
+
+        let too_complex = EncoderOptions::default().complexity(11);
+        assert!(matches!(
+            crate::encode_with_options::<16000, 1>(&audio, &too_complex),
+            Err(crate::Error::OpusError(_))
+        ));
+    }
+
+    #[test]
+    fn stream_encoder_live() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let mut encoder = crate::StreamEncoder::<_, 16000, 1>::configure(Vec::new())
+            .unwrap()
+            .flush_pages()
+            .start()
+            .unwrap();
+
+        // Every push past the first packet makes it to the writer
+        let mut written = encoder.get_ref().len();
+        for chunk in audio.chunks(16000).take(5) {
+            encoder.push_samples(chunk).unwrap();
+            assert!(encoder.get_ref().len() > written);
+            written = encoder.get_ref().len();
+        }
+        for chunk in audio.chunks(16000).skip(5) {
+            encoder.push_samples(chunk).unwrap();
+        }
+
+        let opus = encoder.finish().unwrap().into_inner();
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        assert_eq!(a2.len(), audio.len());
+    }
+
+    #[test]
+    fn float_roundtrip() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let floats: Vec<f32> = audio.iter().map(|&s| s as f32 / 32768.0).collect();
+
+        // Same audio whichever way it comes in
+        let from_ints = crate::encode::<16000, 1>(&audio).unwrap();
+        let from_floats = crate::encode_float::<16000, 1>(&floats).unwrap();
+        let (ints, _) = crate::decode::<_, 16000>(Cursor::new(&from_ints)).unwrap();
+        let (same, _) = crate::decode::<_, 16000>(Cursor::new(&from_floats)).unwrap();
+        assert_eq!(ints, same);
+
+        let (decoded, _) = crate::decode_float::<_, 16000>(Cursor::new(&from_floats)).unwrap();
+        assert_eq!(decoded.len(), ints.len());
+        for (f, &i) in decoded.iter().zip(&ints) {
+            assert!((f * 32768.0 - i as f32).abs() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn encode_into_writer() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let options = crate::EncoderOptions::default();
+
+        let mut opus = Vec::new();
+        let report = crate::encode_to_writer::<16000, 1, _>(&audio, &options, &mut opus).unwrap();
+        let (_, usual) = crate::encode_with_report::<16000, 1>(&audio, &options).unwrap();
+        assert_eq!(report, usual);
+
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        assert_eq!(a2.len(), audio.len());
+    }
+
+    #[test]
+    fn decode_forward_only() {
+        // Reads in small pieces and can't seek, like a pipe
+        struct Pipe<'a>(&'a [u8]);
+        impl std::io::Read for Pipe<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(100).min(self.0.len());
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let (expected, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        let (a2, _) = crate::decode::<_, 16000>(Pipe(&opus)).unwrap();
+        assert_eq!(a2, expected);
+    }
+
+    #[test]
+    fn frame_sizes() {
+        use crate::{EncoderOptions, FrameSize};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        for frame in [
+            FrameSize::Ms2_5,
+            FrameSize::Ms5,
+            FrameSize::Ms10,
+            FrameSize::Ms20,
+            FrameSize::Ms40,
+            FrameSize::Ms60,
+        ] {
+            let options = EncoderOptions::default().frame_size(frame);
+            let (opus, report) = crate::encode_with_report::<16000, 1>(&audio, &options).unwrap();
+
+            // Whole frames, plus the smaller ones that end the stream
+            let whole = report.duration.as_micros() as u64 / frame.as_micros() as u64;
+            assert!(report.packets >= whole && report.packets <= whole + 4);
+            let (a2, _) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+            assert_eq!(a2.len(), audio.len());
+        }
+    }
+
+    #[test]
+    fn runtime_rates() {
+        use crate::SampleRate;
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let rate = SampleRate::from_hz(16000).unwrap();
+        let opus = crate::encode_at(rate, 1, &audio, &Default::default()).unwrap();
+        let (a2, play_data) = crate::decode_at(Cursor::new(&opus), rate).unwrap();
+        assert_eq!(a2.len(), audio.len());
+        assert_eq!(play_data.channels, 1);
+
+        let (a3, _) = crate::decode_at(Cursor::new(&opus), SampleRate::Hz48000).unwrap();
+        assert_eq!(a3.len(), audio.len() * 3);
+
+        assert!(matches!(
+            SampleRate::from_hz(44100),
+            Err(crate::Error::InvalidSps)
+        ));
+        assert!(matches!(
+            crate::encode_at(rate, 9, &audio, &Default::default()),
+            Err(crate::Error::InvalidChannels)
+        ));
+    }
+
+    #[test]
+    fn comment_header() {
+        let tags = crate::Tags::default()
+            .with(crate::TITLE, "Intro")
+            .with(crate::ARTIST, "Someone")
+            .with(crate::ENCODER, "my-app 1.0")
+            .with("MOOD", "calm=ish");
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let mut encoder =
+            crate::StreamEncoder::<_, 16000, 1>::with_tags(Vec::new(), &tags).unwrap();
+        encoder.push_samples(&audio).unwrap();
+        let opus = encoder.finish().unwrap().into_inner();
+
+        let (_, play_data) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        assert_eq!(play_data.tags, tags);
+        assert_eq!(play_data.tags.title(), Some("Intro"));
+        assert_eq!(play_data.tags.artist(), Some("Someone"));
+        assert_eq!(play_data.tags.album(), None);
+        assert_eq!(play_data.tags.get("mood"), Some("calm=ish"));
+    }
+
+    #[test]
+    fn comment_header_reading() {
+        // As another encoder would write it
+        let tags = crate::Tags {
+            vendor: "libopus 1.3.1".to_string(),
+            comments: vec![
+                (
+                    "ENCODER".to_string(),
+                    "opusenc from opus-tools 0.2".to_string(),
+                ),
+                ("title".to_string(), "Song".to_string()),
+            ],
+        };
+        let mut encoder =
+            crate::StreamEncoder::<_, 16000, 1>::with_tags(Vec::new(), &tags).unwrap();
+        encoder.push_samples(&[0; 16000]).unwrap();
+        let opus = encoder.finish().unwrap().into_inner();
+
+        let read = crate::read_tags(Cursor::new(&opus)).unwrap();
+        assert_eq!(read.vendor, "libopus 1.3.1");
+        assert_eq!(read.title(), Some("Song"));
+        assert_eq!(
+            read.get(crate::ENCODER),
+            Some("opusenc from opus-tools 0.2")
+        );
+    }
+
+    #[test]
+    fn third_party_layout() {
+        use ogg::{PacketWriteEndInfo, PacketWriter};
+
+        // A comment header big enough to span several pages
+        let tags = crate::Tags::default().with("COVER", "x".repeat(100_000));
+        let audio = read_file_i16("test_assets/small.wav");
+        let mut encoder =
+            crate::StreamEncoder::<_, 16000, 1>::with_tags(Vec::new(), &tags).unwrap();
+        encoder.push_samples(&audio).unwrap();
+        let opus = encoder.finish().unwrap().into_inner();
+        let (expected, play_data) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(play_data.tags, tags);
+
+        // Another stream starting first and with its pages in between ours
+        let mut foreign = PacketWriter::new(Vec::new());
+        let mut foreign_page = |data: &[u8], end: PacketWriteEndInfo| {
+            foreign.write_packet(data.to_vec(), 1, end, 0).unwrap();
+            std::mem::take(foreign.inner_mut())
+        };
+        let mut muxed = foreign_page(b"fishead\0", PacketWriteEndInfo::EndPage);
+        let mut rest = &opus[..];
+        while let Some(len) = crate::page::page_len(rest) {
+            muxed.extend_from_slice(&rest[..len]);
+            muxed.extend(foreign_page(b"video", PacketWriteEndInfo::EndPage));
+            rest = &rest[len..];
+        }
+        muxed.extend(foreign_page(b"", PacketWriteEndInfo::EndStream));
+
+        let (a2, play_data) = crate::decode::<_, 16000>(Cursor::new(&muxed)).unwrap();
+        assert_eq!(a2, expected);
+        assert_eq!(play_data.tags, tags);
+    }
+
+    #[test]
+    fn surround_roundtrip() {
+        let mono = read_file_i16("test_assets/small.wav");
+        // 5.1 with the voice on the center channel only
+        let audio: Vec<i16> = mono.iter().flat_map(|&s| [0, s, 0, 0, 0, 0]).collect();
+
+        let opus = crate::encode::<16000, 6>(&audio).unwrap();
+        let (a2, play_data) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(play_data.channels, 6);
+        assert_eq!(a2.len(), audio.len());
+
+        // Mapping family 1 in the header
+        let head = ogg::PacketReader::new(Cursor::new(&opus))
+            .read_packet_expected()
+            .unwrap();
+        assert_eq!(head.data[18], 1);
+
+        let energy = |channel: usize| -> u64 {
+            a2.chunks_exact(6)
+                .map(|f| (f[channel] as i64 * f[channel] as i64) as u64)
+                .sum()
+        };
+        assert!(energy(1) > 100 * energy(4));
+
+        let floats = crate::decode_float::<_, 48000>(Cursor::new(&opus))
+            .unwrap()
+            .0;
+        assert_eq!(floats.len(), audio.len() * 3);
+    }
+
+    #[test]
+    fn rate_control() {
+        use crate::{EncoderOptions, RateControl};
+        use std::sync::{Arc, Mutex};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let sizes = |rate_control| {
+            let sizes = Arc::new(Mutex::new(Vec::new()));
+            let sink = sizes.clone();
+            let options = EncoderOptions::default().rate_control(rate_control);
+            let mut encoder =
+                crate::StreamEncoder::<_, 16000, 1>::configure_with(Vec::new(), &options)
+                    .unwrap()
+                    .on_packet(move |stats| sink.lock().unwrap().push(stats.bytes))
+                    .start()
+                    .unwrap();
+            encoder.push_samples(&audio).unwrap();
+            encoder.finish().unwrap();
+            let mut sizes = Arc::try_unwrap(sizes).unwrap().into_inner().unwrap();
+            // The end of the stream goes in shorter frames
+            sizes.truncate(sizes.len() - 4);
+            sizes
+        };
+
+        let cbr = sizes(RateControl::Cbr);
+        assert!(cbr.iter().all(|&size| size == cbr[0]));
+        let vbr = sizes(RateControl::Vbr);
+        assert!(vbr.iter().any(|&size| size != vbr[0]));
+    }
+
+    #[test]
+    fn inband_fec() {
+        use crate::{Application, DecodeEvent, DecodeOptions, DecoderCore, EncoderOptions};
+        use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+
+        // Somewhere in the speech, silent frames carry no FEC
+        const LOST: usize = 50;
+        let audio = read_file_i16("test_assets/small.wav");
+
+        // Drops the `LOST`th audio packet, then decodes telling about it
+        let decode_lossy = |options: &EncoderOptions| {
+            let opus = crate::encode_with_options::<16000, 1>(&audio, options).unwrap();
+            let mut reader = PacketReader::new(Cursor::new(opus));
+            let mut writer = PacketWriter::new(Vec::new());
+            let mut index = 0;
+            while let Some(packet) = reader.read_packet().unwrap() {
+                let serial = packet.stream_serial();
+                let end_info = if packet.last_in_stream() {
+                    PacketWriteEndInfo::EndStream
+                } else {
+                    PacketWriteEndInfo::EndPage
+                };
+                let absgp = packet.absgp_page();
+                if index != LOST + 2 {
+                    writer
+                        .write_packet(packet.data, serial, end_info, absgp)
+                        .unwrap();
+                }
+                index += 1;
+            }
+
+            let mut core = DecoderCore::<16000>::new(&DecodeOptions::default());
+            core.push_bytes(&writer.into_inner());
+            let mut frames = Vec::new();
+            while let Some(event) = core.poll().unwrap() {
+                match event {
+                    DecodeEvent::Frame(frame) => {
+                        frames.push(frame.samples);
+                        if frames.len() == LOST {
+                            core.packet_lost(Duration::from_millis(20));
+                        }
+                    }
+                    DecodeEvent::End => break,
+                    DecodeEvent::Headers(_) => {}
+                }
+            }
+            frames
+        };
+        let error = |frames: &[Vec<i16>], reference: &[Vec<i16>]| -> u64 {
+            frames[LOST]
+                .iter()
+                .zip(&reference[LOST])
+                .map(|(&a, &b)| (a as i64 - b as i64).pow(2) as u64)
+                .sum()
+        };
+
+        let voip = EncoderOptions::voice().application(Application::Voip);
+        let with_fec = voip.clone().inband_fec(20);
+        let reference: Vec<Vec<i16>> = {
+            let opus = crate::encode_with_options::<16000, 1>(&audio, &with_fec).unwrap();
+            let mut decoder = crate::Decoder::<_, 16000>::new(Cursor::new(opus)).unwrap();
+            std::iter::from_fn(|| decoder.next_frame().unwrap())
+                .map(|frame| frame.samples)
+                .collect()
+        };
+
+        let recovered = decode_lossy(&with_fec);
+        // Nothing is missing, the lost packet comes back as its own frame
+        assert_eq!(recovered.len(), reference.len());
+        assert_eq!(recovered[LOST].len(), 320);
+        assert_eq!(recovered.concat().len(), reference.concat().len());
+
+        // Recovering beats guessing
+        let concealed = decode_lossy(&voip);
+        assert!(error(&recovered, &reference) < error(&concealed, &reference));
+    }
+
+    #[test]
+    fn packet_loss_concealment() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+
+        let mut decoder = crate::Decoder::<_, 16000>::new(Cursor::new(opus)).unwrap();
+        for _ in 0..50 {
+            decoder.next_frame().unwrap();
+        }
+        let next = decoder.next_frame().unwrap().unwrap();
+
+        // Carries on from the audio before it, on the timeline after it
+        let concealed = decoder.conceal_frame(Duration::from_millis(20)).unwrap();
+        assert_eq!(concealed.samples.len(), 320);
+        assert_eq!(
+            concealed.timestamp,
+            next.timestamp + Duration::from_millis(20)
+        );
+        assert!(concealed.samples.iter().any(|&s| s != 0));
+
+        let floats = decoder
+            .conceal_frame_float(Duration::from_micros(12_500))
+            .unwrap();
+        assert_eq!(floats.samples.len(), 200);
+        // Not even one 2.5 ms step
+        assert!(matches!(
+            decoder.conceal_frame(Duration::from_millis(2)),
+            Err(crate::Error::InvalidRange)
+        ));
+        assert!(decoder.next_frame().unwrap().is_some());
+
+        let mut core = crate::DecoderCore::<16000>::new(&Default::default());
+        assert!(matches!(
+            core.conceal(Duration::from_millis(20)),
+            Err(crate::Error::MissingHeaders)
+        ));
+    }
+
+    #[test]
+    fn seeking() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let (reference, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+
+        let mut decoder = crate::Decoder::<_, 16000>::new(Cursor::new(&opus)).unwrap();
+        let rest = |decoder: &mut crate::Decoder<_, 16000>| -> Vec<i16> {
+            std::iter::from_fn(|| decoder.next_frame().unwrap())
+                .flat_map(|frame| frame.samples)
+                .collect()
+        };
+
+        // Far enough in for the bisection to kick in
+        decoder.seek_to_time(Duration::from_millis(7125)).unwrap();
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(frame.timestamp, Duration::from_millis(7125));
+        let mut after = frame.samples;
+        after.extend(rest(&mut decoder));
+        let expected = &reference[114_000..];
+        assert_eq!(after.len(), expected.len());
+        // The pre-roll got the decoder where it would have been
+        let error: i64 = after
+            .iter()
+            .zip(expected)
+            .map(|(&a, &b)| (a as i64 - b as i64).pow(2))
+            .sum();
+        let energy: i64 = expected.iter().map(|&s| (s as i64).pow(2)).sum();
+        assert!(error * 1000 < energy);
+
+        // Back to the start, exactly as the first time
+        decoder.seek_to_sample(0).unwrap();
+        assert_eq!(rest(&mut decoder), reference);
+
+        decoder
+            .seek_to_sample(reference.len() as u64 + 16000)
+            .unwrap();
+        assert!(decoder.next_frame().unwrap().is_none());
+        decoder.seek_to_sample(u64::MAX).unwrap();
+        assert!(decoder.next_frame().unwrap().is_none());
+        decoder.seek_to_time(Duration::MAX).unwrap();
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn stream_length() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+
+        let mut decoder = crate::Decoder::<_, 16000>::new(Cursor::new(&opus)).unwrap();
+        let first = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(decoder.total_samples().unwrap(), audio.len() as u64);
+        // 188836 samples at 16 kHz
+        assert_eq!(
+            decoder.duration().unwrap(),
+            Duration::from_micros(11_802_250)
+        );
+
+        // Nothing got lost in between
+        let rest: usize = std::iter::from_fn(|| decoder.next_frame().unwrap())
+            .map(|frame| frame.samples.len())
+            .sum();
+        assert_eq!(first.samples.len() + rest, audio.len());
+
+        let mut decoder = crate::Decoder::<_, 48000>::new(Cursor::new(&opus)).unwrap();
+        assert_eq!(decoder.total_samples().unwrap(), audio.len() as u64 * 3);
+    }
+
+    #[test]
+    fn remux() {
+        use crate::{MuxMeta, OpusPacket};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let (reference, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+
+        let demuxer = crate::demux(Cursor::new(&opus)).unwrap();
+        let meta = demuxer.meta().clone();
+        assert_eq!(meta.channels, 1);
+        assert_eq!(meta.input_sps, 16000);
+        let packets: Vec<OpusPacket> = demuxer.map(|p| p.unwrap()).collect();
+        assert_eq!(packets[0].duration(), Some(Duration::from_millis(20)));
+
+        // Same audio back, plus whatever the end trimming took out
+        let muxed = crate::mux_packets(packets.clone(), &meta).unwrap();
+        let (a2, play_data) = crate::decode::<_, 16000>(Cursor::new(&muxed)).unwrap();
+        assert_eq!(play_data.tags, meta.tags);
+        assert_eq!(a2[..reference.len()], reference[..]);
+        assert!(a2.len() - reference.len() < 320);
+
+        let again: Vec<OpusPacket> = crate::demux(Cursor::new(&muxed))
+            .unwrap()
+            .map(|p| p.unwrap())
+            .collect();
+        assert_eq!(again, packets);
+
+        let stereo = MuxMeta::default();
+        assert!(crate::mux_packets(Vec::new(), &stereo).is_ok());
+        assert!(matches!(
+            crate::mux_packets(vec![OpusPacket::from(Vec::new())], &stereo),
+            Err(crate::Error::MalformedAudio)
+        ));
+    }
+
+    #[test]
+    fn output_gain() {
+        use crate::{DecodeOptions, EncoderOptions, R128Gain, Tags};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let peak = |audio: &[i16]| audio.iter().map(|s| s.unsigned_abs()).max().unwrap();
+        let plain = crate::encode::<16000, 1>(&audio).unwrap();
+        let (reference, _) = crate::decode::<_, 16000>(Cursor::new(&plain)).unwrap();
+
+        let options = EncoderOptions::default().output_gain(-6.0);
+        let quiet = crate::encode_with_options::<16000, 1>(&audio, &options).unwrap();
+        let head = ogg::PacketReader::new(Cursor::new(&quiet))
+            .read_packet_expected()
+            .unwrap();
+        assert_eq!(head.data[16..18], (-1536i16).to_le_bytes());
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(&quiet)).unwrap();
+        let ratio = peak(&a2) as f64 / peak(&reference) as f64;
+        assert!((ratio - 0.5).abs() < 0.02);
+
+        // Only followed when asked for
+        let mut tags = Tags::default();
+        tags.set_track_gain(-1536);
+        assert_eq!(tags.track_gain(), Some(-1536));
+        let mut tagged = Vec::new();
+        let mut encoder = crate::StreamEncoder::<_, 16000, 1>::configure(&mut tagged)
+            .unwrap()
+            .tags(&tags)
+            .start()
+            .unwrap();
+        encoder.push_samples(&audio).unwrap();
+        encoder.finish().unwrap();
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(&tagged)).unwrap();
+        assert_eq!(peak(&a2), peak(&reference));
+
+        let options = DecodeOptions {
+            r128_gain: Some(R128Gain::Album),
+            ..DecodeOptions::default()
+        };
+        let (a2, _) =
+            crate::decode_with_options::<_, 16000>(Cursor::new(&tagged), &options).unwrap();
+        let ratio = peak(&a2) as f64 / peak(&reference) as f64;
+        assert!((ratio - 0.5).abs() < 0.02);
+    }
+
+    #[test]
+    fn loudness_gain() {
+        use crate::{DecodeOptions, EncoderOptions, LoudnessGain, R128Gain};
+
+        let audio = read_file_i16("test_assets/big.wav");
+        let lufs = |audio: &[i16]| crate::loudness::integrated_loudness(audio, 16000, 1).unwrap();
+
+        let options = EncoderOptions::default()
+            .loudness_gain(LoudnessGain::OutputGain { target_lufs: -30.0 });
+        let (opus, report) = crate::encode_with_report::<16000, 1>(&audio, &options).unwrap();
+        let head = ogg::PacketReader::new(Cursor::new(&opus))
+            .read_packet_expected()
+            .unwrap();
+        assert_eq!(
+            Some(i16::from_le_bytes([head.data[16], head.data[17]])),
+            report.loudness_gain
+        );
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert!((lufs(&a2) + 30.0).abs() < 0.5);
+
+        let options = EncoderOptions::default().loudness_gain(LoudnessGain::R128Tag);
+        let (opus, report) = crate::encode_with_report::<16000, 1>(&audio, &options).unwrap();
+        let tags = crate::read_tags(Cursor::new(&opus)).unwrap();
+        assert_eq!(tags.track_gain(), report.loudness_gain);
+        let options = DecodeOptions {
+            r128_gain: Some(R128Gain::Track),
+            ..DecodeOptions::default()
+        };
+        let (a2, _) = crate::decode_with_options::<_, 16000>(Cursor::new(&opus), &options).unwrap();
+        assert!((lufs(&a2) + 23.0).abs() < 0.5);
+
+        // Nothing to measure in silence
+        let options = EncoderOptions::default().loudness_gain(LoudnessGain::R128Tag);
+        let (_, report) = crate::encode_with_report::<16000, 1>(&vec![0; 16000], &options).unwrap();
+        assert_eq!(report.loudness_gain, None);
+    }
+
+    #[test]
+    fn chained_streams() {
+        use crate::{DecodeEvent, DecoderCore, StreamEncoder, Tags};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let encode_titled = |title| {
+            let mut opus = Vec::new();
+            let mut encoder = StreamEncoder::<_, 16000, 1>::configure(&mut opus)
+                .unwrap()
+                .tags(&Tags::default().with(crate::TITLE, title))
+                .start()
+                .unwrap();
+            encoder.push_samples(&audio).unwrap();
+            encoder.finish().unwrap();
+            opus
+        };
+        let mut chained = encode_titled("One");
+        chained.extend(encode_titled("Two"));
+        // Players put all kinds of things at the end
+        chained.extend_from_slice(b"TAG\0\0\0");
+
+        let (a2, play_data) = crate::decode::<_, 16000>(Cursor::new(&chained)).unwrap();
+        assert_eq!(a2.len(), audio.len() * 2);
+        assert_eq!(play_data.tags.title(), Some("One"));
+
+        let mut decoder = crate::Decoder::<_, 16000>::new(Cursor::new(&chained)).unwrap();
+        let mut titles = Vec::new();
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            if frame.timestamp == Duration::ZERO {
+                titles.push(decoder.play_data().tags.title().unwrap().to_string());
+            }
+        }
+        assert_eq!(titles, ["One", "Two"]);
+
+        let mut core = DecoderCore::<16000>::new(&Default::default());
+        core.push_bytes(&chained);
+        let mut events = Vec::new();
+        while let Some(event) = core.poll().unwrap() {
+            match event {
+                DecodeEvent::Headers(_) => events.push("headers"),
+                DecodeEvent::End => events.push("end"),
+                DecodeEvent::Frame(_) => {}
+            }
+        }
+        assert_eq!(events, ["headers", "end", "headers", "end"]);
+        assert!(!core.has_pending());
+    }
+
+    #[test]
+    fn planar_output() {
+        let left = read_file_i16("test_assets/small.wav");
+        let right: Vec<i16> = left.iter().map(|&s| s / 2).collect();
+        let stereo = crate::interleave(&[&left[..], &right[..]]);
+        let opus = crate::encode::<16000, 2>(&stereo).unwrap();
+
+        let (decoded, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        let (planes, play_data) = crate::decode_planar::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(play_data.channels, 2);
+        assert_eq!(planes, crate::deinterleave(&decoded, 2));
+
+        // Into buffers already there, as much as fits
+        let mut l = vec![0; 100];
+        let mut r = vec![0; 200];
+        let frames = crate::deinterleave_into(&decoded, 2, &mut [&mut l, &mut r]);
+        assert_eq!(frames, 100);
+        assert_eq!(l, planes[0][..100]);
+        assert_eq!(r[..100], planes[1][..100]);
+        assert!(r[100..].iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn channel_policy() {
+        use crate::{ChannelPolicy, DecodeOptions};
+
+        let mono = read_file_i16("test_assets/small.wav");
+        let decode_as = |opus: &[u8], channels| {
+            let options = DecodeOptions {
+                channels,
+                ..DecodeOptions::default()
+            };
+            crate::decode_with_options::<_, 16000>(Cursor::new(opus), &options).unwrap()
+        };
+
+        let opus = crate::encode::<16000, 1>(&mono).unwrap();
+        let (stereo, play_data) = decode_as(&opus, ChannelPolicy::ForceStereo);
+        assert_eq!(play_data.channels, 2);
+        assert_eq!(stereo.len(), mono.len() * 2);
+        assert!(stereo.chunks_exact(2).all(|f| f[0] == f[1]));
+
+        let both: Vec<i16> = mono.iter().flat_map(|&s| [s, s]).collect();
+        let opus = crate::encode::<16000, 2>(&both).unwrap();
+        let (a2, play_data) = decode_as(&opus, ChannelPolicy::ForceMono);
+        assert_eq!(play_data.channels, 1);
+        assert_eq!(a2.len(), mono.len());
+
+        // 5.1 with the voice on the center only
+        let surround: Vec<i16> = mono.iter().flat_map(|&s| [0, s, 0, 0, 0, 0]).collect();
+        let opus = crate::encode::<16000, 6>(&surround).unwrap();
+        let (a2, play_data) = decode_as(&opus, ChannelPolicy::ForceStereo);
+        assert_eq!(play_data.channels, 2);
+        assert_eq!(a2.len(), mono.len() * 2);
+        let (center, _) = decode_as(&opus, ChannelPolicy::AsIs);
+        let energy = |audio: &[i16]| audio.iter().map(|&s| (s as i64).pow(2)).sum::<i64>();
+        // Same on both sides, a bit quieter than it was
+        let left: Vec<i16> = a2.iter().step_by(2).copied().collect();
+        let right: Vec<i16> = a2.iter().skip(1).step_by(2).copied().collect();
+        assert!((energy(&left) - energy(&right)).abs() * 100 < energy(&left));
+        let centered: Vec<i16> = center.iter().skip(1).step_by(6).copied().collect();
+        assert!(energy(&left) < energy(&centered));
+        let (a2, _) = decode_as(&opus, ChannelPolicy::ForceMono);
+        assert_eq!(a2.len(), mono.len());
+
+        // Mono and stereo can come as family 1 too
+        let family_1 = |opus: &[u8], channels: u8| {
+            let mut head = opus[28..28 + 18].to_vec();
+            head.extend_from_slice(&[1, 1, channels - 1]);
+            head.extend(0..channels);
+            let tags = crate::read_tags(Cursor::new(opus)).unwrap().to_packet();
+            crate::page::replace_headers(opus, &head, &tags).unwrap()
+        };
+        let opus = family_1(&crate::encode::<16000, 1>(&mono).unwrap(), 1);
+        let (a2, play_data) = decode_as(&opus, ChannelPolicy::ForceStereo);
+        assert_eq!(
+            (play_data.channels, play_data.channel_mapping_family),
+            (2, 1)
+        );
+        assert_eq!(a2.len(), mono.len() * 2);
+        let opus = family_1(&crate::encode::<16000, 2>(&both).unwrap(), 2);
+        let (a2, play_data) = decode_as(&opus, ChannelPolicy::ForceMono);
+        assert_eq!(
+            (play_data.channels, play_data.channel_mapping_family),
+            (1, 1)
+        );
+        assert_eq!(a2.len(), mono.len());
+        let (a2, _) = decode_as(&opus, ChannelPolicy::AsIs);
+        assert_eq!(a2.len(), mono.len() * 2);
+        // Any other mapping gets a weight for every channel as well
+        for channels in 1..=8 {
+            assert_eq!(
+                crate::interleave::stereo_weights(channels, 1).len(),
+                channels
+            );
+        }
+    }
+
+    #[cfg(feature = "resample")]
+    #[test]
+    fn resampling() {
+        use crate::EncoderOptions;
+
+        // A 1 kHz tone at CD rate
+        let tone: Vec<i16> = (0..44100)
+            .map(|i| ((i as f64 * 1000.0 * std::f64::consts::TAU / 44100.0).sin() * 8000.0) as i16)
+            .collect();
+        let zero_crossings = |audio: &[i16]| {
+            audio
+                .windows(2)
+                .filter(|w| (w[0] < 0) != (w[1] < 0))
+                .count()
+        };
+
+        let a16 = crate::resample(&tone, 1, 44100, 16000);
+        assert_eq!(a16.len(), 16000);
+        assert!(zero_crossings(&a16).abs_diff(2000) <= 2);
+        let back = crate::resample(&a16, 1, 16000, 44100);
+        assert_eq!(back.len(), tone.len());
+        let err: i64 = back[1000..43000]
+            .iter()
+            .zip(&tone[1000..43000])
+            .map(|(&a, &b)| (a as i64 - b as i64).abs())
+            .max()
+            .unwrap();
+        assert!(err < 200, "{}", err);
+
+        let opus = crate::encode_resampled(44100, 1, &tone, &EncoderOptions::default()).unwrap();
+        let (decoded, play_data) = crate::decode_resampled(Cursor::new(&opus), 44100).unwrap();
+        assert_eq!(play_data.channels, 1);
+        assert!(decoded.len().abs_diff(tone.len()) < 100);
+        // Away from the edges, where the codec fades in and out
+        assert!(zero_crossings(&decoded[4410..39690]).abs_diff(1600) <= 4);
+    }
+
+    #[test]
+    fn explicit_serial() {
+        use crate::{EncoderOptions, MuxMeta, OpusPacket};
+        use byteorder::{ByteOrder, LittleEndian};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let options = EncoderOptions::default().serial(0xdead_beef);
+        let a = crate::encode_with_options::<16000, 1>(&audio, &options).unwrap();
+        let b = crate::encode_with_options::<16000, 1>(&audio, &options).unwrap();
+        assert_eq!(LittleEndian::read_u32(&a[14..18]), 0xdead_beef);
+        assert_eq!(a, b);
+
+        let meta = MuxMeta {
+            serial: Some(7),
+            ..MuxMeta::default()
+        };
+        let packets = crate::demux(Cursor::new(&a)).unwrap();
+        let packets: Vec<OpusPacket> = packets.map(Result::unwrap).collect();
+        let muxed = crate::mux_packets(packets, &meta).unwrap();
+        assert_eq!(LittleEndian::read_u32(&muxed[14..18]), 7);
+    }
+
+    #[test]
+    fn reproducible_encoding() {
+        use crate::EncoderOptions;
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let options = EncoderOptions::default().reproducible();
+        let a = crate::encode_with_options::<16000, 1>(&audio, &options).unwrap();
+        let b = crate::encode_with_options::<16000, 1>(&audio, &options).unwrap();
+        assert_eq!(a, b);
+        let tags = crate::read_tags(Cursor::new(&a)).unwrap();
+        assert_eq!(tags.vendor, "ogg-opus");
+
+        // An explicit serial still wins
+        let c = crate::encode_with_options::<16000, 1>(&audio, &options.serial(1)).unwrap();
+        assert_ne!(a, c);
+        assert_eq!(a.len(), c.len());
+    }
+
+    #[test]
+    fn decode_into_buffer() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let (expected, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+
+        let mut out = vec![1; 3];
+        let play_data = crate::decode_into::<_, 16000>(Cursor::new(&opus), &mut out).unwrap();
+        assert_eq!(play_data.channels, 1);
+        assert_eq!(out, expected);
+
+        // The second time around it fits in what's already there
+        let ptr = out.as_ptr();
+        crate::decode_into::<_, 16000>(Cursor::new(&opus), &mut out).unwrap();
+        assert_eq!(out.as_ptr(), ptr);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn header_fields() {
+        use crate::EncoderOptions;
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let options = EncoderOptions::default().serial(42).output_gain(-3.0);
+        let opus = crate::encode_with_options::<16000, 1>(&audio, &options).unwrap();
+        let (_, play_data) = crate::decode::<_, 48000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(play_data.input_sample_rate, 16000);
+        assert_eq!(play_data.output_gain, -768);
+        assert_eq!(play_data.channel_mapping_family, 0);
+        assert_eq!(play_data.serial, 42);
+        // Same whatever the output rate
+        let (_, at_16k) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(play_data.pre_skip, at_16k.pre_skip);
+        assert!(play_data.pre_skip > 0);
+
+        let surround: Vec<i16> = audio.iter().flat_map(|&s| [s; 6]).collect();
+        let opus = crate::encode::<16000, 6>(&surround).unwrap();
+        let (_, play_data) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(play_data.channel_mapping_family, 1);
+    }
+
+    #[test]
+    fn parse_headers() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let tags = crate::Tags::default().with(crate::TITLE, "Probe");
+        let options = crate::EncoderOptions::default().output_gain(1.0);
+        let mut encoder =
+            crate::StreamEncoder::<_, 16000, 2>::configure_with(Vec::new(), &options).unwrap();
+        encoder = encoder.tags(&tags);
+        let mut encoder = encoder.start().unwrap();
+        let stereo: Vec<i16> = audio.iter().flat_map(|&s| [s, s]).collect();
+        encoder.push_samples(&stereo).unwrap();
+        let opus = encoder.finish().unwrap().into_inner();
+
+        let (head, read) = crate::headers::parse(Cursor::new(&opus)).unwrap();
+        assert_eq!(head.version, 1);
+        assert_eq!(head.channels, 2);
+        assert_eq!(head.input_sample_rate, 16000);
+        assert_eq!(head.output_gain, 256);
+        assert_eq!(head.channel_mapping_family, 0);
+        assert_eq!((head.stream_count, head.coupled_count), (1, 1));
+        assert_eq!(head.channel_mapping, vec![0, 1]);
+        assert_eq!(read.title(), Some("Probe"));
+
+        let surround: Vec<i16> = audio.iter().flat_map(|&s| [s; 6]).collect();
+        let opus = crate::encode::<16000, 6>(&surround).unwrap();
+        let (head, _) = crate::headers::parse(Cursor::new(&opus)).unwrap();
+        assert_eq!(head.channel_mapping_family, 1);
+        assert_eq!(head.channel_mapping.len(), 6);
+        assert_eq!(head.stream_count + head.coupled_count, 6);
+
+        assert!(crate::headers::parse(Cursor::new(&opus[..30])).is_err());
+    }
+
+    #[test]
+    fn sniffing() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        assert!(crate::is_ogg_opus(Cursor::new(&opus)));
+        assert!(!crate::is_ogg_opus(Cursor::new(&opus[..20])));
+        assert!(!crate::is_ogg_opus(Cursor::new(b"RIFF....WAVEfmt ")));
+        assert!(!crate::is_ogg_opus(Cursor::new(&[])));
+
+        // Ogg, but something else than Opus
+        let mut vorbis = opus.clone();
+        vorbis[28..36].copy_from_slice(b"\x01vorbis\0");
+        assert!(!crate::is_ogg_opus(Cursor::new(&vorbis)));
+
+        // Opus after another stream's first page
+        let mut muxed = vorbis[..crate::page::page_len(&vorbis).unwrap()].to_vec();
+        muxed.extend_from_slice(&opus);
+        assert!(crate::is_ogg_opus(Cursor::new(&muxed)));
+    }
+
+    #[test]
+    fn detailed_errors() {
+        use crate::Error;
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let decode = |opus: &[u8]| crate::decode::<_, 16000>(Cursor::new(opus.to_vec()));
+        // Changing a byte of a page means fixing its checksum too
+        let patched = |at: usize, value: u8| {
+            let mut opus = opus.clone();
+            opus[at] = value;
+            let len = crate::page::page_len(&opus).unwrap();
+            crate::page::update_checksum(&mut opus[..len]);
+            opus
+        };
+
+        // OpusHead starts at byte 28, right after the page header
+        assert!(matches!(
+            decode(&patched(28, b'X')),
+            Err(Error::MissingOpusHead)
+        ));
+        // Only the major version (upper 4 bits) has to match
+        assert!(decode(&patched(36, 15)).is_ok());
+        assert!(matches!(
+            decode(&patched(36, 0x10)),
+            Err(Error::UnsupportedVersion { found: 0x10 })
+        ));
+        assert!(matches!(
+            decode(&patched(37, 3)),
+            Err(Error::BadChannelCount(3))
+        ));
+
+        let head_len = crate::page::page_len(&opus).unwrap();
+        assert!(matches!(
+            decode(&opus[..head_len]),
+            Err(Error::MissingOpusTags)
+        ));
+
+        // Two comments claimed, only one there
+        let mut tags = crate::Tags::default().with(crate::TITLE, "Cut").to_packet();
+        let count = tags.len() - "TITLE=Cut".len() - 8;
+        tags[count] = 2;
+        assert!(matches!(
+            crate::Tags::from_packet(&tags),
+            Err(Error::TruncatedCommentHeader { offset }) if offset == tags.len()
+        ));
+
+        // Ambisonics, with a table as surround has
+        let surround: Vec<i16> = audio.iter().flat_map(|&s| [s; 6]).collect();
+        let mut ambisonics = crate::encode::<16000, 6>(&surround).unwrap();
+        ambisonics[46] = 2;
+        let len = crate::page::page_len(&ambisonics).unwrap();
+        crate::page::update_checksum(&mut ambisonics[..len]);
+        assert!(matches!(
+            decode(&ambisonics),
+            Err(Error::UnsupportedMappingFamily(2))
+        ));
+
+        let mut junk = opus.clone();
+        junk.splice(head_len..head_len, *b"junk");
+        assert!(matches!(
+            decode(&junk),
+            Err(Error::LostSync { offset }) if offset == head_len as u64
+        ));
+    }
+
+    #[test]
+    fn lossy_decode() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let (reference, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+
+        // Damage a page in the middle, its checksum won't match anymore
+        let mut pages = Vec::new();
+        let mut at = 0;
+        while at < opus.len() {
+            let len = crate::page::page_len(&opus[at..]).unwrap();
+            pages.push(at..at + len);
+            at += len;
+        }
+        assert!(pages.len() > 4);
+        let bad = pages[pages.len() - 2].clone();
+        let mut damaged = opus.clone();
+        damaged[bad.end - 10] ^= 0xff;
+        // And some garbage after the headers
+        damaged.splice(pages[2].start..pages[2].start, *b"garbage");
+        let bad = bad.start + 7..bad.end + 7;
+
+        assert!(crate::decode::<_, 16000>(Cursor::new(&damaged)).is_err());
+        let (a2, play_data, report) =
+            crate::decode_lossy::<_, 16000>(Cursor::new(&damaged)).unwrap();
+        assert_eq!(play_data.channels, 1);
+        assert_eq!(
+            report.skipped[0],
+            pages[2].start as u64..pages[2].start as u64 + 7
+        );
+        assert_eq!(report.skipped[1], bad.start as u64..bad.end as u64);
+        assert_eq!(report.skipped.len(), 2);
+        assert_eq!(report.bad_packets, 0);
+        assert!(a2.len() < reference.len());
+        assert!(a2.len() > reference.len() / 2);
+
+        // Cut in the middle of the last page
+        let cut = &opus[..opus.len() - 5];
+        let (_, _, report) = crate::decode_lossy::<_, 16000>(Cursor::new(cut)).unwrap();
+        let last = pages.last().unwrap();
+        assert_eq!(report.skipped, vec![last.start as u64..cut.len() as u64]);
+    }
+
+    #[test]
+    fn encoder_presets() {
+        use crate::{EncoderOptions, Preset};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let encode = |preset| {
+            let options = EncoderOptions::preset(preset);
+            crate::encode_with_options::<16000, 1>(&audio, &options).unwrap()
+        };
+        let frame = |opus: &[u8]| {
+            let mut packets = crate::demux(Cursor::new(opus)).unwrap();
+            packets.next().unwrap().unwrap().duration().unwrap()
+        };
+
+        let voice = encode(Preset::VoiceMessage);
+        let music = encode(Preset::Music);
+        let low = encode(Preset::LowLatency);
+        assert_eq!(frame(&voice), Duration::from_millis(20));
+        assert_eq!(frame(&music), Duration::from_millis(20));
+        assert_eq!(frame(&low), Duration::from_millis(10));
+        assert!(voice.len() < music.len());
+
+        // Less lookahead, so less to skip
+        let pre_skip = |opus: &[u8]| crate::headers::parse(Cursor::new(opus)).unwrap().0.pre_skip;
+        assert!(pre_skip(&low) < pre_skip(&music));
+    }
+
+    #[cfg(feature = "telegram")]
+    #[test]
+    fn telegram_voice_note() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let (opus, waveform) = crate::telegram::encode_voice_note::<16000>(&audio).unwrap();
+        assert!(crate::is_ogg_opus(Cursor::new(&opus)));
+        assert_eq!(waveform.len(), 63);
+
+        let bars: Vec<u8> = (0..100)
+            .map(|i| {
+                let bits = u16::from_le_bytes([
+                    waveform[i * 5 / 8],
+                    *waveform.get(i * 5 / 8 + 1).unwrap_or(&0),
+                ]);
+                ((bits >> (i * 5 % 8)) & 31) as u8
+            })
+            .collect();
+        assert_eq!(bars.iter().max(), Some(&31));
+
+        // A ramp goes up bar by bar
+        let ramp: Vec<i16> = (0..10000).map(|i| (i * 3) as i16).collect();
+        let packed = crate::telegram::waveform(&ramp);
+        assert_eq!(packed[0] & 31, 0);
+        // 500 bits, the last 4 are padding
+        assert_eq!(packed[62] >> 4, 0);
+        assert!(crate::telegram::waveform(&[]).iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn discord_packets() {
+        use crate::{DiscordEncoder, MuxMeta, SILENCE_FRAME};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        // Up to 48 kHz stereo, each sample three times on both sides
+        let audio: Vec<i16> = audio.iter().flat_map(|&s| [s; 6]).collect();
+
+        let mut encoder = DiscordEncoder::new().unwrap();
+        let mut packets = Vec::new();
+        for chunk in audio.chunks(1234) {
+            packets.extend(encoder.push_samples(chunk).unwrap());
+        }
+        packets.extend(encoder.finish().unwrap());
+
+        let (packets, silence) = packets.split_at(packets.len() - 5);
+        assert!(silence.iter().all(|p| p.data == SILENCE_FRAME));
+        assert!(packets
+            .iter()
+            .all(|p| p.duration() == Some(Duration::from_millis(20))));
+        // The lookahead goes in front, and the last frame is filled up
+        let frames = audio.len() / 2;
+        assert_eq!(packets.len(), (frames + 312).div_ceil(960));
+
+        // Same audio as encoding to Ogg would give
+        let meta = MuxMeta::default();
+        let opus = crate::mux_packets(packets.to_vec(), &meta).unwrap();
+        let (decoded, play_data) = crate::decode::<_, 48000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(play_data.channels, 2);
+        assert!(decoded.len() >= audio.len());
+    }
+
+    #[cfg(feature = "wav")]
+    #[test]
+    fn wav_helpers() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let mut out = Cursor::new(Vec::new());
+        let play_data = crate::decode_to_wav_writer(Cursor::new(&opus), &mut out).unwrap();
+        assert_eq!(play_data.channels, 1);
+
+        // Read back by another implementation
+        out.set_position(0);
+        #[allow(deprecated)]
+        let (header, decoded) = wav::read(&mut out).unwrap();
+        #[allow(deprecated)]
+        let (sps, channels, decoded) = (
+            header.sampling_rate,
+            header.channel_count,
+            decoded.try_into_sixteen().unwrap(),
+        );
+        assert_eq!((sps, channels), (48000, 1));
+        assert!(decoded.len().abs_diff(audio.len() * 3) < 3 * 320);
+
+        // And back into Opus, from a file
+        let path = std::env::temp_dir().join(format!("ogg-opus-wav-{}.wav", std::process::id()));
+        crate::decode_to_wav(Cursor::new(&opus), &path).unwrap();
+        let again = crate::encode_wav_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (redecoded, _) = crate::decode::<_, 48000>(Cursor::new(again)).unwrap();
+        assert!(redecoded.len().abs_diff(decoded.len()) < 960);
+
+        assert!(matches!(
+            crate::encode_wav(Cursor::new(&opus), &crate::EncoderOptions::default()),
+            Err(crate::Error::InvalidWav)
+        ));
+        // The asset itself is at 44.1 kHz
+        let cd = crate::encode_wav_file("test_assets/small.wav");
+        #[cfg(feature = "resample")]
+        assert!(cd.is_ok());
+        #[cfg(not(feature = "resample"))]
+        assert!(matches!(cd, Err(crate::Error::InvalidSps)));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_io() {
+        fn is_send<T: Send>(_: &T) {}
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let options = crate::EncoderOptions::default().reproducible();
+        let mut sync = crate::StreamEncoder::<_, 16000, 1>::configure_with(Vec::new(), &options)
+            .unwrap()
+            .start()
+            .unwrap();
+        sync.push_samples(&audio).unwrap();
+        let sync = sync.finish().unwrap();
+        let sync_report = sync.report();
+        let opus = sync.into_inner();
+
+        let encode = async {
+            let tags = crate::Tags::default();
+            let mut encoder =
+                crate::AsyncEncoder::<_, 16000, 1>::with_options(Vec::new(), &tags, &options)
+                    .await?;
+            for chunk in audio.chunks(1000) {
+                encoder.push_samples(chunk).await?;
+            }
+            encoder.finish().await
+        };
+        is_send(&encode);
+        let (encoded, report) = runtime.block_on(encode).unwrap();
+        assert_eq!(encoded, opus);
+        assert_eq!(report.packets, sync_report.packets);
+
+        let decode = crate::decode_async::<_, 16000>(opus.as_slice());
+        is_send(&decode);
+        let (decoded, play_data) = runtime.block_on(decode).unwrap();
+        let (expected, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(decoded, expected);
+        assert_eq!(play_data.channels, 1);
+
+        // Headers cut short
+        assert!(matches!(
+            runtime.block_on(crate::decode_async::<_, 16000>(&opus[..20])),
+            Err(crate::Error::MissingOpusHead)
+        ));
+        let cut = runtime.block_on(crate::decode_async::<_, 16000>(&opus[..opus.len() - 10]));
+        assert!(matches!(cut, Err(crate::Error::MalformedAudio)));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_seeking() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+
+        let mut decoder = crate::Decoder::<_, 16000>::new(Cursor::new(&opus)).unwrap();
+        runtime.block_on(async {
+            let data = futures_util::io::Cursor::new(&opus);
+            let mut async_decoder = crate::AsyncDecoder::<_, 16000>::new(data).await.unwrap();
+            assert_eq!(
+                async_decoder.total_samples().await.unwrap(),
+                audio.len() as u64
+            );
+            assert_eq!(
+                async_decoder.duration().await.unwrap(),
+                decoder.duration().unwrap()
+            );
+
+            // Lands on the same pages as the blocking one
+            for sample in [114_000, 0, 3, 150_000, 60_001, audio.len() as u64 - 100] {
+                decoder.seek_to_sample(sample).unwrap();
+                async_decoder.seek_to_sample(sample).await.unwrap();
+                for _ in 0..3 {
+                    let frame = decoder.next_frame().unwrap().unwrap();
+                    let async_frame = async_decoder.next_frame().await.unwrap().unwrap();
+                    assert_eq!(async_frame.timestamp, frame.timestamp);
+                    assert_eq!(async_frame.samples, frame.samples);
+                    if frame.timestamp.as_secs() > 11 {
+                        break;
+                    }
+                }
+            }
+
+            async_decoder
+                .seek_to_time(Duration::from_millis(7125))
+                .await
+                .unwrap();
+            let frame = async_decoder.next_frame().await.unwrap().unwrap();
+            assert_eq!(frame.timestamp, Duration::from_millis(7125));
+            async_decoder.seek_to_sample(u64::MAX).await.unwrap();
+            assert!(async_decoder.next_frame().await.unwrap().is_none());
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn tokio_io() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let audio = read_file_i16("test_assets/small.wav");
+        let options = crate::EncoderOptions::default().reproducible();
+        let opus = crate::encode_with_options::<16000, 1>(&audio, &options).unwrap();
+        let (reference, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+
+        runtime.block_on(async {
+            let tags = crate::Tags::default();
+            let mut encoder =
+                crate::tokio_io::encoder::<_, 16000, 1>(Cursor::new(Vec::new()), &tags, &options)
+                    .await
+                    .unwrap();
+            encoder.push_samples(&audio).await.unwrap();
+            let (encoded, _) = encoder.finish().await.unwrap();
+            let encoded = encoded.into_inner().into_inner();
+            let (decoded, _) = crate::tokio_io::decode_async::<_, 16000>(encoded.as_slice())
+                .await
+                .unwrap();
+            assert_eq!(decoded, reference);
+
+            // tokio's `AsyncSeek` goes through too
+            let mut decoder =
+                crate::tokio_io::decoder::<_, 16000>(Cursor::new(&opus), &Default::default())
+                    .await
+                    .unwrap();
+            assert_eq!(decoder.total_samples().await.unwrap(), audio.len() as u64);
+            decoder.seek_to_sample(0).await.unwrap();
+            let mut again = Vec::new();
+            while let Some(frame) = decoder.next_frame().await.unwrap() {
+                again.extend(frame.samples);
+            }
+            assert_eq!(again, reference);
+        });
+    }
+
+    #[cfg(feature = "bevy")]
+    #[test]
+    fn bevy_source() {
+        use bevy_audio::{Decodable, Source};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let options = crate::DecodeOptions {
+            channels: crate::ChannelPolicy::ForceStereo,
+            ..Default::default()
+        };
+        let (reference, _) =
+            crate::decode_with_options::<_, 48000>(Cursor::new(&opus), &options).unwrap();
+
+        let asset = crate::OpusAudio::new(opus.clone()).unwrap();
+        let source = asset.decoder();
+        assert_eq!((source.channels(), source.sample_rate()), (2, 48000));
+        assert_eq!(
+            source.total_duration(),
+            Some(Duration::from_micros(
+                reference.len() as u64 * 1_000_000 / 96000
+            ))
+        );
+        assert_eq!(source.collect::<Vec<_>>(), reference);
+        // Plays again from the start
+        assert_eq!(asset.decoder().count(), reference.len());
+
+        // Cut short, what's there still plays
+        let big = crate::encode::<16000, 1>(&read_file_i16("test_assets/big.wav")).unwrap();
+        let whole = crate::OpusAudio::new(big.clone())
+            .unwrap()
+            .decoder()
+            .count();
+        let cut = crate::OpusAudio::new(big[..big.len() / 2].to_vec()).unwrap();
+        let played = cut.decoder().count();
+        assert!(played > whole / 3 && played < whole);
+        assert!(crate::OpusAudio::new(vec![0u8; 100]).is_err());
+
+        // `.ogg` stays Vorbis'
+        assert_eq!(
+            bevy_asset::AssetLoader::extensions(&crate::OpusLoader),
+            ["opus"]
+        );
+    }
+
+    #[cfg(feature = "capture")]
+    #[test]
+    fn capture_sink() {
+        use crate::capture::CaptureSink;
+        use cpal::{BufferSize, StreamConfig};
+
+        let config = |channels, sample_rate| StreamConfig {
+            channels,
+            sample_rate,
+            buffer_size: BufferSize::Default,
+        };
+        let audio = read_file_i16("test_assets/small.wav");
+        let (reference, _) =
+            crate::decode::<_, 16000>(Cursor::new(crate::encode::<16000, 1>(&audio).unwrap()))
+                .unwrap();
+
+        // Stereo `f32` from the device, averaged back into the same mono
+        let captured: Vec<f32> = audio
+            .iter()
+            .flat_map(|&s| [s as f32 / 32768.0; 2])
+            .collect();
+        let mut sink = CaptureSink::<_, 16000, 1>::new(Vec::new(), &config(2, 16000)).unwrap();
+        for chunk in captured.chunks(960) {
+            sink.push(chunk).unwrap();
+        }
+        let opus = sink.finish().unwrap().into_inner();
+        let (decoded, play_data) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        assert_eq!(play_data.channels, 1);
+        assert_eq!(decoded, reference);
+
+        // Mono `u8` copied to both sides
+        let captured: Vec<u8> = audio.iter().map(|&s| (s >> 8) as u8 ^ 0x80).collect();
+        let mut sink = CaptureSink::<_, 16000, 2>::new(Vec::new(), &config(1, 16000)).unwrap();
+        sink.push(&captured).unwrap();
+        let opus = sink.finish().unwrap().into_inner();
+        let (decoded, play_data) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        assert_eq!(play_data.channels, 2);
+        assert_eq!(decoded.len(), audio.len() * 2);
+
+        assert!(matches!(
+            CaptureSink::<_, 48000, 1>::new(Vec::new(), &config(1, 44100)),
+            Err(crate::Error::InvalidSps)
+        ));
+        assert!(matches!(
+            CaptureSink::<_, 48000, 1>::new(Vec::new(), &config(0, 48000)),
+            Err(crate::Error::InvalidChannels)
+        ));
+    }
+
+    #[cfg(feature = "opusfile")]
+    #[test]
+    fn opusfile_decoder() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let tags = crate::Tags::default().with(crate::TITLE, "Big");
+        let mut encoder =
+            crate::StreamEncoder::<_, 16000, 1>::with_tags(Vec::new(), &tags).unwrap();
+        encoder.push_samples(&audio).unwrap();
+        let opus = encoder.finish().unwrap().into_inner();
+        let (reference, play_data) = crate::decode_float::<_, 48000>(Cursor::new(&opus)).unwrap();
+
+        let mut file = crate::OggOpusFile::new(Cursor::new(&opus)).unwrap();
+        assert_eq!(file.play_data(), &play_data);
+        assert_eq!(file.total_samples().unwrap(), reference.len() as u64);
+        let mut decoded = Vec::new();
+        while let Some(frame) = file.next_frame_float().unwrap() {
+            assert_eq!(
+                frame.timestamp,
+                crate::common::samples_to_duration(decoded.len() as u64, 48000)
+            );
+            decoded.extend(frame.samples);
+        }
+        assert_eq!(decoded, reference);
+
+        // Exact to the sample, and nothing left past the end
+        file.seek_to_sample(30000).unwrap();
+        let frame = file.next_frame_float().unwrap().unwrap();
+        assert_eq!(frame.timestamp, Duration::from_micros(625000));
+        let mut rest = frame.samples.len();
+        while let Some(frame) = file.next_frame().unwrap() {
+            rest += frame.samples.len();
+        }
+        assert_eq!(rest, reference.len() - 30000);
+        file.seek_to_time(Duration::from_secs(3600)).unwrap();
+        assert!(file.next_frame().unwrap().is_none());
+
+        // Forward only, all the same audio
+        let mut file = crate::OggOpusFile::streaming(&opus[..]).unwrap();
+        let mut decoded = Vec::new();
+        while let Some(frame) = file.next_frame_float().unwrap() {
+            decoded.extend(frame.samples);
+        }
+        assert_eq!(decoded, reference);
+
+        assert!(matches!(
+            crate::OggOpusFile::new(Cursor::new(vec![0u8; 100])),
+            Err(crate::Error::MissingOpusHead)
+        ));
+    }
+
+    #[cfg(feature = "opusenc")]
+    #[test]
+    fn opusenc_backend() {
+        use crate::{EncoderOptions, FrameSize};
+        use byteorder::{ByteOrder, LittleEndian};
+        use std::sync::{Arc, Mutex};
+
+        let audio = read_file_i16("test_assets/big.wav");
+        let tags = crate::Tags::default().with(crate::TITLE, "Big");
+        let options = EncoderOptions::default().libopusenc().serial(7);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = seen.clone();
+        let mut encoder = crate::StreamEncoder::<_, 16000, 1>::configure_with(Vec::new(), &options)
+            .unwrap()
+            .tags(&tags)
+            .on_packet(move |stats| seen2.lock().unwrap().push(*stats))
+            .start()
+            .unwrap();
+        // Headers are out right away
+        assert!(crate::page::is_page(encoder.get_ref()));
+        for chunk in audio.chunks(1000) {
+            encoder.push_samples(chunk).unwrap();
+        }
+        let encoder = encoder.finish().unwrap();
+        let report = encoder.report();
+        let opus = encoder.into_inner();
+
+        assert_eq!(LittleEndian::read_u32(&opus[14..18]), 7);
+        let read = crate::read_tags(Cursor::new(&opus)).unwrap();
+        assert_eq!(read.title(), Some("Big"));
+        assert!(read.vendor.contains("libopusenc"));
+        let (decoded, play_data) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(decoded.len(), audio.len());
+        assert_eq!(report.pre_skip, play_data.pre_skip);
+        assert_eq!(
+            report.duration,
+            crate::common::samples_to_duration(audio.len() as u64, 16000)
+        );
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len() as u64, report.packets);
+        assert_eq!(
+            seen.iter().map(|s| s.bytes as u64).sum::<u64>(),
+            report.bytes
+        );
+        assert_eq!(seen[0].duration, Duration::from_millis(20));
+        assert!(seen.windows(2).all(|w| w[0].granule < w[1].granule));
+
+        // Out with every push
+        let mut live = crate::StreamEncoder::<_, 16000, 1>::configure_with(Vec::new(), &options)
+            .unwrap()
+            .flush_pages()
+            .start()
+            .unwrap();
+        let headers = live.get_ref().len();
+        live.push_samples(&audio[..1600]).unwrap();
+        assert!(live.get_ref().len() > headers);
+
+        // Same settings through the one-shot calls
+        let packed = options.max_packing();
+        let (opus, report) = crate::encode_with_report::<16000, 1>(&audio, &packed).unwrap();
+        let (decoded, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(decoded.len(), audio.len());
+        assert!(report.packets < seen.len() as u64);
+        assert_eq!(
+            report.duration,
+            crate::common::samples_to_duration(audio.len() as u64, 16000)
+        );
+        assert_ne!(report.final_range, 0);
+
+        let short = EncoderOptions::default()
+            .libopusenc()
+            .frame_size(FrameSize::Ms10);
+        let (opus, _) = crate::encode_with_report::<16000, 2>(&audio, &short).unwrap();
+        let (decoded, play_data) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(play_data.channels, 2);
+        assert_eq!(decoded.len(), audio.len() / 2 * 2);
+    }
+
+    #[cfg(feature = "symphonia")]
+    #[test]
+    fn symphonia_opus() {
+        use symphonia_core::audio::{AudioBufferRef, Signal};
+        use symphonia_core::codecs::{CodecRegistry, Decoder, DecoderOptions};
+        use symphonia_core::errors::Error as SymphoniaError;
+        use symphonia_core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+        use symphonia_core::io::MediaSourceStream;
+        use symphonia_core::meta::MetadataOptions;
+        use symphonia_core::probe::{Hint, Probe};
+
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let (reference, _) = crate::decode_float::<_, 48000>(Cursor::new(&opus)).unwrap();
+
+        let mut probe = Probe::default();
+        probe.register_all::<crate::OggOpusReader>();
+        let mut codecs = CodecRegistry::new();
+        codecs.register_all::<crate::OpusDecoder>();
+
+        let source =
+            MediaSourceStream::new(Box::new(Cursor::new(opus.clone())), Default::default());
+        let options = FormatOptions {
+            enable_gapless: true,
+            ..FormatOptions::default()
+        };
+        let mut format = probe
+            .format(&Hint::new(), source, &options, &MetadataOptions::default())
+            .unwrap()
+            .format;
+        let track = format.default_track().unwrap().clone();
+        assert_eq!(track.codec_params.n_frames, Some(reference.len() as u64));
+        let mut decoder = codecs
+            .make(&track.codec_params, &DecoderOptions::default())
+            .unwrap();
+
+        let decode = |format: &mut Box<dyn FormatReader>,
+                      decoder: &mut Box<dyn Decoder>,
+                      out: &mut Vec<f32>| {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e)) => {
+                    assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+                    return None;
+                }
+                Err(e) => panic!("{}", e),
+            };
+            match decoder.decode(&packet).unwrap() {
+                AudioBufferRef::F32(buffer) => out.extend_from_slice(buffer.chan(0)),
+                _ => unreachable!(),
+            }
+            Some(packet.ts)
+        };
+
+        // Trimmed just like `decode_float` does
+        let mut decoded = Vec::new();
+        while let Some(ts) = decode(&mut format, &mut decoder, &mut decoded) {
+            assert!(ts <= decoded.len() as u64);
+        }
+        assert_eq!(decoded, reference);
+
+        // Lands where the blocking `Decoder` does
+        let mut blocking = crate::Decoder::<_, 48000>::new(Cursor::new(&opus)).unwrap();
+        for sample in [150_000, 0, 7, 333_333, reference.len() as u64 - 2000] {
+            let to = SeekTo::TimeStamp {
+                ts: sample,
+                track_id: track.id,
+            };
+            let seeked = format.seek(SeekMode::Accurate, to).unwrap();
+            assert_eq!(seeked.required_ts, sample);
+            assert!(seeked.actual_ts <= sample);
+            decoder.reset();
+            blocking.seek_to_sample(sample).unwrap();
+            let frame = blocking.next_frame_float().unwrap().unwrap();
+
+            let skip = (sample - seeked.actual_ts) as usize;
+            let mut samples = Vec::new();
+            while samples.len() < skip + frame.samples.len() {
+                decode(&mut format, &mut decoder, &mut samples).unwrap();
+            }
+            assert_eq!(
+                &samples[skip..skip + frame.samples.len()],
+                &frame.samples[..]
+            );
+        }
+        let to = SeekTo::TimeStamp {
+            ts: reference.len() as u64 + 1,
+            track_id: track.id,
+        };
+        assert!(format.seek(SeekMode::Accurate, to).is_err());
+
+        // Only Ogg with an OpusHead in its first page
+        use symphonia_core::probe::QueryDescriptor;
+        assert_eq!(crate::OggOpusReader::score(&opus[..64]), 255);
+        assert_eq!(crate::OggOpusReader::score(&opus[..16]), 0);
+        let mut vorbis = opus[..64].to_vec();
+        vorbis[28..36].copy_from_slice(b"\x01vorbis\0");
+        assert_eq!(crate::OggOpusReader::score(&vorbis), 0);
+    }
+
+    #[cfg(feature = "kira")]
+    #[test]
+    fn kira_sound() {
+        use kira::sound::streaming::Decoder as _;
+
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let (reference, _) = crate::decode_float::<_, 48000>(Cursor::new(&opus)).unwrap();
+
+        let data = crate::static_sound_data(Cursor::new(&opus)).unwrap();
+        assert_eq!(data.sample_rate, 48000);
+        assert_eq!(data.frames.len(), reference.len());
+        assert!(data
+            .frames
+            .iter()
+            .zip(&reference)
+            .all(|(frame, &s)| { frame.left == frame.right && (frame.left - s).abs() < 1e-3 }));
+
+        let mut decoder = crate::KiraDecoder::new(Cursor::new(&opus)).unwrap();
+        assert_eq!(decoder.num_frames(), reference.len());
+        let mut frames = Vec::new();
+        loop {
+            let chunk = decoder.decode().unwrap();
+            if chunk.is_empty() {
+                break;
+            }
+            frames.extend(chunk);
+        }
+        assert_eq!(frames.len(), reference.len());
+        assert_eq!(decoder.seek(96_000).unwrap(), 96_000);
+        let after = decoder.decode().unwrap();
+        let error: f32 = after
+            .iter()
+            .zip(&frames[96_000..])
+            .map(|(a, b)| (a.left - b.left).powi(2))
+            .sum();
+        assert!(error < 0.01 * after.len() as f32);
+
+        assert!(crate::streaming_sound_data(Cursor::new(opus)).is_ok());
+    }
+
+    #[cfg(feature = "rodio")]
+    #[test]
+    fn rodio_source() {
+        use rodio::Source;
+        use std::io::{Read, Seek, SeekFrom};
+
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let (reference, _) = crate::decode_float::<_, 48000>(Cursor::new(&opus)).unwrap();
+
+        let mut source = crate::OggOpusSource::new(Cursor::new(&opus)).unwrap();
+        assert_eq!(
+            (source.channels().get(), source.sample_rate().get()),
+            (1, 48000)
+        );
+        assert_eq!(
+            source.total_duration(),
+            Some(Duration::from_micros(11_802_250))
+        );
+        assert!(source.current_span_len().unwrap() > 0);
+        let played: Vec<f32> = source.by_ref().collect();
+        assert_eq!(played, reference);
+        assert_eq!(source.current_span_len(), Some(0));
+        assert!(source.error().is_none());
+
+        // Exact to the sample, after the end too
+        source.try_seek(Duration::from_millis(7125)).unwrap();
+        let after: Vec<f32> = source.by_ref().collect();
+        assert_eq!(after.len(), reference.len() - 342_000);
+        source.try_seek(Duration::from_secs(0)).unwrap();
+        assert_eq!(source.by_ref().count(), reference.len());
+        source.try_seek(Duration::from_secs(60)).unwrap();
+        assert_eq!(source.next(), None);
+
+        // Broken halfway, it ends early and keeps the reason
+        let mut broken = opus.clone();
+        let middle = broken.len() / 2;
+        broken[middle] ^= 0xFF;
+        let mut source = crate::OggOpusSource::new(Cursor::new(&broken)).unwrap();
+        assert!(source.by_ref().count() < reference.len());
+        assert!(source.error().is_some());
+
+        // Nor is the length needed, when the end can't be reached
+        struct NoEnd<T>(T);
+        impl<T: Read> Read for NoEnd<T> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+        impl<T: Seek> Seek for NoEnd<T> {
+            fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+                match pos {
+                    SeekFrom::End(_) => Err(std::io::ErrorKind::Unsupported.into()),
+                    _ => self.0.seek(pos),
+                }
+            }
+        }
+        let mut source = crate::OggOpusSource::new(NoEnd(Cursor::new(&opus))).unwrap();
+        assert_eq!(source.total_duration(), None);
+        assert_eq!(source.by_ref().count(), reference.len());
+    }
+
+    #[cfg(feature = "wav")]
+    #[test]
+    fn wav_sizes() {
+        // 16 kHz mono, with `chunks` between "fmt " and the data
+        let wav = |chunks: &[u8], data_len: u32, data: &[u8]| {
+            let mut out = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0".to_vec();
+            out.extend_from_slice(&16000u32.to_le_bytes());
+            out.extend_from_slice(&32000u32.to_le_bytes());
+            out.extend_from_slice(b"\x02\0\x10\0");
+            out.extend_from_slice(chunks);
+            out.extend_from_slice(b"data");
+            out.extend_from_slice(&data_len.to_le_bytes());
+            out.extend_from_slice(data);
+            out
+        };
+        let decoded_len = |wav: Vec<u8>| {
+            let opus = crate::encode_wav(Cursor::new(wav), &crate::EncoderOptions::default())?;
+            crate::decode::<_, 16000>(Cursor::new(opus)).map(|(audio, _)| audio.len())
+        };
+        let audio: Vec<u8> = (0..16000i16)
+            .flat_map(|i| ((i % 200) * 100).to_le_bytes())
+            .collect();
+
+        let full = decoded_len(wav(&[], audio.len() as u32, &audio)).unwrap();
+        assert!(full.abs_diff(16000) < 320);
+        // Streamed, the size never got written
+        assert_eq!(decoded_len(wav(&[], u32::MAX, &audio)).unwrap(), full);
+        assert_eq!(decoded_len(wav(&[], 0, &audio)).unwrap(), full);
+        // Odd chunks, the pad byte of the last one missing
+        let mut odd = audio.clone();
+        odd.push(0);
+        let list = b"LIST\x03\0\0\0abc\0";
+        assert_eq!(
+            decoded_len(wav(list, odd.len() as u32, &odd)).unwrap(),
+            full
+        );
+        // Sizes beyond the end of the file
+        assert!(decoded_len(wav(&[], u32::MAX - 1, &audio)).is_ok());
+        assert!(matches!(
+            decoded_len(wav(b"LIST\xfe\xff\xff\xff", 4, &[0; 4])),
+            Err(crate::Error::InvalidWav)
+        ));
+    }
+
+    #[test]
+    fn parallel_encode() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let options = crate::EncoderOptions::default();
+        let sequential = crate::encode_with_options::<16000, 1>(&audio, &options).unwrap();
+        let parallel = crate::encode_parallel::<16000, 1>(&audio, &options, 4).unwrap();
+        assert_ne!(parallel, sequential);
+
+        let (sequential, _) = crate::decode::<_, 16000>(Cursor::new(sequential)).unwrap();
+        let (parallel, _) = crate::decode::<_, 16000>(Cursor::new(parallel)).unwrap();
+        assert_eq!(parallel.len(), sequential.len());
+        // Segment boundaries don't sound any worse than the rest
+        let error = |decoded: &[i16]| {
+            decoded
+                .iter()
+                .zip(&audio)
+                .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+                .sum::<f64>()
+        };
+        assert!(error(&parallel) < error(&sequential) * 1.02);
+    }
+
+    #[test]
+    fn reusable_encoder() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let options = crate::EncoderOptions::default().reproducible();
+        let expected = crate::encode_with_options::<16000, 1>(&audio, &options).unwrap();
+
+        let mut encoder = crate::OggOpusEncoder::<16000, 1>::new(&options).unwrap();
+        // Nothing from one call leaks into the next
+        assert_eq!(encoder.encode(&audio).unwrap(), expected);
+        encoder.encode(&audio[..5000]).unwrap();
+        assert_eq!(encoder.encode(&audio).unwrap(), expected);
+    }
+
+    #[test]
+    fn decoder_read_to_end() {
+        let opus = crate::encode::<16000, 1>(&read_file_i16("test_assets/big.wav")).unwrap();
+        let (expected, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+
+        let mut decoder = crate::Decoder::<_, 16000>::new(Cursor::new(&opus)).unwrap();
+        let mut out = vec![1, 2, 3];
+        assert_eq!(decoder.read_to_end(&mut out).unwrap(), expected.len());
+        assert_eq!(out[..3], [1, 2, 3]);
+        assert_eq!(out[3..], expected[..]);
+        // Reserved from the granule, never grown after that
+        assert_eq!(out.capacity(), out.len());
+
+        // A last granule far beyond what the file could hold isn't believed
+        let opus = crate::encode::<16000, 1>(&read_file_i16("test_assets/small.wav")).unwrap();
+        let mut lying = opus.clone();
+        let last = crate::page::split_pages(&opus)
+            .unwrap()
+            .last()
+            .unwrap()
+            .len();
+        let start = lying.len() - last;
+        lying[start + 6..start + 14].copy_from_slice(&(1u64 << 61).to_le_bytes());
+        crate::page::update_checksum(&mut lying[start..]);
+        let mut decoder = crate::Decoder::<_, 16000>::new(Cursor::new(&lying)).unwrap();
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).unwrap();
+        assert!(out.capacity() <= opus.len() / 2 * 5760);
+    }
+
+    #[test]
+    fn mapping_family_255() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let surround: Vec<i16> = audio.iter().flat_map(|&s| [s; 6]).collect();
+        let opus = crate::encode::<16000, 6>(&surround).unwrap();
+        let mut unordered = opus.clone();
+        unordered[46] = 255;
+        let len = crate::page::page_len(&unordered).unwrap();
+        crate::page::update_checksum(&mut unordered[..len]);
+
+        // Same streams and table, only what the channels mean is unknown
+        let (expected, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        let (decoded, play_data) = crate::decode::<_, 16000>(Cursor::new(&unordered)).unwrap();
+        assert_eq!(play_data.channels, 6);
+        assert_eq!(play_data.channel_mapping_family, 255);
+        assert_eq!(decoded, expected);
+
+        let options = crate::DecodeOptions {
+            channels: crate::ChannelPolicy::ForceStereo,
+            ..Default::default()
+        };
+        let (stereo, play_data) =
+            crate::decode_with_options::<_, 16000>(Cursor::new(&unordered), &options).unwrap();
+        assert_eq!(play_data.channels, 2);
+        assert_eq!(stereo.len(), decoded.len() / 3);
+        assert!(stereo.chunks(2).all(|frame| frame[0] == frame[1]));
+    }
+
+    #[test]
+    fn cover_art() {
+        let mut cover = crate::Picture::front_cover("image/png", b"\x89PNG".to_vec());
+        cover.description = "Cover".to_string();
+        (cover.width, cover.height, cover.depth) = (1, 1, 24);
+        // As Python's base64 gives it for the same block
+        let encoded = "AAAAAwAAAAlpbWFnZS9wbmcAAAAFQ292ZXIAAAABAAAAAQAAABgAAAAAAAAABIlQTkc=";
+        assert_eq!(cover.to_base64(), encoded);
+        assert_eq!(crate::Picture::parse(encoded).unwrap(), cover);
+        assert!(matches!(
+            crate::Picture::parse(&encoded[..40]),
+            Err(crate::Error::InvalidPicture)
+        ));
+
+        let mut tags = crate::Tags::default();
+        tags.add_picture(&cover);
+        tags.add("METADATA_BLOCK_PICTURE", "not base64");
+        let back = crate::Picture::front_cover("image/jpeg", vec![0xff; 1000]);
+        tags.add_picture(&back);
+        let mut encoder =
+            crate::StreamEncoder::<_, 16000, 1>::with_tags(Vec::new(), &tags).unwrap();
+        encoder.push_samples(&[0; 16000]).unwrap();
+        let opus = encoder.finish().unwrap().into_inner();
+
+        let mut read = crate::read_tags(Cursor::new(&opus)).unwrap();
+        assert_eq!(read.pictures(), [cover, back]);
+        read.remove_pictures();
+        assert!(read.comments.is_empty());
+    }
+
+    #[test]
+    fn rewrite_tags_in_place() {
+        let opus = crate::encode::<16000, 1>(&read_file_i16("test_assets/big.wav")).unwrap();
+        let original = crate::read_tags(Cursor::new(&opus)).unwrap();
+
+        // Big enough for the comment header to take a few pages
+        let mut tags = original.clone().with(crate::TITLE, "Retagged");
+        tags.add_picture(&crate::Picture::front_cover("image/png", vec![7; 100_000]));
+        let mut retagged = Vec::new();
+        crate::rewrite_tags(Cursor::new(&opus), &mut retagged, &tags).unwrap();
+        assert_eq!(crate::read_tags(Cursor::new(&retagged)).unwrap(), tags);
+        assert_eq!(
+            crate::decode::<_, 16000>(Cursor::new(&retagged)).unwrap().0,
+            crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap().0
+        );
+
+        // Nothing else was touched, going back gives the very same bytes
+        let mut restored = Vec::new();
+        crate::rewrite_tags(Cursor::new(&retagged), &mut restored, &original).unwrap();
+        assert_eq!(restored, opus);
+    }
+
+    #[test]
+    fn rewrite_tags_chained_and_muxed() {
+        use crate::page::{split_pages, update_checksum, HEADER_SIZE};
+
+        let opus = crate::encode::<16000, 1>(&read_file_i16("test_assets/small.wav")).unwrap();
+        let decode = |opus: &[u8]| crate::decode::<_, 16000>(Cursor::new(opus)).unwrap().0;
+        // Sequence numbers of every link of every stream go 0, 1, 2 ...
+        let assert_numbered = |opus: &[u8]| {
+            let mut next = std::collections::HashMap::new();
+            for page in split_pages(opus).unwrap() {
+                let serial = u32::from_le_bytes([page[14], page[15], page[16], page[17]]);
+                let seq = next.entry(serial).or_insert(0);
+                if page[5] & 0x02 != 0 {
+                    *seq = 0;
+                }
+                assert_eq!(crate::page::sequence(page), *seq);
+                *seq += 1;
+            }
+        };
+
+        let mut tags = crate::read_tags(Cursor::new(&opus)).unwrap();
+        tags.add_picture(&crate::Picture::front_cover("image/png", vec![7; 200_000]));
+        let mut tagged = Vec::new();
+        crate::rewrite_tags(Cursor::new(&opus), &mut tagged, &tags).unwrap();
+        let chained = crate::concat_chained([Cursor::new(&tagged), Cursor::new(&opus)]).unwrap();
+        let mut retagged = Vec::new();
+        crate::rewrite_tags(
+            Cursor::new(&chained),
+            &mut retagged,
+            &crate::Tags::default(),
+        )
+        .unwrap();
+        assert_numbered(&retagged);
+        assert_eq!(decode(&retagged), decode(&chained));
+        assert_eq!(
+            crate::read_tags(Cursor::new(&retagged)).unwrap(),
+            crate::Tags::default()
+        );
+
+        // Another stream in between, left as it was
+        let other: Vec<Vec<u8>> = split_pages(&opus)
+            .unwrap()
+            .into_iter()
+            .map(|page| {
+                let mut page = page.to_vec();
+                page[14] ^= 0xff;
+                if page[5] & 0x02 != 0 {
+                    let body = HEADER_SIZE + page[26] as usize;
+                    page[body..body + 8].copy_from_slice(b"NotOpus!");
+                }
+                update_checksum(&mut page);
+                page
+            })
+            .collect();
+        let mut muxed = Vec::new();
+        for (i, page) in split_pages(&tagged).unwrap().into_iter().enumerate() {
+            if let Some(other) = other.get(i) {
+                muxed.extend_from_slice(other);
+            }
+            muxed.extend_from_slice(page);
+        }
+        let mut retagged = Vec::new();
+        crate::rewrite_tags(Cursor::new(&muxed), &mut retagged, &crate::Tags::default()).unwrap();
+        assert_numbered(&retagged);
+        assert_eq!(decode(&retagged), decode(&opus));
+        let others = |muxed: &[u8]| -> Vec<Vec<u8>> {
+            split_pages(muxed)
+                .unwrap()
+                .into_iter()
+                .filter(|page| page[14..18] != opus[14..18])
+                .map(<[u8]>::to_vec)
+                .collect()
+        };
+        assert_eq!(others(&retagged), other);
+
+        // Audio on the same page as the comment header is kept
+        let squashed = rewrite_stream(opus.clone(), true, |p| p.absgp_page());
+        let mut retagged = Vec::new();
+        crate::rewrite_tags(Cursor::new(&squashed), &mut retagged, &tags).unwrap();
+        assert_numbered(&retagged);
+        let packets = |opus: &[u8]| {
+            let mut reader = ogg::PacketReader::new(Cursor::new(opus.to_vec()));
+            let mut packets = Vec::new();
+            while let Some(packet) = reader.read_packet().unwrap() {
+                packets.push((packet.absgp_page(), packet.data));
+            }
+            packets.split_off(2)
+        };
+        assert_eq!(packets(&retagged), packets(&squashed));
+    }
+
+    #[test]
+    fn granule_validation() {
+        let options = crate::DecodeOptions {
+            check_granules: true,
+            ..Default::default()
+        };
+        let opus = crate::encode::<16000, 1>(&read_file_i16("test_assets/big.wav")).unwrap();
+        let decode = |opus: &[u8]| {
+            crate::decode_with_options::<_, 16000>(Cursor::new(opus.to_vec()), &options)
+        };
+        assert!(decode(&opus).is_ok());
+
+        // The first audio page is already off
+        let shifted = rewrite_stream(opus, false, |p| p.absgp_page() + 48);
+        assert!(matches!(
+            decode(&shifted),
+            Err(crate::Error::GranulePositionMismatch { page: 2, expected, found })
+                if found == expected + 48
+        ));
+    }
+
+    #[test]
+    fn decoded_reader() {
+        use std::io::Read;
+
+        let opus = crate::encode::<16000, 1>(&read_file_i16("test_assets/big.wav")).unwrap();
+        let format = crate::PcmFormat::F32;
+        let (expected, _) =
+            crate::decode_pcm_bytes::<_, 16000>(Cursor::new(&opus), format).unwrap();
+
+        let mut reader =
+            crate::DecodedReader::<_, 16000>::with_format(Cursor::new(&opus), format).unwrap();
+        assert_eq!(reader.play_data().channels, 1);
+        // Reads not lining up with frames
+        let mut bytes = Vec::new();
+        let mut buf = [0u8; 333];
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => break,
+                read => bytes.extend_from_slice(&buf[..read]),
+            }
+        }
+        // Straight from libopus' float decoder, not from i16
+        let floats = |bytes: &[u8]| -> Vec<f32> {
+            bytes
+                .chunks(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect()
+        };
+        let (read, expected) = (floats(&bytes), floats(&expected));
+        assert_eq!(read.len(), expected.len());
+        assert!(read
+            .iter()
+            .zip(&expected)
+            .all(|(a, b)| (a - b).abs() < 1e-3));
+
+        let mut reader = crate::DecodedReader::<_, 16000>::new(Cursor::new(&opus)).unwrap();
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).unwrap();
+        let (expected, _) =
+            crate::decode_pcm_bytes::<_, 16000>(Cursor::new(&opus), crate::PcmFormat::I16).unwrap();
+        assert_eq!(bytes, expected);
+
+        let mut broken =
+            crate::DecodedReader::<_, 16000>::new(Cursor::new(&opus[..50_000])).unwrap();
+        let error = broken.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_metadata() {
+        fn persistable<T: serde::Serialize + serde::de::DeserializeOwned>() {}
+        persistable::<crate::PlayData>();
+        persistable::<crate::headers::OpusHead>();
+        persistable::<crate::Tags>();
+        persistable::<crate::EncoderOptions>();
+        persistable::<crate::EncodeReport>();
+        persistable::<crate::DecodeOptions>();
+    }
+
+    #[test]
+    fn final_range_report() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let (opus, report) =
+            crate::encode_with_report::<16000, 1>(&audio, &crate::EncoderOptions::default())
+                .unwrap();
+        assert_eq!(report.final_range, crate::encode::get_final_range());
+
+        let mut decoder = crate::Decoder::<_, 16000>::new(Cursor::new(&opus)).unwrap();
+        assert_eq!(report.pre_skip, decoder.play_data().pre_skip);
+        for frame in &mut decoder {
+            frame.unwrap();
+        }
+        assert_eq!(decoder.final_range(), Some(report.final_range));
+
+        let mut encoder =
+            crate::OggOpusEncoder::<16000, 1>::new(&crate::EncoderOptions::default()).unwrap();
+        encoder.encode(&audio).unwrap();
+        assert_eq!(encoder.pre_skip(), report.pre_skip);
+        assert_eq!(encoder.report().final_range, report.final_range);
+    }
+
+    #[test]
+    fn target_size() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let options = crate::EncoderOptions::default();
+        for max_bytes in [4000, 12000] {
+            let (opus, report) =
+                crate::encode_with_target_size::<16000, 1>(&audio, max_bytes, &options).unwrap();
+            assert!(opus.len() <= max_bytes);
+            assert!(
+                opus.len() > max_bytes * 3 / 4,
+                "{} for {max_bytes}",
+                opus.len()
+            );
+            assert!(report.bytes < opus.len() as u64);
+        }
+
+        assert!(matches!(
+            crate::encode_with_target_size::<16000, 1>(&audio, 300, &options),
+            Err(crate::Error::TargetSizeTooSmall)
+        ));
+    }
+
+    #[test]
+    fn two_pass() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let options =
+            crate::EncoderOptions::default().bitrate(crate::Bitrate::BitsPerSecond(12000));
+        let (one, one_report) = crate::encode_with_report::<16000, 1>(&audio, &options).unwrap();
+        let options = options.two_pass().reproducible();
+        let (two, two_report) = crate::encode_with_report::<16000, 1>(&audio, &options).unwrap();
+        assert_ne!(one, two);
+        assert_eq!(one_report.duration, two_report.duration);
+        let ratio = two.len() as f64 / one.len() as f64;
+        assert!((0.75..1.25).contains(&ratio), "{}", ratio);
+
+        let (decoded, _) = crate::decode::<_, 16000>(Cursor::new(&two)).unwrap();
+        assert_eq!(decoded.len(), audio.len());
+
+        // Every stream gets its own plan, starting from the same bitrate
+        let mut encoder = crate::OggOpusEncoder::<16000, 1>::new(&options).unwrap();
+        assert_eq!(encoder.encode(&audio).unwrap(), two);
+        assert_eq!(encoder.encode(&audio).unwrap(), two);
+    }
+
+    #[test]
+    fn trimmed_encode() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let options = crate::EncoderOptions::default();
+        let (opus, report) =
+            crate::encode_trimmed::<16000, 1>(&audio, 4000..12000, &options).unwrap();
+        assert_eq!(report.duration, Duration::from_millis(500));
+
+        let (decoded, _) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        assert_eq!(decoded.len(), 8000);
+        // Lined up with the input, not a lookahead late
+        let correlation = |lag: usize| -> f64 {
+            decoded[lag..]
+                .iter()
+                .zip(&audio[4000..])
+                .map(|(&a, &b)| a as f64 * b as f64)
+                .sum()
+        };
+        assert!(correlation(0) > correlation(104));
+
+        // Up to the very end of the input
+        let frames = audio.len();
+        let (_, report) =
+            crate::encode_trimmed::<16000, 1>(&audio, frames - 100..frames, &options).unwrap();
+        assert_eq!(
+            report.duration,
+            Duration::from_micros(100 * 1_000_000 / 16000)
+        );
+
+        assert!(matches!(
+            crate::encode_trimmed::<16000, 1>(&audio, 10..frames + 1, &options),
+            Err(crate::Error::InvalidRange)
+        ));
+
+        // What the lookahead sees past the end gets the same gain as the rest,
+        // a loud tail would smear into the last frame. The range ends with a
+        // whole frame, so that the tail alone makes the end
+        let loud: Vec<i16> = (0..32000)
+            .map(|i| ((i as f64 * 0.17).sin() * 20000.0) as i16)
+            .collect();
+        let normalized = options.normalize_to(-40.0);
+        let range = 4000..4000 + 25 * 320 - 104;
+        let (opus, _) =
+            crate::encode_trimmed::<16000, 1>(&loud, range.clone(), &normalized).unwrap();
+        let (decoded, _) = crate::decode::<_, 16000>(Cursor::new(opus)).unwrap();
+        let measured =
+            crate::loudness::integrated_loudness(&loud[range.clone()], 16000, 1).unwrap();
+        let expected = crate::loudness::apply_gain(&loud[range], -40.0 - measured);
+        // Relative to the level, over the last frame and the one before
+        let error = |range: std::ops::Range<usize>| -> f64 {
+            let (error, level) = decoded[range.clone()].iter().zip(&expected[range]).fold(
+                (0.0, 0.0),
+                |(error, level), (&a, &b)| {
+                    let (a, b) = (a as f64, b as f64);
+                    (error + (a - b).powi(2), level + b * b)
+                },
+            );
+            error / level
+        };
+        let end = decoded.len();
+        assert!(error(end - 320..end) < 10.0 * error(end - 640..end - 320));
+    }
+
+    #[test]
+    fn concat_streams() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let (one, two) = audio.split_at(8000);
+        let encode = |audio: &[i16], options: &crate::EncoderOptions| {
+            crate::encode_with_options::<16000, 1>(audio, options).unwrap()
+        };
+        let serials = |opus: &[u8]| {
+            let mut serials: Vec<&[u8]> = crate::page::split_pages(opus)
+                .unwrap()
+                .into_iter()
+                .map(|page| &page[14..18])
+                .collect();
+            serials.dedup();
+            serials.len()
+        };
+
+        // Matching streams become one, every lookahead and padding included
+        let options = crate::EncoderOptions::default();
+        let parts = [encode(one, &options), encode(two, &options)];
+        let joined = crate::concat(parts.iter().map(Cursor::new)).unwrap();
+        assert_eq!(serials(&joined), 1);
+        let (decoded, _) = crate::decode::<_, 16000>(Cursor::new(&joined)).unwrap();
+        assert!(decoded.len() > audio.len());
+        assert!(decoded.len() < audio.len() + 16000 / 50);
+        let chained = crate::concat_chained(parts.iter().map(Cursor::new)).unwrap();
+        assert_eq!(serials(&chained), 2);
+
+        // Different gains can't share a header
+        let parts = [
+            encode(one, &options),
+            encode(two, &options.clone().output_gain(-3.0)),
+        ];
+        let joined = crate::concat(parts.iter().map(Cursor::new)).unwrap();
+        assert_eq!(serials(&joined), 2);
+        let (decoded, _) = crate::decode::<_, 16000>(Cursor::new(&joined)).unwrap();
+        assert_eq!(decoded.len(), audio.len());
+
+        assert!(crate::concat(Vec::<Cursor<Vec<u8>>>::new()).is_err());
+    }
+
+    #[test]
+    fn lossless_split() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let (first, second) = crate::split_at(Cursor::new(&opus), Duration::from_secs(1)).unwrap();
+
+        let (first, _) = crate::decode::<_, 16000>(Cursor::new(first)).unwrap();
+        let (second, _) = crate::decode::<_, 16000>(Cursor::new(second)).unwrap();
+        assert_eq!(first.len(), 16000);
+        assert_eq!(first.len() + second.len(), audio.len());
+
+        let part = crate::extract_range(
+            Cursor::new(&opus),
+            Duration::from_millis(500)..Duration::from_millis(1250),
+        )
+        .unwrap();
+        let (part, _) = crate::decode::<_, 16000>(Cursor::new(part)).unwrap();
+        assert_eq!(part.len(), 12000);
+
+        assert!(matches!(
+            crate::split_at(Cursor::new(&opus), Duration::from_secs(3600)),
+            Err(crate::Error::InvalidRange)
+        ));
+    }
+
+    #[test]
+    fn reencoded_split() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let at = Duration::from_millis(1003);
+        let options = crate::EncoderOptions::default();
+        let (first, second) = crate::split_at_reencoded(Cursor::new(&opus), at, &options).unwrap();
+        let (_, copied) = crate::split_at(Cursor::new(&opus), at).unwrap();
+        assert!(second.len() < copied.len());
+
+        let (first, _) = crate::decode::<_, 16000>(Cursor::new(first)).unwrap();
+        let (second, play_data) = crate::decode::<_, 16000>(Cursor::new(second)).unwrap();
+        assert!(play_data.pre_skip < 960);
+        assert_eq!(first.len(), 16048);
+        assert_eq!(first.len() + second.len(), audio.len());
+
+        // Sounds like the copied cut
+        let (copied, _) = crate::decode::<_, 16000>(Cursor::new(copied)).unwrap();
+        let energy = |audio: &mut dyn Iterator<Item = f64>| audio.map(|s| s * s).sum::<f64>();
+        let signal = energy(&mut copied.iter().map(|&s| s as f64));
+        let noise = energy(
+            &mut copied
+                .iter()
+                .zip(&second)
+                .map(|(&a, &b)| a as f64 - b as f64),
+        );
+        assert!(noise < signal / 10.0, "{} against {}", noise, signal);
+
+        let part = crate::extract_range_reencoded(
+            Cursor::new(&opus),
+            Duration::from_millis(510)..Duration::from_millis(1250),
+            &options,
+        )
+        .unwrap();
+        let (part, _) = crate::decode::<_, 16000>(Cursor::new(part)).unwrap();
+        assert_eq!(part.len(), 11840);
+    }
+
+    #[test]
+    fn packet_toc() {
+        use crate::packet::{self, Bandwidth, Mode};
+
+        // CELT, full band, 20 ms, stereo, a single frame
+        let toc = packet::parse(&[0xfc, 0xff, 0xfe]).unwrap();
+        assert_eq!((toc.mode, toc.bandwidth), (Mode::Celt, Bandwidth::Full));
+        assert_eq!(toc.frame_duration(), Duration::from_millis(20));
+        assert_eq!((toc.frames, toc.stereo), (1, true));
+        // SILK, narrow band, 3 frames of 60 ms
+        let toc = packet::parse(&[0x1b, 0x03]).unwrap();
+        assert_eq!((toc.mode, toc.bandwidth), (Mode::Silk, Bandwidth::Narrow));
+        assert_eq!(toc.duration(), Duration::from_millis(180));
+        assert!(!toc.stereo);
+        assert_eq!(packet::parse(&[0x1b]), None);
+        assert_eq!(packet::parse(&[]), None);
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        for frame in crate::Decoder::<_, 16000>::new(Cursor::new(opus)).unwrap() {
+            let toc = frame.unwrap().toc.unwrap();
+            assert!(!toc.stereo);
+            assert!(toc.frame_duration() <= Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn frame_info() {
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let last_granule = crate::page::split_pages(&opus)
+            .unwrap()
+            .last()
+            .map(|page| crate::page::granule(page))
+            .unwrap();
+
+        let mut infos = Vec::new();
+        for frame in crate::Decoder::<_, 16000>::new(Cursor::new(&opus)).unwrap() {
+            let frame = frame.unwrap();
+            let info = frame.info.unwrap();
+            assert_eq!(info.start_time, frame.timestamp);
+            assert!(info.packet_len > 0);
+            infos.push(info);
+        }
+        // Both headers have a page of their own
+        assert_eq!(infos[0].page_seq, 2);
+        assert!(infos.windows(2).all(|w| w[0].granule < w[1].granule));
+        assert!(infos.windows(2).all(|w| w[0].page_seq <= w[1].page_seq));
+        assert!(infos.last().unwrap().page_seq > 2);
+        assert!(infos.last().unwrap().granule >= last_granule);
+    }
+
+    #[test]
+    fn waveform_peaks() {
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let peaks = crate::peaks(Cursor::new(&opus), 50).unwrap();
+        assert_eq!(peaks.len(), 50);
+        assert!(peaks.iter().all(|(low, high)| low <= high));
+
+        let highest = peaks.iter().map(|p| p.1).max().unwrap() as f64;
+        let expected = *audio.iter().max().unwrap() as f64;
+        assert!(
+            (highest - expected).abs() < expected * 0.2,
+            "{} against {}",
+            highest,
+            expected
+        );
+
+        // More buckets than samples, most of them are left empty
+        let opus = crate::encode::<16000, 1>(&audio[..160]).unwrap();
+        let peaks = crate::peaks(Cursor::new(&opus), 1000).unwrap();
+        assert_eq!(peaks.len(), 1000);
+        assert!(peaks.iter().filter(|&&p| p == (0, 0)).count() > 800);
+        assert!(crate::peaks(Cursor::new(&opus), 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn decode_limits() {
+        use crate::{DecodeOptions, Error};
+
+        let audio = read_file_i16("test_assets/big.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let decode = |options: &DecodeOptions| {
+            crate::decode_with_options::<_, 16000>(Cursor::new(&opus), options)
+        };
+
+        let options = DecodeOptions {
+            max_samples: Some(48000),
+            ..DecodeOptions::default()
+        };
+        assert!(matches!(decode(&options), Err(Error::TooLong)));
+        let options = DecodeOptions {
+            max_pages: Some(3),
+            ..DecodeOptions::default()
+        };
+        assert!(matches!(decode(&options), Err(Error::TooManyPages)));
+        let options = DecodeOptions {
+            max_packet_size: Some(10),
+            ..DecodeOptions::default()
+        };
+        assert!(matches!(
+            decode(&options),
+            Err(Error::PacketTooLarge { size }) if size > 10
+        ));
+
+        // Limits the stream is within change nothing
+        let options = DecodeOptions {
+            max_samples: Some(audio.len() as u64 * 3 + 48000),
+            max_pages: Some(1000),
+            max_packet_size: Some(4000),
+            ..DecodeOptions::default()
+        };
+        let (limited, _) = decode(&options).unwrap();
+        let (a2, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(limited, a2);
+
+        // Audio made up for losses counts too, with room for all the rest
+        let mut decoder =
+            crate::Decoder::<_, 16000>::with_options(Cursor::new(&opus), &options).unwrap();
+        decoder.read_to_end(&mut Vec::new()).unwrap();
+        let error = (0..20)
+            .find_map(|_| decoder.conceal_frame(Duration::from_millis(120)).err())
+            .unwrap();
+        assert!(matches!(error, Error::TooLong));
+        let mut core = crate::DecoderCore::<16000>::new(&options);
+        core.push_bytes(&opus);
+        let error = loop {
+            core.packet_lost(Duration::from_millis(120));
+            match core.poll() {
+                Ok(Some(crate::DecodeEvent::End)) | Ok(None) => panic!("no limit"),
+                Ok(Some(_)) => {}
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(error, Error::TooLong));
+    }
+
+    #[test]
+    fn strict_mode() {
+        use crate::page::{split_pages, update_checksum};
+        use crate::{DecodeOptions, Error};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let options = DecodeOptions {
+            strict: true,
+            ..DecodeOptions::default()
+        };
+        let decode =
+            |opus: &[u8]| crate::decode_with_options::<_, 16000>(Cursor::new(opus), &options);
+        let (a2, _) = decode(&opus).unwrap();
+        assert_eq!(a2, crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap().0);
+        let chained = crate::concat_chained([Cursor::new(&opus), Cursor::new(&opus)]).unwrap();
+        decode(&chained).unwrap();
+
+        // Changes the page at `index` and nothing else
+        let edit_page = |index: usize, edit: &dyn Fn(&mut Vec<u8>)| {
+            let pages = split_pages(&opus).unwrap();
+            let mut out = Vec::new();
+            for (i, page) in pages.into_iter().enumerate() {
+                let mut page = page.to_vec();
+                if i == index {
+                    edit(&mut page);
+                    update_checksum(&mut page);
+                }
+                out.extend_from_slice(&page);
+            }
+            out
+        };
+        let serial = u32::from_le_bytes([opus[14], opus[15], opus[16], opus[17]]);
+
+        let no_bos = edit_page(0, &|page| page[5] &= !0x02);
+        assert!(matches!(decode(&no_bos), Err(Error::MissingBos { serial: s }) if s == serial));
+        let last = split_pages(&opus).unwrap().len() - 1;
+        let no_eos = edit_page(last, &|page| page[5] &= !0x04);
+        assert!(matches!(decode(&no_eos), Err(Error::MissingEos { serial: s }) if s == serial));
+        // Not strict, all of that goes
+        crate::decode::<_, 16000>(Cursor::new(&no_eos)).unwrap();
+        let again = edit_page(2, &|page| page[5] |= 0x02);
+        assert!(matches!(decode(&again), Err(Error::UnexpectedBos { .. })));
+        let granule = edit_page(1, &|page| page[6] = 1);
+        assert!(matches!(
+            decode(&granule),
+            Err(Error::HeaderGranule { page: 1, found: 1 })
+        ));
+        let squashed = rewrite_stream(opus.clone(), true, |p| p.absgp_page());
+        assert!(matches!(
+            decode(&squashed),
+            Err(Error::HeaderNotAlone { page: 0 })
+        ));
+    }
+
+    #[test]
+    fn broken_tags() {
+        use crate::page::{set_sequence, split_pages, update_checksum};
+        use crate::{DecodeOptions, Decoder, Error};
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let (reference, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        let pages = split_pages(&opus).unwrap();
+
+        // Without the comment header page, the rest numbered again
+        let mut missing = pages[0].to_vec();
+        for (seq, page) in pages[2..].iter().enumerate() {
+            let mut page = page.to_vec();
+            set_sequence(&mut page, seq as u32 + 1);
+            missing.extend_from_slice(&page);
+        }
+        // With it cut right after the vendor string
+        let vendor = crate::common::VENDOR_STR;
+        let tags = &pages[1][28..28 + 8 + 4 + vendor.len() + 2];
+        let mut tags_page = pages[1][..26].to_vec();
+        tags_page.extend_from_slice(&[1, tags.len() as u8]);
+        tags_page.extend_from_slice(tags);
+        update_checksum(&mut tags_page);
+        let mut truncated = pages[0].to_vec();
+        truncated.extend_from_slice(&tags_page);
+        for page in &pages[2..] {
+            truncated.extend_from_slice(page);
+        }
+
+        assert!(matches!(
+            crate::decode::<_, 16000>(Cursor::new(&missing)),
+            Err(Error::MissingOpusTags)
+        ));
+        assert!(matches!(
+            crate::decode::<_, 16000>(Cursor::new(&truncated)),
+            Err(Error::TruncatedCommentHeader { .. })
+        ));
+
+        let options = DecodeOptions {
+            lenient: true,
+            ..DecodeOptions::default()
+        };
+        for (opus, vendor) in [(&missing, ""), (&truncated, vendor)] {
+            let mut decoder =
+                Decoder::<_, 16000>::with_options(Cursor::new(opus), &options).unwrap();
+            assert_eq!(decoder.play_data().tags.vendor, vendor);
+            let mut a2 = Vec::new();
+            decoder.read_to_end(&mut a2).unwrap();
+            assert_eq!(a2, reference);
+            assert!(decoder.loss_report().broken_tags);
+        }
+        let decoder = Decoder::<_, 16000>::with_options(Cursor::new(&opus), &options).unwrap();
+        assert!(!decoder.loss_report().broken_tags);
+    }
+
+    #[test]
+    fn gain_policy() {
+        use crate::{DecodeOptions, EncoderOptions, GainPolicy, LoudnessGain};
+
+        let audio = read_file_i16("test_assets/big.wav");
+        let options = EncoderOptions::default()
+            .loudness_gain(LoudnessGain::OutputGain { target_lufs: -40.0 });
+        let opus = crate::encode_with_options::<16000, 1>(&audio, &options).unwrap();
+        let decode = |gain| {
+            let options = DecodeOptions {
+                gain,
+                ..DecodeOptions::default()
+            };
+            crate::decode_with_options::<_, 16000>(Cursor::new(&opus), &options).unwrap()
+        };
+        let peak = |audio: &[i16]| audio.iter().map(|s| s.unsigned_abs()).max().unwrap();
+
+        let (applied, play_data) = decode(GainPolicy::Apply);
+        assert!(play_data.output_gain < 0);
+        let (ignored, _) = decode(GainPolicy::Ignore);
+        assert!(peak(&ignored) > peak(&applied));
+        let header = play_data.output_gain as i32;
+        assert_eq!(decode(GainPolicy::Custom(header)).0, applied);
+        assert_eq!(decode(GainPolicy::Custom(0)).0, ignored);
+        // 6 dB more on top of the header's, twice as loud
+        let (louder, _) = decode(GainPolicy::Custom(header + 6 * 256));
+        let ratio = peak(&louder) as f64 / peak(&applied) as f64;
+        assert!((ratio - 2.0).abs() < 0.05, "{}", ratio);
+    }
+
+    #[test]
+    fn decode_24_bits() {
+        use byteorder::{ByteOrder, LittleEndian};
+        use std::io::Read;
+
+        let audio = read_file_i16("test_assets/small.wav");
+        let opus = crate::encode::<16000, 1>(&audio).unwrap();
+        let (decoded, _) = crate::decode::<_, 16000>(Cursor::new(&opus)).unwrap();
+        let (floats, _) = crate::decode_float::<_, 16000>(Cursor::new(&opus)).unwrap();
+        let (wide, play_data) = crate::decode_i24::<_, 16000>(Cursor::new(&opus)).unwrap();
+        assert_eq!(play_data.channels, 1);
+        assert_eq!(wide.len(), decoded.len());
+        for ((&w, &f), &s) in wide.iter().zip(&floats).zip(&decoded) {
+            assert_eq!(w, (f as f64 * 8388608.0).round() as i32);
+            // The 16 bits output, less a rounding or so
+            assert!((w - ((s as i32) << 8)).abs() <= 512);
+        }
+        // The precision that 16 bits would have dropped
+        assert!(wide.iter().any(|w| w & 0xff != 0));
+
+        let format = crate::PcmFormat::I24;
+        let (bytes, _) = crate::decode_pcm_bytes::<_, 16000>(Cursor::new(&opus), format).unwrap();
+        let mut samples = vec![0i32; bytes.len() / 3];
+        for (sample, bytes) in samples.iter_mut().zip(bytes.chunks_exact(3)) {
+            *sample = LittleEndian::read_i24(bytes);
+        }
+        assert_eq!(samples, wide);
+        let mut reader =
+            crate::DecodedReader::<_, 16000>::with_format(Cursor::new(&opus), format).unwrap();
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, bytes);
+    }
+
+    #[test]
+    fn drift_compensation() {
+        // A capture clock 0.125% fast, 100 ms chunks for 10 minutes
+        let mut drift = crate::DriftCompensator::<16000, 1>::new();
+        let start = std::time::Instant::now();
+        let chunk = vec![0i16; 1602];
+        let mut produced = 0;
+        for i in 1..=6000u64 {
+            let at = start + Duration::from_millis(100 * i);
+            produced += drift.process(&chunk, at).len();
+        }
+
+        let expected = 6000 * 1600;
+        let tolerance = 16000 * 20 / 1000;
+        assert!(produced.abs_diff(expected) <= tolerance);
+    }
+
     #[test]
     // Record, encode, decode , encode and decode again, finally compare the
     // first and second decodes, to make sure nothing is lost (can't compare
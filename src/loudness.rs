@@ -0,0 +1,127 @@
+// Integrated loudness as defined by EBU R128 / ITU-R BS.1770: K-weighting,
+// 400 ms blocks overlapping by 75% and the absolute plus relative gates
+
+use std::f64::consts::PI;
+
+use crate::common::Sample;
+
+const BLOCK_MS: u32 = 400;
+const STEP_MS: u32 = 100;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+// K-weighting for any sample rate, same formulas as libebur128
+fn k_weighting(sps: u32) -> [Biquad; 2] {
+    let fs = sps as f64;
+
+    // High shelf, models the head
+    let f0 = 1_681.974_450_955_533;
+    let g = 3.999_843_853_973_347;
+    let q = 0.707_175_236_955_419_6;
+    let k = (PI * f0 / fs).tan();
+    let vh = 10f64.powf(g / 20.0);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    // High pass
+    let f0 = 38.135_470_876_024_44;
+    let q = 0.500_327_037_323_877_3;
+    let k = (PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+fn to_lufs(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+/// Integrated loudness (LUFS) of interleaved audio, `None` if everything is
+/// below the absolute gate (e.g. silence) or it's shorter than a block
+pub(crate) fn integrated_loudness<S: Sample>(
+    audio: &[S],
+    sps: u32,
+    channels: usize,
+) -> Option<f64> {
+    if channels == 0 {
+        return None;
+    }
+    let step = (sps * STEP_MS / 1000) as usize;
+    let steps_per_block = (BLOCK_MS / STEP_MS) as usize;
+
+    // Sum of squares of every 100 ms step, blocks are made of 4 of them
+    let mut filters = vec![k_weighting(sps); channels];
+    let mut steps = Vec::new();
+    let mut acc = 0.0;
+    for (i, frame) in audio.chunks_exact(channels).enumerate() {
+        for (sample, filter) in frame.iter().zip(filters.iter_mut()) {
+            let x = sample.to_f32() as f64;
+            let [shelf, high_pass] = filter;
+            let y = high_pass.process(shelf.process(x));
+            // Left, right (and mono) all have a weight of 1
+            acc += y * y;
+        }
+        if (i + 1) % step == 0 {
+            steps.push(acc);
+            acc = 0.0;
+        }
+    }
+
+    let block_len = (step * steps_per_block) as f64;
+    let blocks: Vec<f64> = steps
+        .windows(steps_per_block)
+        .map(|w| w.iter().sum::<f64>() / block_len)
+        .filter(|&ms| ms > 0.0 && to_lufs(ms) > ABSOLUTE_GATE)
+        .collect();
+    if blocks.is_empty() {
+        return None;
+    }
+
+    let abs_mean = blocks.iter().sum::<f64>() / blocks.len() as f64;
+    let threshold = to_lufs(abs_mean) + RELATIVE_GATE;
+    let gated: Vec<f64> = blocks
+        .into_iter()
+        .filter(|&ms| to_lufs(ms) > threshold)
+        .collect();
+    if gated.is_empty() {
+        return None;
+    }
+
+    Some(to_lufs(gated.iter().sum::<f64>() / gated.len() as f64))
+}
+
+/// Applies `gain_db` to the audio, clipping whatever goes out of range
+pub(crate) fn apply_gain<S: Sample>(audio: &[S], gain_db: f64) -> Vec<S> {
+    let factor = 10f64.powf(gain_db / 20.0);
+    audio.iter().map(|&s| s.scale(factor)).collect()
+}
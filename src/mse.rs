@@ -0,0 +1,274 @@
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+use audiopus::SampleRate;
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::common::*;
+use crate::page::PacketSplitter;
+use crate::Error;
+
+// Matroska/WebM element ids
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const CODEC_DELAY: u32 = 0x56AA;
+const SEEK_PRE_ROLL: u32 = 0x56BB;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const BLOCK_GROUP: u32 = 0xA0;
+const BLOCK: u32 = 0xA1;
+const DISCARD_PADDING: u32 = 0x75A2;
+
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+const TRACK_TYPE_AUDIO: u64 = 2;
+const SEEK_PRE_ROLL_NS: u64 = 80_000_000; // As recommended by RFC 7845
+const NS_PER_MS: u64 = 1_000_000;
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+/**Repackages an Ogg Opus stream into WebM chunks, each of them can be
+appended on its own to a Media Source Extensions `SourceBuffer`
+(`audio/webm; codecs="opus"`). The first chunk is the init segment, every
+other one is a self-contained cluster with the audio of one Ogg page.
+Anything producing Ogg Opus can be written into it (e.g. the output of
+`encode`), chunks are handed to `on_chunk` as soon as they are ready. The
+pre-skip goes in the init segment's `CodecDelay` and the end trimming in the
+last block's `DiscardPadding`. Every link of a chained stream gets an init
+segment of its own and starts again from 0, so append those to a
+`SourceBuffer` in `"sequence"` mode.*/
+pub struct MseChunker<F: FnMut(&[u8]) -> io::Result<()>> {
+    on_chunk: F,
+    splitter: PacketSplitter,
+    head: Option<Vec<u8>>,
+    headers_left: u8,
+    // Audio muxed so far in this link, 48 kHz samples
+    position: u64,
+}
+
+impl<F: FnMut(&[u8]) -> io::Result<()>> MseChunker<F> {
+    pub fn new(on_chunk: F) -> Self {
+        Self {
+            on_chunk,
+            splitter: PacketSplitter::new(),
+            head: None,
+            headers_left: 2,
+            position: 0,
+        }
+    }
+
+    /// Checks that the stream ended in a whole page
+    pub fn finish(self) -> Result<(), Error> {
+        if self.splitter.has_pending() || self.headers_left != 0 {
+            return Err(Error::MalformedAudio);
+        }
+        Ok(())
+    }
+
+    fn push_packets(&mut self, packets: Vec<ogg::Packet>) -> Result<(), Error> {
+        let mut blocks = Vec::new();
+        let mut cluster_ms = self.position / ms_to_samples(1);
+
+        for packet in packets {
+            // A chained link, with headers of its own
+            if packet.first_in_stream() && self.headers_left == 0 {
+                self.headers_left = 2;
+                self.position = 0;
+                cluster_ms = 0;
+            }
+            match self.headers_left {
+                2 => {
+                    self.head = Some(packet.data);
+                    self.headers_left -= 1;
+                }
+                1 => {
+                    // Nothing from OpusTags goes into WebM
+                    self.headers_left -= 1;
+                    let init = init_segment(self.head.as_deref().unwrap_or_default())?;
+                    (self.on_chunk)(&init)?;
+                }
+                _ => {
+                    let inner = audiopus::packet::Packet::try_from(&packet.data)?;
+                    let samples = audiopus::packet::nb_samples(inner, SampleRate::Hz48000)?;
+
+                    // Block timestamps are relative to the cluster's, in an i16
+                    let mut rel_ms = self.position / ms_to_samples(1) - cluster_ms;
+                    if rel_ms > i16::MAX as u64 {
+                        self.write_cluster(cluster_ms, std::mem::take(&mut blocks))?;
+                        cluster_ms += rel_ms;
+                        rel_ms = 0;
+                    }
+                    self.position += samples as u64;
+
+                    // What the last granule leaves out of the last packet
+                    let padding = if packet.last_in_stream() {
+                        self.position.saturating_sub(packet.absgp_page())
+                    } else {
+                        0
+                    };
+                    if padding > 0 {
+                        let padding_ns = padding * NS_PER_SEC / OGG_OPUS_SPS as u64;
+                        blocks.extend(block_group(rel_ms as i16, &packet.data, padding_ns));
+                    } else {
+                        blocks.extend(simple_block(rel_ms as i16, &packet.data));
+                    }
+                }
+            }
+        }
+
+        self.write_cluster(cluster_ms, blocks)
+    }
+
+    fn write_cluster(&mut self, cluster_ms: u64, blocks: Vec<u8>) -> Result<(), Error> {
+        if !blocks.is_empty() {
+            let mut body = uint_element(TIMESTAMP, cluster_ms);
+            body.extend(blocks);
+            (self.on_chunk)(&element(CLUSTER, &body))?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&[u8]) -> io::Result<()>> Write for MseChunker<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.splitter.push_bytes(buf);
+
+        while let Some(packets) = self.splitter.read_page().map_err(into_io)? {
+            self.push_packets(packets).map_err(into_io)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const fn ms_to_samples(ms: u64) -> u64 {
+    (OGG_OPUS_SPS as u64 * ms) / 1000
+}
+
+fn init_segment(head: &[u8]) -> Result<Vec<u8>, Error> {
+    if head.len() < 19 || head[0..8] != OPUS_MAGIC_HEADER {
+        return Err(Error::MalformedAudio);
+    }
+    let channels = head[9] as u64;
+    let pre_skip = LittleEndian::read_u16(&head[10..12]) as u64;
+
+    let mut ebml = uint_element(EBML_VERSION, 1);
+    ebml.extend(uint_element(EBML_READ_VERSION, 1));
+    ebml.extend(uint_element(EBML_MAX_ID_LENGTH, 4));
+    ebml.extend(uint_element(EBML_MAX_SIZE_LENGTH, 8));
+    ebml.extend(element(DOC_TYPE, b"webm"));
+    ebml.extend(uint_element(DOC_TYPE_VERSION, 4));
+    ebml.extend(uint_element(DOC_TYPE_READ_VERSION, 2));
+
+    let mut info = uint_element(TIMESTAMP_SCALE, NS_PER_MS);
+    info.extend(element(MUXING_APP, VENDOR_STR.as_bytes()));
+    info.extend(element(WRITING_APP, VENDOR_STR.as_bytes()));
+
+    let mut audio = element(SAMPLING_FREQUENCY, &(OGG_OPUS_SPS as f64).to_be_bytes());
+    audio.extend(uint_element(CHANNELS, channels));
+
+    let mut track = uint_element(TRACK_NUMBER, 1);
+    track.extend(uint_element(TRACK_UID, 1));
+    track.extend(uint_element(TRACK_TYPE, TRACK_TYPE_AUDIO));
+    track.extend(element(CODEC_ID, b"A_OPUS"));
+    track.extend(element(CODEC_PRIVATE, head));
+    track.extend(uint_element(
+        CODEC_DELAY,
+        pre_skip * NS_PER_SEC / OGG_OPUS_SPS as u64,
+    ));
+    track.extend(uint_element(SEEK_PRE_ROLL, SEEK_PRE_ROLL_NS));
+    track.extend(element(AUDIO, &audio));
+
+    let mut out = element(EBML, &ebml);
+    // Live stream, we can't know the size of the segment beforehand
+    out.extend(id_bytes(SEGMENT));
+    out.extend(UNKNOWN_SIZE);
+    out.extend(element(INFO, &info));
+    out.extend(element(TRACKS, &element(TRACK_ENTRY, &track)));
+
+    Ok(out)
+}
+
+fn simple_block(rel_ms: i16, data: &[u8]) -> Vec<u8> {
+    // Keyframe, every Opus packet can be decoded on its own
+    element(SIMPLE_BLOCK, &block_body(rel_ms, 0x80, data))
+}
+
+// Only block groups can have a `DiscardPadding`, `Block` has no keyframe flag
+fn block_group(rel_ms: i16, data: &[u8], padding_ns: u64) -> Vec<u8> {
+    let mut group = element(BLOCK, &block_body(rel_ms, 0, data));
+    group.extend(int_element(DISCARD_PADDING, padding_ns as i64));
+    element(BLOCK_GROUP, &group)
+}
+
+fn block_body(rel_ms: i16, flags: u8, data: &[u8]) -> Vec<u8> {
+    let mut body = vec![0x81]; // Track number 1, as a vint
+    body.extend(rel_ms.to_be_bytes());
+    body.push(flags);
+    body.extend_from_slice(data);
+    body
+}
+
+fn id_bytes(id: u32) -> Vec<u8> {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+fn size_vint(size: usize) -> Vec<u8> {
+    // All ones is reserved for "unknown size", hence the -1
+    let len = (1..8)
+        .find(|&l| (size as u64) < (1 << (7 * l)) - 1)
+        .unwrap_or(8);
+    let marked = (size as u64) | (1 << (7 * len));
+    marked.to_be_bytes()[8 - len..].to_vec()
+}
+
+fn element(id: u32, body: &[u8]) -> Vec<u8> {
+    let mut out = id_bytes(id);
+    out.extend(size_vint(body.len()));
+    out.extend_from_slice(body);
+    out
+}
+
+fn uint_element(id: u32, val: u64) -> Vec<u8> {
+    let bytes = val.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count().min(7);
+    element(id, &bytes[skip..])
+}
+
+// Signed, so the first byte kept has to carry the sign
+fn int_element(id: u32, val: i64) -> Vec<u8> {
+    let bytes = val.to_be_bytes();
+    let skip = (0..7)
+        .take_while(|&i| {
+            let (byte, next) = (bytes[i], bytes[i + 1]);
+            (byte == 0 && next & 0x80 == 0) || (byte == 0xFF && next & 0x80 != 0)
+        })
+        .count();
+    element(id, &bytes[skip..])
+}
@@ -0,0 +1,290 @@
+// Surround audio (channel mapping family 1) through libopus' multistream API,
+// audiopus doesn't wrap it so we do it ourselves on top of its bindings
+
+use std::os::raw::c_int;
+use std::ptr::{self, NonNull};
+
+use audiopus::ffi;
+
+use crate::{Application, Bitrate, Error};
+
+// Vorbis channel order only goes this far
+pub(crate) const MAX_SURROUND_CHANNELS: u8 = 8;
+
+fn check(code: c_int) -> Result<c_int, Error> {
+    if code < 0 {
+        Err(audiopus::Error::Opus(audiopus::ErrorCode::from(code)).into())
+    } else {
+        Ok(code)
+    }
+}
+
+fn alloc_failed() -> Error {
+    audiopus::Error::Opus(audiopus::ErrorCode::AllocFail).into()
+}
+
+/// How channels are spread over the Opus streams of a packet, as written in
+/// the OpusHead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Mapping {
+    pub(crate) streams: u8,
+    pub(crate) coupled: u8,
+    // Decoded channel to stream channel, 255 for silence
+    pub(crate) table: Vec<u8>,
+}
+
+impl Mapping {
+    /// `None` if it doesn't make sense for `channels`
+    pub(crate) fn parse(data: &[u8], channels: u8) -> Option<Self> {
+        let (&streams, data) = data.split_first()?;
+        let (&coupled, data) = data.split_first()?;
+        let table = data.get(..channels as usize)?.to_vec();
+
+        let stream_channels = streams as u16 + coupled as u16;
+        let valid = streams > 0
+            && coupled <= streams
+            && stream_channels <= 255
+            && table
+                .iter()
+                .all(|&c| c == 255 || (c as u16) < stream_channels);
+        if valid {
+            Some(Self {
+                streams,
+                coupled,
+                table,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn write(&self, head: &mut Vec<u8>) {
+        head.push(self.streams);
+        head.push(self.coupled);
+        head.extend_from_slice(&self.table);
+    }
+}
+
+fn application_code(application: Application) -> c_int {
+    match application {
+        Application::Voip => ffi::OPUS_APPLICATION_VOIP,
+        Application::Audio => ffi::OPUS_APPLICATION_AUDIO,
+        Application::LowDelay => ffi::OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+    }
+}
+
+pub(crate) struct SurroundEncoder {
+    encoder: NonNull<ffi::OpusMSEncoder>,
+    channels: u8,
+    mapping: Mapping,
+}
+
+// Nothing in the state is tied to the thread that made it
+unsafe impl Send for SurroundEncoder {}
+
+impl SurroundEncoder {
+    pub(crate) fn new(sps: u32, channels: u8, application: Application) -> Result<Self, Error> {
+        let mut streams = 0;
+        let mut coupled = 0;
+        let mut table = vec![0u8; channels as usize];
+        let mut err = 0;
+        let encoder = unsafe {
+            ffi::opus_multistream_surround_encoder_create(
+                sps as i32,
+                channels as c_int,
+                1,
+                &mut streams,
+                &mut coupled,
+                table.as_mut_ptr(),
+                application_code(application),
+                &mut err,
+            )
+        };
+        check(err)?;
+
+        Ok(Self {
+            encoder: NonNull::new(encoder).ok_or_else(alloc_failed)?,
+            channels,
+            mapping: Mapping {
+                streams: streams as u8,
+                coupled: coupled as u8,
+                table,
+            },
+        })
+    }
+
+    pub(crate) fn mapping(&self) -> &Mapping {
+        &self.mapping
+    }
+
+    pub(crate) fn encode_float(&self, pcm: &[f32], output: &mut [u8]) -> Result<usize, Error> {
+        let len = unsafe {
+            ffi::opus_multistream_encode_float(
+                self.encoder.as_ptr(),
+                pcm.as_ptr(),
+                (pcm.len() / self.channels as usize) as c_int,
+                output.as_mut_ptr(),
+                output.len() as i32,
+            )
+        };
+        Ok(check(len)? as usize)
+    }
+
+    pub(crate) fn set(&mut self, request: i32, value: i32) -> Result<(), Error> {
+        check(unsafe { ffi::opus_multistream_encoder_ctl(self.encoder.as_ptr(), request, value) })?;
+        Ok(())
+    }
+
+    fn get(&self, request: i32) -> Result<i32, Error> {
+        let mut value = 0i32;
+        check(unsafe {
+            ffi::opus_multistream_encoder_ctl(self.encoder.as_ptr(), request, &mut value)
+        })?;
+        Ok(value)
+    }
+
+    pub(crate) fn set_bitrate(&mut self, bitrate: Bitrate) -> Result<(), Error> {
+        let value = match bitrate {
+            Bitrate::BitsPerSecond(bps) => bps,
+            Bitrate::Max => ffi::OPUS_BITRATE_MAX,
+            Bitrate::Auto => ffi::OPUS_AUTO,
+        };
+        self.set(ffi::OPUS_SET_BITRATE_REQUEST, value)
+    }
+
+    pub(crate) fn lookahead(&self) -> Result<u32, Error> {
+        Ok(self.get(ffi::OPUS_GET_LOOKAHEAD_REQUEST)? as u32)
+    }
+
+    pub(crate) fn reset(&mut self) -> Result<(), Error> {
+        check(unsafe {
+            ffi::opus_multistream_encoder_ctl(self.encoder.as_ptr(), ffi::OPUS_RESET_STATE)
+        })?;
+        Ok(())
+    }
+
+    pub(crate) fn final_range(&self) -> Result<u32, Error> {
+        Ok(self.get(ffi::OPUS_GET_FINAL_RANGE_REQUEST)? as u32)
+    }
+}
+
+impl Drop for SurroundEncoder {
+    fn drop(&mut self) {
+        unsafe { ffi::opus_multistream_encoder_destroy(self.encoder.as_ptr()) }
+    }
+}
+
+pub(crate) struct MultistreamDecoder {
+    decoder: NonNull<ffi::OpusMSDecoder>,
+    channels: u8,
+}
+
+// Nothing in the state is tied to the thread that made it
+unsafe impl Send for MultistreamDecoder {}
+
+impl MultistreamDecoder {
+    pub(crate) fn new(sps: u32, channels: u8, mapping: &Mapping) -> Result<Self, Error> {
+        let mut err = 0;
+        let decoder = unsafe {
+            ffi::opus_multistream_decoder_create(
+                sps as i32,
+                channels as c_int,
+                mapping.streams as c_int,
+                mapping.coupled as c_int,
+                mapping.table.as_ptr(),
+                &mut err,
+            )
+        };
+        check(err)?;
+
+        Ok(Self {
+            decoder: NonNull::new(decoder).ok_or_else(alloc_failed)?,
+            channels,
+        })
+    }
+
+    /// Per channel samples decoded, a `None` packet conceals a lost one, and
+    /// `fec` recovers the one before `packet` instead of decoding it
+    pub(crate) fn decode(
+        &mut self,
+        packet: Option<&[u8]>,
+        output: &mut [i16],
+        fec: bool,
+    ) -> Result<usize, Error> {
+        let (data, len) = packet_ptr(packet);
+        let decoded = unsafe {
+            ffi::opus_multistream_decode(
+                self.decoder.as_ptr(),
+                data,
+                len,
+                output.as_mut_ptr(),
+                (output.len() / self.channels as usize) as c_int,
+                fec as c_int,
+            )
+        };
+        Ok(check(decoded)? as usize)
+    }
+
+    pub(crate) fn decode_float(
+        &mut self,
+        packet: Option<&[u8]>,
+        output: &mut [f32],
+        fec: bool,
+    ) -> Result<usize, Error> {
+        let (data, len) = packet_ptr(packet);
+        let decoded = unsafe {
+            ffi::opus_multistream_decode_float(
+                self.decoder.as_ptr(),
+                data,
+                len,
+                output.as_mut_ptr(),
+                (output.len() / self.channels as usize) as c_int,
+                fec as c_int,
+            )
+        };
+        Ok(check(decoded)? as usize)
+    }
+
+    pub(crate) fn reset(&mut self) -> Result<(), Error> {
+        check(unsafe {
+            ffi::opus_multistream_decoder_ctl(self.decoder.as_ptr(), ffi::OPUS_RESET_STATE)
+        })?;
+        Ok(())
+    }
+
+    pub(crate) fn set_gain(&mut self, gain: i32) -> Result<(), Error> {
+        check(unsafe {
+            ffi::opus_multistream_decoder_ctl(
+                self.decoder.as_ptr(),
+                ffi::OPUS_SET_GAIN_REQUEST,
+                gain,
+            )
+        })?;
+        Ok(())
+    }
+
+    pub(crate) fn final_range(&self) -> Result<u32, Error> {
+        let mut value = 0u32;
+        check(unsafe {
+            ffi::opus_multistream_decoder_ctl(
+                self.decoder.as_ptr(),
+                ffi::OPUS_GET_FINAL_RANGE_REQUEST,
+                &mut value,
+            )
+        })?;
+        Ok(value)
+    }
+}
+
+impl Drop for MultistreamDecoder {
+    fn drop(&mut self) {
+        unsafe { ffi::opus_multistream_decoder_destroy(self.decoder.as_ptr()) }
+    }
+}
+
+fn packet_ptr(packet: Option<&[u8]>) -> (*const u8, i32) {
+    match packet {
+        Some(packet) => (packet.as_ptr(), packet.len() as i32),
+        None => (ptr::null(), 0),
+    }
+}
@@ -0,0 +1,369 @@
+// Encoding through libopusenc instead of our own Ogg side, picked with
+// `EncoderOptions::libopusenc`, for those that need xiph's paging, padding
+// and channel mappings exactly as opusenc writes them
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_uchar};
+use std::ptr::{self, NonNull};
+use std::slice;
+
+use audiopus::ffi;
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::common::{db_to_q78, samples_to_duration, Sample, OGG_OPUS_SPS};
+use crate::encode::{
+    default_bitrate, pick_serial, usual_overhead, EncodeReport, EncoderOptions, FrameSize,
+    PacketCallback, PacketStats, RateControl,
+};
+use crate::packet::Toc;
+use crate::page::PacketSplitter;
+use crate::{Application, Bitrate, Error, Tags};
+
+// What's in opusenc.h, which has kept it the same since 0.1
+#[repr(C)]
+struct OggOpusEnc {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct OggOpusComments {
+    _private: [u8; 0],
+}
+
+#[link(name = "opusenc")]
+extern "C" {
+    fn ope_comments_create() -> *mut OggOpusComments;
+    fn ope_comments_destroy(comments: *mut OggOpusComments);
+    fn ope_comments_add(
+        comments: *mut OggOpusComments,
+        tag: *const c_char,
+        val: *const c_char,
+    ) -> c_int;
+    fn ope_encoder_create_pull(
+        comments: *mut OggOpusComments,
+        rate: i32,
+        channels: c_int,
+        family: c_int,
+        error: *mut c_int,
+    ) -> *mut OggOpusEnc;
+    fn ope_encoder_write_float(enc: *mut OggOpusEnc, pcm: *const f32, samples: c_int) -> c_int;
+    fn ope_encoder_get_page(
+        enc: *mut OggOpusEnc,
+        page: *mut *mut c_uchar,
+        len: *mut i32,
+        flush: c_int,
+    ) -> c_int;
+    fn ope_encoder_flush_header(enc: *mut OggOpusEnc) -> c_int;
+    fn ope_encoder_drain(enc: *mut OggOpusEnc) -> c_int;
+    fn ope_encoder_destroy(enc: *mut OggOpusEnc);
+    fn ope_encoder_ctl(enc: *mut OggOpusEnc, request: c_int, ...) -> c_int;
+}
+
+const OPE_BAD_ARG: c_int = -11;
+const OPE_SET_DECISION_DELAY_REQUEST: c_int = 14000;
+const OPE_SET_SERIALNO_REQUEST: c_int = 14006;
+const OPE_SET_HEADER_GAIN_REQUEST: c_int = 14010;
+const OPE_GET_NB_STREAMS_REQUEST: c_int = 14013;
+const OPE_GET_NB_COUPLED_STREAMS_REQUEST: c_int = 14015;
+
+fn check(code: c_int) -> Result<(), Error> {
+    if code < 0 {
+        Err(Error::OpusEncError(code))
+    } else {
+        Ok(())
+    }
+}
+
+/**Same calls as `EncoderCore` as far as `StreamEncoder` and the `encode`
+functions go, with libopusenc doing the encoding and the paging. Nothing
+exists until the headers are written, comments can only be given at
+creation.*/
+pub(crate) struct OpusEncCore<const S_PS: u32, const NUM_CHANNELS: u8> {
+    encoder: Option<NonNull<OggOpusEnc>>,
+    options: EncoderOptions,
+    flush_pages: bool,
+    frame: FrameSize,
+    converted: Vec<f32>,
+    // Pages out of libopusenc, split again for the report and `on_packet`
+    output: Vec<u8>,
+    splitter: PacketSplitter,
+    headers_left: usize,
+    header_bytes: u64,
+    // Granule at the end of the last packet counted
+    position: u64,
+    total: u64,
+    report: EncodeReport,
+    on_packet: Option<PacketCallback>,
+}
+
+// libopusenc keeps no thread state, the encoder is only ever used by whoever
+// owns it
+unsafe impl<const S_PS: u32, const NUM_CHANNELS: u8> Send for OpusEncCore<S_PS, NUM_CHANNELS> {}
+
+impl<const S_PS: u32, const NUM_CHANNELS: u8> OpusEncCore<S_PS, NUM_CHANNELS> {
+    pub(crate) fn configure(options: &EncoderOptions) -> Result<Self, Error> {
+        if NUM_CHANNELS > 8 {
+            return Err(Error::InvalidChannels);
+        }
+        let frame = options.frame_size.unwrap_or(if options.max_packing {
+            FrameSize::Ms60
+        } else {
+            FrameSize::Ms20
+        });
+        Ok(Self {
+            encoder: None,
+            options: options.clone(),
+            flush_pages: false,
+            frame,
+            converted: Vec::new(),
+            output: Vec::new(),
+            splitter: PacketSplitter::new(),
+            headers_left: 2,
+            header_bytes: 0,
+            position: 0,
+            total: 0,
+            report: EncodeReport::default(),
+            on_packet: None,
+        })
+    }
+
+    pub(crate) fn write_headers(&mut self, tags: &Tags) -> Result<(), Error> {
+        let comments = unsafe { ope_comments_create() };
+        if comments.is_null() {
+            return Err(Error::OpusEncError(OPE_BAD_ARG));
+        }
+        let created = add_comments(comments, tags).and_then(|()| {
+            let channels = (NUM_CHANNELS as c_int).max(1);
+            // Surround goes with family 1, as our own encoder does
+            let family = if channels > 2 { 1 } else { 0 };
+            let mut error = 0;
+            let encoder = unsafe {
+                ope_encoder_create_pull(comments, S_PS as i32, channels, family, &mut error)
+            };
+            NonNull::new(encoder).ok_or(Error::OpusEncError(error))
+        });
+        // The encoder keeps a copy of its own
+        unsafe { ope_comments_destroy(comments) };
+        self.encoder = Some(created?);
+
+        self.apply_options()?;
+        check(unsafe { ope_encoder_flush_header(self.raw()) })?;
+        self.take_pages(false)
+    }
+
+    fn apply_options(&mut self) -> Result<(), Error> {
+        let options = self.options.clone();
+        self.ctl(OPE_SET_SERIALNO_REQUEST, pick_serial(&options) as i32)?;
+        if let Some(db) = options.output_gain {
+            self.ctl(OPE_SET_HEADER_GAIN_REQUEST, db_to_q78(db) as i32)?;
+        }
+        if self.flush_pages {
+            // Otherwise it holds on to up to 2 s of audio before encoding any
+            self.ctl(OPE_SET_DECISION_DELAY_REQUEST, 0)?;
+        }
+
+        if let Some(application) = options.application {
+            let application = match application {
+                Application::Voip => ffi::OPUS_APPLICATION_VOIP,
+                Application::Audio => ffi::OPUS_APPLICATION_AUDIO,
+                Application::LowDelay => ffi::OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+            };
+            self.ctl(ffi::OPUS_SET_APPLICATION_REQUEST, application)?;
+        }
+        let frame = match self.frame {
+            FrameSize::Ms2_5 => ffi::OPUS_FRAMESIZE_2_5_MS,
+            FrameSize::Ms5 => ffi::OPUS_FRAMESIZE_5_MS,
+            FrameSize::Ms10 => ffi::OPUS_FRAMESIZE_10_MS,
+            FrameSize::Ms20 => ffi::OPUS_FRAMESIZE_20_MS,
+            FrameSize::Ms40 => ffi::OPUS_FRAMESIZE_40_MS,
+            FrameSize::Ms60 => ffi::OPUS_FRAMESIZE_60_MS,
+        };
+        self.ctl(ffi::OPUS_SET_EXPERT_FRAME_DURATION_REQUEST, frame)?;
+
+        let bitrate = match options.bitrate {
+            Some(bitrate) => bitrate,
+            None => {
+                let streams = self.get(OPE_GET_NB_STREAMS_REQUEST)? as u8;
+                let coupled = self.get(OPE_GET_NB_COUPLED_STREAMS_REQUEST)? as u8;
+                default_bitrate(streams, coupled, self.frame.as_micros())
+            }
+        };
+        let bitrate = match bitrate {
+            Bitrate::BitsPerSecond(bps) => bps,
+            Bitrate::Max => ffi::OPUS_BITRATE_MAX,
+            Bitrate::Auto => ffi::OPUS_AUTO,
+        };
+        self.ctl(ffi::OPUS_SET_BITRATE_REQUEST, bitrate)?;
+        if let Some(complexity) = options.complexity {
+            self.ctl(ffi::OPUS_SET_COMPLEXITY_REQUEST, complexity as i32)?;
+        }
+        if let Some(rate_control) = options.rate_control {
+            let (vbr, constrained) = match rate_control {
+                RateControl::Vbr => (1, 0),
+                RateControl::ConstrainedVbr => (1, 1),
+                RateControl::Cbr => (0, 0),
+            };
+            self.ctl(ffi::OPUS_SET_VBR_REQUEST, vbr)?;
+            self.ctl(ffi::OPUS_SET_VBR_CONSTRAINT_REQUEST, constrained)?;
+        }
+        if let Some(packet_loss_perc) = options.inband_fec {
+            self.ctl(ffi::OPUS_SET_INBAND_FEC_REQUEST, 1)?;
+            self.ctl(
+                ffi::OPUS_SET_PACKET_LOSS_PERC_REQUEST,
+                packet_loss_perc as i32,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn on_packet(&mut self, on_packet: PacketCallback) {
+        self.on_packet = Some(on_packet);
+    }
+
+    pub(crate) fn set_flush_pages(&mut self, flush: bool) {
+        self.flush_pages = flush;
+    }
+
+    pub(crate) fn push<S: Sample>(&mut self, audio: &[S]) -> Result<(), Error> {
+        let channels = (NUM_CHANNELS as usize).max(1);
+        self.converted.clear();
+        self.converted.extend(audio.iter().map(|s| s.to_f32()));
+        let samples = (self.converted.len() / channels) as c_int;
+        check(unsafe { ope_encoder_write_float(self.raw(), self.converted.as_ptr(), samples) })?;
+        self.take_pages(self.flush_pages)
+    }
+
+    pub(crate) fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub(crate) fn finish_mut(&mut self) -> Result<EncodeReport, Error> {
+        check(unsafe { ope_encoder_drain(self.raw()) })?;
+        self.take_pages(true)?;
+
+        let mut streams = 1;
+        if let Ok(value) = self.get(OPE_GET_NB_STREAMS_REQUEST) {
+            streams = value;
+        }
+        self.report.final_range = (0..streams).fold(0, |range, stream| {
+            let mut state: *mut ffi::OpusEncoder = ptr::null_mut();
+            let mut value = 0u32;
+            unsafe {
+                ope_encoder_ctl(
+                    self.raw(),
+                    ffi::OPUS_MULTISTREAM_GET_ENCODER_STATE_REQUEST,
+                    stream,
+                    &mut state,
+                );
+                if !state.is_null() {
+                    ffi::opus_encoder_ctl(state, ffi::OPUS_GET_FINAL_RANGE_REQUEST, &mut value);
+                }
+            }
+            range ^ value
+        });
+
+        self.report.overhead = self.total - self.report.bytes - self.header_bytes;
+        if self.frame != FrameSize::Ms20 {
+            let usual = usual_overhead(&self.report);
+            self.report.overhead_saved = usual.saturating_sub(self.report.overhead);
+        }
+        Ok(self.report)
+    }
+
+    pub(crate) fn report(&self) -> EncodeReport {
+        self.report
+    }
+
+    // Only called once the headers are written, which always creates it
+    fn raw(&self) -> *mut OggOpusEnc {
+        self.encoder.map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    fn ctl(&mut self, request: c_int, value: i32) -> Result<(), Error> {
+        check(unsafe { ope_encoder_ctl(self.raw(), request, value) })
+    }
+
+    fn get(&self, request: c_int) -> Result<i32, Error> {
+        let mut value = 0i32;
+        check(unsafe { ope_encoder_ctl(self.raw(), request, &mut value) })?;
+        Ok(value)
+    }
+
+    // Everything libopusenc has ready goes to the output, and through the
+    // splitter to be counted
+    fn take_pages(&mut self, flush: bool) -> Result<(), Error> {
+        let mut page: *mut c_uchar = ptr::null_mut();
+        let mut len = 0;
+        while unsafe { ope_encoder_get_page(self.raw(), &mut page, &mut len, flush as c_int) } == 1
+        {
+            // Valid until the next call
+            let page = unsafe { slice::from_raw_parts(page, len as usize) };
+            self.output.extend_from_slice(page);
+            self.total += page.len() as u64;
+            self.splitter.push_bytes(page);
+            while let Some(packets) = self.splitter.read_page()? {
+                for packet in packets {
+                    self.count(&packet);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn count(&mut self, packet: &ogg::Packet) {
+        if self.headers_left > 0 {
+            if self.headers_left == 2 && packet.data.len() >= 12 {
+                self.report.pre_skip = LittleEndian::read_u16(&packet.data[10..12]);
+            }
+            self.headers_left -= 1;
+            self.header_bytes += packet.data.len() as u64;
+            return;
+        }
+
+        let toc = Toc::parse(&packet.data);
+        let samples = toc.map(|t| t.samples()).unwrap_or(0) as u64;
+        // Only the last packet of a page has its granule written, the one of
+        // the stream being end-trimmed
+        self.position = if packet.last_in_page() {
+            packet.absgp_page()
+        } else {
+            self.position + samples
+        };
+        self.report.packets += 1;
+        self.report.bytes += packet.data.len() as u64;
+        self.report.duration = samples_to_duration(
+            self.position.saturating_sub(self.report.pre_skip as u64),
+            OGG_OPUS_SPS,
+        );
+
+        if let Some(on_packet) = &mut self.on_packet {
+            on_packet(&PacketStats {
+                bytes: packet.data.len(),
+                duration: samples_to_duration(samples, OGG_OPUS_SPS),
+                granule: self.position,
+                mode: toc.map(|t| t.mode),
+                bandwidth: toc.map(|t| t.bandwidth),
+            });
+        }
+    }
+}
+
+impl<const S_PS: u32, const NUM_CHANNELS: u8> Drop for OpusEncCore<S_PS, NUM_CHANNELS> {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.encoder {
+            unsafe { ope_encoder_destroy(encoder.as_ptr()) }
+        }
+    }
+}
+
+// The vendor string is libopusenc's own, it has no call to change it
+fn add_comments(comments: *mut OggOpusComments, tags: &Tags) -> Result<(), Error> {
+    for (key, value) in &tags.comments {
+        let (key, value) = match (CString::new(key.as_str()), CString::new(value.as_str())) {
+            (Ok(key), Ok(value)) => (key, value),
+            _ => return Err(Error::OpusEncError(OPE_BAD_ARG)),
+        };
+        check(unsafe { ope_comments_add(comments, key.as_ptr(), value.as_ptr()) })?;
+    }
+    Ok(())
+}
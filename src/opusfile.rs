@@ -0,0 +1,404 @@
+// Decoding through libopusfile instead of our own Ogg side, with the same
+// calls as `Decoder`, for those that want xiph's reader (its seeking, its
+// take on broken and chained files) until ours has been around long enough
+
+use std::any::Any;
+use std::ffi::CStr;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::raw::{c_char, c_int, c_uchar, c_uint, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+
+use crate::common::{duration_to_samples, samples_to_duration, MAX_FRAME_SAMPLES, OGG_OPUS_SPS};
+use crate::tags::split_comment;
+use crate::{DecodedFrame, Error, PlayData, Tags};
+
+// What's in opusfile.h, which has kept it the same since 0.1
+#[repr(C)]
+struct OggOpusFileHandle {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct OpusHead {
+    version: c_int,
+    channel_count: c_int,
+    pre_skip: c_uint,
+    input_sample_rate: u32,
+    output_gain: c_int,
+    mapping_family: c_int,
+    stream_count: c_int,
+    coupled_count: c_int,
+    mapping: [c_uchar; 255],
+}
+
+#[repr(C)]
+struct OpusTags {
+    user_comments: *mut *mut c_char,
+    comment_lengths: *mut c_int,
+    comments: c_int,
+    vendor: *mut c_char,
+}
+
+#[repr(C)]
+struct OpusFileCallbacks {
+    read: extern "C" fn(*mut c_void, *mut c_uchar, c_int) -> c_int,
+    seek: Option<extern "C" fn(*mut c_void, i64, c_int) -> c_int>,
+    tell: Option<extern "C" fn(*mut c_void) -> i64>,
+    close: Option<extern "C" fn(*mut c_void) -> c_int>,
+}
+
+#[link(name = "opusfile")]
+extern "C" {
+    fn op_open_callbacks(
+        source: *mut c_void,
+        cb: *const OpusFileCallbacks,
+        initial_data: *const c_uchar,
+        initial_bytes: usize,
+        error: *mut c_int,
+    ) -> *mut OggOpusFileHandle;
+    fn op_free(of: *mut OggOpusFileHandle);
+    fn op_head(of: *const OggOpusFileHandle, li: c_int) -> *const OpusHead;
+    fn op_tags(of: *const OggOpusFileHandle, li: c_int) -> *const OpusTags;
+    fn op_serialno(of: *const OggOpusFileHandle, li: c_int) -> u32;
+    fn op_pcm_total(of: *const OggOpusFileHandle, li: c_int) -> i64;
+    fn op_pcm_tell(of: *const OggOpusFileHandle) -> i64;
+    fn op_pcm_seek(of: *mut OggOpusFileHandle, pcm_offset: i64) -> c_int;
+    fn op_read(of: *mut OggOpusFileHandle, pcm: *mut i16, buf_size: c_int, li: *mut c_int)
+        -> c_int;
+    fn op_read_float(
+        of: *mut OggOpusFileHandle,
+        pcm: *mut f32,
+        buf_size: c_int,
+        li: *mut c_int,
+    ) -> c_int;
+}
+
+// libopusfile needs it, a static libopusfile doesn't bring it along
+#[link(name = "ogg")]
+extern "C" {}
+
+// libopusfile's error codes we tell apart
+const OP_EREAD: c_int = -128;
+const OP_ENOTFORMAT: c_int = -132;
+
+const SEEK_SET: c_int = 0;
+const SEEK_CUR: c_int = 1;
+const SEEK_END: c_int = 2;
+
+// What the callbacks go through, panics are carried over libopusfile and
+// picked up again on our side
+struct Source<T> {
+    data: T,
+    error: Option<io::Error>,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl<T> Source<T> {
+    // `None` on an error or a panic, kept for later
+    fn call<R>(&mut self, f: impl FnOnce(&mut T) -> io::Result<R>) -> Option<R> {
+        if self.panic.is_some() {
+            return None;
+        }
+        match panic::catch_unwind(AssertUnwindSafe(|| f(&mut self.data))) {
+            Ok(Ok(value)) => Some(value),
+            Ok(Err(e)) => {
+                self.error = Some(e);
+                None
+            }
+            Err(payload) => {
+                self.panic = Some(payload);
+                None
+            }
+        }
+    }
+}
+
+extern "C" fn read<T: Read>(source: *mut c_void, ptr: *mut c_uchar, nbytes: c_int) -> c_int {
+    let source = unsafe { &mut *(source as *mut Source<T>) };
+    let buffer = unsafe { slice::from_raw_parts_mut(ptr, nbytes.max(0) as usize) };
+    let read = source.call(|data| loop {
+        match data.read(buffer) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            result => return result,
+        }
+    });
+    read.map_or(-1, |read| read as c_int)
+}
+
+extern "C" fn seek<T: Seek>(source: *mut c_void, offset: i64, whence: c_int) -> c_int {
+    let source = unsafe { &mut *(source as *mut Source<T>) };
+    let to = match whence {
+        SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return -1,
+    };
+    source.call(|data| data.seek(to)).map_or(-1, |_| 0)
+}
+
+extern "C" fn tell<T: Seek>(source: *mut c_void) -> i64 {
+    let source = unsafe { &mut *(source as *mut Source<T>) };
+    source
+        .call(|data| data.stream_position())
+        .map_or(-1, |position| position as i64)
+}
+
+/**Same calls as `Decoder`, at 48 kHz (libopusfile only decodes at that rate),
+going through libopusfile instead. It has to be installed, only linking it is
+up to us. `new` opens seekable input (files, `Cursor`), `streaming` anything
+else, read forward only. Chained files go on link after link as with
+`Decoder`, but positions and lengths are over the whole file, as
+libopusfile counts them. Frames carry no TOC nor `FrameInfo`, libopusfile
+doesn't tell which packet they came from.*/
+pub struct OggOpusFile<T: Read> {
+    handle: *mut OggOpusFileHandle,
+    // Stays where it is for the callbacks, freed after the handle
+    source: Box<Source<T>>,
+    play_data: PlayData,
+    link: c_int,
+    // Seeked past the end, until the next seek
+    ended: bool,
+}
+
+// libopusfile's state is only ever touched through `&mut self`
+unsafe impl<T: Read + Send> Send for OggOpusFile<T> {}
+
+impl<T: Read + Seek> OggOpusFile<T> {
+    /// Reads the headers, and goes through the file to find its links and
+    /// its length, as libopusfile does for seekable input
+    pub fn new(data: T) -> Result<Self, Error> {
+        let callbacks = OpusFileCallbacks {
+            read: read::<T>,
+            seek: Some(seek::<T>),
+            tell: Some(tell::<T>),
+            close: None,
+        };
+        Self::open(data, &callbacks)
+    }
+
+    /// Jumps to `sample` (per channel, counted like `DecodedFrame::timestamp`),
+    /// exact to the sample as libopusfile seeks. Seeking past the end leaves
+    /// nothing else to decode
+    pub fn seek_to_sample(&mut self, sample: u64) -> Result<(), Error> {
+        let total = self.total_samples()?;
+        self.ended = sample >= total;
+        if !self.ended {
+            let result = unsafe { op_pcm_seek(self.handle, sample as i64) };
+            self.check(result)?;
+        }
+        Ok(())
+    }
+
+    /// Same as `seek_to_sample`, with the position as time
+    pub fn seek_to_time(&mut self, time: Duration) -> Result<(), Error> {
+        self.seek_to_sample(duration_to_samples(time, OGG_OPUS_SPS))
+    }
+
+    /// Per channel samples in the whole file, all links together, the same
+    /// that decoding it gives
+    pub fn total_samples(&mut self) -> Result<u64, Error> {
+        let total = unsafe { op_pcm_total(self.handle, -1) };
+        Ok(self.check(total)? as u64)
+    }
+
+    /// Same as `total_samples`, as time
+    pub fn duration(&mut self) -> Result<Duration, Error> {
+        Ok(samples_to_duration(self.total_samples()?, OGG_OPUS_SPS))
+    }
+
+    /// Decodes everything left into `out`, after what's already there, and
+    /// tells how many samples (of all channels) went in
+    pub fn read_to_end(&mut self, out: &mut Vec<i16>) -> Result<usize, Error> {
+        let before = out.len();
+        while let Some(frame) = self.next_frame()? {
+            out.extend_from_slice(&frame.samples);
+        }
+        Ok(out.len() - before)
+    }
+}
+
+impl<T: Read> OggOpusFile<T> {
+    /// Same as `new` for input that can't seek (sockets, HTTP bodies ...),
+    /// the seeking calls fail on it
+    pub fn streaming(data: T) -> Result<Self, Error> {
+        let callbacks = OpusFileCallbacks {
+            read: read::<T>,
+            seek: None,
+            tell: None,
+            close: None,
+        };
+        Self::open(data, &callbacks)
+    }
+
+    fn open(data: T, callbacks: &OpusFileCallbacks) -> Result<Self, Error> {
+        let mut source = Box::new(Source {
+            data,
+            error: None,
+            panic: None,
+        });
+        let mut error = 0;
+        let handle = unsafe {
+            op_open_callbacks(
+                &mut *source as *mut Source<T> as *mut c_void,
+                callbacks,
+                ptr::null(),
+                0,
+                &mut error,
+            )
+        };
+        if handle.is_null() {
+            return Err(source_error(&mut source, error));
+        }
+
+        let mut file = Self {
+            handle,
+            source,
+            play_data: no_play_data(),
+            link: 0,
+            ended: false,
+        };
+        file.play_data = file.link_play_data(-1);
+        Ok(file)
+    }
+
+    pub fn play_data(&self) -> &PlayData {
+        &self.play_data
+    }
+
+    /// Next frame of audio, `None` at the end
+    pub fn next_frame(&mut self) -> Result<Option<DecodedFrame>, Error> {
+        self.next_as(|handle, buffer: &mut [i16], link| unsafe {
+            op_read(handle, buffer.as_mut_ptr(), buffer.len() as c_int, link)
+        })
+    }
+
+    /// Same as `next_frame`, with the audio as `f32`
+    pub fn next_frame_float(&mut self) -> Result<Option<DecodedFrame<f32>>, Error> {
+        self.next_as(|handle, buffer: &mut [f32], link| unsafe {
+            op_read_float(handle, buffer.as_mut_ptr(), buffer.len() as c_int, link)
+        })
+    }
+
+    fn next_as<S: Copy + Default>(
+        &mut self,
+        read: impl Fn(*mut OggOpusFileHandle, &mut [S], *mut c_int) -> c_int,
+    ) -> Result<Option<DecodedFrame<S>>, Error> {
+        if self.ended {
+            return Ok(None);
+        }
+        let position = unsafe { op_pcm_tell(self.handle) };
+        let position = self.check(position)? as u64;
+
+        // A packet at most, the rest of a bigger one (from a link with more
+        // channels) comes next time
+        let channels = self.play_data.channels.max(1) as usize;
+        let mut samples = vec![S::default(); MAX_FRAME_SAMPLES * channels];
+        let mut link = self.link;
+        let read = read(self.handle, &mut samples, &mut link);
+        let read = self.check(read)? as usize;
+        if link != self.link {
+            self.link = link;
+            self.play_data = self.link_play_data(link);
+        }
+        if read == 0 {
+            return Ok(None);
+        }
+
+        samples.truncate(read * self.play_data.channels as usize);
+        Ok(Some(DecodedFrame {
+            samples,
+            timestamp: samples_to_duration(position, OGG_OPUS_SPS),
+            toc: None,
+            info: None,
+        }))
+    }
+
+    // Headers of link `link`, the current one for -1
+    fn link_play_data(&self, link: c_int) -> PlayData {
+        let head = unsafe { &*op_head(self.handle, link) };
+        let tags = unsafe { &*op_tags(self.handle, link) };
+        PlayData {
+            channels: head.channel_count as u16,
+            tags: to_tags(tags),
+            pre_skip: head.pre_skip as u16,
+            input_sample_rate: head.input_sample_rate,
+            output_gain: head.output_gain as i16,
+            channel_mapping_family: head.mapping_family as u8,
+            serial: unsafe { op_serialno(self.handle, link) },
+        }
+    }
+
+    // libopusfile's negative results as errors, a panic in the callbacks
+    // goes on from here
+    fn check<N: Into<i64> + Copy>(&mut self, result: N) -> Result<N, Error> {
+        if let Some(payload) = self.source.panic.take() {
+            panic::resume_unwind(payload);
+        }
+        match result.into() {
+            code if code < 0 => Err(source_error(&mut self.source, code as c_int)),
+            _ => Ok(result),
+        }
+    }
+}
+
+impl<T: Read> Drop for OggOpusFile<T> {
+    fn drop(&mut self) {
+        unsafe { op_free(self.handle) };
+    }
+}
+
+impl<T: Read> Iterator for OggOpusFile<T> {
+    type Item = Result<DecodedFrame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+fn source_error<T>(source: &mut Source<T>, code: c_int) -> Error {
+    if let Some(payload) = source.panic.take() {
+        panic::resume_unwind(payload);
+    }
+    match (code, source.error.take()) {
+        (OP_EREAD, Some(e)) => Error::OggWriteError(e),
+        (OP_ENOTFORMAT, _) => Error::MissingOpusHead,
+        (code, _) => Error::OpusFileError(code),
+    }
+}
+
+// Only until the first link's are read
+fn no_play_data() -> PlayData {
+    PlayData {
+        channels: 0,
+        tags: Tags::default(),
+        pre_skip: 0,
+        input_sample_rate: 0,
+        output_gain: 0,
+        channel_mapping_family: 0,
+        serial: 0,
+    }
+}
+
+fn to_tags(tags: &OpusTags) -> Tags {
+    let string = |data: *const c_char, len: usize| {
+        let bytes = unsafe { slice::from_raw_parts(data as *const u8, len) };
+        String::from_utf8_lossy(bytes).into_owned()
+    };
+    let vendor = if tags.vendor.is_null() {
+        String::new()
+    } else {
+        unsafe { CStr::from_ptr(tags.vendor) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    let comments = (0..tags.comments.max(0) as usize)
+        .map(|i| unsafe {
+            let len = *tags.comment_lengths.add(i);
+            split_comment(string(*tags.user_comments.add(i), len.max(0) as usize))
+        })
+        .collect();
+    Tags { vendor, comments }
+}
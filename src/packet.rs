@@ -0,0 +1,129 @@
+// What the TOC byte at the start of every Opus packet tells (RFC 6716, 3.1),
+// for diagnostics and jitter buffers that need to know what's in a packet
+// without decoding it
+
+use std::time::Duration;
+
+use crate::common::{samples_to_duration, OGG_OPUS_SPS};
+
+/// Coding mode of a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Mode {
+    /// Speech oriented, up to wideband
+    Silk,
+    /// SILK for the low band plus CELT for the rest
+    Hybrid,
+    /// Music oriented, lowest latency
+    Celt,
+}
+
+/// Audio bandwidth of a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Bandwidth {
+    /// 4 kHz
+    Narrow,
+    /// 6 kHz
+    Medium,
+    /// 8 kHz
+    Wide,
+    /// 12 kHz
+    SuperWide,
+    /// 20 kHz
+    Full,
+}
+
+/// What a packet holds, as its TOC byte (and frame count) tells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Toc {
+    pub mode: Mode,
+    pub bandwidth: Bandwidth,
+    /// Every frame in a packet lasts the same, 48 kHz samples
+    pub frame_samples: u32,
+    /// From 1 to 48, 0 only in broken packets
+    pub frames: u8,
+    /// Whether the frames are coded as stereo, on their own it says nothing
+    /// about the channels the stream is decoded to
+    pub stereo: bool,
+}
+
+/// Same as `Toc::parse`
+pub fn parse(packet: &[u8]) -> Option<Toc> {
+    Toc::parse(packet)
+}
+
+impl Toc {
+    /// `None` for an empty packet or one too short for its frame count
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let toc = *packet.first()?;
+        let config = toc >> 3;
+
+        let (mode, bandwidth, frame_samples) = match config {
+            0..=11 => {
+                let bandwidth = match config / 4 {
+                    0 => Bandwidth::Narrow,
+                    1 => Bandwidth::Medium,
+                    _ => Bandwidth::Wide,
+                };
+                (
+                    Mode::Silk,
+                    bandwidth,
+                    [480, 960, 1920, 2880][config as usize % 4],
+                )
+            }
+            12..=15 => {
+                let bandwidth = if config < 14 {
+                    Bandwidth::SuperWide
+                } else {
+                    Bandwidth::Full
+                };
+                (Mode::Hybrid, bandwidth, [480, 960][config as usize % 2])
+            }
+            _ => {
+                let bandwidth = match (config - 16) / 4 {
+                    0 => Bandwidth::Narrow,
+                    1 => Bandwidth::Wide,
+                    2 => Bandwidth::SuperWide,
+                    _ => Bandwidth::Full,
+                };
+                (
+                    Mode::Celt,
+                    bandwidth,
+                    [120, 240, 480, 960][config as usize % 4],
+                )
+            }
+        };
+
+        // Code 3 packets carry their frame count in the next byte
+        let frames = match toc & 0x3 {
+            0 => 1,
+            1 | 2 => 2,
+            _ => packet.get(1)? & 0x3f,
+        };
+
+        Some(Self {
+            mode,
+            bandwidth,
+            frame_samples,
+            frames,
+            stereo: toc & 0x4 != 0,
+        })
+    }
+
+    /// Length of the whole packet, 48 kHz samples
+    pub fn samples(&self) -> u32 {
+        self.frame_samples * self.frames as u32
+    }
+
+    /// Length of each frame, from 2.5 to 60 ms
+    pub fn frame_duration(&self) -> Duration {
+        samples_to_duration(self.frame_samples as u64, OGG_OPUS_SPS)
+    }
+
+    /// Length of the whole packet
+    pub fn duration(&self) -> Duration {
+        samples_to_duration(self.samples() as u64, OGG_OPUS_SPS)
+    }
+}
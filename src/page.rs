@@ -0,0 +1,675 @@
+// Minimal helpers to work with whole Ogg pages straight from bytes, for the
+// places where we need to move pages around without touching packets
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Seek, SeekFrom};
+
+use byteorder::{ByteOrder, LittleEndian};
+use ogg::reading::{BasePacketReader, PageParser};
+use ogg::{Packet, PacketWriteEndInfo, PacketWriter};
+
+use crate::common::OPUS_MAGIC_HEADER;
+use crate::Error;
+
+pub(crate) const CAPTURE_PATTERN: [u8; 4] = [b'O', b'g', b'g', b'S'];
+pub(crate) const HEADER_SIZE: usize = 27;
+
+/// Length of the page at the beginning of `data`, `None` if there's not a
+/// whole page there (yet)
+pub(crate) fn page_len(data: &[u8]) -> Option<usize> {
+    if data.len() < HEADER_SIZE {
+        return None;
+    }
+
+    let num_segments = data[26] as usize;
+    let segments = data.get(HEADER_SIZE..HEADER_SIZE + num_segments)?;
+    let len = HEADER_SIZE + num_segments + segments.iter().map(|&s| s as usize).sum::<usize>();
+
+    if data.len() < len {
+        None
+    } else {
+        Some(len)
+    }
+}
+
+pub(crate) fn is_page(data: &[u8]) -> bool {
+    data.starts_with(&CAPTURE_PATTERN)
+}
+
+pub(crate) fn granule(page: &[u8]) -> u64 {
+    LittleEndian::read_u64(&page[6..14])
+}
+
+/// Turns Ogg bytes into packets as soon as they are available, page by page,
+/// without needing the input to be `Read + Seek`
+pub(crate) struct PacketSplitter {
+    // Bytes pushed, of which the first `read` are done with. They're only
+    // dropped when more come, a page at a time would move all that's left
+    // for every page
+    pending: Vec<u8>,
+    read: usize,
+    reader: BasePacketReader,
+    // Where in the input what's waiting starts, for errors
+    offset: u64,
+    // Sequence number of the last page read
+    sequence: u32,
+    // With a limit on packet sizes, how much of its unfinished packet every
+    // stream has had so far
+    max_packet: Option<usize>,
+    unfinished: HashMap<u32, usize>,
+    conformance: Option<Conformance>,
+}
+
+impl PacketSplitter {
+    pub(crate) fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            read: 0,
+            reader: BasePacketReader::new(),
+            offset: 0,
+            sequence: 0,
+            max_packet: None,
+            unfinished: HashMap::new(),
+            conformance: None,
+        }
+    }
+
+    /// Check every page against RFC 7845's rules on how a stream is laid
+    /// out, see `DecodeOptions::strict`
+    pub(crate) fn strict(&mut self) {
+        self.conformance = Some(Conformance::default());
+    }
+
+    /// With `strict`, fails if a stream seen so far wasn't ended properly,
+    /// for when the input is over
+    pub(crate) fn check_ended(&self) -> Result<(), Error> {
+        match &self.conformance {
+            Some(conformance) => conformance.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Fail with `Error::PacketTooLarge` as soon as a packet goes over `max`
+    /// bytes, before the pages it spans pile up
+    pub(crate) fn limit_packets(&mut self, max: usize) {
+        self.max_packet = Some(max);
+    }
+
+    pub(crate) fn push_bytes(&mut self, data: &[u8]) {
+        self.pending.drain(..self.read);
+        self.read = 0;
+        self.pending.extend_from_slice(data);
+    }
+
+    // What's still to be read
+    fn waiting(&self) -> &[u8] {
+        &self.pending[self.read..]
+    }
+
+    /// Whether there are bytes waiting for the rest of their page
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.waiting().is_empty()
+    }
+
+    /// Whether what's waiting could be the start of a page at all
+    pub(crate) fn pending_page(&self) -> bool {
+        let waiting = self.waiting();
+        let len = waiting.len().min(CAPTURE_PATTERN.len());
+        self.has_pending() && CAPTURE_PATTERN.starts_with(&waiting[..len])
+    }
+
+    /// Forgets everything pushed so far, the next byte pushed starts a page
+    /// at `offset` of the input. A packet continued from before is dropped
+    pub(crate) fn seeked(&mut self, offset: u64) {
+        self.pending.clear();
+        self.read = 0;
+        self.offset = offset;
+        self.reader.update_after_seek();
+        if let Some(conformance) = &mut self.conformance {
+            conformance.resume();
+        }
+    }
+
+    /// Where in the input the bytes waiting start
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Drops what's waiting up to where the next page could start, together
+    /// with any packet left unfinished. How much was dropped
+    pub(crate) fn resync(&mut self) -> u64 {
+        let waiting = self.waiting();
+        let next = waiting[1.min(waiting.len())..]
+            .windows(CAPTURE_PATTERN.len())
+            .position(|w| w == CAPTURE_PATTERN)
+            .map(|pos| pos + 1)
+            // A capture pattern could still be coming at the very end
+            .unwrap_or_else(|| {
+                waiting
+                    .len()
+                    .saturating_sub(CAPTURE_PATTERN.len() - 1)
+                    .max(1.min(waiting.len()))
+            });
+        self.read += next;
+        self.offset += next as u64;
+        self.reader.update_after_seek();
+        next as u64
+    }
+
+    /// Sequence number (index in its stream) of the page `read_page` read
+    /// last
+    pub(crate) fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// Packets finished by the next whole page, `None` if there's no whole
+    /// page yet
+    pub(crate) fn read_page(&mut self) -> Result<Option<Vec<Packet>>, Error> {
+        // Known as soon as there's enough for the capture pattern
+        if !self.pending_page() && self.has_pending() {
+            return Err(Error::LostSync {
+                offset: self.offset,
+            });
+        }
+        let len = match page_len(self.waiting()) {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let data = &self.pending[self.read..self.read + len];
+
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(&data[..HEADER_SIZE]);
+        let (mut parser, num_segments) = PageParser::new(header)?;
+        let body_start = HEADER_SIZE + num_segments;
+        if let Some(max) = self.max_packet {
+            check_sizes(&mut self.unfinished, max, &data[..body_start])?;
+        }
+        if let Some(conformance) = &mut self.conformance {
+            conformance.check(data)?;
+        }
+        parser.parse_segments(data[HEADER_SIZE..body_start].to_vec());
+        let page = parser.parse_packet_data(data[body_start..].to_vec())?;
+        self.sequence = sequence(data);
+        self.read += len;
+        self.offset += len as u64;
+
+        self.reader.push_page(page)?;
+        let mut packets = Vec::new();
+        while let Some(packet) = self.reader.read_packet() {
+            packets.push(packet);
+        }
+        Ok(Some(packets))
+    }
+}
+
+// Goes through the lacing values of a page (`header` being everything up to
+// its body), a packet ends with the first value under 255. `unfinished`
+// keeps how much of its last packet every stream had so far
+fn check_sizes(
+    unfinished: &mut HashMap<u32, usize>,
+    max: usize,
+    header: &[u8],
+) -> Result<(), Error> {
+    let serial = LittleEndian::read_u32(&header[14..18]);
+    let continued = header[5] & 0x1 != 0;
+    let mut len = match unfinished.get(&serial) {
+        Some(&len) if continued => len,
+        _ => 0,
+    };
+    for &value in &header[HEADER_SIZE..] {
+        len += value as usize;
+        if len > max {
+            return Err(Error::PacketTooLarge { size: len });
+        }
+        if value < 255 {
+            len = 0;
+        }
+    }
+    unfinished.insert(serial, len);
+    Ok(())
+}
+
+// Page header flags
+pub(crate) const BOS: u8 = 0x02;
+const EOS: u8 = 0x04;
+
+// Where a stream is in its life, as far as how its pages are laid out goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamState {
+    // Opus stream with its OpusHead read, the OpusTags is next
+    Tags,
+    // Audio, or any other stream than Opus
+    Data,
+    Ended,
+}
+
+// Ogg's and RFC 7845's rules on pages, for `PacketSplitter::strict`
+#[derive(Debug, Default)]
+struct Conformance {
+    streams: HashMap<u32, StreamState>,
+    // After a seek, streams already going are first seen halfway
+    resumed: bool,
+}
+
+impl Conformance {
+    fn check(&mut self, page: &[u8]) -> Result<(), Error> {
+        let serial = LittleEndian::read_u32(&page[14..18]);
+        let flags = page[5];
+        let seq = sequence(page);
+        let lacing = &page[HEADER_SIZE..HEADER_SIZE + page[26] as usize];
+        let body = &page[HEADER_SIZE + lacing.len()..];
+        let header_granule = || match granule(page) {
+            0 => Ok(()),
+            found => Err(Error::HeaderGranule { page: seq, found }),
+        };
+
+        let state = match self.streams.get(&serial) {
+            None if flags & BOS == 0 && self.resumed => StreamState::Data,
+            None if flags & BOS == 0 => return Err(Error::MissingBos { serial }),
+            None if body.starts_with(&OPUS_MAGIC_HEADER) => {
+                // The whole OpusHead and nothing else
+                if !header_ends(lacing, seq)? {
+                    return Err(Error::HeaderNotAlone { page: seq });
+                }
+                header_granule()?;
+                StreamState::Tags
+            }
+            None => StreamState::Data,
+            Some(_) if flags & BOS != 0 => return Err(Error::UnexpectedBos { serial }),
+            Some(StreamState::Ended) => return Err(Error::PageAfterEos { serial }),
+            Some(StreamState::Tags) => {
+                let state = match header_ends(lacing, seq)? {
+                    true => StreamState::Data,
+                    false => StreamState::Tags,
+                };
+                header_granule()?;
+                state
+            }
+            Some(&state) => state,
+        };
+        let state = match flags & EOS {
+            0 => state,
+            _ => StreamState::Ended,
+        };
+        self.streams.insert(serial, state);
+        Ok(())
+    }
+
+    // Streams seen so far may be ended or not, new ones still have to start
+    // properly
+    fn resume(&mut self) {
+        self.streams.clear();
+        self.resumed = true;
+    }
+
+    fn finish(&self) -> Result<(), Error> {
+        let open = self
+            .streams
+            .iter()
+            .filter(|(_, &state)| state != StreamState::Ended)
+            .map(|(&serial, _)| serial)
+            .min();
+        match open {
+            Some(serial) => Err(Error::MissingEos { serial }),
+            None => Ok(()),
+        }
+    }
+}
+
+// Whether a header packet still going on a page (going by its lacing
+// values) finishes there, it has to be the last thing on it if so
+fn header_ends(lacing: &[u8], page: u32) -> Result<bool, Error> {
+    match lacing.iter().position(|&value| value < 255) {
+        Some(end) if end + 1 < lacing.len() => Err(Error::HeaderNotAlone { page }),
+        end => Ok(end.is_some()),
+    }
+}
+
+/// Packets of the first Opus stream in `data`, read as they are needed.
+/// Other multiplexed streams are skipped, as when decoding
+pub(crate) struct OpusPackets<T: Read> {
+    data: T,
+    splitter: PacketSplitter,
+    packets: VecDeque<Packet>,
+    serial: Option<u32>,
+}
+
+impl<T: Read> OpusPackets<T> {
+    pub(crate) fn new(data: T) -> Self {
+        Self {
+            data,
+            splitter: PacketSplitter::new(),
+            packets: VecDeque::new(),
+            serial: None,
+        }
+    }
+
+    #[cfg(feature = "symphonia")]
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+
+    #[cfg(feature = "symphonia")]
+    pub(crate) fn into_inner(self) -> T {
+        self.data
+    }
+
+    #[cfg(feature = "symphonia")]
+    /// Forgets what was read ahead, for when `data` was sought to a page
+    /// starting at `offset`. The stream stays the same one
+    pub(crate) fn seeked(&mut self, offset: u64) {
+        self.splitter.seeked(offset);
+        self.packets.clear();
+    }
+
+    /// `None` at the end of the input, bytes left without making a whole page
+    /// are `MalformedAudio`
+    pub(crate) fn next_packet(&mut self) -> Result<Option<Packet>, Error> {
+        loop {
+            while let Some(packet) = self.packets.pop_front() {
+                match self.serial {
+                    Some(serial) if packet.stream_serial() != serial => {}
+                    Some(_) => return Ok(Some(packet)),
+                    None if packet.first_in_stream()
+                        && !packet.data.starts_with(&OPUS_MAGIC_HEADER) => {}
+                    None => {
+                        self.serial = Some(packet.stream_serial());
+                        return Ok(Some(packet));
+                    }
+                }
+            }
+
+            match self.splitter.read_page()? {
+                Some(packets) => self.packets.extend(packets),
+                None => {
+                    let mut buffer = [0u8; 4096];
+                    let read = self.data.read(&mut buffer)?;
+                    if read == 0 {
+                        return if self.splitter.has_pending() {
+                            Err(Error::MalformedAudio)
+                        } else {
+                            Ok(None)
+                        };
+                    }
+                    self.splitter.push_bytes(&buffer[..read]);
+                }
+            }
+        }
+    }
+}
+
+// Ogg's CRC32: polynomial 0x04c11db7, no reflection, starting from 0
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut r = (i as u32) << 24;
+        let mut j = 0;
+        while j < 8 {
+            r = if r & 0x8000_0000 != 0 {
+                (r << 1) ^ 0x04c1_1db7
+            } else {
+                r << 1
+            };
+            j += 1;
+        }
+        table[i] = r;
+        i += 1;
+    }
+    table
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &b| {
+        (crc << 8) ^ CRC_TABLE[(((crc >> 24) as u8) ^ b) as usize]
+    })
+}
+
+pub(crate) fn sequence(page: &[u8]) -> u32 {
+    LittleEndian::read_u32(&page[18..22])
+}
+
+/// Changes the sequence number of a page, fixing its checksum
+pub(crate) fn set_sequence(page: &mut [u8], seq: u32) {
+    LittleEndian::write_u32(&mut page[18..22], seq);
+    update_checksum(page);
+}
+
+pub(crate) fn update_checksum(page: &mut [u8]) {
+    page[22..26].copy_from_slice(&[0; 4]);
+    let crc = crc32(page);
+    LittleEndian::write_u32(&mut page[22..26], crc);
+}
+
+/// Where a page is in a seekable input and what's needed to seek with it
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PageInfo {
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+    pub(crate) serial: u32,
+    pub(crate) granule: u64,
+    // No packet goes on into the next page
+    pub(crate) ends_clean: bool,
+}
+
+impl PageInfo {
+    pub(crate) fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+/// First valid page starting at or after `from` and ending before `end`,
+/// garbage in between (or a capture pattern inside some packet) is skipped
+pub(crate) fn next_page<R: Read + Seek>(
+    data: &mut R,
+    from: u64,
+    end: u64,
+) -> Result<Option<PageInfo>, Error> {
+    let mut block = [0u8; 4096];
+    let mut offset = from;
+    while offset + HEADER_SIZE as u64 <= end {
+        data.seek(SeekFrom::Start(offset))?;
+        let len = read_up_to(data, &mut block[..(end - offset).min(4096) as usize])?;
+        for i in 0..len.saturating_sub(CAPTURE_PATTERN.len() - 1) {
+            if block[i..].starts_with(&CAPTURE_PATTERN) {
+                if let Some(page) = page_at(data, offset + i as u64, end)? {
+                    return Ok(Some(page));
+                }
+            }
+        }
+        // The pattern might be cut by the end of the block
+        offset += len.saturating_sub(CAPTURE_PATTERN.len() - 1).max(1) as u64;
+    }
+    Ok(None)
+}
+
+// The page at `offset`, if there's one whose checksum matches
+fn page_at<R: Read + Seek>(data: &mut R, offset: u64, end: u64) -> Result<Option<PageInfo>, Error> {
+    data.seek(SeekFrom::Start(offset))?;
+    let mut page = vec![0u8; HEADER_SIZE + 255];
+    let read = read_up_to(data, &mut page)?;
+    let len = match page.get(26) {
+        Some(&segments) if read >= HEADER_SIZE + segments as usize => {
+            let segments = &page[HEADER_SIZE..HEADER_SIZE + segments as usize];
+            HEADER_SIZE + segments.len() + segments.iter().map(|&s| s as usize).sum::<usize>()
+        }
+        _ => return Ok(None),
+    };
+    if offset + len as u64 > end {
+        return Ok(None);
+    }
+
+    page.resize(len, 0);
+    data.seek(SeekFrom::Start(offset))?;
+    data.read_exact(&mut page)?;
+    Ok(check_page(offset, &mut page))
+}
+
+// What's known of the whole `page` found at `offset`, if its checksum
+// matches. The checksum in it gets overwritten
+pub(crate) fn check_page(offset: u64, page: &mut [u8]) -> Option<PageInfo> {
+    let crc = LittleEndian::read_u32(&page[22..26]);
+    update_checksum(page);
+    if LittleEndian::read_u32(&page[22..26]) != crc {
+        return None;
+    }
+
+    let num_segments = page[26] as usize;
+    Some(PageInfo {
+        offset,
+        len: page.len() as u64,
+        serial: LittleEndian::read_u32(&page[14..18]),
+        granule: granule(page),
+        ends_clean: num_segments == 0 || page[HEADER_SIZE + num_segments - 1] < 255,
+    })
+}
+
+// Like `read_exact`, but a short read at the end of the input is fine
+fn read_up_to<R: Read>(data: &mut R, buffer: &mut [u8]) -> Result<usize, Error> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match data.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// Splits a whole stream in its pages
+pub(crate) fn split_pages(mut data: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let mut pages = Vec::new();
+    while !data.is_empty() {
+        let len = page_len(data).ok_or(Error::MalformedAudio)?;
+        if !is_page(data) {
+            return Err(Error::MalformedAudio);
+        }
+        pages.push(&data[..len]);
+        data = &data[len..];
+    }
+    Ok(pages)
+}
+
+fn page_serial(page: &[u8]) -> u32 {
+    LittleEndian::read_u32(&page[14..18])
+}
+
+fn lacing(page: &[u8]) -> &[u8] {
+    &page[HEADER_SIZE..HEADER_SIZE + page[26] as usize]
+}
+
+/// Rewrites the ID and comment headers of the first Opus stream, copying
+/// the audio pages untouched except for their sequence numbers (when the
+/// number of header pages changes). Pages of other streams multiplexed with
+/// it, and whatever is chained after it, are copied as they are
+pub(crate) fn replace_headers(stream: &[u8], head: &[u8], tags: &[u8]) -> Result<Vec<u8>, Error> {
+    let pages = split_pages(stream)?;
+    let first = pages
+        .iter()
+        .position(|page| {
+            page[5] & BOS != 0
+                && page[HEADER_SIZE + page[26] as usize..].starts_with(&OPUS_MAGIC_HEADER)
+        })
+        .ok_or(Error::MissingOpusHead)?;
+    let serial = page_serial(pages[first]);
+
+    // Last page with a header in it, and where in its lacing values the
+    // comment header ends
+    let mut finished = 0;
+    let mut old_header_pages = 0;
+    let mut last = None;
+    for (i, page) in pages.iter().enumerate().skip(first) {
+        if page_serial(page) != serial {
+            continue;
+        }
+        old_header_pages += 1;
+        for (segment, &value) in lacing(page).iter().enumerate() {
+            if value < 255 {
+                finished += 1;
+                if finished == 2 {
+                    last = Some((i, segment + 1));
+                    break;
+                }
+            }
+        }
+        if last.is_some() {
+            break;
+        }
+    }
+    let (last, tags_end) = last.ok_or(Error::MalformedAudio)?;
+
+    let mut new_headers = Vec::new();
+    let mut writer = PacketWriter::new(&mut new_headers);
+    writer.write_packet(head.to_vec(), serial, PacketWriteEndInfo::EndPage, 0)?;
+    writer.write_packet(tags.to_vec(), serial, PacketWriteEndInfo::EndPage, 0)?;
+    let mut new_pages: Vec<Vec<u8>> = split_pages(&new_headers)?
+        .into_iter()
+        .map(<[u8]>::to_vec)
+        .collect();
+    let mut new_header_pages = new_pages.len() as u32;
+
+    // Audio sharing the last page with the comment header goes on a page of
+    // its own, keeping that page's flags and granule
+    let last_page = pages[last];
+    let last_lacing = lacing(last_page);
+    let leftover = (tags_end < last_lacing.len()).then(|| {
+        let body_start = HEADER_SIZE + last_lacing.len();
+        let skipped: usize = last_lacing[..tags_end].iter().map(|&v| v as usize).sum();
+        let mut page = last_page[..26].to_vec();
+        page[5] &= !(0x01 | BOS);
+        page.push((last_lacing.len() - tags_end) as u8);
+        page.extend_from_slice(&last_lacing[tags_end..]);
+        page.extend_from_slice(&last_page[body_start + skipped..]);
+        set_sequence(&mut page, new_header_pages);
+        new_header_pages += 1;
+        page
+    });
+    // A stream with no audio at all ends on its headers
+    if leftover.is_none() && last_page[5] & EOS != 0 {
+        let end = new_pages.last_mut().unwrap();
+        end[5] |= EOS;
+        update_checksum(end);
+    }
+    let old_header_pages = old_header_pages as u32;
+
+    let mut out = Vec::with_capacity(stream.len() + new_headers.len());
+    // Only pages of this link get renumbered, a chained stream after it
+    // starts over
+    let mut in_link = true;
+    for (i, page) in pages.iter().enumerate() {
+        if i < first || !in_link || page_serial(page) != serial {
+            out.extend_from_slice(page);
+            continue;
+        }
+        if i == first {
+            out.extend_from_slice(&new_pages[0]);
+        }
+        if i == last {
+            for page in &new_pages[1..] {
+                out.extend_from_slice(page);
+            }
+            if let Some(leftover) = &leftover {
+                out.extend_from_slice(leftover);
+            }
+        }
+        if i <= last {
+            in_link = page[5] & EOS == 0;
+            continue;
+        }
+
+        let start = out.len();
+        out.extend_from_slice(page);
+        if new_header_pages != old_header_pages {
+            // Sequence numbers wrap around in Ogg
+            let seq = sequence(page)
+                .wrapping_sub(old_header_pages)
+                .wrapping_add(new_header_pages);
+            set_sequence(&mut out[start..], seq);
+        }
+        in_link = page[5] & EOS == 0;
+    }
+
+    Ok(out)
+}
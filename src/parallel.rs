@@ -0,0 +1,102 @@
+// Long inputs cut in segments that get encoded each on its own thread, for
+// batch transcoding where a single encoder can't keep up
+
+use std::panic;
+use std::thread;
+
+use crate::encode::{prepare, EncoderCore};
+use crate::{EncoderOptions, Error};
+
+// Frames every segment encoder goes through before its own audio and throws
+// away, so that its state has settled by the time it gets there
+const WARMUP_FRAMES: usize = 10;
+// Below this many frames a segment isn't worth a thread
+const MIN_SEGMENT_FRAMES: usize = 50;
+
+/**Same as `encode_with_options`, split over up to `threads` threads
+(`std::thread::available_parallelism` is a good pick). Each one encodes a
+stretch of the input with its own encoder, which first goes through the
+audio right before it and drops those packets, and the packets are then
+written as a single stream. The result is the same length and plays the
+same as a regular encode, though not byte for byte. Short inputs are
+encoded on a single thread.*/
+pub fn encode_parallel<const S_PS: u32, const NUM_CHANNELS: u8>(
+    audio: &[i16],
+    options: &EncoderOptions,
+    threads: usize,
+) -> Result<Vec<u8>, Error> {
+    let prepared = prepare::<_, S_PS, NUM_CHANNELS>(audio, options);
+    let audio = prepared.audio();
+    let mut stream =
+        EncoderCore::<S_PS, NUM_CHANNELS>::with_options(&prepared.tags, &prepared.options)?;
+
+    // Whole frames, what's left over goes with the last segment
+    let frames = (audio.len() + stream.lookahead_samples()) / stream.frame_samples();
+    let segments = threads.min(frames / MIN_SEGMENT_FRAMES).max(1);
+    if segments == 1 {
+        stream.plan_bitrates(audio);
+        stream.push(audio)?;
+        return Ok(stream.finish()?.0);
+    }
+    let bounds: Vec<usize> = (0..=segments).map(|i| i * frames / segments).collect();
+
+    let options = &prepared.options;
+    let encoded = thread::scope(|scope| {
+        let workers: Vec<_> = bounds
+            .windows(2)
+            .map(|range| {
+                let (first, end) = (range[0], range[1]);
+                let last = end == frames;
+                scope.spawn(move || {
+                    encode_segment::<S_PS, NUM_CHANNELS>(audio, options, first, end, last)
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+
+    // Everything is already encoded, the lookahead included
+    stream.resume_at(0);
+    for packets in encoded {
+        stream.write_encoded(packets)?;
+    }
+    Ok(stream.finish()?.0)
+}
+
+// Packets for frames `first..end`, to the end of the input if `last`
+fn encode_segment<const S_PS: u32, const NUM_CHANNELS: u8>(
+    audio: &[i16],
+    options: &EncoderOptions,
+    first: usize,
+    end: usize,
+    last: bool,
+) -> Result<Vec<(Vec<u8>, u64)>, Error> {
+    let mut encoder = EncoderCore::<S_PS, NUM_CHANNELS>::configure(options)?;
+    encoder.plan_bitrates(audio);
+    let frame = encoder.frame_samples();
+    let lookahead = encoder.lookahead_samples();
+    // Where a frame starts in the input, the first one begins with the
+    // lookahead silence instead
+    let start_of = |index: usize| (index * frame).saturating_sub(lookahead);
+
+    // Starting from the beginning keeps the lookahead the encoder begins
+    // with, otherwise it's dropped
+    let warmup = match first.saturating_sub(WARMUP_FRAMES) {
+        index if index * frame >= lookahead => {
+            encoder.resume_at(index as u64);
+            index
+        }
+        _ => 0,
+    };
+    encoder.encode_frames(&audio[start_of(warmup)..start_of(first)])?;
+
+    let until = if last { audio.len() } else { start_of(end) };
+    let mut packets = encoder.encode_frames(&audio[start_of(first)..until])?;
+    if last {
+        packets.extend(encoder.finish_frames(false)?);
+    }
+    Ok(packets)
+}
@@ -0,0 +1,43 @@
+// Waveform previews for messaging and editor UIs, decoded a frame at a time
+// so that only the peaks are ever kept
+
+use std::io::{Read, Seek};
+
+use crate::{Decoder, Error};
+
+// Plenty for drawing, and cheaper to decode than 48 kHz
+const PEAKS_SPS: u32 = 16000;
+
+/**Lowest and highest sample (of any channel) in each of `buckets` equal
+stretches of the audio, for drawing its waveform. The length comes from the
+last page, then the audio is decoded a frame at a time, so memory stays the
+same however long the file is. Buckets with no audio at all are (0, 0)*/
+pub fn peaks<T: Read + Seek>(data: T, buckets: usize) -> Result<Vec<(i16, i16)>, Error> {
+    let mut decoder = Decoder::<T, PEAKS_SPS>::new(data)?;
+    if buckets == 0 {
+        return Ok(Vec::new());
+    }
+    let total = decoder.total_samples()?.max(1);
+    let channels = decoder.play_data().channels.max(1) as usize;
+
+    let mut peaks: Vec<Option<(i16, i16)>> = vec![None; buckets];
+    let mut position = 0;
+    while let Some(frame) = decoder.next_frame()? {
+        for samples in frame.samples.chunks_exact(channels) {
+            let bucket = ((position * buckets as u64 / total) as usize).min(buckets - 1);
+            let (low, high) = samples
+                .iter()
+                .fold((i16::MAX, i16::MIN), |(low, high), &s| {
+                    (low.min(s), high.max(s))
+                });
+            peaks[bucket] = Some(match peaks[bucket] {
+                Some((min, max)) => (min.min(low), max.max(high)),
+                None => (low, high),
+            });
+            position += 1;
+        }
+        decoder.recycle(frame);
+    }
+
+    Ok(peaks.into_iter().map(|p| p.unwrap_or((0, 0))).collect())
+}
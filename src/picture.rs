@@ -0,0 +1,152 @@
+// Cover art as Vorbis comments carry it: a FLAC picture block, base64 encoded
+// in the METADATA_BLOCK_PICTURE comment
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::Error;
+
+pub(crate) const PICTURE_TAG: &str = "METADATA_BLOCK_PICTURE";
+
+/// Picture type of a front cover, the one players show
+pub const FRONT_COVER: u32 = 3;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Picture embedded in the tags, fields as in FLAC's picture block
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Picture {
+    /// What it shows (ID3v2 APIC types, `FRONT_COVER` ...)
+    pub picture_type: u32,
+    /// `image/png`, `image/jpeg` ...
+    pub mime: String,
+    pub description: String,
+    /// Size in pixels, bits per pixel and colors used (indexed images only),
+    /// 0 if unknown
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+    pub colors: u32,
+    /// The image file itself
+    pub data: Vec<u8>,
+}
+
+impl Picture {
+    /// A front cover, without any of the optional fields
+    pub fn front_cover<M: Into<String>>(mime: M, data: Vec<u8>) -> Self {
+        Self {
+            picture_type: FRONT_COVER,
+            mime: mime.into(),
+            description: String::new(),
+            width: 0,
+            height: 0,
+            depth: 0,
+            colors: 0,
+            data,
+        }
+    }
+
+    /// From the value of a `METADATA_BLOCK_PICTURE` comment
+    pub fn parse(base64: &str) -> Result<Self, Error> {
+        let block = decode_base64(base64.trim()).ok_or(Error::InvalidPicture)?;
+        let mut data = block.as_slice();
+        let picture_type = read_u32(&mut data)?;
+        let mime = read_string(&mut data)?;
+        let description = read_string(&mut data)?;
+        let width = read_u32(&mut data)?;
+        let height = read_u32(&mut data)?;
+        let depth = read_u32(&mut data)?;
+        let colors = read_u32(&mut data)?;
+        let len = read_u32(&mut data)? as usize;
+
+        Ok(Self {
+            picture_type,
+            mime,
+            description,
+            width,
+            height,
+            depth,
+            colors,
+            data: data.get(..len).ok_or(Error::InvalidPicture)?.to_vec(),
+        })
+    }
+
+    /// As the value of a `METADATA_BLOCK_PICTURE` comment
+    pub fn to_base64(&self) -> String {
+        let mut block = Vec::with_capacity(32 + self.mime.len() + self.description.len());
+        push_u32(&mut block, self.picture_type);
+        push_bytes(&mut block, self.mime.as_bytes());
+        push_bytes(&mut block, self.description.as_bytes());
+        for value in [self.width, self.height, self.depth, self.colors] {
+            push_u32(&mut block, value);
+        }
+        push_bytes(&mut block, &self.data);
+        encode_base64(&block)
+    }
+}
+
+fn read_u32(data: &mut &[u8]) -> Result<u32, Error> {
+    if data.len() < 4 {
+        return Err(Error::InvalidPicture);
+    }
+    let value = BigEndian::read_u32(data);
+    *data = &data[4..];
+    Ok(value)
+}
+
+fn read_string(data: &mut &[u8]) -> Result<String, Error> {
+    let len = read_u32(data)? as usize;
+    let bytes = data.get(..len).ok_or(Error::InvalidPicture)?;
+    *data = &data[len..];
+    String::from_utf8(bytes.to_vec()).map_err(|_| Error::InvalidPicture)
+}
+
+fn push_u32(block: &mut Vec<u8>, value: u32) {
+    block.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_bytes(block: &mut Vec<u8>, bytes: &[u8]) {
+    push_u32(block, bytes.len() as u32);
+    block.extend_from_slice(bytes);
+}
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// `None` on anything that isn't padded base64
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let chunks = text.as_bytes().chunks_exact(4);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    for chunk in chunks {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let value = BASE64.iter().position(|&b| b == c)? as u32;
+            bits = bits << 6 | value;
+        }
+        bits <<= 6 * padding as u32;
+        out.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
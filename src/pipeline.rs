@@ -0,0 +1,105 @@
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crate::encode::{EncodeReport, StreamEncoder};
+use crate::{Error, Tags};
+
+// Chunks of audio waiting for the encoder, past this, pushing blocks
+const DEFAULT_CAPACITY: usize = 32;
+
+/// Sets up a `Pipeline`
+#[derive(Debug, Clone)]
+pub struct PipelineBuilder {
+    capacity: usize,
+    tags: Tags,
+}
+
+impl Default for PipelineBuilder {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CAPACITY,
+            tags: Tags::default(),
+        }
+    }
+}
+
+impl PipelineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many chunks can be waiting for the encoder before `push` blocks,
+    /// so that a slow sink slows the capture instead of eating memory
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn tags(mut self, tags: Tags) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Writes the headers to `sink` and starts the encoding thread
+    pub fn spawn<W, const S_PS: u32, const NUM_CHANNELS: u8>(
+        self,
+        sink: W,
+    ) -> Result<Pipeline<S_PS, NUM_CHANNELS>, Error>
+    where
+        W: Write + Send + 'static,
+    {
+        let encoder = StreamEncoder::<W, S_PS, NUM_CHANNELS>::with_tags(sink, &self.tags)?;
+        let (sender, receiver) = mpsc::sync_channel(self.capacity);
+        let handle = thread::spawn(move || run(encoder, receiver));
+
+        Ok(Pipeline { sender, handle })
+    }
+}
+
+/**Capture → encode → sink, with the encoder on its own thread. Audio pushed
+(from any thread, see `sender`) gets encoded and written to the sink as it
+comes. Call `finish` to end the stream: every sender has to be gone by then,
+the encoder waits for all the audio before writing the end of the stream.*/
+pub struct Pipeline<const S_PS: u32, const NUM_CHANNELS: u8> {
+    sender: SyncSender<Vec<i16>>,
+    handle: JoinHandle<Result<EncodeReport, Error>>,
+}
+
+impl<const S_PS: u32, const NUM_CHANNELS: u8> Pipeline<S_PS, NUM_CHANNELS> {
+    /// Queues interleaved audio, blocks if the encoder is `capacity` chunks
+    /// behind. Fails if the encoder stopped, `finish` tells why
+    pub fn push(&self, audio: Vec<i16>) -> Result<(), Error> {
+        self.sender.send(audio).map_err(|_| Error::PipelineClosed)
+    }
+
+    /// Another handle to push audio from, e.g. for a capture callback
+    pub fn sender(&self) -> SyncSender<Vec<i16>> {
+        self.sender.clone()
+    }
+
+    /// Waits for everything queued to be encoded, ends the stream and
+    /// flushes the sink
+    pub fn finish(self) -> Result<EncodeReport, Error> {
+        drop(self.sender);
+        match self.handle.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+fn run<W: Write, const S_PS: u32, const NUM_CHANNELS: u8>(
+    mut encoder: StreamEncoder<W, S_PS, NUM_CHANNELS>,
+    receiver: Receiver<Vec<i16>>,
+) -> Result<EncodeReport, Error> {
+    // Ends once every sender is dropped
+    for audio in receiver {
+        encoder.push_samples(&audio)?;
+    }
+
+    let finished = encoder.finish()?;
+    let report = finished.report();
+    finished.into_inner().flush()?;
+    Ok(report)
+}
@@ -0,0 +1,94 @@
+// Decoded audio as a byte stream, for whatever takes an `io::Read` (audio
+// sinks, WAV writers, a subprocess' stdin ...)
+
+use std::io::{self, Read};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::common::into_io;
+use crate::decode::{i24_bytes, to_i24};
+use crate::{Decoder, Error, PcmFormat, PlayData};
+
+/**Decodes as it's read, giving raw interleaved little endian PCM (laid out
+as `decode_pcm_bytes` does, `f32` and 24 bits straight from libopus' float
+decoder) without the whole audio ever being in memory. Decoding errors come
+out as `io::ErrorKind::InvalidData`.*/
+pub struct DecodedReader<T: Read, const TARGET_SPS: u32> {
+    decoder: Decoder<T, TARGET_SPS>,
+    format: PcmFormat,
+    // Last frame as bytes, and how much of it was read already
+    bytes: Vec<u8>,
+    read: usize,
+}
+
+impl<T: Read, const TARGET_SPS: u32> DecodedReader<T, TARGET_SPS> {
+    /// Reads the headers, audio comes as `i16`
+    pub fn new(data: T) -> Result<Self, Error> {
+        Self::with_format(data, PcmFormat::I16)
+    }
+
+    pub fn with_format(data: T, format: PcmFormat) -> Result<Self, Error> {
+        Ok(Self {
+            decoder: Decoder::new(data)?,
+            format,
+            bytes: Vec::new(),
+            read: 0,
+        })
+    }
+
+    /// Channels and such, to know how to play the bytes
+    pub fn play_data(&self) -> &PlayData {
+        self.decoder.play_data()
+    }
+
+    // Puts the next frame in `bytes`, false at the end
+    fn next_frame(&mut self) -> Result<bool, Error> {
+        self.read = 0;
+        match self.format {
+            PcmFormat::I16 => {
+                let frame = match self.decoder.next_frame()? {
+                    Some(frame) => frame,
+                    None => return Ok(false),
+                };
+                self.bytes.resize(frame.samples.len() * 2, 0);
+                LittleEndian::write_i16_into(&frame.samples, &mut self.bytes);
+                self.decoder.recycle(frame);
+            }
+            PcmFormat::F32 => {
+                let frame = match self.decoder.next_frame_float()? {
+                    Some(frame) => frame,
+                    None => return Ok(false),
+                };
+                self.bytes.resize(frame.samples.len() * 4, 0);
+                LittleEndian::write_f32_into(&frame.samples, &mut self.bytes);
+            }
+            PcmFormat::I24 => {
+                let frame = match self.decoder.next_frame_float()? {
+                    Some(frame) => frame,
+                    None => return Ok(false),
+                };
+                let samples: Vec<i32> = frame.samples.iter().map(|&s| to_i24(s)).collect();
+                self.bytes = i24_bytes(&samples);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<T: Read, const TARGET_SPS: u32> Read for DecodedReader<T, TARGET_SPS> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Frames might be empty after trimming
+        while self.read == self.bytes.len() {
+            if !self.next_frame().map_err(into_io)? {
+                self.bytes.clear();
+                self.read = 0;
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.bytes.len() - self.read);
+        buf[..len].copy_from_slice(&self.bytes[self.read..self.read + len]);
+        self.read += len;
+        Ok(len)
+    }
+}
@@ -0,0 +1,189 @@
+// Opus packets in and out of Ogg as they are, for audio that's already
+// encoded somewhere else (WebRTC, Discord ...) or that's going there
+
+use std::io::Read;
+use std::time::Duration;
+
+use ogg::{PacketWriteEndInfo, PacketWriter};
+
+use crate::common::*;
+use crate::headers::OpusHead;
+use crate::packet::Toc;
+use crate::page::OpusPackets;
+use crate::{Error, Tags};
+
+/// A single Opus packet, just as it goes over the network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpusPacket {
+    pub data: Vec<u8>,
+}
+
+impl OpusPacket {
+    /// How much audio it carries, from its TOC byte, `None` if it's empty or
+    /// too short for what the TOC says
+    pub fn duration(&self) -> Option<Duration> {
+        let toc = Toc::parse(&self.data)?;
+        Some(samples_to_duration(toc.samples() as u64, OGG_OPUS_SPS))
+    }
+}
+
+impl From<Vec<u8>> for OpusPacket {
+    fn from(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+}
+
+/// What goes into the headers of a stream, packets alone don't tell
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MuxMeta {
+    /// 1 or 2
+    pub channels: u8,
+    /// 48 kHz samples to drop from the start, the encoder lookahead
+    pub pre_skip: u16,
+    /// Rate of the audio before encoding, informational only
+    pub input_sps: u32,
+    /// Gain to apply when decoding, in 1/256 dB
+    pub output_gain: i16,
+    pub tags: Tags,
+    /// Stream serial, a random one if `None`
+    pub serial: Option<u32>,
+}
+
+impl Default for MuxMeta {
+    /// Stereo, as WebRTC always signals, with the 6.5 ms of lookahead that
+    /// libopus has unless asked for low delay
+    fn default() -> Self {
+        Self {
+            channels: 2,
+            pre_skip: 312,
+            input_sps: OGG_OPUS_SPS,
+            output_gain: 0,
+            tags: Tags::default(),
+            serial: None,
+        }
+    }
+}
+
+/// Wraps already encoded packets in Ogg, without decoding them. Granule
+/// positions come from each packet's TOC, so no end trimming is written: the
+/// last packet plays whole
+pub fn mux_packets(
+    packets: impl IntoIterator<Item = OpusPacket>,
+    meta: &MuxMeta,
+) -> Result<Vec<u8>, Error> {
+    if meta.channels != 1 && meta.channels != 2 {
+        return Err(Error::InvalidChannels);
+    }
+
+    let serial = meta.serial.unwrap_or_else(new_serial);
+    let mut out = Vec::new();
+    let mut writer = PacketWriter::new(&mut out);
+    let head = opus_head(
+        meta.channels,
+        meta.pre_skip,
+        meta.input_sps,
+        meta.output_gain,
+    );
+    writer.write_packet(head, serial, PacketWriteEndInfo::EndPage, 0)?;
+
+    // Every packet waits for the next one to know whether it's the last
+    let mut held = None;
+    let mut granule = 0u64;
+    for packet in packets {
+        let toc = Toc::parse(&packet.data).ok_or(Error::MalformedAudio)?;
+        match held.replace((packet.data, granule + toc.samples() as u64)) {
+            Some((data, end)) => {
+                writer.write_packet(data, serial, PacketWriteEndInfo::NormalPacket, end)?
+            }
+            None => writer.write_packet(
+                meta.tags.to_packet(),
+                serial,
+                PacketWriteEndInfo::EndPage,
+                0,
+            )?,
+        }
+        granule += toc.samples() as u64;
+    }
+
+    match held {
+        Some((data, end)) => {
+            writer.write_packet(data, serial, PacketWriteEndInfo::EndStream, end)?
+        }
+        // Just the headers
+        None => writer.write_packet(
+            meta.tags.to_packet(),
+            serial,
+            PacketWriteEndInfo::EndStream,
+            0,
+        )?,
+    }
+
+    Ok(out)
+}
+
+/// Reads the headers of an Ogg Opus stream, the packets are then taken out
+/// one by one as they are, without decoding. Mono and stereo only
+pub fn demux<T: Read>(data: T) -> Result<Demuxer<T>, Error> {
+    let mut demuxer = Demuxer {
+        packets: OpusPackets::new(data),
+        meta: MuxMeta::default(),
+        ended: false,
+    };
+
+    let head = demuxer
+        .packets
+        .next_packet()?
+        .ok_or(Error::MissingOpusHead)?;
+    let head = OpusHead::from_packet(&head.data)?;
+    if head.channel_mapping_family != 0 {
+        return Err(Error::InvalidChannels);
+    }
+    demuxer.meta.channels = head.channels;
+    demuxer.meta.pre_skip = head.pre_skip;
+    demuxer.meta.input_sps = head.input_sample_rate;
+    demuxer.meta.output_gain = head.output_gain;
+
+    let tags = demuxer
+        .packets
+        .next_packet()?
+        .ok_or(Error::MissingOpusTags)?;
+    demuxer.meta.tags = Tags::from_packet(&tags.data)?;
+
+    Ok(demuxer)
+}
+
+/// Packets of a stream opened with `demux`, in order
+pub struct Demuxer<T: Read> {
+    packets: OpusPackets<T>,
+    meta: MuxMeta,
+    ended: bool,
+}
+
+impl<T: Read> Demuxer<T> {
+    /// Headers of the stream, enough to `mux_packets` it back
+    pub fn meta(&self) -> &MuxMeta {
+        &self.meta
+    }
+}
+
+impl<T: Read> Iterator for Demuxer<T> {
+    type Item = Result<OpusPacket, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ended {
+            return None;
+        }
+        match self.packets.next_packet() {
+            Ok(Some(packet)) => {
+                self.ended = packet.last_in_stream();
+                Some(Ok(OpusPacket::from(packet.data)))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                self.ended = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// Going to and from rates Opus doesn't work at (44.1 kHz above all), with a
+// windowed sinc, good enough for voice and music without pulling in a DSP
+// crate. Audio goes through f32 whatever it comes as
+
+use std::io::Read;
+
+use crate::common::Sample;
+use crate::{decode_at, encode_at, EncoderOptions, Error, PlayData, SampleRate};
+
+// Zero crossings of the sinc on each side, more is sharper but slower
+const ZERO_CROSSINGS: f64 = 16.0;
+// Where the lowpass starts, as a fraction of the lowest Nyquist, leaving room
+// for the transition band
+const ROLLOFF: f64 = 0.94;
+
+/// Changes the rate of interleaved audio from `from` to `to` (both in Hz),
+/// anything goes, not only what Opus takes
+pub fn resample(audio: &[i16], channels: u8, from: u32, to: u32) -> Vec<i16> {
+    resample_as(audio, channels, from, to)
+}
+
+/// Same as `resample`, for `f32` audio
+pub fn resample_float(audio: &[f32], channels: u8, from: u32, to: u32) -> Vec<f32> {
+    resample_as(audio, channels, from, to)
+}
+
+fn resample_as<S: Sample>(audio: &[S], channels: u8, from: u32, to: u32) -> Vec<S> {
+    let channels = channels.max(1) as usize;
+    if from == to || from == 0 || to == 0 {
+        return audio.to_vec();
+    }
+
+    let frames = audio.len() / channels;
+    let out_frames = (frames as u64 * to as u64).div_ceil(from as u64) as usize;
+
+    // Relative to the input rate, the kernel is widened when going down so
+    // that it filters out what wouldn't fit
+    let cutoff = ROLLOFF * (to as f64 / from as f64).min(1.0);
+    let half_width = ZERO_CROSSINGS / cutoff;
+    let reach = half_width.ceil() as i64;
+
+    let mut out = Vec::with_capacity(out_frames * channels);
+    let mut acc = vec![0.0f64; channels];
+    for n in 0..out_frames {
+        // Exact position in the input, whole and fractional parts
+        let pos = n as u64 * from as u64;
+        let center = (pos / to as u64) as i64;
+        let frac = (pos % to as u64) as f64 / to as f64;
+
+        acc.iter_mut().for_each(|a| *a = 0.0);
+        let first = (center - reach + 1).max(0);
+        let last = (center + reach).min(frames as i64 - 1);
+        for i in first..=last {
+            let weight = kernel((i - center) as f64 - frac, cutoff, half_width);
+            let frame = &audio[i as usize * channels..(i as usize + 1) * channels];
+            for (a, &s) in acc.iter_mut().zip(frame) {
+                *a += s.to_f32() as f64 * weight;
+            }
+        }
+        out.extend(acc.iter().map(|&a| S::from_f32(a as f32)));
+    }
+
+    out
+}
+
+// Lowpass at `cutoff` of the input Nyquist, Blackman windowed
+fn kernel(x: f64, cutoff: f64, half_width: f64) -> f64 {
+    use std::f64::consts::PI;
+
+    if x.abs() >= half_width {
+        return 0.0;
+    }
+    let sinc = if x == 0.0 {
+        1.0
+    } else {
+        (PI * cutoff * x).sin() / (PI * cutoff * x)
+    };
+    let w = PI * (x / half_width + 1.0);
+    let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+    cutoff * sinc * window
+}
+
+// The one Opus rate that keeps all of `sps`
+fn opus_rate_for(sps: u32) -> SampleRate {
+    match sps {
+        0..=8000 => SampleRate::Hz8000,
+        8001..=12000 => SampleRate::Hz12000,
+        12001..=16000 => SampleRate::Hz16000,
+        16001..=24000 => SampleRate::Hz24000,
+        _ => SampleRate::Hz48000,
+    }
+}
+
+/// Same as `encode_at`, for audio at any rate (e.g. 44.1 kHz from a CD or a
+/// sound card). It's resampled first to the lowest Opus rate above it
+pub fn encode_resampled(
+    sps: u32,
+    channels: u8,
+    audio: &[i16],
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, Error> {
+    if sps == 0 {
+        return Err(Error::InvalidSps);
+    }
+    let rate = opus_rate_for(sps);
+    let audio = resample(audio, channels, sps, rate.as_hz());
+    encode_at(rate, channels, &audio, options)
+}
+
+/// Same as `decode_at`, but the audio comes out at any rate. It's decoded at
+/// 48 kHz and resampled from there
+pub fn decode_resampled<T: Read>(data: T, sps: u32) -> Result<(Vec<i16>, PlayData), Error> {
+    if sps == 0 {
+        return Err(Error::InvalidSps);
+    }
+    let rate = SampleRate::from_hz(sps).unwrap_or(SampleRate::Hz48000);
+    let (audio, play_data) = decode_at(data, rate)?;
+    let audio = resample(&audio, play_data.channels as u8, rate.as_hz(), sps);
+    Ok((audio, play_data))
+}
@@ -0,0 +1,132 @@
+// Ogg Opus as a rodio `Source`, decoding as it plays
+
+use std::io::{Read, Seek};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::source::SeekError;
+use rodio::{ChannelCount, SampleRate, Source};
+
+use crate::{Decoder, Error};
+
+const RODIO_SPS: u32 = 48000;
+
+/**Plays Ogg Opus through rodio, `play_raw(OggOpusSource::new(file)?)` or
+`Sink::append`. Audio comes at 48 kHz with the stream's own channels, as
+`f32` straight from the decoder, and seeking (`try_seek`) is exact to the
+sample. A stream broken halfway ends there, `error` tells why.*/
+pub struct OggOpusSource<T: Read + Seek> {
+    decoder: Decoder<T, RODIO_SPS>,
+    duration: Option<Duration>,
+    // Current frame and how much of it went out, only used up at the end
+    frame: Vec<f32>,
+    read: usize,
+    channels: ChannelCount,
+    ended: bool,
+    // What ended it early, if anything did
+    error: Option<Error>,
+}
+
+impl<T: Read + Seek> OggOpusSource<T> {
+    /// Reads the headers, and the length from the last page if it can be
+    /// found (`total_duration` is `None` otherwise)
+    pub fn new(data: T) -> Result<Self, Error> {
+        let mut decoder = Decoder::new(data)?;
+        let duration = decoder.duration().ok();
+        let channels = channel_count(&decoder)?;
+        let mut source = Self {
+            decoder,
+            duration,
+            frame: Vec::new(),
+            read: 0,
+            channels,
+            ended: false,
+            error: None,
+        };
+        source.next_frame();
+        Ok(source)
+    }
+
+    /// The error that ended the stream before its end, rodio itself just
+    /// sees the samples running out
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    fn fail(&mut self, error: Error) {
+        self.error = Some(error);
+        self.ended = true;
+    }
+
+    // Gets the next frame with anything in it, so that a span never looks
+    // over before the audio is
+    fn next_frame(&mut self) {
+        self.read = 0;
+        self.frame.clear();
+        while !self.ended && self.frame.is_empty() {
+            match self.decoder.next_frame_float() {
+                Ok(Some(frame)) => match channel_count(&self.decoder) {
+                    Ok(channels) => {
+                        self.frame = frame.samples;
+                        self.channels = channels;
+                    }
+                    Err(e) => self.fail(e),
+                },
+                Ok(None) => self.ended = true,
+                Err(e) => self.fail(e),
+            }
+        }
+    }
+}
+
+impl<T: Read + Seek> Iterator for OggOpusSource<T> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = *self.frame.get(self.read)?;
+        self.read += 1;
+        if self.read == self.frame.len() {
+            self.next_frame();
+        }
+        Some(sample)
+    }
+}
+
+impl<T: Read + Seek> Source for OggOpusSource<T> {
+    fn current_span_len(&self) -> Option<usize> {
+        Some(self.frame.len() - self.read)
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        const {
+            match SampleRate::new(RODIO_SPS) {
+                Some(sps) => sps,
+                None => panic!("Wrong SampleRate"),
+            }
+        }
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.decoder
+            .seek_to_time(pos)
+            .map_err(|e| SeekError::Other(Arc::new(e)))?;
+        self.ended = false;
+        self.error = None;
+        self.next_frame();
+        Ok(())
+    }
+}
+
+// Never 0, the decoder doesn't take such streams
+fn channel_count<T: Read>(decoder: &Decoder<T, RODIO_SPS>) -> Result<ChannelCount, Error> {
+    let channels = decoder.play_data().channels;
+    ChannelCount::new(channels).ok_or(Error::BadChannelCount(channels as u8))
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use crate::common::*;
+use crate::encode::EncoderCore;
+use crate::page;
+use crate::{EncoderOptions, Error, Tags};
+
+/// Sample rate used by `SttEncoder`, what most speech-to-text services expect
+pub const STT_SPS: u32 = 16000;
+
+/// Piece of an Ogg Opus stream along with the span of audio it holds
+#[derive(Debug, Clone)]
+pub struct TimedChunk {
+    pub data: Vec<u8>,
+    pub start: Duration,
+    pub end: Duration,
+}
+
+/**Streaming encoder preset for speech-to-text services: 16 kHz mono at
+24 kb/s. Each push gives back the Ogg data encoded so far tagged with the
+span of audio it covers, ready to be sent to a websocket API that needs
+time-aligned audio. The first chunk carries the headers too.*/
+pub struct SttEncoder {
+    inner: EncoderCore<STT_SPS, 1>,
+    last_end: Duration,
+}
+
+impl SttEncoder {
+    pub fn new() -> Result<Self, Error> {
+        let mut inner = EncoderCore::with_options(&Tags::default(), &EncoderOptions::voice())?;
+        inner.set_flush_pages(true);
+        Ok(Self {
+            inner,
+            last_end: Duration::ZERO,
+        })
+    }
+
+    /// `None` if there wasn't enough audio yet for a whole packet
+    pub fn push_samples(&mut self, audio: &[i16]) -> Result<Option<TimedChunk>, Error> {
+        self.inner.push_samples(audio)?;
+        let data = self.inner.take_output();
+        let pre_skip = self.inner.pre_skip();
+        Ok(make_chunk(&mut self.last_end, data, pre_skip))
+    }
+
+    /// Gives back the end of the stream
+    pub fn finish(mut self) -> Result<Option<TimedChunk>, Error> {
+        let pre_skip = self.inner.pre_skip();
+        let (data, _) = self.inner.finish()?;
+        Ok(make_chunk(&mut self.last_end, data, pre_skip))
+    }
+}
+
+fn make_chunk(last_end: &mut Duration, data: Vec<u8>, pre_skip: u16) -> Option<TimedChunk> {
+    if data.is_empty() {
+        return None;
+    }
+
+    // Data always ends in a whole page, the last one tells where we are
+    let mut end_granule = 0;
+    let mut rest = &data[..];
+    while let Some(len) = page::page_len(rest) {
+        end_granule = page::granule(rest);
+        rest = &rest[len..];
+    }
+
+    let start = *last_end;
+    let end = samples_to_duration(end_granule.saturating_sub(pre_skip as u64), OGG_OPUS_SPS);
+    *last_end = end.max(start);
+
+    Some(TimedChunk {
+        data,
+        start,
+        end: *last_end,
+    })
+}
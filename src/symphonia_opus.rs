@@ -0,0 +1,359 @@
+// Ogg Opus for Symphonia: a codec `Decoder` over libopus and a
+// `FormatReader` for the Ogg side, both to be registered into Symphonia's
+// own `CodecRegistry` and `Probe`
+
+use std::io::{Seek, SeekFrom};
+
+use symphonia_core::audio::{
+    AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec,
+};
+use symphonia_core::codecs::{
+    CodecDescriptor, CodecParameters, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
+};
+use symphonia_core::errors::{self, SeekErrorKind};
+use symphonia_core::formats::util::trim_packet;
+use symphonia_core::formats::{
+    Cue, FormatOptions, FormatReader, Packet, SeekMode, SeekTo, SeekedTo, Track,
+};
+use symphonia_core::io::{MediaSource, MediaSourceStream, ReadBytes};
+use symphonia_core::meta::{Metadata, MetadataBuilder, MetadataLog, Tag, Value};
+use symphonia_core::probe::{Descriptor, Instantiate, QueryDescriptor};
+use symphonia_core::units::TimeBase;
+use symphonia_core::{support_codec, support_format};
+
+use crate::common::{MAX_FRAME_SAMPLES, OGG_OPUS_SPS, OPUS_MAGIC_HEADER, UNSET_GRANULE};
+use crate::decode::{decoder_mapping, find_page, last_granule, Codec};
+use crate::edit::PRE_ROLL;
+use crate::headers::OpusHead;
+use crate::page::{OpusPackets, HEADER_SIZE};
+use crate::{Error, Tags, Toc};
+
+/**Symphonia's codec `Decoder` for `CODEC_TYPE_OPUS`, add it to a
+`CodecRegistry` with `register_all::<OpusDecoder>()`. It goes by the OpusHead
+in `extra_data`, which both `OggOpusReader` and Symphonia's own Ogg reader put
+there. Audio comes at 48 kHz as `f32`, in Symphonia's channel order, with the
+header's output gain applied. Streams whose channels have no defined meaning
+(mapping family 255) aren't supported.*/
+pub struct OpusDecoder {
+    codec: Codec,
+    params: CodecParameters,
+    // Output channel to decoded one
+    order: &'static [usize],
+    decoded: Vec<f32>,
+    buffer: AudioBuffer<f32>,
+}
+
+// libopus' decoders aren't `Sync`, but they're only ever used through
+// `&mut self`
+unsafe impl Sync for OpusDecoder {}
+
+impl symphonia_core::codecs::Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> errors::Result<Self> {
+        let head = match params.extra_data.as_deref() {
+            Some(extra_data) => OpusHead::from_packet(extra_data).map_err(into_symphonia)?,
+            None => return errors::decode_error("opus: no OpusHead in extra_data"),
+        };
+        let (channels, order) = match layout(&head) {
+            Some(layout) => layout,
+            None => return errors::unsupported_error("opus: channels with no defined layout"),
+        };
+        let mapping = decoder_mapping(&head).map_err(into_symphonia)?;
+        let mut codec =
+            Codec::open::<OGG_OPUS_SPS>(head.channels, mapping.as_ref()).map_err(into_symphonia)?;
+        codec
+            .set_gain(head.output_gain as i32)
+            .map_err(into_symphonia)?;
+
+        let spec = SignalSpec::new(OGG_OPUS_SPS, channels);
+        Ok(Self {
+            codec,
+            params: params.clone(),
+            order,
+            decoded: vec![0.0; MAX_FRAME_SAMPLES * order.len()],
+            buffer: AudioBuffer::new(MAX_FRAME_SAMPLES as u64, spec),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        // Only fails for a broken decoder, which would fail decoding as well
+        let _ = self.codec.reset();
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> errors::Result<AudioBufferRef<'_>> {
+        self.buffer.clear();
+        let samples = self
+            .codec
+            .decode_float(Some(&packet.data), &mut self.decoded, false)
+            .map_err(into_symphonia)?;
+
+        self.buffer.render_reserved(Some(samples));
+        let channels = self.order.len();
+        for (out, &from) in self.order.iter().enumerate() {
+            let decoded = self.decoded[from..].iter().step_by(channels);
+            for (sample, &value) in self.buffer.chan_mut(out).iter_mut().zip(decoded) {
+                *sample = value;
+            }
+        }
+        self.buffer
+            .trim(packet.trim_start as usize, packet.trim_end as usize);
+        Ok(self.buffer.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buffer.as_audio_buffer_ref()
+    }
+}
+
+/**Symphonia's `FormatReader` for Ogg Opus, add it to a `Probe` with
+`register_all::<OggOpusReader>()`. The (first) Opus stream is the only track,
+its id being the stream serial, and its tags the metadata. Seeking is by
+bisection over granule positions, as it is for `Decoder`, landing 80 ms early
+as RFC 7845 asks. With `enable_gapless`, packets come trimmed of the pre-skip
+and the end padding.
+
+Ogg files are all marked by `OggS`, so `score` looks for the OpusHead in the
+first page and gives 0 without one, and only `.opus` and `audio/opus` are
+claimed. Symphonia 0.5 doesn't score yet, it takes the first reader
+registered for a marker: next to its own Ogg reader, register that one first
+(it gives Opus tracks to `OpusDecoder` as well), or only this one where
+nothing but Opus is expected.*/
+pub struct OggOpusReader {
+    packets: OpusPackets<MediaSourceStream>,
+    tracks: Vec<Track>,
+    metadata: MetadataLog,
+    serial: u32,
+    pre_skip: u64,
+    gapless: bool,
+    // Per channel samples after trimming, from the last granule
+    frames: Option<u64>,
+    // Where the stream begins in the source
+    start: u64,
+    // 48 kHz granule at which the next packet starts
+    next_ts: u64,
+}
+
+impl QueryDescriptor for OggOpusReader {
+    fn query() -> &'static [Descriptor] {
+        &[support_format!(
+            "ogg-opus",
+            "Ogg Opus",
+            &["opus"],
+            &["audio/opus"],
+            &[b"OggS"]
+        )]
+    }
+
+    // Whatever doesn't reach the OpusHead can't be told apart from Vorbis or
+    // FLAC in Ogg
+    fn score(context: &[u8]) -> u8 {
+        let body = HEADER_SIZE + context.get(HEADER_SIZE - 1).copied().unwrap_or(0) as usize;
+        match context.get(body..body + OPUS_MAGIC_HEADER.len()) {
+            Some(magic) if context.starts_with(b"OggS") && magic == OPUS_MAGIC_HEADER => 255,
+            _ => 0,
+        }
+    }
+}
+
+impl FormatReader for OggOpusReader {
+    fn try_new(source: MediaSourceStream, options: &FormatOptions) -> errors::Result<Self> {
+        let start = source.pos();
+        let mut packets = OpusPackets::new(source);
+        let head = packets
+            .next_packet()
+            .map_err(into_symphonia)?
+            .ok_or_else(|| into_symphonia(Error::MissingOpusHead))?;
+        let serial = head.stream_serial();
+        let opus_head = OpusHead::from_packet(&head.data).map_err(into_symphonia)?;
+        let tags = packets
+            .next_packet()
+            .map_err(into_symphonia)?
+            .ok_or_else(|| into_symphonia(Error::MissingOpusTags))?;
+        let tags = Tags::from_packet(&tags.data).map_err(into_symphonia)?;
+
+        let pre_skip = opus_head.pre_skip as u64;
+        let gapless = options.enable_gapless;
+        // Only a seekable source can tell its length without being read
+        let data = packets.get_mut();
+        let granule = match (data.is_seekable(), data.byte_len()) {
+            (true, Some(end)) => {
+                let position = data.stream_position()?;
+                let last = last_granule(data, serial, start, end).ok();
+                data.seek(SeekFrom::Start(position))?;
+                last
+            }
+            _ => None,
+        };
+        let frames = granule.map(|granule| granule.saturating_sub(pre_skip));
+
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_OPUS)
+            .with_sample_rate(OGG_OPUS_SPS)
+            .with_time_base(TimeBase::new(1, OGG_OPUS_SPS))
+            .with_delay(opus_head.pre_skip as u32)
+            .with_extra_data(head.data.into_boxed_slice());
+        if let Some((channels, _)) = layout(&opus_head) {
+            params.with_channels(channels);
+        }
+        if let Some(n_frames) = if gapless { frames } else { granule } {
+            params.with_n_frames(n_frames);
+        }
+
+        let mut builder = MetadataBuilder::new();
+        for (key, value) in &tags.comments {
+            builder.add_tag(Tag::new(None, key, Value::from(value.as_str())));
+        }
+        let mut metadata = MetadataLog::default();
+        metadata.push(builder.metadata());
+
+        Ok(Self {
+            packets,
+            tracks: vec![Track::new(serial, params)],
+            metadata,
+            serial,
+            pre_skip,
+            gapless,
+            frames,
+            start,
+            next_ts: 0,
+        })
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &[]
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    /// Always accurate, what it lands on is never after `to`
+    fn seek(&mut self, _mode: SeekMode, to: SeekTo) -> errors::Result<SeekedTo> {
+        let required_ts = match to {
+            SeekTo::TimeStamp { track_id, .. } if track_id != self.serial => {
+                return errors::seek_error(SeekErrorKind::InvalidTrack)
+            }
+            SeekTo::TimeStamp { ts, .. } => ts,
+            SeekTo::Time { time, .. } => TimeBase::new(1, OGG_OPUS_SPS).calc_timestamp(time),
+        };
+        let target = match self.gapless {
+            true => required_ts.saturating_add(self.pre_skip),
+            false => required_ts,
+        };
+        if self
+            .frames
+            .is_some_and(|frames| target > frames + self.pre_skip)
+        {
+            return errors::seek_error(SeekErrorKind::OutOfRange);
+        }
+
+        let data = self.packets.get_mut();
+        let end = match (data.is_seekable(), data.byte_len()) {
+            (true, Some(end)) => end,
+            _ => return errors::seek_error(SeekErrorKind::Unseekable),
+        };
+        let goal = target.saturating_sub(PRE_ROLL);
+        let page = find_page(data, self.serial, goal, self.start, end).map_err(into_symphonia)?;
+        data.seek(SeekFrom::Start(page.end()))?;
+        self.packets.seeked(page.end());
+        self.next_ts = page.granule;
+
+        let actual_ts = match self.gapless {
+            true => page.granule.saturating_sub(self.pre_skip),
+            false => page.granule,
+        };
+        Ok(SeekedTo {
+            track_id: self.serial,
+            required_ts,
+            actual_ts,
+        })
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn next_packet(&mut self) -> errors::Result<Packet> {
+        let packet = match self.packets.next_packet().map_err(into_symphonia)? {
+            Some(packet) => packet,
+            None => return errors::end_of_stream_error(),
+        };
+        let ts = self.next_ts;
+        let dur = Toc::parse(&packet.data).map_or(0, |toc| toc.samples() as u64);
+        self.next_ts += dur;
+        // Streams cut from a live one don't start at 0, their granules tell
+        if packet.last_in_page() && packet.absgp_page() != UNSET_GRANULE {
+            self.next_ts = self.next_ts.max(packet.absgp_page());
+        }
+
+        let mut out = Packet::new_from_boxed_slice(self.serial, ts, dur, packet.data.into());
+        if self.gapless {
+            trim_packet(&mut out, self.pre_skip as u32, self.frames);
+        }
+        Ok(out)
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.packets.into_inner()
+    }
+}
+
+// Symphonia's channels for the header's, and which decoded channel goes in
+// each of them: Vorbis order (RFC 7845 5.1.1.2) is not Symphonia's
+fn layout(head: &OpusHead) -> Option<(Channels, &'static [usize])> {
+    const FL: Channels = Channels::FRONT_LEFT;
+    const FR: Channels = Channels::FRONT_RIGHT;
+    const FC: Channels = Channels::FRONT_CENTRE;
+    const LFE: Channels = Channels::LFE1;
+    const RL: Channels = Channels::REAR_LEFT;
+    const RR: Channels = Channels::REAR_RIGHT;
+    const RC: Channels = Channels::REAR_CENTRE;
+    const SL: Channels = Channels::SIDE_LEFT;
+    const SR: Channels = Channels::SIDE_RIGHT;
+
+    if head.channel_mapping_family > 1 {
+        return None;
+    }
+    Some(match head.channels {
+        1 => (FL, &[0]),
+        2 => (FL | FR, &[0, 1]),
+        3 => (FL | FC | FR, &[0, 2, 1]),
+        4 => (FL | FR | RL | RR, &[0, 1, 2, 3]),
+        5 => (FL | FC | FR | RL | RR, &[0, 2, 1, 3, 4]),
+        6 => (FL | FC | FR | RL | RR | LFE, &[0, 2, 1, 5, 3, 4]),
+        7 => (FL | FC | FR | SL | SR | RC | LFE, &[0, 2, 1, 6, 5, 3, 4]),
+        8 => (
+            FL | FC | FR | SL | SR | RL | RR | LFE,
+            &[0, 2, 1, 7, 5, 6, 3, 4],
+        ),
+        _ => return None,
+    })
+}
+
+fn into_symphonia(e: Error) -> errors::Error {
+    match e {
+        Error::OggWriteError(e) => errors::Error::IoError(e),
+        Error::OggReadError(ogg::OggReadError::ReadError(e)) => errors::Error::IoError(e),
+        Error::UnsupportedVersion { .. } => errors::Error::Unsupported("opus: header version"),
+        Error::UnsupportedMappingFamily(_) => {
+            errors::Error::Unsupported("opus: channel mapping family")
+        }
+        Error::MissingOpusHead => errors::Error::DecodeError("opus: no OpusHead header"),
+        Error::MissingOpusTags | Error::TruncatedCommentHeader { .. } => {
+            errors::Error::DecodeError("opus: bad OpusTags header")
+        }
+        _ => errors::Error::DecodeError("opus: malformed stream"),
+    }
+}
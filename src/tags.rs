@@ -0,0 +1,283 @@
+use std::io::{Read, Write};
+use std::ops::Range;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::common::*;
+use crate::cue::{CueSheet, CUESHEET_TAG};
+use crate::headers::OpusHead;
+use crate::page::{replace_headers, OpusPackets};
+use crate::picture::{Picture, PICTURE_TAG};
+use crate::Error;
+
+const OPUS_TAGS_MAGIC: [u8; 8] = [b'O', b'p', b'u', b's', b'T', b'a', b'g', b's'];
+
+// Field names from the Vorbis comment spec
+pub const TITLE: &str = "TITLE";
+pub const ARTIST: &str = "ARTIST";
+pub const ALBUM: &str = "ALBUM";
+pub const ENCODER: &str = "ENCODER";
+// Gains from RFC 7845, in addition to the header's output gain
+pub const R128_TRACK_GAIN: &str = "R128_TRACK_GAIN";
+pub const R128_ALBUM_GAIN: &str = "R128_ALBUM_GAIN";
+
+const LOOP_START: &str = "LOOPSTART";
+const LOOP_LENGTH: &str = "LOOPLENGTH";
+const LOOP_END: &str = "LOOPEND";
+
+/**Contents of the comment header (OpusTags): the vendor string plus user
+comments in `KEY=value` form. Keys are compared ignoring case, as the spec
+says, and the same key might appear more than once.*/
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tags {
+    pub vendor: String,
+    pub comments: Vec<(String, String)>,
+}
+
+impl Default for Tags {
+    fn default() -> Self {
+        Self {
+            vendor: VENDOR_STR.to_string(),
+            comments: Vec::new(),
+        }
+    }
+}
+
+impl Tags {
+    /// First value for `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.comments
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Adds a comment, keeping any other with the same key
+    pub fn add<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.comments.push((key.into(), value.into()));
+    }
+
+    /// Replaces every comment with this key by a single one
+    pub fn set<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        let key = key.into();
+        self.remove(&key);
+        self.comments.push((key, value.into()));
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.comments.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    }
+
+    /// Same as `set`, for building tags in one go:
+    /// `Tags::default().with("TITLE", "Intro").with("ARTIST", "Me")`
+    pub fn with<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.get(TITLE)
+    }
+
+    pub fn artist(&self) -> Option<&str> {
+        self.get(ARTIST)
+    }
+
+    pub fn album(&self) -> Option<&str> {
+        self.get(ALBUM)
+    }
+
+    /// `R128_TRACK_GAIN`, in 1/256 dB (Q7.8) as the tag has it
+    pub fn track_gain(&self) -> Option<i16> {
+        self.get(R128_TRACK_GAIN)?.trim().parse().ok()
+    }
+
+    pub fn set_track_gain(&mut self, gain: i16) {
+        self.set(R128_TRACK_GAIN, gain.to_string());
+    }
+
+    /// `R128_ALBUM_GAIN`, in 1/256 dB (Q7.8) as the tag has it
+    pub fn album_gain(&self) -> Option<i16> {
+        self.get(R128_ALBUM_GAIN)?.trim().parse().ok()
+    }
+
+    pub fn set_album_gain(&mut self, gain: i16) {
+        self.set(R128_ALBUM_GAIN, gain.to_string());
+    }
+
+    /// Loop region from `LOOPSTART` plus either `LOOPLENGTH` or `LOOPEND`
+    pub fn loop_points(&self) -> Option<LoopPoints> {
+        let start = self.get(LOOP_START)?.trim().parse().ok()?;
+        let length = match self.get(LOOP_LENGTH) {
+            Some(length) => length.trim().parse().ok()?,
+            None => {
+                let end: u64 = self.get(LOOP_END)?.trim().parse().ok()?;
+                end.checked_sub(start)?
+            }
+        };
+
+        Some(LoopPoints { start, length })
+    }
+
+    /// Writes `LOOPSTART` and `LOOPLENGTH`
+    pub fn set_loop_points(&mut self, points: LoopPoints) {
+        self.remove(LOOP_END);
+        self.set(LOOP_START, points.start.to_string());
+        self.set(LOOP_LENGTH, points.length.to_string());
+    }
+
+    /// CUE sheet in the `CUESHEET` comment, if there's a valid one
+    pub fn cue_sheet(&self) -> Option<CueSheet> {
+        CueSheet::parse(self.get(CUESHEET_TAG)?).ok()
+    }
+
+    pub fn set_cue_sheet(&mut self, cue: &CueSheet) {
+        self.set(CUESHEET_TAG, cue.to_string());
+    }
+
+    /// Every valid picture in `METADATA_BLOCK_PICTURE` comments, in order
+    pub fn pictures(&self) -> Vec<Picture> {
+        self.comments
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(PICTURE_TAG))
+            .filter_map(|(_, v)| Picture::parse(v).ok())
+            .collect()
+    }
+
+    /// Adds a picture, keeping those already there
+    pub fn add_picture(&mut self, picture: &Picture) {
+        self.add(PICTURE_TAG, picture.to_base64());
+    }
+
+    pub fn remove_pictures(&mut self) {
+        self.remove(PICTURE_TAG);
+    }
+
+    pub(crate) fn to_packet(&self) -> Vec<u8> {
+        let mut packet = OPUS_TAGS_MAGIC.to_vec();
+        push_string(&mut packet, self.vendor.as_bytes());
+        push_len(&mut packet, self.comments.len());
+        for (key, value) in &self.comments {
+            push_string(&mut packet, format!("{}={}", key, value).as_bytes());
+        }
+        packet
+    }
+
+    pub(crate) fn from_packet(data: &[u8]) -> Result<Self, Error> {
+        if !data.starts_with(&OPUS_TAGS_MAGIC) {
+            return Err(Error::MissingOpusTags);
+        }
+
+        let mut rest = &data[8..];
+        let truncated = |rest: &[u8]| Error::TruncatedCommentHeader {
+            offset: data.len() - rest.len(),
+        };
+        let vendor = read_string(&mut rest).ok_or_else(|| truncated(rest))?;
+        let num_comments = read_len(&mut rest).ok_or_else(|| truncated(rest))?;
+
+        // Don't trust the count for the allocation, a comment is 4 bytes at least
+        let mut comments = Vec::with_capacity(num_comments.min(rest.len() / 4));
+        for _ in 0..num_comments {
+            let comment = read_string(&mut rest).ok_or_else(|| truncated(rest))?;
+            comments.push(split_comment(comment));
+        }
+
+        Ok(Self { vendor, comments })
+    }
+
+    /// Whatever can be read of a comment header that's cut short, `None` if
+    /// `data` isn't one at all
+    pub(crate) fn from_packet_lenient(data: &[u8]) -> Option<Self> {
+        if !data.starts_with(&OPUS_TAGS_MAGIC) {
+            return None;
+        }
+
+        let mut rest = &data[8..];
+        let mut tags = Self {
+            vendor: read_string(&mut rest).unwrap_or_default(),
+            comments: Vec::new(),
+        };
+        for _ in 0..read_len(&mut rest).unwrap_or(0) {
+            match read_string(&mut rest) {
+                Some(comment) => tags.comments.push(split_comment(comment)),
+                None => break,
+            }
+        }
+        Some(tags)
+    }
+}
+
+// Comments without '=' are not valid, but worth keeping anyway
+pub(crate) fn split_comment(comment: String) -> (String, String) {
+    match comment.find('=') {
+        Some(pos) => (comment[..pos].to_string(), comment[pos + 1..].to_string()),
+        None => (comment, String::new()),
+    }
+}
+
+/// Loop region of a track, in 48 kHz samples counted from the start of the
+/// decoded audio (so after the pre-skip), as game engines expect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoopPoints {
+    pub start: u64,
+    pub length: u64,
+}
+
+impl LoopPoints {
+    /// Loop region as indices into an interleaved buffer
+    pub fn sample_range(&self, sps: u32, channels: u16) -> Range<usize> {
+        let to_index = |val: u64| (calc_sr_u64(val, OGG_OPUS_SPS, sps) * channels as u64) as usize;
+        to_index(self.start)..to_index(self.start.saturating_add(self.length))
+    }
+}
+
+/**Copies a stream to `output` with `tags` as its comment header instead,
+nothing gets re-encoded: the ID header and the audio pages are the same
+bytes, only renumbered (and their checksum updated) if the tags take more
+or fewer pages than before. Only the first Opus stream is retagged, other
+streams multiplexed with it or chained after it are copied as they are. For
+editing metadata without any loss*/
+pub fn rewrite_tags<T: Read, W: Write>(
+    mut input: T,
+    mut output: W,
+    tags: &Tags,
+) -> Result<(), Error> {
+    let mut stream = Vec::new();
+    input.read_to_end(&mut stream)?;
+
+    let head = OpusPackets::new(stream.as_slice())
+        .next_packet()?
+        .ok_or(Error::MissingOpusHead)?;
+    OpusHead::from_packet(&head.data)?;
+    output.write_all(&replace_headers(&stream, &head.data, &tags.to_packet())?)?;
+    Ok(())
+}
+
+fn push_len(packet: &mut Vec<u8>, len: usize) {
+    let mut buf = [0u8; 4];
+    LittleEndian::write_u32(&mut buf, len as u32);
+    packet.extend_from_slice(&buf);
+}
+
+fn push_string(packet: &mut Vec<u8>, s: &[u8]) {
+    push_len(packet, s.len());
+    packet.extend_from_slice(s);
+}
+
+// `None` if there's not enough left, leaving `data` as it was
+fn read_len(data: &mut &[u8]) -> Option<usize> {
+    let len = LittleEndian::read_u32(data.get(..4)?) as usize;
+    *data = &data[4..];
+    Some(len)
+}
+
+fn read_string(data: &mut &[u8]) -> Option<String> {
+    let mut rest = *data;
+    let len = read_len(&mut rest)?;
+    // Be forgiving with encoders that don't write proper UTF-8
+    let s = String::from_utf8_lossy(rest.get(..len)?).into_owned();
+    *data = &rest[len..];
+    Some(s)
+}
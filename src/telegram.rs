@@ -0,0 +1,43 @@
+// Voice notes as Telegram's API wants them: Ogg Opus plus the waveform its
+// clients draw on the message bubble
+
+use crate::{encode_with_options, EncoderOptions, Error, Preset};
+
+// Bars in the waveform and how many levels each one has
+const WAVEFORM_BARS: usize = 100;
+const WAVEFORM_BITS: usize = 5;
+const WAVEFORM_MAX: u32 = (1 << WAVEFORM_BITS) - 1;
+
+/// Encodes mono audio as a voice note, giving back the Ogg Opus bytes and
+/// its packed waveform, ready for `sendVoice` or `inputMediaUploadedDocument`
+pub fn encode_voice_note<const S_PS: u32>(pcm: &[i16]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let opus = encode_with_options::<S_PS, 1>(pcm, &EncoderOptions::preset(Preset::VoiceMessage))?;
+    Ok((opus, waveform(pcm)))
+}
+
+/**Waveform of mono audio the way Telegram clients make it: 100 bars of the
+peak level in each stretch, relative to the loudest one, 5 bits each
+packed from the lowest bit up (63 bytes). Empty audio gives all bars at 0*/
+pub fn waveform(pcm: &[i16]) -> Vec<u8> {
+    let mut peaks = [0u32; WAVEFORM_BARS];
+    if !pcm.is_empty() {
+        for (i, &sample) in pcm.iter().enumerate() {
+            let bar = i * WAVEFORM_BARS / pcm.len();
+            peaks[bar] = peaks[bar].max(sample.unsigned_abs() as u32);
+        }
+    }
+    let max = peaks.iter().copied().max().unwrap_or(0).max(1);
+
+    let mut packed = vec![0u8; (WAVEFORM_BARS * WAVEFORM_BITS).div_ceil(8)];
+    for (i, &peak) in peaks.iter().enumerate() {
+        let value = peak * WAVEFORM_MAX / max;
+        let bit = i * WAVEFORM_BITS;
+        // A value might go over into the next byte
+        let bits = (value as u16) << (bit % 8);
+        packed[bit / 8] |= bits as u8;
+        if let Some(next) = packed.get_mut(bit / 8 + 1) {
+            *next |= (bits >> 8) as u8;
+        }
+    }
+    packed
+}
@@ -0,0 +1,42 @@
+// The async API over tokio's `AsyncRead`, `AsyncWrite` and `AsyncSeek`, which
+// are futures-io's in all but name: the reader or writer gets wrapped in
+// tokio-util's `Compat` and goes on to `decode_async` and the rest
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+use crate::{AsyncDecoder, AsyncEncoder, DecodeOptions, EncoderOptions, Error, PlayData, Tags};
+
+/// `crate::decode_async`, from a tokio reader
+pub async fn decode_async<T: AsyncRead + Unpin, const TARGET_SPS: u32>(
+    data: T,
+) -> Result<(Vec<i16>, PlayData), Error> {
+    crate::decode_async::<_, TARGET_SPS>(data.compat()).await
+}
+
+/// `crate::decode_async_with_options`, from a tokio reader
+pub async fn decode_async_with_options<T: AsyncRead + Unpin, const TARGET_SPS: u32>(
+    data: T,
+    options: &DecodeOptions,
+) -> Result<(Vec<i16>, PlayData), Error> {
+    crate::decode_async_with_options::<_, TARGET_SPS>(data.compat(), options).await
+}
+
+/// `AsyncDecoder::with_options` over a tokio reader, it can seek if that
+/// can (`tokio::fs::File` ...)
+pub async fn decoder<T: AsyncRead + Unpin, const TARGET_SPS: u32>(
+    data: T,
+    options: &DecodeOptions,
+) -> Result<AsyncDecoder<Compat<T>, TARGET_SPS>, Error> {
+    AsyncDecoder::with_options(data.compat(), options).await
+}
+
+/// `AsyncEncoder::with_options` over a tokio writer, `finish` gives it back
+/// through `Compat::into_inner`
+pub async fn encoder<W: AsyncWrite + Unpin, const S_PS: u32, const NUM_CHANNELS: u8>(
+    writer: W,
+    tags: &Tags,
+    options: &EncoderOptions,
+) -> Result<AsyncEncoder<Compat<W>, S_PS, NUM_CHANNELS>, Error> {
+    AsyncEncoder::with_options(writer.compat_write(), tags, options).await
+}
@@ -0,0 +1,141 @@
+// Straight from WAV to Ogg Opus and back, for CLI tools and tests that would
+// otherwise all write the same glue. Only 16-bit PCM, which is all Opus
+// takes in anyway, so a reader of a few chunks does it
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use crate::{decode, encode_at, EncoderOptions, Error, PlayData, SampleRate};
+
+// What decoded audio is written at, as RFC 7845 recommends playing it
+const WAV_OUT_SPS: u32 = 48000;
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+const FMT_LEN: usize = 16;
+// Data size of WAVs written as they're recorded, that never get to go back
+const STREAMED_LEN: u32 = u32::MAX;
+
+/// Encodes a 16-bit PCM WAV file with the default options
+pub fn encode_wav_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
+    encode_wav(
+        BufReader::new(File::open(path)?),
+        &EncoderOptions::default(),
+    )
+}
+
+/**Encodes 16-bit PCM WAV data, at its own rate and channels (1 to 8). Rates
+Opus doesn't work at (44.1 kHz ...) fail with `Error::InvalidSps` unless the
+`resample` feature is on*/
+pub fn encode_wav<R: Read>(data: R, options: &EncoderOptions) -> Result<Vec<u8>, Error> {
+    let (channels, sps, audio) = read_wav(data)?;
+    let channels = u8::try_from(channels).map_err(|_| Error::InvalidChannels)?;
+
+    match SampleRate::from_hz(sps) {
+        Ok(rate) => encode_at(rate, channels, &audio, options),
+        #[cfg(feature = "resample")]
+        Err(_) => crate::encode_resampled(sps, channels, &audio, options),
+        #[cfg(not(feature = "resample"))]
+        Err(e) => Err(e),
+    }
+}
+
+/// Decodes Ogg Opus into a 16-bit PCM WAV file at 48 kHz
+pub fn decode_to_wav<T: Read, P: AsRef<Path>>(data: T, path: P) -> Result<PlayData, Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let play_data = decode_to_wav_writer(data, &mut out)?;
+    out.flush()?;
+    Ok(play_data)
+}
+
+/// Same as `decode_to_wav`, into any writer
+pub fn decode_to_wav_writer<T: Read, W: Write>(data: T, out: W) -> Result<PlayData, Error> {
+    let (audio, play_data) = decode::<T, WAV_OUT_SPS>(data)?;
+    write_wav(out, play_data.channels, WAV_OUT_SPS, &audio)?;
+    Ok(play_data)
+}
+
+// Channels, rate and samples of the first "data" chunk, every other chunk
+// (LIST, fact ...) is skipped. Sizes in the header are never trusted for an
+// allocation, what's actually there is read
+fn read_wav<R: Read>(mut data: R) -> Result<(u16, u32, Vec<i16>), Error> {
+    let mut riff = [0u8; 12];
+    data.read_exact(&mut riff).map_err(|_| Error::InvalidWav)?;
+    if &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+        return Err(Error::InvalidWav);
+    }
+
+    let mut format = None;
+    loop {
+        let mut chunk = [0u8; 8];
+        data.read_exact(&mut chunk).map_err(|_| Error::InvalidWav)?;
+        let len = LittleEndian::read_u32(&chunk[4..]);
+
+        match &chunk[..4] {
+            b"data" => {
+                let (channels, sps) = format.ok_or(Error::InvalidWav)?;
+                let mut body = Vec::new();
+                match len {
+                    // Written as it was recorded, the size never got filled in
+                    0 | STREAMED_LEN => data.read_to_end(&mut body)?,
+                    // Cut short is fine, whatever made it is kept
+                    len => data.take(len as u64).read_to_end(&mut body)?,
+                };
+                let mut audio = vec![0i16; body.len() / 2];
+                LittleEndian::read_i16_into(&body[..audio.len() * 2], &mut audio);
+                return Ok((channels, sps, audio));
+            }
+            b"fmt " if len as usize >= FMT_LEN => {
+                let mut body = Vec::new();
+                (&mut data).take(len as u64).read_to_end(&mut body)?;
+                if body.len() < len as usize {
+                    return Err(Error::InvalidWav);
+                }
+                let tag = LittleEndian::read_u16(&body[0..2]);
+                let bits = LittleEndian::read_u16(&body[14..16]);
+                if (tag != FORMAT_PCM && tag != FORMAT_EXTENSIBLE) || bits != 16 {
+                    return Err(Error::InvalidWav);
+                }
+                format = Some((
+                    LittleEndian::read_u16(&body[2..4]),
+                    LittleEndian::read_u32(&body[4..8]),
+                ));
+            }
+            _ => {
+                let skipped = io::copy(&mut (&mut data).take(len as u64), &mut io::sink())?;
+                if skipped < len as u64 {
+                    return Err(Error::InvalidWav);
+                }
+            }
+        }
+        // Chunks are padded to an even length, not always at the very end
+        if len % 2 == 1 {
+            io::copy(&mut (&mut data).take(1), &mut io::sink())?;
+        }
+    }
+}
+
+fn write_wav<W: Write>(mut out: W, channels: u16, sps: u32, audio: &[i16]) -> Result<(), Error> {
+    let data_len = (audio.len() * 2) as u32;
+    out.write_all(b"RIFF")?;
+    out.write_u32::<LittleEndian>(4 + 8 + FMT_LEN as u32 + 8 + data_len)?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_u32::<LittleEndian>(FMT_LEN as u32)?;
+    out.write_u16::<LittleEndian>(FORMAT_PCM)?;
+    out.write_u16::<LittleEndian>(channels)?;
+    out.write_u32::<LittleEndian>(sps)?;
+    out.write_u32::<LittleEndian>(sps * channels as u32 * 2)?;
+    out.write_u16::<LittleEndian>(channels * 2)?;
+    out.write_u16::<LittleEndian>(16)?;
+    out.write_all(b"data")?;
+    out.write_u32::<LittleEndian>(data_len)?;
+
+    let mut bytes = vec![0u8; audio.len() * 2];
+    LittleEndian::write_i16_into(audio, &mut bytes);
+    out.write_all(&bytes)?;
+    Ok(())
+}